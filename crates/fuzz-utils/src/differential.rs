@@ -0,0 +1,441 @@
+//! Differential fuzzing of arbitrary module transformations.
+//!
+//! A `Differential` generates modules with `wasm-smith`, runs them through a
+//! transformation, validates the result, and compares the execution traces of
+//! the module before and after the transformation in walrus's own
+//! interpreter. Failing inputs are delta-reduced before they are reported.
+
+use crate::Result;
+use anyhow::Context;
+use arbitrary::Unstructured;
+use rand::Rng;
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::panic::{self, AssertUnwindSafe};
+use std::time;
+use walrus::interp::{DummyHost, Instance, Ref, Trap, Val};
+use walrus::{ExportItem, Module};
+
+/// How many times more fuel the transformed module may use than the original.
+const FUEL_SLACK: u64 = 10;
+
+/// Configuration for differentially fuzzing a module transformation.
+pub struct Differential<F> {
+    transform: F,
+    smith: wasm_smith::Config,
+    fuel: u64,
+    input_len: usize,
+    timeout: u64,
+    compare_traps: bool,
+}
+
+impl<F> Differential<F>
+where
+    F: Fn(&mut Module),
+{
+    /// The default number of instructions the original module may execute.
+    pub const DEFAULT_FUEL: u64 = 100_000;
+
+    /// The default number of random bytes handed to `wasm-smith`.
+    pub const DEFAULT_INPUT_LEN: usize = 2048;
+
+    /// The default timeout (in seconds) for `run`.
+    pub const DEFAULT_TIMEOUT_SECS: u64 = 5;
+
+    /// Construct a new configuration for fuzzing `transform`.
+    ///
+    /// By default `wasm-smith` only generates features that the interpreter
+    /// can execute, and exports everything so that there is something to run.
+    pub fn new(transform: F) -> Differential<F> {
+        let smith = wasm_smith::Config {
+            export_everything: true,
+            simd_enabled: false,
+            relaxed_simd_enabled: false,
+            threads_enabled: false,
+            gc_enabled: false,
+            max_memory32_bytes: 1 << 20,
+            max_memory64_bytes: 1 << 20,
+            max_table_elements: 1000,
+            ..Default::default()
+        };
+
+        Differential {
+            transform,
+            smith,
+            fuel: Self::DEFAULT_FUEL,
+            input_len: Self::DEFAULT_INPUT_LEN,
+            timeout: Self::DEFAULT_TIMEOUT_SECS,
+            compare_traps: true,
+        }
+    }
+
+    /// Set the `wasm-smith` configuration used to generate modules.
+    pub fn set_smith_config(mut self, smith: wasm_smith::Config) -> Differential<F> {
+        self.smith = smith;
+        self
+    }
+
+    /// Set the number of instructions the original module may execute.
+    ///
+    /// Modules that run out of fuel are skipped.
+    pub fn set_fuel(mut self, fuel: u64) -> Differential<F> {
+        assert!(fuel > 0);
+        self.fuel = fuel;
+        self
+    }
+
+    /// Set the number of random bytes `run` hands to `wasm-smith`.
+    pub fn set_input_len(mut self, input_len: usize) -> Differential<F> {
+        self.input_len = input_len;
+        self
+    }
+
+    /// Set the timeout (in seconds) for `run`.
+    pub fn set_timeout(mut self, timeout: u64) -> Differential<F> {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Set whether traps must be of the same kind, or whether any trap is as
+    /// good as any other. Transformations that insert their own checks, such
+    /// as bounds checks ending in `unreachable`, need the latter.
+    pub fn set_compare_traps(mut self, compare_traps: bool) -> Differential<F> {
+        self.compare_traps = compare_traps;
+        self
+    }
+
+    /// Check the transformation on a single wasm binary.
+    ///
+    /// Inputs that walrus can't parse, or whose behaviour the interpreter
+    /// can't pin down, pass trivially. Failures are `FailingTransform`s, and
+    /// are not reduced.
+    pub fn check(&self, wasm: &[u8]) -> Result<()> {
+        match self.failure(wasm) {
+            None => Ok(()),
+            Some(failure) => Err(FailingTransform::new(wasm, failure).into()),
+        }
+    }
+
+    /// Check the transformation on a single module given as WAT.
+    pub fn check_wat(&self, wat: &str) -> Result<()> {
+        let wasm = wat::parse_str(wat)?;
+        self.check(&wasm)
+    }
+
+    /// Check the transformation on the module `wasm-smith` generates from
+    /// `data`, such as a fuzzer's input.
+    ///
+    /// Failing inputs are reduced before being returned as a
+    /// `FailingTransform`.
+    pub fn run_one(&self, data: &[u8]) -> Result<()> {
+        let failure = match self.smith(data).and_then(|wasm| self.failure(&wasm)) {
+            Some(failure) => failure,
+            None => return Ok(()),
+        };
+        let (data, failure) = self.reduce(data.to_vec(), failure);
+        let wasm = self
+            .smith(&data)
+            .context("the reduced input should still generate a module")?;
+        Err(FailingTransform::new(&wasm, failure).into())
+    }
+
+    /// Generate and test as many modules as we can within the configured
+    /// timeout budget.
+    ///
+    /// Returns the reduced failing test case, if any.
+    pub fn run(&self, rng: &mut impl Rng) -> Result<()> {
+        let start = time::Instant::now();
+        let timeout = time::Duration::from_secs(self.timeout);
+        let mut data = vec![0; self.input_len];
+        while start.elapsed() < timeout {
+            rng.fill_bytes(&mut data);
+            self.run_one(&data)?;
+        }
+        Ok(())
+    }
+
+    fn smith(&self, data: &[u8]) -> Option<Vec<u8>> {
+        let mut u = Unstructured::new(data);
+        let module = wasm_smith::Module::new(self.smith.clone(), &mut u).ok()?;
+        Some(module.to_bytes())
+    }
+
+    /// How the transformation fails on `wasm`, if it does.
+    fn failure(&self, wasm: &[u8]) -> Option<Failure> {
+        let original = Module::from_buffer(wasm).ok()?;
+        let expected = self.trace(&original, &original, self.fuel)?;
+
+        let transformed = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut module = Module::from_buffer(wasm).unwrap();
+            (self.transform)(&mut module);
+            module.emit_wasm()
+        }));
+        let transformed = match transformed {
+            Ok(transformed) => transformed,
+            Err(payload) => return Some(Failure::Panicked(panic_message(&*payload))),
+        };
+
+        let mut validator =
+            wasmparser::Validator::new_with_features(wasmparser::WasmFeatures::all());
+        if let Err(e) = validator.validate_all(&transformed) {
+            return Some(Failure::Invalid(e.to_string()));
+        }
+        let transformed = match Module::from_buffer(&transformed) {
+            Ok(module) => module,
+            Err(e) => return Some(Failure::Invalid(format!("{:?}", e))),
+        };
+
+        let actual = self
+            .trace(
+                &original,
+                &transformed,
+                self.fuel.saturating_mul(FUEL_SLACK),
+            )
+            .unwrap_or_else(|| vec!["ran out of fuel or hit an unsupported feature".into()]);
+        if expected != actual {
+            return Some(Failure::Mismatch { expected, actual });
+        }
+        None
+    }
+
+    /// Run every function that `original` exports from `module`, and record
+    /// the calls to imports, the results, and finally the exported globals and
+    /// memories.
+    ///
+    /// Returns `None` when the execution doesn't tell us anything: it ran out
+    /// of fuel or stack, or needed something the interpreter doesn't support.
+    fn trace(&self, original: &Module, module: &Module, fuel: u64) -> Option<Vec<String>> {
+        let inconclusive = |trap: &Trap| {
+            matches!(
+                trap,
+                Trap::OutOfFuel | Trap::CallStackExhausted | Trap::Unsupported(_)
+            )
+        };
+        let trap = |trap: &Trap| match trap {
+            // Tag ids needn't survive the transformation, so only compare
+            // the exception's values.
+            Trap::UncaughtException(e) => {
+                format!("uncaught exception: {}", values(module, &e.values))
+            }
+            _ if self.compare_traps => format!("trap: {}", trap),
+            _ => "trap".to_string(),
+        };
+
+        let mut trace = Vec::new();
+        let mut instance = match Instance::with_fuel(module, DummyHost::default(), Some(fuel)) {
+            Ok(instance) => instance,
+            Err(e) if inconclusive(&e) => return None,
+            Err(e) => {
+                trace.push(format!("instantiate: {}", trap(&e)));
+                return Some(trace);
+            }
+        };
+
+        for export in original.exports.iter() {
+            let ExportItem::Function(_) = export.item else {
+                continue;
+            };
+            let Ok(func) = module.exports.get_func(&export.name) else {
+                trace.push(format!("{}: missing", export.name));
+                continue;
+            };
+            let args = module
+                .types
+                .params(module.funcs.get(func).ty())
+                .iter()
+                .map(|ty| Val::default(*ty))
+                .collect::<Vec<_>>();
+            let result = instance.call(func, &args);
+            let calls = instance.host_mut().calls.drain(..).collect::<Vec<_>>();
+            for (import_module, name, args) in calls {
+                trace.push(format!(
+                    "{}: call {}.{}{}",
+                    export.name,
+                    import_module,
+                    name,
+                    values(module, &args)
+                ));
+            }
+            match result {
+                Ok(results) => trace.push(format!("{}: {}", export.name, values(module, &results))),
+                Err(e) if inconclusive(&e) => return None,
+                Err(e) => trace.push(format!("{}: {}", export.name, trap(&e))),
+            }
+        }
+
+        for export in original.exports.iter() {
+            let item = module.exports.iter().find(|e| e.name == export.name);
+            match (export.item, item.map(|e| e.item)) {
+                (ExportItem::Global(_), Some(ExportItem::Global(global))) => {
+                    let value = instance.global(global);
+                    trace.push(format!(
+                        "global {}: {}",
+                        export.name,
+                        values(module, &[value])
+                    ));
+                }
+                (ExportItem::Memory(_), Some(ExportItem::Memory(memory))) => {
+                    let bytes = instance.memory(memory);
+                    let mut hasher = DefaultHasher::new();
+                    bytes.hash(&mut hasher);
+                    trace.push(format!(
+                        "memory {}: {} bytes, hash {:016x}",
+                        export.name,
+                        bytes.len(),
+                        hasher.finish()
+                    ));
+                }
+                (ExportItem::Global(_) | ExportItem::Memory(_), _) => {
+                    trace.push(format!("{}: missing", export.name));
+                }
+                _ => {}
+            }
+        }
+        Some(trace)
+    }
+
+    /// Delta-reduce the `wasm-smith` input `data`, keeping each smaller input
+    /// that still fails the same way.
+    ///
+    /// First chunks of ever smaller sizes are removed, and then each remaining byte is
+    /// zeroed, which steers `wasm-smith` towards its simplest choices.
+    fn reduce(&self, mut data: Vec<u8>, mut failure: Failure) -> (Vec<u8>, Failure) {
+        let still_fails = |candidate: &[u8], failure: &mut Failure| {
+            let found = match self.smith(candidate).and_then(|wasm| self.failure(&wasm)) {
+                Some(found) => found,
+                None => return false,
+            };
+            if !found.same_kind(failure) {
+                return false;
+            }
+            *failure = found;
+            true
+        };
+
+        let mut chunk = data.len() / 2;
+        while chunk > 0 {
+            let mut i = 0;
+            let mut reduced = false;
+            while i < data.len() {
+                let mut candidate = data.clone();
+                candidate.drain(i..(i + chunk).min(data.len()));
+                if still_fails(&candidate, &mut failure) {
+                    data = candidate;
+                    reduced = true;
+                } else {
+                    i += chunk;
+                }
+            }
+            if !reduced {
+                chunk /= 2;
+            }
+        }
+
+        for i in 0..data.len() {
+            if data[i] == 0 {
+                continue;
+            }
+            let mut candidate = data.clone();
+            candidate[i] = 0;
+            if still_fails(&candidate, &mut failure) {
+                data = candidate;
+            }
+        }
+
+        (data, failure)
+    }
+}
+
+/// The way in which a transformation went wrong.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Failure {
+    /// The transformation, or emitting its result, panicked with this message.
+    Panicked(String),
+
+    /// The transformation produced a module that doesn't validate.
+    Invalid(String),
+
+    /// The transformation changed the module's observable behaviour.
+    Mismatch {
+        /// The trace of the module before the transformation.
+        expected: Vec<String>,
+
+        /// The trace of the module after the transformation.
+        actual: Vec<String>,
+    },
+}
+
+impl Failure {
+    fn same_kind(&self, other: &Failure) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+    }
+}
+
+/// A wasm test case on which a transformation fails.
+#[derive(Clone, Debug)]
+pub struct FailingTransform {
+    /// The WAT disassembly of the wasm test case, before the transformation.
+    pub wat: String,
+
+    /// How the transformation failed.
+    pub failure: Failure,
+}
+
+impl FailingTransform {
+    fn new(wasm: &[u8], failure: Failure) -> FailingTransform {
+        let wat = wasmprinter::print_bytes(wasm)
+            .unwrap_or_else(|e| format!(";; failed to print the test case: {}", e));
+        FailingTransform { wat, failure }
+    }
+}
+
+impl fmt::Display for FailingTransform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Found a failing test case!\n\n{}\n", self.wat)?;
+        match &self.failure {
+            Failure::Panicked(message) => writeln!(f, "The transformation panicked: {}", message),
+            Failure::Invalid(error) => writeln!(f, "The transformed module is invalid: {}", error),
+            Failure::Mismatch { expected, actual } => writeln!(
+                f,
+                "BEFORE the transformation:\n\n{}\n\nAFTER the transformation:\n\n{}",
+                expected.join("\n"),
+                actual.join("\n"),
+            ),
+        }
+    }
+}
+
+impl std::error::Error for FailingTransform {}
+
+/// Format values for a trace. Function references are named by their exports,
+/// since their ids needn't survive the transformation.
+fn values(module: &Module, values: &[Val]) -> String {
+    let values = values
+        .iter()
+        .map(|value| match value {
+            Val::Ref(Ref::Func(func)) => {
+                let export = module
+                    .exports
+                    .iter()
+                    .find(|e| matches!(e.item, ExportItem::Function(f) if f == *func));
+                match export {
+                    Some(export) => format!("Ref(Func({:?}))", export.name),
+                    None => "Ref(Func(_))".to_string(),
+                }
+            }
+            value => format!("{:?}", value),
+        })
+        .collect::<Vec<_>>();
+    format!("[{}]", values.join(", "))
+}
+
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "<non-string panic payload>".to_string()
+    }
+}
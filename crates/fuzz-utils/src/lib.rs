@@ -12,6 +12,10 @@ use std::path::Path;
 use std::time;
 use walrus_tests_utils::wasm_interp;
 
+mod differential;
+
+pub use differential::{Differential, FailingTransform, Failure};
+
 /// A simple RNG that reads bytes from a fixed buffer, wrapping around when
 /// exhausted. Intended for fuzz testing only.
 pub struct BufRng<'a> {
@@ -636,4 +640,64 @@ mod tests {
         eprintln!("wasm_smith_gc_fuzz: tested {tested} modules in {timeout}s");
         assert!(tested > 0, "should have tested at least one module");
     }
+
+    #[test]
+    fn differential_gc_fuzz() {
+        let timeout = get_timeout()
+            .unwrap_or(super::Differential::<fn(&mut walrus::Module)>::DEFAULT_TIMEOUT_SECS);
+        let differential = super::Differential::new(walrus::passes::gc::run).set_timeout(timeout);
+        if let Err(failing_test_case) = differential.run(&mut SmallRng::seed_from_u64(42)) {
+            print_err(&failing_test_case);
+            panic!("Found a failing test case");
+        }
+    }
+
+    #[test]
+    fn differential_reduces_miscompilations() {
+        use rand::RngCore;
+        use walrus::ir::{dfs_pre_order_mut, Const, Value, VisitorMut};
+
+        // Miscompile every `i32.const` into one that is off by one.
+        #[derive(Default)]
+        struct AddOneToI32Consts;
+
+        impl VisitorMut for AddOneToI32Consts {
+            fn visit_const_mut(&mut self, c: &mut Const) {
+                if let Value::I32(x) = &mut c.value {
+                    *x = x.wrapping_add(1);
+                }
+            }
+        }
+
+        let differential = super::Differential::new(|module: &mut walrus::Module| {
+            for (_, func) in module.funcs.iter_local_mut() {
+                let entry = func.entry_block();
+                dfs_pre_order_mut(&mut AddOneToI32Consts, func, entry);
+            }
+        });
+
+        differential
+            .check_wat(r#"(module (func (export "f") (result i64) i64.const 1))"#)
+            .unwrap();
+        let e = differential
+            .check_wat(r#"(module (func (export "f") (result i32) i32.const 1))"#)
+            .unwrap_err();
+        let e = e.downcast::<super::FailingTransform>().unwrap();
+        assert!(matches!(e.failure, super::Failure::Mismatch { .. }));
+
+        let mut rng = SmallRng::seed_from_u64(0);
+        let mut data = vec![0; 512];
+        let e = (0..1000)
+            .find_map(|_| {
+                rng.fill_bytes(&mut data);
+                differential.run_one(&data).err()
+            })
+            .expect("no miscompilation found in 1000 random modules")
+            .downcast::<super::FailingTransform>()
+            .unwrap();
+        print_err(&e.clone().into());
+        assert!(matches!(e.failure, super::Failure::Mismatch { .. }));
+        assert!(e.wat.contains("i32.const"));
+        assert!(e.wat.lines().count() < 30, "not reduced:\n{}", e.wat);
+    }
 }
@@ -6,6 +6,8 @@ use std::path::Path;
 use std::process::{Command, Stdio};
 use std::sync::Once;
 use std::time::Duration;
+use walrus::interp::{DummyHost, Host, Instance, Trap, Val};
+use walrus::Module;
 
 pub type Result<T> = std::result::Result<T, anyhow::Error>;
 
@@ -161,6 +163,97 @@ where
     Ok(buf)
 }
 
+/// Parse the text format `wat` into a module.
+pub fn parse(wat: &str) -> Module {
+    Module::from_buffer(&wat::parse_str(wat).unwrap()).unwrap()
+}
+
+/// Emit `module`, check that the result is valid with the features walrus
+/// parses by default, and return it.
+pub fn emit(module: &mut Module) -> Vec<u8> {
+    let wasm = module.emit_wasm();
+    let mut features = wasmparser::WasmFeatures::default();
+    features.insert(wasmparser::WasmFeatures::LEGACY_EXCEPTIONS);
+    wasmparser::Validator::new_with_features(features)
+        .validate_all(&wasm)
+        .unwrap();
+    wasm
+}
+
+/// Emit `module`, check that the result is valid, and print it in the text
+/// format.
+pub fn print(module: &mut Module) -> String {
+    wasmprinter::print_bytes(emit(module)).unwrap()
+}
+
+/// The text of the function named `func` in the printed module `text`.
+pub fn body<'a>(text: &'a str, func: &str) -> &'a str {
+    let start = text.find(&format!("(func ${} ", func)).unwrap();
+    let end = text[start + 1..]
+        .find("(func ")
+        .map_or(text.len(), |i| start + 1 + i);
+    &text[start..end]
+}
+
+/// Call each export in `calls` in turn on one instance of `module`, and
+/// return their results, or `None` for those that trap.
+pub fn outcomes(module: &Module, calls: &[(&str, &[Val])]) -> Vec<Option<Vec<Val>>> {
+    let mut instance = Instance::new(module, DummyHost::default()).unwrap();
+    calls
+        .iter()
+        .map(|(name, args)| {
+            let func = module.exports.get_func(name).unwrap();
+            instance.call(func, args).ok()
+        })
+        .collect()
+}
+
+/// Parse `wat`, run an instrumentation `pass` over it, and check that the
+/// result is valid. Returns the instrumented module and what the pass
+/// returned.
+pub fn instrument<T>(wat: &str, pass: impl FnOnce(&mut Module) -> Result<T>) -> (Module, T) {
+    let mut module = parse(wat);
+    let output = pass(&mut module).unwrap();
+    emit(&mut module);
+    (module, output)
+}
+
+/// Call the export `name` of `module` on `instance` with `args`.
+pub fn call<H: Host>(
+    module: &Module,
+    instance: &mut Instance<H>,
+    name: &str,
+    args: &[Val],
+) -> std::result::Result<Vec<Val>, Trap> {
+    let func = module.exports.get_func(name).unwrap();
+    instance.call(func, args)
+}
+
+/// Call the export `name` of a fresh instance of `module` with `args`. Returns
+/// the results, or the trap the call ended with, and the instance, whose host
+/// recorded every call to an imported function.
+pub fn trace<'a>(
+    module: &'a Module,
+    name: &str,
+    args: &[Val],
+) -> (std::result::Result<Vec<Val>, Trap>, Instance<'a, DummyHost>) {
+    let mut instance = Instance::new(module, DummyHost::default()).unwrap();
+    let result = call(module, &mut instance, name, args);
+    (result, instance)
+}
+
+/// The calls `instance` made to the functions imported from `hooks`, as the
+/// name of each function with its arguments.
+pub fn hooks<'a>(instance: &'a Instance<DummyHost>, hooks: &str) -> Vec<(&'a str, &'a [Val])> {
+    instance
+        .host()
+        .calls
+        .iter()
+        .filter(|(module, _, _)| module == hooks)
+        .map(|(_, name, args)| (&name[..], &args[..]))
+        .collect()
+}
+
 pub fn handle<T: TestResult>(result: T) {
     result.handle();
 }
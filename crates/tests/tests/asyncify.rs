@@ -0,0 +1,272 @@
+//! Tests for instrumenting functions so that they can unwind and rewind.
+
+use walrus::interp::{Host, Instance, Trap, Val};
+use walrus::ir::BinaryOp;
+use walrus::passes::asyncify::{self, Options};
+use walrus::{Import, Module, ValType};
+use walrus_tests_utils::{body, emit, parse, print};
+
+fn options() -> Options {
+    Options {
+        imports: vec![("env".to_string(), "sleep".to_string())],
+        ..Options::default()
+    }
+}
+
+fn asyncify(wat: &str, options: &Options) -> String {
+    let mut module = parse(wat);
+    asyncify::run(&mut module, options).unwrap();
+    print(&mut module)
+}
+
+fn instrumented(text: &str, func: &str) -> bool {
+    body(text, func).contains("global.get $__asyncify_state")
+}
+
+const WAT: &str = r#"
+    (module
+        (import "env" "sleep" (func $sleep (param i32) (result i32)))
+        (import "env" "log" (func $log (param i32)))
+        (memory 1)
+        (table 1 funcref)
+        (type $unary (func (param i32) (result i32)))
+        (func $inner (param $x i32) (result i32)
+            (local $y i64) (local $v v128)
+            local.get $x
+            i32.const 100
+            local.get $x
+            call $sleep
+            i32.add
+            i32.mul)
+        (func $outer (export "outer") (param i32) (result i32 i64)
+            i32.const 1
+            local.get 0
+            block (param i32) (result i32)
+                call $inner
+            end
+            i32.add
+            i64.const 2)
+        (func $pure (export "pure") (param i32) (result i32)
+            local.get 0
+            call $log
+            local.get 0)
+        (func $indirect (export "indirect") (param i32) (result i32)
+            local.get 0
+            i32.const 0
+            call_indirect (type $unary)))
+"#;
+
+#[test]
+fn instruments_callers_of_suspending_imports() {
+    let text = asyncify(WAT, &options());
+    assert!(instrumented(&text, "inner"));
+    assert!(instrumented(&text, "outer"));
+    assert!(instrumented(&text, "indirect"));
+    assert!(!instrumented(&text, "pure"));
+    for name in [
+        "asyncify_start_unwind",
+        "asyncify_stop_unwind",
+        "asyncify_start_rewind",
+        "asyncify_stop_rewind",
+        "asyncify_get_state",
+    ] {
+        assert!(text.contains(&format!("(export \"{}\"", name)), "{}", name);
+    }
+    assert!(text.contains("(global $__asyncify_data (;1;) (mut i32)"));
+}
+
+#[test]
+fn indirect_calls_can_be_ignored() {
+    let options = Options {
+        ignore_indirect: true,
+        ..options()
+    };
+    let text = asyncify(WAT, &options);
+    assert!(instrumented(&text, "outer"));
+    assert!(!instrumented(&text, "indirect"));
+}
+
+#[test]
+fn missing_imports_instrument_nothing() {
+    let options = Options {
+        imports: vec![("env".to_string(), "missing".to_string())],
+        ignore_indirect: true,
+        ..Options::default()
+    };
+    let text = asyncify(WAT, &options);
+    assert!(!instrumented(&text, "inner"));
+    assert!(text.contains("(export \"asyncify_get_state\""));
+}
+
+#[test]
+fn unsupported_code_is_left_unchanged() {
+    for wat in [
+        r#"
+        (module
+            (import "env" "sleep" (func $sleep (param i32) (result i32)))
+            (memory 1)
+            (func $f (param i32) (result i32)
+                local.get 0
+                call $sleep)
+            (func (export "tail") (param i32) (result i32)
+                local.get 0
+                return_call $f))
+        "#,
+        r#"
+        (module
+            (import "env" "sleep" (func $sleep (param i32) (result i32)))
+            (memory 1)
+            (func (export "reference") (param i32) (result i32)
+                ref.null extern
+                local.get 0
+                call $sleep
+                drop
+                drop
+                i32.const 0))
+        "#,
+    ] {
+        let mut module = parse(wat);
+        let before = module.emit_wasm();
+        assert!(asyncify::run(&mut module, &options()).is_err());
+        assert_eq!(module.emit_wasm(), before);
+    }
+}
+
+/// Suspends in `host.suspend`, recording how long for and returning the
+/// address of the frame buffer, and resumes with `wake` from `host.wake`.
+#[derive(Default)]
+struct Scheduler {
+    sleeps: Vec<i32>,
+    wake: i32,
+    logs: Vec<Vec<Val>>,
+}
+
+impl Host for Scheduler {
+    fn call(&mut self, import: &Import, args: &[Val], _: &[ValType]) -> Result<Vec<Val>, Trap> {
+        match &import.name[..] {
+            "suspend" => {
+                let [Val::I32(ms)] = args else {
+                    panic!("bad arguments {:?}", args)
+                };
+                self.sleeps.push(*ms);
+                Ok(vec![Val::I32(16)])
+            }
+            "wake" => Ok(vec![Val::I32(self.wake)]),
+            "log" => {
+                self.logs.push(args.to_vec());
+                Ok(vec![])
+            }
+            name => panic!("unexpected import {}", name),
+        }
+    }
+}
+
+#[test]
+fn unwinds_and_rewinds_through_the_interpreter() {
+    let mut module = parse(
+        r#"
+        (module
+            (import "env" "sleep" (func $sleep (param i32) (result i32)))
+            (import "env" "log" (func $log (param i64 f64)))
+            (memory 1)
+            ;; Frames go from 24 up to 1024.
+            (data (i32.const 16) "\18\00\00\00\00\04\00\00")
+            (func $inner (param $x i32) (result i32)
+                (local $y i64) (local $f f64)
+                local.get $x
+                i64.extend_i32_s
+                i64.const 10
+                i64.mul
+                local.set $y
+                local.get $x
+                f64.convert_i32_s
+                f64.const 0.5
+                f64.add
+                local.set $f
+                local.get $x
+                i32.const 100
+                local.get $x
+                call $sleep
+                i32.add
+                i32.mul
+                local.get $y
+                local.get $f
+                call $log
+                local.get $y
+                i32.wrap_i64
+                i32.add
+                local.get $f
+                i32.trunc_f64_s
+                i32.add)
+            (func (export "run") (param i32) (result i32)
+                (local $z i32)
+                i32.const 1000
+                local.set $z
+                local.get 0
+                call $inner
+                local.get $z
+                i32.add))
+        "#,
+    );
+    asyncify::run(&mut module, &options()).unwrap();
+
+    // Stand in for the embedder's side of `env.sleep`.
+    let export = |module: &Module, name| module.exports.get_func(name).unwrap();
+    let (get_state, start_unwind, stop_rewind) = (
+        export(&module, "asyncify_get_state"),
+        export(&module, "asyncify_start_unwind"),
+        export(&module, "asyncify_stop_rewind"),
+    );
+    let suspend = module.types.add(&[ValType::I32], &[ValType::I32]);
+    let (suspend, _) = module.add_import_func("host", "suspend", suspend);
+    let wake = module.types.add(&[], &[ValType::I32]);
+    let (wake, _) = module.add_import_func("host", "wake", wake);
+    let sleep = module.imports.get_func("env", "sleep").unwrap();
+    module
+        .replace_imported_func(sleep, |(body, args)| {
+            body.call(get_state)
+                .i32_const(2)
+                .binop(BinaryOp::I32Eq)
+                .if_else(
+                    ValType::I32,
+                    |rewinding| {
+                        rewinding.call(stop_rewind).call(wake);
+                    },
+                    |running| {
+                        running
+                            .local_get(args[0])
+                            .call(suspend)
+                            .call(start_unwind)
+                            .i32_const(0);
+                    },
+                );
+        })
+        .unwrap();
+    emit(&mut module);
+
+    let run = export(&module, "run");
+    let stop_unwind = export(&module, "asyncify_stop_unwind");
+    let start_rewind = export(&module, "asyncify_start_rewind");
+    let mut instance = Instance::new(&module, Scheduler::default()).unwrap();
+    instance.call(run, &[Val::I32(3)]).unwrap();
+    assert_eq!(instance.call(get_state, &[]), Ok(vec![Val::I32(1)]));
+    assert_eq!(instance.host().sleeps, [3]);
+    assert!(instance.host().logs.is_empty());
+    instance.call(stop_unwind, &[]).unwrap();
+    assert_eq!(instance.call(get_state, &[]), Ok(vec![Val::I32(0)]));
+
+    // Rewinding restores the locals and operands from the first call, so the
+    // argument it's re-entered with is ignored.
+    instance.host_mut().wake = 5;
+    instance.call(start_rewind, &[Val::I32(16)]).unwrap();
+    assert_eq!(
+        instance.call(run, &[Val::I32(0)]),
+        Ok(vec![Val::I32(3 * (100 + 5) + 30 + 3 + 1000)])
+    );
+    assert_eq!(instance.call(get_state, &[]), Ok(vec![Val::I32(0)]));
+    assert_eq!(instance.host().sleeps, [3]);
+    assert_eq!(
+        instance.host().logs,
+        [vec![Val::I64(30), Val::F64(3.5f64.to_bits())]]
+    );
+}
@@ -0,0 +1,75 @@
+//! Tests for keeping and generating the `build_id` custom section.
+
+use walrus::{BuildId, Module, ModuleConfig};
+
+fn wasm(body: &str) -> Vec<u8> {
+    wat::parse_str(format!(
+        r#"
+        (module
+            (func (export "f") (result i32)
+                {})
+            (@custom "build_id" "\04\de\ad\be\ef"))
+        "#,
+        body
+    ))
+    .unwrap()
+}
+
+fn build_id_section(wasm: &[u8]) -> Option<Vec<u8>> {
+    wasmparser::Parser::new(0)
+        .parse_all(wasm)
+        .find_map(|payload| match payload.unwrap() {
+            wasmparser::Payload::CustomSection(s) if s.name() == "build_id" => {
+                Some(s.data().to_vec())
+            }
+            _ => None,
+        })
+}
+
+#[test]
+fn build_id_is_kept() {
+    let mut module = Module::from_buffer(&wasm("i32.const 1")).unwrap();
+    assert_eq!(
+        module.build_id,
+        Some(BuildId {
+            id: vec![0xde, 0xad, 0xbe, 0xef]
+        })
+    );
+    assert!(module.customs.iter().next().is_none());
+
+    let output = module.emit_wasm();
+    assert_eq!(
+        build_id_section(&output).unwrap(),
+        [4, 0xde, 0xad, 0xbe, 0xef]
+    );
+
+    module.build_id = None;
+    assert_eq!(build_id_section(&module.emit_wasm()), None);
+}
+
+#[test]
+fn build_id_is_generated_from_contents() {
+    let mut config = ModuleConfig::new();
+    config.generate_build_id(true);
+    let emit = |body: &str| {
+        let mut module = config.parse(&wasm(body)).unwrap();
+        let output = module.emit_wasm();
+        (output, module.build_id.unwrap().id)
+    };
+
+    let (output, id) = emit("i32.const 1");
+    assert_eq!(id.len(), 16);
+    assert_ne!(id, [0xde, 0xad, 0xbe, 0xef]);
+    let section = build_id_section(&output).unwrap();
+    assert_eq!(section[0] as usize, id.len());
+    assert_eq!(section[1..], id[..]);
+
+    // The same output always gets the same id, including when the input
+    // already had a generated one, and different output gets a different id.
+    let (again, again_id) = emit("i32.const 1");
+    assert_eq!((again, again_id), (output.clone(), id.clone()));
+    let mut module = config.parse(&output).unwrap();
+    assert_eq!(module.emit_wasm(), output);
+    let (_, other_id) = emit("i32.const 2");
+    assert_ne!(other_id, id);
+}
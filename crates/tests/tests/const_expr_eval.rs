@@ -0,0 +1,106 @@
+//! Tests for statically evaluating constant expressions.
+
+use std::collections::HashMap;
+use walrus::ir::Value;
+use walrus::{ConstExpr, ConstOp, DataKind, GlobalId, GlobalKind, Module, RefType, ValType};
+use walrus_tests_utils::parse;
+
+const WAT: &str = r#"
+    (module
+        (import "env" "base" (global $base i32))
+        (global $stride i32 (i32.const 16))
+        (global $offset i32 (i32.add (global.get $stride) (i32.const 8)))
+        (global $counter (mut i32) (i32.const 0))
+        (memory 1)
+        (data (i32.add (global.get $base) (global.get $offset)) "x")
+        (data (i32.mul (global.get $stride) (i32.const 4)) "y"))
+    "#;
+
+/// `Value` has no `PartialEq`, so compare through this.
+fn i32(value: Option<Value>) -> Option<i32> {
+    match value {
+        Some(Value::I32(v)) => Some(v),
+        None => None,
+        Some(v) => panic!("expected an i32, got {:?}", v),
+    }
+}
+
+fn global(module: &Module, index: usize) -> GlobalId {
+    module.globals.iter().nth(index).unwrap().id()
+}
+
+fn data_offsets(module: &Module) -> Vec<&ConstExpr> {
+    module
+        .data
+        .iter()
+        .map(|d| match &d.kind {
+            DataKind::Active { offset, .. } => offset,
+            DataKind::Passive => panic!("expected active data"),
+        })
+        .collect()
+}
+
+#[test]
+fn evaluates_globals_and_segment_offsets() {
+    let module = parse(WAT);
+    let no_imports = |_| None;
+    let offsets = data_offsets(&module);
+
+    let offset = global(&module, 2);
+    let GlobalKind::Local(init) = &module.globals.get(offset).kind else {
+        panic!("expected a local global");
+    };
+    assert_eq!(i32(init.eval(&module, &no_imports).unwrap()), Some(24));
+    assert_eq!(
+        i32(offsets[1].eval(&module, &no_imports).unwrap()),
+        Some(64)
+    );
+
+    // The first segment depends on an import.
+    assert_eq!(i32(offsets[0].eval(&module, &no_imports).unwrap()), None);
+    let base = global(&module, 0);
+    let mut imports = HashMap::new();
+    imports.insert(base, Value::I32(1024));
+    assert_eq!(i32(offsets[0].eval(&module, &imports).unwrap()), Some(1048));
+}
+
+#[test]
+fn unknown_values() {
+    let module = parse(WAT);
+    let counter = global(&module, 3);
+    let none = |_| None;
+
+    // Mutable globals may change before they're read.
+    let expr = ConstExpr::Extended(vec![
+        ConstOp::GlobalGet(counter),
+        ConstOp::I32Const(1),
+        ConstOp::I32Add,
+    ]);
+    assert_eq!(i32(expr.eval(&module, &none).unwrap()), None);
+
+    // References have no `Value`.
+    let expr = ConstExpr::RefNull(RefType::FUNCREF);
+    assert_eq!(i32(expr.eval(&module, &none).unwrap()), None);
+    let expr = ConstExpr::Extended(vec![ConstOp::I32Const(1), ConstOp::RefI31]);
+    assert_eq!(i32(expr.eval(&module, &none).unwrap()), None);
+}
+
+#[test]
+fn malformed_expressions() {
+    let mut module = parse(WAT);
+    let none = |_| None;
+
+    let expr = ConstExpr::Extended(vec![ConstOp::I32Const(1), ConstOp::I64Add]);
+    assert!(expr.eval(&module, &none).is_err());
+    let expr = ConstExpr::Extended(vec![ConstOp::I32Const(1), ConstOp::I32Const(2)]);
+    assert!(expr.eval(&module, &none).is_err());
+
+    // Globals built by hand may refer to themselves.
+    let cyclic =
+        module
+            .globals
+            .add_local(ValType::I32, false, false, ConstExpr::Value(Value::I32(0)));
+    module.globals.get_mut(cyclic).kind = GlobalKind::Local(ConstExpr::Global(cyclic));
+    let err = ConstExpr::Global(cyclic).eval(&module, &none).unwrap_err();
+    assert!(format!("{:?}", err).contains("cycle"));
+}
@@ -0,0 +1,142 @@
+//! Tests for counting how many times each basic block runs.
+
+use walrus::interp::{DummyHost, Instance, Val};
+use walrus::passes::coverage::{self, Coverage, Options};
+use walrus::Module;
+use walrus_tests_utils::call;
+
+fn instrument(wat: &str, options: impl FnOnce(&Module) -> Options) -> (Module, Coverage) {
+    walrus_tests_utils::instrument(wat, |module| {
+        let options = options(module);
+        coverage::run(module, &options)
+    })
+}
+
+/// Call each export with its arguments, and return the counts afterwards.
+fn counts(module: &Module, coverage: &Coverage, calls: &[(&str, i32)]) -> Vec<u64> {
+    let mut instance = Instance::new(module, DummyHost::default()).unwrap();
+    for (name, arg) in calls {
+        call(module, &mut instance, name, &[Val::I32(*arg)]).unwrap();
+    }
+    coverage.counts(instance.memory(coverage.memory))
+}
+
+#[test]
+fn counts_basic_blocks() {
+    let (module, coverage) = instrument(
+        r#"
+        (module
+            (func (export "count") (param i32) (result i32)
+                (local i32)
+                block
+                    loop
+                        local.get 0
+                        i32.eqz
+                        br_if 1
+                        local.get 0
+                        i32.const 1
+                        i32.sub
+                        local.set 0
+                        local.get 1
+                        i32.const 1
+                        i32.add
+                        local.set 1
+                        br 0
+                    end
+                end
+                local.get 1)
+            (func (export "sign") (param i32) (result i32)
+                local.get 0
+                i32.const 0
+                i32.lt_s
+                if (result i32)
+                    i32.const -1
+                else
+                    i32.const 1
+                end))
+        "#,
+        |_| Options {
+            export: Some("coverage".to_string()),
+            ..Options::default()
+        },
+    );
+    let export = module.exports.get_exported_memory(coverage.memory).unwrap();
+    assert_eq!(export.name, "coverage");
+    assert_eq!(coverage.counters.len(), 8);
+    assert!(coverage
+        .counters
+        .iter()
+        .all(|counter| counter.loc.is_some_and(|loc| !loc.is_default())));
+
+    let counts = counts(
+        &module,
+        &coverage,
+        &[("count", 3), ("sign", -5), ("sign", -1), ("sign", 2)],
+    );
+    // `count`: its body, after the block, the block, the loop, and after the
+    // `br_if`. `sign`: its body, then the `if` arms.
+    assert_eq!(counts, [1, 1, 1, 4, 3, 3, 2, 1]);
+}
+
+#[test]
+fn counters_in_an_existing_memory() {
+    let wat = r#"
+        (module
+            (memory i64 1)
+            (func (export "run") (param i32)
+                local.get 0
+                if
+                    nop
+                end))
+    "#;
+    let (module, coverage) = instrument(wat, |module| Options {
+        memory: Some(module.memories.iter().next().unwrap().id()),
+        address: 1024,
+        ..Options::default()
+    });
+    assert_eq!(module.memories.iter().count(), 1);
+    // The missing `else` arm is an empty sequence with no location.
+    assert_eq!(coverage.counters.len(), 3);
+    assert!(coverage.counters[2].loc.is_none());
+
+    let counts = counts(&module, &coverage, &[("run", 1), ("run", 0), ("run", 0)]);
+    assert_eq!(counts, [3, 1, 2]);
+}
+
+#[test]
+fn counters_must_fit() {
+    let mut module =
+        Module::from_buffer(&wat::parse_str("(module (memory 1) (func nop))").unwrap()).unwrap();
+    let options = Options {
+        memory: Some(module.memories.iter().next().unwrap().id()),
+        address: 65536 - 4,
+        ..Options::default()
+    };
+    assert!(coverage::run(&mut module, &options).is_err());
+}
+
+#[test]
+fn legacy_try_blocks_end_basic_blocks() {
+    let (module, coverage) = instrument(
+        r#"
+        (module
+            (tag $e)
+            (func (export "run") (param i32) (result i32)
+                try
+                    local.get 0
+                    if
+                        i32.const 0
+                        return
+                    end
+                    throw $e
+                catch_all
+                end
+                i32.const 1))
+        "#,
+        |_| Options::default(),
+    );
+    let counts = counts(&module, &coverage, &[("run", 1), ("run", 0), ("run", 0)]);
+    // The body, after the `try`, which returning skips, the `try` body, after
+    // the `if`, its arms, and the `catch_all`.
+    assert_eq!(counts, [3, 2, 3, 2, 1, 2, 2]);
+}
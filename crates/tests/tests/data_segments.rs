@@ -0,0 +1,135 @@
+//! Tests for the pass that merges, trims and splits data segments.
+
+use walrus::passes::data_segments::{self, Options};
+use walrus::{DataKind, Module};
+use walrus_tests_utils::{emit, parse};
+
+/// Active segments as `(offset, bytes)`, plus the number of passive ones.
+fn segments(module: &Module) -> (Vec<(i32, Vec<u8>)>, usize) {
+    let mut active = Vec::new();
+    let mut passive = 0;
+    for data in module.data.iter() {
+        match &data.kind {
+            DataKind::Active { offset, .. } => {
+                let offset = match offset.eval(module, &|_| None).unwrap() {
+                    Some(walrus::ir::Value::I32(offset)) => offset,
+                    v => panic!("unexpected offset {:?}", v),
+                };
+                active.push((offset, data.value.clone()));
+            }
+            DataKind::Passive => passive += 1,
+        }
+    }
+    active.sort();
+    (active, passive)
+}
+
+fn contents(module: &Module) -> Vec<u8> {
+    let images = module.memories.images(module, &|_| None).unwrap();
+    images[0].read(0, 256)
+}
+
+fn run(module: &mut Module, options: &Options) {
+    let before = contents(module);
+    data_segments::run(module, options);
+    assert_eq!(contents(module), before);
+    emit(module);
+}
+
+const WAT: &str = r#"
+    (module
+        (memory 1)
+        (data (i32.const 8) "ab")
+        (data (i32.const 10) "cd")
+        (data (i32.const 11) "XY")
+        (data (i32.const 100) "\00\00\00xyz\00")
+        (data (i32.const 110) "q")
+        (data (i32.const 150) "m\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00\00n")
+        (data "passive"))
+"#;
+
+#[test]
+fn merges_trims_and_splits() {
+    let mut module = parse(WAT);
+    run(&mut module, &Options::default());
+    let (active, passive) = segments(&module);
+    assert_eq!(
+        active,
+        [
+            (8, b"abcXY".to_vec()),
+            (103, b"xyz\0\0\0\0q".to_vec()),
+            (150, b"m".to_vec()),
+            (170, b"n".to_vec()),
+        ]
+    );
+    assert_eq!(passive, 1);
+}
+
+#[test]
+fn respects_the_segment_limit() {
+    let mut module = parse(WAT);
+    let options = Options {
+        max_segments: 3,
+        ..Options::default()
+    };
+    run(&mut module, &options);
+    let (active, passive) = segments(&module);
+    assert_eq!(active.len() + passive, 3);
+    assert_eq!(active[0], (8, b"abcXY".to_vec()));
+}
+
+#[test]
+fn leaves_referenced_segments_alone() {
+    let mut module = parse(
+        r#"
+        (module
+            (memory 1)
+            (data $a (i32.const 8) "a\00X")
+            (data $b (i32.const 9) "b")
+            (data (i32.const 12) "c")
+            (data (i32.const 13) "d")
+            (data $z (i32.const 30) "zz")
+            (data (i32.const 31) "\00")
+            (func (export "f")
+                i32.const 0
+                i32.const 0
+                i32.const 0
+                memory.init $z
+                data.drop $a))
+        "#,
+    );
+    let a = module.data.iter().next().unwrap().id();
+    let z = module.data.iter().nth(4).unwrap().id();
+    run(&mut module, &Options::default());
+    assert_eq!(module.data.get(a).value, b"a\0X");
+    assert_eq!(module.data.get(z).value, b"zz");
+    // `b` isn't merged with `cd` across the `X` that `a` leaves, and the zero
+    // written over `z` is kept.
+    let (active, _) = segments(&module);
+    assert_eq!(
+        active,
+        [
+            (8, b"a\0X".to_vec()),
+            (9, b"b".to_vec()),
+            (12, b"cd".to_vec()),
+            (30, b"zz".to_vec()),
+            (31, b"\0".to_vec()),
+        ]
+    );
+}
+
+#[test]
+fn keeps_zeros_in_imported_memories() {
+    let mut module = parse(
+        r#"
+        (module
+            (import "env" "memory" (memory 1))
+            (data (i32.const 8) "\00a")
+            (data (i32.const 10) "b\00")
+            (data (i32.const 40) "\00"))
+        "#,
+    );
+    run(&mut module, &Options::default());
+    let (active, _) = segments(&module);
+    assert_eq!(active, [(8, b"\0ab\0".to_vec()), (40, b"\0".to_vec())]);
+}
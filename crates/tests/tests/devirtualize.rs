@@ -0,0 +1,100 @@
+//! Tests for turning indirect calls through immutable tables into direct
+//! calls.
+
+use walrus::passes::devirtualize::{self, Options};
+use walrus_tests_utils::{body, parse, print};
+
+fn devirtualize(wat: &str, options: &Options) -> String {
+    let mut module = parse(wat);
+    devirtualize::run(&mut module, options);
+    print(&mut module)
+}
+
+const WAT: &str = r#"
+    (module
+        (type $unary (func (param i32) (result i32)))
+        (table 4 funcref)
+        (func $a (type $unary) local.get 0)
+        (func $b (type $unary) local.get 0 i32.const 1 i32.add)
+        (func $c (result i32) i32.const 0)
+        (elem (i32.const 1) func $a $b $c)
+        (func $constant (export "constant") (param i32) (result i32)
+            local.get 0
+            i32.const 2
+            call_indirect (type $unary))
+        (func $tail (export "tail") (param i32) (result i32)
+            local.get 0
+            i32.const 1
+            return_call_indirect (type $unary))
+        (func $traps (export "traps") (param i32) (result i32)
+            local.get 0
+            i32.const 3
+            call_indirect (type $unary))
+        (func $dynamic (export "dynamic") (param i32 i32) (result i32)
+            local.get 0
+            local.get 1
+            call_indirect (type $unary))
+        (func $twice (export "twice") (param i32) (result i32)
+            local.get 0
+            local.get 0
+            call_indirect (type $unary)
+            local.get 0
+            call_indirect (type $unary)))
+"#;
+
+#[test]
+fn constant_indices_become_direct_calls() {
+    let text = devirtualize(WAT, &Options::default());
+    assert!(body(&text, "constant").contains("call $b"));
+    assert!(!body(&text, "constant").contains("call_indirect"));
+    assert!(body(&text, "tail").contains("return_call $a"));
+    // Slot 3 holds `$c`, which has the wrong type and so traps.
+    assert!(body(&text, "traps").contains("call_indirect"));
+    // Without dispatch enabled, dynamic indices are left alone.
+    assert!(!body(&text, "dynamic").contains("br_table"));
+}
+
+#[test]
+fn dynamic_indices_dispatch() {
+    let options = Options {
+        max_dispatch_targets: 2,
+    };
+    let text = devirtualize(WAT, &options);
+    let dynamic = body(&text, "dynamic");
+    assert!(dynamic.contains("br_table"), "{}", dynamic);
+    assert!(dynamic.contains("call $a"));
+    assert!(dynamic.contains("call $b"));
+    // Everything else still goes through the table, to trap as before.
+    assert!(dynamic.contains("call_indirect"));
+    // Dispatches in the same function share their index local.
+    let twice = body(&text, "twice");
+    assert_eq!(twice.matches("br_table").count(), 2, "{}", twice);
+    assert!(twice.contains("(local i32)"), "{}", twice);
+
+    let options = Options {
+        max_dispatch_targets: 1,
+    };
+    let text = devirtualize(WAT, &options);
+    assert!(!body(&text, "dynamic").contains("br_table"));
+}
+
+#[test]
+fn mutable_tables_are_left_alone() {
+    let exported = WAT.replace("(table 4 funcref)", "(table (export \"t\") 4 funcref)");
+    let text = devirtualize(&exported, &Options::default());
+    assert!(body(&text, "constant").contains("call_indirect"));
+
+    let written = WAT.replace(
+        "(elem (i32.const 1)",
+        "(func (export \"set\") i32.const 2 ref.func $a table.set 0) (elem (i32.const 1)",
+    );
+    let text = devirtualize(&written, &Options::default());
+    assert!(body(&text, "constant").contains("call_indirect"));
+}
+
+#[test]
+fn huge_tables_are_left_alone() {
+    let huge = WAT.replace("(table 4 funcref)", "(table 0xffff_ffff funcref)");
+    let text = devirtualize(&huge, &Options::default());
+    assert!(body(&text, "constant").contains("call_indirect"));
+}
@@ -0,0 +1,299 @@
+//! Tests that DWARF addresses, ranges and wasm locations follow the code they
+//! describe through a transformation.
+
+use gimli::write::{self, Address, AttributeValue, Expression, Sections, Unit, UnitTable};
+use gimli::{constants, Encoding, EndianSlice, Format, LittleEndian};
+use walrus::ir::Value;
+use walrus::{ExternalDebugInfo, Module, ModuleConfig};
+
+const WAT: &str = r#"
+    (module
+        (func $first (export "first") (result i32)
+            i32.const 1)
+        (func $second (export "second") (param i32) (result i64)
+            (local f64 i64 f64)
+            local.get 0
+            i64.extend_i32_u
+            local.set 2
+            f64.const 1
+            local.set 1
+            f64.const 2
+            local.set 3
+            local.get 2))
+"#;
+
+/// The offsets of a function's instructions, and the types of its locals.
+type Func = (Vec<usize>, Vec<wasmparser::ValType>);
+
+/// The code section payload offset, and every function in it.
+fn code(wasm: &[u8]) -> (usize, Vec<Func>) {
+    let mut start = 0;
+    let mut funcs = Vec::new();
+    for payload in wasmparser::Parser::new(0).parse_all(wasm) {
+        match payload.unwrap() {
+            wasmparser::Payload::CodeSectionStart { range, .. } => start = range.start,
+            wasmparser::Payload::CodeSectionEntry(body) => {
+                let mut locals = Vec::new();
+                for local in body.get_locals_reader().unwrap() {
+                    let (count, ty) = local.unwrap();
+                    locals.extend((0..count).map(|_| ty));
+                }
+                let mut reader = body.get_operators_reader().unwrap();
+                let mut offsets = Vec::new();
+                while !reader.eof() {
+                    offsets.push(reader.original_position());
+                    reader.read().unwrap();
+                }
+                funcs.push((offsets, locals));
+            }
+            _ => {}
+        }
+    }
+    (start, funcs)
+}
+
+fn custom_section(wasm: &mut Vec<u8>, name: &str, data: &[u8]) {
+    let mut payload = Vec::new();
+    leb(&mut payload, name.len());
+    payload.extend_from_slice(name.as_bytes());
+    payload.extend_from_slice(data);
+    wasm.push(0);
+    leb(wasm, payload.len());
+    wasm.extend_from_slice(&payload);
+}
+
+fn leb(out: &mut Vec<u8>, mut n: usize) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+/// Describe `$second`, with a variable in its second local, and attach the
+/// result to `wasm`.
+fn with_dwarf(mut wasm: Vec<u8>) -> Vec<u8> {
+    let (start, funcs) = code(&wasm);
+    let offsets = &funcs[1].0;
+    let begin = (offsets[0] - start) as u64;
+    let end = (*offsets.last().unwrap() + 1 - start) as u64;
+
+    let encoding = Encoding {
+        version: 4,
+        address_size: 4,
+        format: Format::Dwarf32,
+    };
+    let mut unit = Unit::new(encoding, write::LineProgram::none());
+    let root = unit.root();
+    unit.get_mut(root).set(
+        constants::DW_AT_low_pc,
+        AttributeValue::Address(Address::Constant(0)),
+    );
+    let subprogram = unit.add(root, constants::DW_TAG_subprogram);
+    unit.get_mut(subprogram).set(
+        constants::DW_AT_low_pc,
+        AttributeValue::Address(Address::Constant(begin)),
+    );
+    unit.get_mut(subprogram)
+        .set(constants::DW_AT_high_pc, AttributeValue::Udata(end - begin));
+    let ranges = unit
+        .ranges
+        .add(write::RangeList(vec![write::Range::StartEnd {
+            begin: Address::Constant((offsets[3] - start) as u64),
+            end: Address::Constant((offsets[5] - start) as u64),
+        }]));
+    let block = unit.add(subprogram, constants::DW_TAG_lexical_block);
+    unit.get_mut(block).set(
+        constants::DW_AT_ranges,
+        AttributeValue::RangeListRef(ranges),
+    );
+    let variable = unit.add(subprogram, constants::DW_TAG_variable);
+    let mut location = Expression::new();
+    location.op_wasm_local(2);
+    unit.get_mut(variable)
+        .set(constants::DW_AT_location, AttributeValue::Exprloc(location));
+
+    let mut units = UnitTable::default();
+    units.add(unit);
+    let mut sections = Sections::new(write::EndianVec::new(LittleEndian));
+    units
+        .write(
+            &mut sections,
+            &write::DebugLineStrOffsets::none(),
+            &write::DebugStrOffsets::none(),
+        )
+        .unwrap();
+    sections
+        .for_each(|id, data| -> gimli::write::Result<()> {
+            if !data.slice().is_empty() {
+                custom_section(&mut wasm, id.name(), data.slice());
+            }
+            Ok(())
+        })
+        .unwrap();
+    wasm
+}
+
+struct Described {
+    low_pc: u64,
+    high_pc: u64,
+    block: Vec<(u64, u64)>,
+    local: u32,
+}
+
+fn read_dwarf(wasm: &[u8]) -> Described {
+    let module = Module::from_buffer(wasm).unwrap();
+    #[allow(deprecated)]
+    let dwarf = module
+        .debug
+        .dwarf
+        .borrow(|section| EndianSlice::new(section, LittleEndian));
+    let header = dwarf.units().next().unwrap().unwrap();
+    let unit = dwarf.unit(header).unwrap();
+    let mut entries = unit.entries();
+    let mut described = Described {
+        low_pc: 0,
+        high_pc: 0,
+        block: Vec::new(),
+        local: u32::MAX,
+    };
+    while let Some((_, entry)) = entries.next_dfs().unwrap() {
+        match entry.tag() {
+            constants::DW_TAG_subprogram => {
+                let low_pc = dwarf.attr_address(
+                    &unit,
+                    entry.attr_value(constants::DW_AT_low_pc).unwrap().unwrap(),
+                );
+                described.low_pc = low_pc.unwrap().unwrap();
+                described.high_pc = described.low_pc
+                    + entry
+                        .attr_value(constants::DW_AT_high_pc)
+                        .unwrap()
+                        .unwrap()
+                        .udata_value()
+                        .unwrap();
+            }
+            constants::DW_TAG_lexical_block => {
+                let mut ranges = dwarf.die_ranges(&unit, entry).unwrap();
+                while let Some(range) = ranges.next().unwrap() {
+                    described.block.push((range.begin, range.end));
+                }
+            }
+            constants::DW_TAG_variable => {
+                let location = entry
+                    .attr_value(constants::DW_AT_location)
+                    .unwrap()
+                    .unwrap();
+                let expression = location.exprloc_value().unwrap();
+                let mut ops = expression.operations(unit.encoding());
+                match ops.next().unwrap().unwrap() {
+                    gimli::Operation::WasmLocal { index } => described.local = index,
+                    op => panic!("unexpected operation {:?}", op),
+                }
+            }
+            _ => {}
+        }
+    }
+    described
+}
+
+#[test]
+fn dwarf_follows_transformed_code() {
+    let input = with_dwarf(wat::parse_str(WAT).unwrap());
+    let (start, funcs) = code(&input);
+    let offsets = &funcs[1].0;
+
+    let mut config = ModuleConfig::new();
+    config.generate_dwarf(true);
+    let mut module = config.parse(&input).unwrap();
+
+    // Grow `$first` past `$second`, so that it is emitted first and moves
+    // `$second` along.
+    let first = module.funcs.by_name("first").unwrap();
+    let func = module.funcs.get_mut(first).kind.unwrap_local_mut();
+    let entry = func.entry_block();
+    let mut builder = func.builder_mut().instr_seq(entry);
+    for i in 0..10 {
+        builder.const_(Value::I32(i)).drop();
+    }
+    let output = module.emit_wasm();
+
+    let (new_start, new_funcs) = code(&output);
+    let (new_offsets, new_locals) = new_funcs
+        .iter()
+        .find(|(_, locals)| !locals.is_empty())
+        .unwrap();
+    let address = |offset: usize| (offset - new_start) as u64;
+    assert!(address(new_offsets[0]) > (offsets[0] - start) as u64);
+
+    let described = read_dwarf(&output);
+    assert_eq!(described.low_pc, address(new_offsets[0]));
+    assert_eq!(described.high_pc, address(*new_offsets.last().unwrap() + 1));
+    assert_eq!(
+        described.block,
+        [(address(new_offsets[3]), address(new_offsets[5]))]
+    );
+    // Locals are renumbered on emission, and the variable must still point at
+    // the `i64` one. The first local is the parameter.
+    assert_eq!(
+        new_locals[described.local as usize - 1],
+        wasmparser::ValType::I64
+    );
+}
+
+fn custom_sections(wasm: &[u8]) -> Vec<(String, Vec<u8>)> {
+    wasmparser::Parser::new(0)
+        .parse_all(wasm)
+        .filter_map(|payload| match payload.unwrap() {
+            wasmparser::Payload::CustomSection(s) => {
+                Some((s.name().to_string(), s.data().to_vec()))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn split_dwarf_into_debug_file() {
+    let mut input = with_dwarf(wat::parse_str(WAT).unwrap());
+    custom_section(&mut input, "build_id", &[4, 0xde, 0xad, 0xbe, 0xef]);
+
+    let mut config = ModuleConfig::new();
+    config.generate_producers_section(false);
+    let mut module = config.parse(&input).unwrap();
+    module.external_debug_info = Some(ExternalDebugInfo {
+        url: "app.debug.wasm".to_string(),
+    });
+    let (wasm, debug) = module.emit_wasm_split_dwarf();
+
+    let names = |sections: &[(String, Vec<u8>)]| {
+        sections
+            .iter()
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>()
+    };
+    let main_sections = custom_sections(&wasm);
+    assert_eq!(
+        names(&main_sections),
+        ["name", "external_debug_info", "build_id"]
+    );
+    assert_eq!(main_sections[1].1, b"\x0eapp.debug.wasm");
+    let module = Module::from_buffer(&wasm).unwrap();
+    assert_eq!(module.external_debug_info.unwrap().url, "app.debug.wasm");
+
+    let debug_sections = custom_sections(&debug);
+    assert_eq!(debug_sections.last(), main_sections.last());
+    assert!(debug_sections[..debug_sections.len() - 1]
+        .iter()
+        .all(|(name, _)| name.starts_with(".debug_")));
+
+    // The debug file describes the code in the stripped module.
+    let (start, funcs) = code(&wasm);
+    let (offsets, _) = funcs.iter().find(|(_, locals)| !locals.is_empty()).unwrap();
+    let described = read_dwarf(&debug);
+    assert_eq!(described.low_pc, (offsets[0] - start) as u64);
+}
@@ -0,0 +1,224 @@
+//! Tests for switching memories and tables between 32-bit and 64-bit indices.
+
+use walrus::interp::Val;
+use walrus::ir::Value;
+use walrus::passes::index_type;
+use walrus::{ConstExpr, DataKind, ElementKind};
+use walrus_tests_utils::{body, emit, outcomes, parse, print};
+
+const WAT: &str = r#"
+    (module
+        (memory $m i64 1)
+        (table $t i64 2 funcref)
+        (data (memory $m) (i64.const 8) "a")
+        (elem (table $t) (i64.const 1) func $load)
+        (func $load (export "load") (param i64) (result i32)
+            local.get 0
+            i32.load)
+        (func $store (export "store") (param i64 i32)
+            local.get 0
+            local.get 1
+            i32.store)
+        (func $size (export "size") (result i64)
+            memory.size)
+        (func $grow (export "grow") (param i64) (result i64)
+            local.get 0
+            memory.grow)
+        (func $call (export "call") (param i64) (result i32)
+            i64.const 0
+            local.get 0
+            call_indirect $t (param i64) (result i32)))
+"#;
+
+#[test]
+fn memories_can_be_lowered_and_raised() {
+    let mut module = parse(WAT);
+    let memory = module.memories.iter().next().unwrap().id();
+    index_type::set_memory64(&mut module, memory, false).unwrap();
+    let m = module.memories.get(memory);
+    assert!(!m.memory64);
+    assert_eq!(m.maximum, Some(65536));
+    let data = module.data.iter().next().unwrap();
+    assert!(matches!(
+        data.kind,
+        DataKind::Active {
+            offset: ConstExpr::Value(Value::I32(8)),
+            ..
+        }
+    ));
+
+    let text = print(&mut module);
+    assert!(text.contains("(memory $m (;0;) 1 65536)"));
+    assert!(body(&text, "load").contains("i32.wrap_i64"));
+    assert!(body(&text, "load").contains("unreachable"));
+    assert!(body(&text, "store").contains("i32.wrap_i64"));
+    assert!(body(&text, "size").contains("i64.extend_i32_u"));
+    // Growing by more than fits in 32 bits fails rather than trapping.
+    assert!(body(&text, "grow").contains("i64.const -1"));
+    assert!(!body(&text, "call").contains("i32.wrap_i64"));
+
+    index_type::set_memory64(&mut module, memory, true).unwrap();
+    let text = print(&mut module);
+    assert!(text.contains("(memory $m (;0;) i64 1 65536)"));
+    assert!(body(&text, "size").contains("i32.wrap_i64"));
+}
+
+#[test]
+fn tables_can_be_lowered() {
+    let mut module = parse(WAT);
+    let table = module.tables.iter().next().unwrap().id();
+    index_type::set_table64(&mut module, table, false).unwrap();
+    assert!(!module.tables.get(table).table64);
+    let elem = module.elements.iter().next().unwrap();
+    assert!(matches!(
+        elem.kind,
+        ElementKind::Active {
+            offset: ConstExpr::Value(Value::I32(1)),
+            ..
+        }
+    ));
+
+    let text = print(&mut module);
+    assert!(body(&text, "call").contains("i32.wrap_i64"));
+    assert!(!body(&text, "load").contains("i32.wrap_i64"));
+}
+
+#[test]
+fn unconvertible_memories_are_rejected() {
+    for wat in [
+        "(module (import \"env\" \"memory\" (memory i64 1)))",
+        "(module (memory (export \"memory\") i64 1))",
+        "(module (memory i64 65537))",
+        "(module (memory i64 1) (data (i64.const 0x1_0000_0000) \"\"))",
+        "(module (import \"env\" \"g\" (global i64)) (memory i64 1) (data (global.get 0) \"\"))",
+    ] {
+        let mut module = parse(wat);
+        let memory = module.memories.iter().next().unwrap().id();
+        assert!(index_type::set_memory64(&mut module, memory, false).is_err());
+        assert!(module.memories.get(memory).memory64);
+    }
+}
+
+#[test]
+fn unconvertible_tables_are_rejected() {
+    for wat in [
+        "(module (import \"env\" \"table\" (table i64 1 funcref)))",
+        "(module (table (export \"table\") i64 1 funcref))",
+    ] {
+        let mut module = parse(wat);
+        let table = module.tables.iter().next().unwrap().id();
+        assert!(index_type::set_table64(&mut module, table, false).is_err());
+        assert!(module.tables.get(table).table64);
+    }
+}
+
+#[test]
+fn lowered_memories_trap_and_fail_like_the_original() {
+    let calls: [(&str, &[Val]); 9] = [
+        ("load", &[Val::I64(8)]),
+        ("load", &[Val::I64(65532)]),
+        ("load", &[Val::I64(65533)]),
+        ("load", &[Val::I64(0x1_0000_0008)]),
+        ("store", &[Val::I64(0x1_0000_0000), Val::I32(1)]),
+        ("store", &[Val::I64(16), Val::I32(0x6362)]),
+        ("load", &[Val::I64(16)]),
+        ("grow", &[Val::I64(1)]),
+        ("size", &[]),
+    ];
+    let expected = outcomes(&parse(WAT), &calls);
+    let mut module = parse(WAT);
+    let memory = module.memories.iter().next().unwrap().id();
+    index_type::set_memory64(&mut module, memory, false).unwrap();
+    emit(&mut module);
+    assert_eq!(outcomes(&module, &calls), expected);
+    assert_eq!(
+        expected,
+        [
+            Some(vec![Val::I32(0x61)]),
+            Some(vec![Val::I32(0)]),
+            None,
+            None,
+            None,
+            Some(vec![]),
+            Some(vec![Val::I32(0x6362)]),
+            Some(vec![Val::I64(1)]),
+            Some(vec![Val::I64(2)]),
+        ]
+    );
+
+    // The original would really try to grow by this much, so only the lowered
+    // memory is checked.
+    let calls: [(&str, &[Val]); 3] = [
+        ("grow", &[Val::I64(0x1_0000_0000)]),
+        ("grow", &[Val::I64(-1)]),
+        ("size", &[]),
+    ];
+    assert_eq!(
+        outcomes(&module, &calls),
+        [
+            Some(vec![Val::I64(-1)]),
+            Some(vec![Val::I64(-1)]),
+            Some(vec![Val::I64(1)]),
+        ]
+    );
+}
+
+#[test]
+fn raising_and_lowering_again_keeps_behaviour() {
+    let wat = r#"
+        (module
+            (memory 1 3)
+            (data (i32.const 8) "abcd")
+            (func (export "load") (param i32) (result i32)
+                local.get 0
+                i32.load offset=2)
+            (func (export "fill") (param i32 i32 i32)
+                local.get 0
+                local.get 1
+                local.get 2
+                memory.fill)
+            (func (export "size") (result i32)
+                memory.size)
+            (func (export "grow") (param i32) (result i32)
+                local.get 0
+                memory.grow))
+    "#;
+    let calls: [(&str, &[Val]); 11] = [
+        ("load", &[Val::I32(8)]),
+        ("load", &[Val::I32(65530)]),
+        ("load", &[Val::I32(65531)]),
+        ("load", &[Val::I32(-1)]),
+        ("fill", &[Val::I32(9), Val::I32(0x7a), Val::I32(2)]),
+        ("load", &[Val::I32(8)]),
+        ("fill", &[Val::I32(65535), Val::I32(0), Val::I32(2)]),
+        ("grow", &[Val::I32(-1)]),
+        ("grow", &[Val::I32(2)]),
+        ("grow", &[Val::I32(1)]),
+        ("size", &[]),
+    ];
+    let expected = outcomes(&parse(wat), &calls);
+    let mut module = parse(wat);
+    let memory = module.memories.iter().next().unwrap().id();
+    index_type::set_memory64(&mut module, memory, true).unwrap();
+    emit(&mut module);
+    assert_eq!(outcomes(&module, &calls), expected);
+    index_type::set_memory64(&mut module, memory, false).unwrap();
+    emit(&mut module);
+    assert_eq!(outcomes(&module, &calls), expected);
+    assert_eq!(
+        expected,
+        [
+            Some(vec![Val::I32(0x6463)]),
+            Some(vec![Val::I32(0)]),
+            None,
+            None,
+            Some(vec![]),
+            Some(vec![Val::I32(0x647a)]),
+            None,
+            Some(vec![Val::I32(-1)]),
+            Some(vec![Val::I32(1)]),
+            Some(vec![Val::I32(-1)]),
+            Some(vec![Val::I32(3)]),
+        ]
+    );
+}
@@ -0,0 +1,185 @@
+//! Tests for calling hooks on function entry and exit and around calls.
+
+use walrus::interp::Val;
+use walrus::passes::instrument::{self, Manifest, Options};
+use walrus::Module;
+use walrus_tests_utils::hooks;
+
+fn hook(name: &str) -> Option<(String, String)> {
+    Some(("trace".to_string(), name.to_string()))
+}
+
+fn instrument(wat: &str, options: &Options) -> (Module, Manifest) {
+    walrus_tests_utils::instrument(wat, |module| instrument::run(module, options))
+}
+
+/// Call the export `name` with `args`, and return its results and the hooks
+/// called along the way, with the names of the functions they were given.
+fn trace(
+    module: &Module,
+    manifest: &Manifest,
+    name: &str,
+    args: &[Val],
+) -> (Vec<Val>, Vec<String>) {
+    let (results, instance) = walrus_tests_utils::trace(module, name, args);
+    let calls = hooks(&instance, "trace")
+        .into_iter()
+        .map(|(hook, args)| {
+            let names = args
+                .iter()
+                .map(|arg| match arg {
+                    Val::I32(-1) => "?",
+                    Val::I32(id) => &manifest.names[*id as usize][..],
+                    _ => unreachable!(),
+                })
+                .collect::<Vec<_>>();
+            format!("{} {}", hook, names.join(" "))
+        })
+        .collect();
+    (results.unwrap_or_default(), calls)
+}
+
+#[test]
+fn every_exit_calls_the_exit_hook() {
+    let options = Options {
+        entry: hook("entry"),
+        exit: hook("exit"),
+        ..Options::default()
+    };
+    let (module, manifest) = instrument(
+        r#"
+        (module
+            (tag $e (param i32))
+            (func $exits (export "exits") (param i32) (result i32)
+                block (result i32)
+                    block (result i32)
+                        block (result i32)
+                            local.get 0
+                            local.get 0
+                            br_table 0 1 2 3
+                        end
+                        drop
+                        i32.const 10
+                        return
+                    end
+                    drop
+                    i32.const 11
+                    local.get 0
+                    br_if 1
+                    drop
+                    i32.const 12
+                end
+                drop
+                i32.const 13)
+            (func $catches (export "catches") (param i32) (result i32)
+                try_table (catch $e 0)
+                    local.get 0
+                    throw $e
+                end
+                unreachable)
+            (func $tail (export "tail") (param i32) (result i32)
+                local.get 0
+                return_call $exits))
+        "#,
+        &options,
+    );
+    assert_eq!(manifest.names, ["exits", "catches", "tail"]);
+
+    let expected = vec!["entry exits".to_string(), "exit exits".to_string()];
+    for (arg, result) in [(0, 10), (1, 11), (2, 13), (3, 3)] {
+        let (results, calls) = trace(&module, &manifest, "exits", &[Val::I32(arg)]);
+        assert_eq!(results, [Val::I32(result)]);
+        assert_eq!(calls, expected);
+    }
+
+    let (results, calls) = trace(&module, &manifest, "catches", &[Val::I32(7)]);
+    assert_eq!(results, [Val::I32(7)]);
+    assert_eq!(calls, ["entry catches", "exit catches"]);
+
+    let (results, calls) = trace(&module, &manifest, "tail", &[Val::I32(0)]);
+    assert_eq!(results, [Val::I32(10)]);
+    assert_eq!(
+        calls,
+        ["entry tail", "exit tail", "entry exits", "exit exits"]
+    );
+}
+
+#[test]
+fn calls_and_exceptions() {
+    let options = Options {
+        entry: hook("entry"),
+        exit: hook("exit"),
+        before_call: hook("before"),
+        after_call: hook("after"),
+        exceptions: true,
+    };
+    let wat = r#"
+        (module
+            (import "env" "log" (func (param i32)))
+            (tag $e)
+            (table 1 funcref)
+            (elem (i32.const 0) func $leaf)
+            (func $leaf (param i32) (result i32)
+                local.get 0
+                i32.eqz
+                if
+                    throw $e
+                end
+                local.get 0)
+            (func $root (export "root") (param i32) (result i32)
+                local.get 0
+                call 0
+                local.get 0
+                i32.const 0
+                call_indirect (param i32) (result i32)))
+    "#;
+    let (module, manifest) = instrument(wat, &options);
+    assert_eq!(manifest.names, ["env.log", "leaf", "root"]);
+    assert_eq!(manifest.to_string(), "0\tenv.log\n1\tleaf\n2\troot\n");
+
+    let (results, calls) = trace(&module, &manifest, "root", &[Val::I32(5)]);
+    assert_eq!(results, [Val::I32(5)]);
+    assert_eq!(
+        calls,
+        [
+            "entry root",
+            "before root env.log",
+            "after root env.log",
+            "before root ?",
+            "entry leaf",
+            "exit leaf",
+            "after root ?",
+            "exit root",
+        ]
+    );
+
+    // The exception unwinds through both functions, calling the exit hook in
+    // each.
+    let (results, calls) = trace(&module, &manifest, "root", &[Val::I32(0)]);
+    assert!(results.is_empty());
+    assert_eq!(
+        calls,
+        [
+            "entry root",
+            "before root env.log",
+            "after root env.log",
+            "before root ?",
+            "entry leaf",
+            "exit leaf",
+            "exit root",
+        ]
+    );
+}
+
+#[test]
+fn existing_imports_are_rejected() {
+    let mut module = Module::from_buffer(
+        &wat::parse_str(r#"(module (import "trace" "entry" (func)))"#).unwrap(),
+    )
+    .unwrap();
+    let options = Options {
+        entry: hook("entry"),
+        ..Options::default()
+    };
+    assert!(instrument::run(&mut module, &options).is_err());
+}
@@ -0,0 +1,147 @@
+//! Tests for calling hooks before every memory access.
+
+use walrus::interp::{Trap, Val};
+use walrus::passes::instrument_memory::{self, Options};
+use walrus::Module;
+use walrus_tests_utils::{hooks, parse};
+
+fn options() -> Options {
+    Options {
+        load: Some(("asan".to_string(), "load".to_string())),
+        store: Some(("asan".to_string(), "store".to_string())),
+    }
+}
+
+fn instrument(wat: &str, options: &Options) -> Module {
+    walrus_tests_utils::instrument(wat, |module| instrument_memory::run(module, options)).0
+}
+
+/// Call the export `name` with `args`, and return its results or trap and the
+/// hooks called along the way as `hook address size offset memory`.
+fn trace(module: &Module, name: &str, args: &[Val]) -> (Result<Vec<Val>, Trap>, Vec<String>) {
+    let (results, instance) = walrus_tests_utils::trace(module, name, args);
+    let calls = hooks(&instance, "asan")
+        .into_iter()
+        .map(|(hook, args)| match *args {
+            [Val::I64(address), Val::I64(size), Val::I64(offset), Val::I32(memory)] => {
+                format!("{} {} {} {} {}", hook, address, size, offset, memory)
+            }
+            _ => unreachable!(),
+        })
+        .collect();
+    (results, calls)
+}
+
+#[test]
+fn loads_and_stores() {
+    let module = instrument(
+        r#"
+        (module
+            (memory 1 1 shared)
+            (func (export "run") (param i32) (result i64)
+                local.get 0
+                i64.const 7
+                i64.store8 offset=3
+                local.get 0
+                f32.const 1
+                f32.store offset=16
+                local.get 0
+                i64.load offset=3)
+            ;; Only validated, since the interpreter doesn't run atomics.
+            (func (param i64) (result i32)
+                i32.const 0
+                local.get 0
+                i64.atomic.rmw8.add_u
+                i32.wrap_i64
+                i32.const 0
+                i32.const 1
+                i32.const 2
+                i32.atomic.rmw.cmpxchg offset=4
+                i32.add))
+        "#,
+        &options(),
+    );
+    let (results, calls) = trace(&module, "run", &[Val::I32(16)]);
+    assert_eq!(results, Ok(vec![Val::I64(7)]));
+    assert_eq!(
+        calls,
+        ["store 16 1 3 0", "store 16 4 16 0", "load 16 8 3 0",]
+    );
+
+    // The hooks see an access before it traps, with 32-bit addresses
+    // zero-extended.
+    let (results, calls) = trace(&module, "run", &[Val::I32(-16)]);
+    assert_eq!(results, Err(Trap::MemoryOutOfBounds));
+    assert_eq!(calls, ["store 4294967280 1 3 0"]);
+}
+
+#[test]
+fn bulk_memory_and_memory64() {
+    let module = instrument(
+        r#"
+        (module
+            (memory $a 1)
+            (memory $b i64 1)
+            (data $d "hello")
+            (func (export "run") (result i32)
+                i64.const 100
+                i32.const 5
+                i64.const 6
+                memory.fill $b
+                i32.const 10
+                i64.const 100
+                i32.const 4
+                memory.copy $a $b
+                i32.const 20
+                i32.const 1
+                i32.const 3
+                memory.init $a $d
+                i32.const 10
+                i32.load8_u $a
+                i32.const 21
+                i32.load8_u $a
+                i32.add))
+        "#,
+        &options(),
+    );
+    let (results, calls) = trace(&module, "run", &[]);
+    assert_eq!(results, Ok(vec![Val::I32(5 + i32::from(b'l'))]));
+    assert_eq!(
+        calls,
+        [
+            "store 100 6 0 1",
+            "load 100 4 0 1",
+            "store 10 4 0 0",
+            "store 20 3 0 0",
+            "load 10 1 0 0",
+            "load 21 1 0 0",
+        ]
+    );
+}
+
+#[test]
+fn hooks_are_optional() {
+    let wat = r#"
+        (module
+            (memory 1)
+            (func (export "run") (result i32)
+                i32.const 0
+                i32.const 42
+                i32.store
+                i32.const 0
+                i32.load))
+    "#;
+    let options = Options {
+        store: None,
+        ..options()
+    };
+    let module = instrument(wat, &options);
+    let (results, calls) = trace(&module, "run", &[]);
+    assert_eq!(results, Ok(vec![Val::I32(42)]));
+    assert_eq!(calls, ["load 0 4 0 0"]);
+
+    let mut module = parse(wat);
+    let ty = module.types.add(&[], &[]);
+    module.add_import_func("asan", "load", ty);
+    assert!(instrument_memory::run(&mut module, &options).is_err());
+}
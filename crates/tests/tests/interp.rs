@@ -0,0 +1,360 @@
+//! Tests for the reference interpreter.
+
+use walrus::interp::{DummyHost, Instance, Ref, Trap, Val};
+use walrus::Module;
+use walrus_tests_utils::parse;
+
+fn call(module: &Module, name: &str, args: &[Val]) -> Result<Vec<Val>, Trap> {
+    let mut instance = Instance::new(module, DummyHost::default()).unwrap();
+    let func = module.exports.get_func(name).unwrap();
+    instance.call(func, args)
+}
+
+#[test]
+fn control_flow_and_multi_value() {
+    let module = parse(
+        r#"
+        (module
+            (func $fac (export "fac") (param i64) (result i64)
+                local.get 0
+                i64.eqz
+                if (result i64)
+                    i64.const 1
+                else
+                    local.get 0
+                    local.get 0
+                    i64.const 1
+                    i64.sub
+                    call $fac
+                    i64.mul
+                end)
+            (func (export "sum") (param i32) (result i32) (local i32)
+                block
+                    loop
+                        local.get 0
+                        i32.eqz
+                        br_if 1
+                        local.get 1
+                        local.get 0
+                        i32.add
+                        local.set 1
+                        local.get 0
+                        i32.const 1
+                        i32.sub
+                        local.set 0
+                        br 0
+                    end
+                end
+                local.get 1)
+            (func (export "swap") (param i32 i32) (result i32 i32)
+                local.get 0
+                local.get 1
+                block (param i32 i32) (result i32 i32)
+                    local.set 0
+                    local.set 1
+                    local.get 0
+                    local.get 1
+                end)
+            (func (export "switch") (param i32) (result i32)
+                block
+                    block
+                        local.get 0
+                        br_table 0 1
+                    end
+                    i32.const 10
+                    return
+                end
+                i32.const 20)
+            (func (export "div") (param i32 i32) (result i32)
+                local.get 0
+                local.get 1
+                i32.div_s))
+        "#,
+    );
+    assert_eq!(
+        call(&module, "fac", &[Val::I64(20)]),
+        Ok(vec![Val::I64(2432902008176640000)])
+    );
+    assert_eq!(
+        call(&module, "sum", &[Val::I32(100)]),
+        Ok(vec![Val::I32(5050)])
+    );
+    assert_eq!(
+        call(&module, "swap", &[Val::I32(1), Val::I32(2)]),
+        Ok(vec![Val::I32(2), Val::I32(1)])
+    );
+    assert_eq!(
+        call(&module, "switch", &[Val::I32(0)]),
+        Ok(vec![Val::I32(10)])
+    );
+    assert_eq!(
+        call(&module, "switch", &[Val::I32(7)]),
+        Ok(vec![Val::I32(20)])
+    );
+    assert_eq!(
+        call(&module, "div", &[Val::I32(1), Val::I32(0)]),
+        Err(Trap::IntegerDivideByZero)
+    );
+    assert_eq!(
+        call(&module, "div", &[Val::I32(i32::MIN), Val::I32(-1)]),
+        Err(Trap::IntegerOverflow)
+    );
+}
+
+#[test]
+fn memories_and_bulk_memory() {
+    let module = parse(
+        r#"
+        (module
+            (memory (export "memory") 1 2)
+            (data (i32.const 0) "\01\02\03\04")
+            (data $passive "hello")
+            (func (export "load") (param i32) (result i32)
+                local.get 0
+                i32.load)
+            (func (export "copy") (result i64)
+                i32.const 100
+                i32.const 0
+                i32.const 4
+                memory.copy
+                i32.const 104
+                i32.const 0xff
+                i32.const 2
+                memory.fill
+                i32.const 100
+                i64.load)
+            (func (export "init") (result i32)
+                i32.const 200
+                i32.const 1
+                i32.const 4
+                memory.init $passive
+                data.drop $passive
+                i32.const 200
+                i32.load)
+            (func (export "grow") (result i32 i32 i32)
+                i32.const 1
+                memory.grow
+                i32.const 1
+                memory.grow
+                memory.size))
+        "#,
+    );
+    assert_eq!(
+        call(&module, "load", &[Val::I32(0)]),
+        Ok(vec![Val::I32(0x04030201)])
+    );
+    assert_eq!(
+        call(&module, "load", &[Val::I32(65533)]),
+        Err(Trap::MemoryOutOfBounds)
+    );
+    assert_eq!(
+        call(&module, "copy", &[]),
+        Ok(vec![Val::I64(0xffff_0403_0201)])
+    );
+    assert_eq!(call(&module, "init", &[]), Ok(vec![Val::I32(0x6f6c6c65)]));
+    assert_eq!(
+        call(&module, "grow", &[]),
+        Ok(vec![Val::I32(1), Val::I32(-1), Val::I32(2)])
+    );
+
+    // Once dropped, only empty ranges of a segment can be used.
+    let mut instance = Instance::new(&module, DummyHost::default()).unwrap();
+    let init = module.exports.get_func("init").unwrap();
+    instance.call(init, &[]).unwrap();
+    assert_eq!(instance.call(init, &[]), Err(Trap::MemoryOutOfBounds));
+    let memory = module.memories.iter().next().unwrap().id();
+    assert_eq!(&instance.memory(memory)[200..204], b"ello");
+}
+
+#[test]
+fn tables_and_references() {
+    let module = parse(
+        r#"
+        (module
+            (type $unary (func (param i32) (result i32)))
+            (table $t 3 funcref)
+            (elem (table $t) (i32.const 0) func $double $nullary)
+            (func $double (type $unary) local.get 0 i32.const 2 i32.mul)
+            (func $nullary (result i32) i32.const 0)
+            (func (export "call") (param i32 i32) (result i32)
+                local.get 1
+                local.get 0
+                call_indirect $t (type $unary))
+            (func (export "grow") (result i32 i32 i32)
+                ref.func $double
+                i32.const 2
+                table.grow $t
+                table.size $t
+                i32.const 4
+                table.get $t
+                ref.is_null)
+            (func (export "tail") (param i32) (result i32)
+                local.get 0
+                i32.const 0
+                return_call_indirect $t (type $unary)))
+        "#,
+    );
+    assert_eq!(
+        call(&module, "call", &[Val::I32(0), Val::I32(21)]),
+        Ok(vec![Val::I32(42)])
+    );
+    assert_eq!(
+        call(&module, "call", &[Val::I32(1), Val::I32(0)]),
+        Err(Trap::IndirectCallTypeMismatch)
+    );
+    assert_eq!(
+        call(&module, "call", &[Val::I32(2), Val::I32(0)]),
+        Err(Trap::UninitializedElement)
+    );
+    assert_eq!(
+        call(&module, "call", &[Val::I32(3), Val::I32(0)]),
+        Err(Trap::TableOutOfBounds)
+    );
+    assert_eq!(
+        call(&module, "grow", &[]),
+        Ok(vec![Val::I32(3), Val::I32(5), Val::I32(0)])
+    );
+    assert_eq!(call(&module, "tail", &[Val::I32(4)]), Ok(vec![Val::I32(8)]));
+}
+
+#[test]
+fn exceptions() {
+    let module = parse(
+        r#"
+        (module
+            (tag $e (param i32))
+            (tag $other)
+            (func $throw (param i32)
+                local.get 0
+                throw $e)
+            (func (export "try_table") (param i32) (result i32)
+                block $caught (result i32)
+                    try_table (catch $e $caught)
+                        local.get 0
+                        call $throw
+                    end
+                    i32.const -1
+                end)
+            (func (export "rethrown") (result i32)
+                block $outer (result i32)
+                    try_table (catch $e $outer)
+                        block $inner (result exnref)
+                            try_table (catch_all_ref $inner)
+                                i32.const 7
+                                call $throw
+                            end
+                            unreachable
+                        end
+                        throw_ref
+                    end
+                    i32.const -1
+                end)
+            (func (export "legacy") (result i32)
+                try (result i32)
+                    try
+                        i32.const 3
+                        call $throw
+                    catch $e
+                        i32.const 1
+                        i32.add
+                        throw $e
+                    end
+                    i32.const -1
+                catch $e
+                catch_all
+                    i32.const -2
+                end)
+            (func (export "legacy_rethrow") (result i32)
+                try (result i32)
+                    try
+                        i32.const 5
+                        call $throw
+                    catch_all
+                        rethrow 0
+                    end
+                    i32.const -1
+                catch $e
+                end)
+            (func (export "delegate") (result i32)
+                try (result i32)
+                    try
+                        try
+                            throw $other
+                        delegate 1
+                    catch_all
+                        unreachable
+                    end
+                    i32.const -1
+                catch $other
+                    i32.const 9
+                end)
+            (func (export "uncaught")
+                throw $other))
+        "#,
+    );
+    assert_eq!(
+        call(&module, "try_table", &[Val::I32(4)]),
+        Ok(vec![Val::I32(4)])
+    );
+    assert_eq!(call(&module, "rethrown", &[]), Ok(vec![Val::I32(7)]));
+    assert_eq!(call(&module, "legacy", &[]), Ok(vec![Val::I32(4)]));
+    assert_eq!(call(&module, "legacy_rethrow", &[]), Ok(vec![Val::I32(5)]));
+    assert_eq!(call(&module, "delegate", &[]), Ok(vec![Val::I32(9)]));
+    match call(&module, "uncaught", &[]) {
+        Err(Trap::UncaughtException(e)) => assert!(e.values.is_empty()),
+        other => panic!("expected an uncaught exception, found {:?}", other),
+    }
+}
+
+#[test]
+fn fuel_call_depth_and_the_host() {
+    let module = parse(
+        r#"
+        (module
+            (import "env" "log" (func $log (param i32) (result i32)))
+            (global $counter (mut i32) (i32.const 0))
+            (func $spin (export "spin")
+                loop
+                    br 0
+                end)
+            (func $recurse (export "recurse")
+                call $recurse)
+            (func (export "log") (result i32)
+                global.get $counter
+                i32.const 1
+                i32.add
+                global.set $counter
+                global.get $counter
+                call $log
+                ref.func $spin
+                ref.is_null
+                i32.add))
+        "#,
+    );
+    let mut instance = Instance::new(&module, DummyHost::default()).unwrap();
+    instance.set_fuel(Some(10_000));
+    let spin = module.exports.get_func("spin").unwrap();
+    assert_eq!(instance.call(spin, &[]), Err(Trap::OutOfFuel));
+    instance.set_fuel(None);
+
+    let recurse = module.exports.get_func("recurse").unwrap();
+    assert_eq!(instance.call(recurse, &[]), Err(Trap::CallStackExhausted));
+
+    let log = module.exports.get_func("log").unwrap();
+    assert_eq!(instance.call(log, &[]), Ok(vec![Val::I32(0)]));
+    assert_eq!(instance.call(log, &[]), Ok(vec![Val::I32(0)]));
+    let calls = &instance.host().calls;
+    assert_eq!(calls.len(), 2);
+    assert_eq!(calls[1], ("env".into(), "log".into(), vec![Val::I32(2)]));
+
+    let mut instance = Instance::new(&module, DummyHost::default()).unwrap();
+    instance.set_fuel(Some(10_000));
+    let outcomes = instance.run_exports();
+    let names = outcomes
+        .iter()
+        .map(|(name, _)| &name[..])
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["spin", "recurse", "log"]);
+    assert_eq!(outcomes[0].1, Err(Trap::OutOfFuel));
+    assert_ne!(Val::Ref(Ref::Null), Val::I32(0));
+}
@@ -0,0 +1,509 @@
+//! Tests for the `linking` and `reloc.*` sections of relocatable objects:
+//! relocation offsets must follow the instructions and data they patch when
+//! code is transformed.
+
+use std::collections::BTreeMap;
+use walrus::ir::{BinaryOp, InstrLocId, LoadKind, MemArg, Value};
+use walrus::{
+    CodeRelocation, ConstExpr, DataKind, DataRelocation, DataSymbolDefinition, FunctionBuilder,
+    Module, ModuleConfig, ModuleLinking, RefType, RelocationTarget, RelocationType, SegmentInfo,
+    Symbol, SymbolKind, ValType,
+};
+
+const ADDR_LOC: u32 = 100;
+const LOAD_LOC: u32 = 101;
+
+/// Build the equivalent of an object file compiled from
+///
+/// ```c
+/// extern void ext(void);
+/// int buf[2];
+/// int *ptr = &buf[1];
+/// int f(void (*g)(void)) { ext(); g(); return buf[1]; }
+/// ```
+fn object() -> Module {
+    let mut config = ModuleConfig::new();
+    config.generate_producers_section(false);
+    let mut module = Module::with_config(config);
+
+    let (memory, _) =
+        module.add_import_memory("env", "__linear_memory", false, false, 1, None, None);
+    let (table, _) = module.add_import_table(
+        "env",
+        "__indirect_function_table",
+        false,
+        0,
+        None,
+        RefType::FUNCREF,
+    );
+    let (stack_pointer, _) =
+        module.add_import_global("env", "__stack_pointer", ValType::I32, true, false);
+    let void = module.types.add(&[], &[]);
+    let (ext, _) = module.add_import_func("env", "ext", void);
+
+    let buf = module.data.add(
+        DataKind::Active {
+            memory,
+            offset: ConstExpr::Value(Value::I32(0)),
+        },
+        vec![0; 8],
+    );
+    let ptr = module.data.add(
+        DataKind::Active {
+            memory,
+            offset: ConstExpr::Value(Value::I32(8)),
+        },
+        vec![4, 0, 0, 0],
+    );
+
+    let arg = module.locals.add(ValType::I32);
+    let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[ValType::I32]);
+    builder
+        .func_body()
+        .global_get(stack_pointer)
+        .drop()
+        .call(ext)
+        .local_get(arg)
+        .call_indirect(void, table)
+        .i32_const(0)
+        .load(
+            memory,
+            LoadKind::I32 { atomic: false },
+            MemArg {
+                align: 4,
+                offset: 4,
+            },
+        );
+    let mut body = builder.func_body();
+    let instrs = body.instrs_mut();
+    instrs[5].1 = InstrLocId::new(ADDR_LOC);
+    instrs[6].1 = InstrLocId::new(LOAD_LOC);
+    let f = builder.finish(vec![arg], &mut module.funcs);
+    module.funcs.get_mut(f).name = Some("f".to_string());
+
+    let symbol = |flags, kind| Symbol { flags, kind };
+    module.linking = Some(ModuleLinking {
+        symbols: vec![
+            symbol(
+                0,
+                SymbolKind::Function {
+                    func: f,
+                    name: Some("f".to_string()),
+                },
+            ),
+            symbol(
+                Symbol::UNDEFINED,
+                SymbolKind::Function {
+                    func: ext,
+                    name: None,
+                },
+            ),
+            symbol(
+                Symbol::UNDEFINED,
+                SymbolKind::Global {
+                    global: stack_pointer,
+                    name: None,
+                },
+            ),
+            symbol(Symbol::UNDEFINED, SymbolKind::Table { table, name: None }),
+            symbol(
+                0,
+                SymbolKind::Data {
+                    name: "buf".to_string(),
+                    definition: Some(DataSymbolDefinition {
+                        data: buf,
+                        offset: 0,
+                        size: 8,
+                    }),
+                },
+            ),
+            symbol(
+                0,
+                SymbolKind::Data {
+                    name: "ptr".to_string(),
+                    definition: Some(DataSymbolDefinition {
+                        data: ptr,
+                        offset: 0,
+                        size: 4,
+                    }),
+                },
+            ),
+        ],
+        segments: vec![SegmentInfo {
+            data: buf,
+            name: ".bss.buf".to_string(),
+            alignment: 2,
+            flags: 0,
+        }],
+        code_relocs: vec![CodeRelocation {
+            ty: RelocationType::MemoryAddrLeb,
+            instr: InstrLocId::new(LOAD_LOC),
+            target: RelocationTarget::Symbol(4),
+            addend: 4,
+        }],
+        data_relocs: vec![DataRelocation {
+            ty: RelocationType::MemoryAddrI32,
+            data: ptr,
+            offset: 0,
+            symbol: 4,
+            addend: 4,
+        }],
+        ..Default::default()
+    });
+    module
+}
+
+#[derive(Debug, PartialEq)]
+struct Reloc {
+    ty: wasmparser::RelocationType,
+    index: u32,
+    addend: i64,
+    /// The value currently encoded at the relocation's offset.
+    value: i64,
+}
+
+/// Check that every relocation points at an immediate of the right width and
+/// that index relocations agree with the symbol table. Returns the code and
+/// data relocations along with the segment names.
+fn relocs(wasm: &[u8]) -> (Vec<Reloc>, Vec<Reloc>, Vec<String>) {
+    use wasmparser::RelocationType::*;
+
+    wasmparser::Validator::new().validate_all(wasm).unwrap();
+
+    // The payload offset of every section, by index.
+    let mut sections = Vec::new();
+    let mut symbols = Vec::new();
+    let mut segments = Vec::new();
+    let mut code_relocs = Vec::new();
+    let mut data_relocs = Vec::new();
+    for payload in wasmparser::Parser::new(0).parse_all(wasm) {
+        match payload.unwrap() {
+            wasmparser::Payload::Version { .. }
+            | wasmparser::Payload::CodeSectionEntry(_)
+            | wasmparser::Payload::End(_) => {}
+            wasmparser::Payload::CodeSectionStart { range, .. } => sections.push(range.start),
+            wasmparser::Payload::DataSection(s) => sections.push(s.range().start),
+            wasmparser::Payload::CustomSection(s) => {
+                sections.push(s.data_offset());
+                let reader = wasmparser::BinaryReader::new(s.data(), s.data_offset());
+                if s.name() == "linking" {
+                    let linking = wasmparser::LinkingSectionReader::new(reader).unwrap();
+                    for subsection in linking {
+                        match subsection.unwrap() {
+                            wasmparser::Linking::SymbolTable(map) => {
+                                symbols = map.into_iter().map(|s| s.unwrap()).collect()
+                            }
+                            wasmparser::Linking::SegmentInfo(map) => {
+                                for segment in map {
+                                    segments.push(segment.unwrap().name.to_string());
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
+                } else if s.name() == "reloc.CODE" || s.name() == "reloc.DATA" {
+                    let reloc = wasmparser::RelocSectionReader::new(reader).unwrap();
+                    let start = sections[reloc.section_index() as usize];
+                    for entry in reloc.entries() {
+                        let entry = entry.unwrap();
+                        let bytes = &wasm[start + entry.offset as usize..][..entry.ty.extent()];
+                        let n = bytes.len();
+                        if entry.ty.extent() == 5 || entry.ty.extent() == 10 {
+                            // Padded LEBs use every byte they're given.
+                            assert!(bytes[..n - 1].iter().all(|b| b & 0x80 != 0));
+                            assert_eq!(bytes[n - 1] & 0x80, 0);
+                        }
+                        let mut reader = wasmparser::BinaryReader::new(bytes, 0);
+                        let value = match entry.ty {
+                            MemoryAddrI32 | TableIndexI32 => {
+                                u32::from_le_bytes(bytes.try_into().unwrap()) as i64
+                            }
+                            FunctionIndexLeb | TypeIndexLeb | GlobalIndexLeb | EventIndexLeb
+                            | TableNumberLeb | MemoryAddrLeb => {
+                                reader.read_var_u32().unwrap() as i64
+                            }
+                            MemoryAddrSleb | TableIndexSleb => {
+                                reader.read_var_i32().unwrap() as i64
+                            }
+                            ty => panic!("unexpected relocation type {:?}", ty),
+                        };
+                        let reloc = Reloc {
+                            ty: entry.ty,
+                            index: entry.index,
+                            addend: entry.addend,
+                            value,
+                        };
+                        match s.name() {
+                            "reloc.CODE" => code_relocs.push(reloc),
+                            _ => data_relocs.push(reloc),
+                        }
+                    }
+                }
+            }
+            _ => sections.push(0),
+        }
+    }
+
+    // Index relocations must encode the index of their symbol.
+    for reloc in &code_relocs {
+        let expected = match reloc.ty {
+            TypeIndexLeb => reloc.index,
+            FunctionIndexLeb | GlobalIndexLeb | TableNumberLeb | EventIndexLeb => {
+                match symbols[reloc.index as usize] {
+                    wasmparser::SymbolInfo::Func { index, .. }
+                    | wasmparser::SymbolInfo::Global { index, .. }
+                    | wasmparser::SymbolInfo::Table { index, .. }
+                    | wasmparser::SymbolInfo::Event { index, .. } => index,
+                    ref s => panic!("index relocation against {:?}", s),
+                }
+            }
+            _ => continue,
+        };
+        assert_eq!(reloc.value, expected as i64, "{:?}", reloc);
+    }
+
+    (code_relocs, data_relocs, segments)
+}
+
+fn reloc(ty: wasmparser::RelocationType, index: u32, addend: i64, value: i64) -> Reloc {
+    Reloc {
+        ty,
+        index,
+        addend,
+        value,
+    }
+}
+
+#[test]
+fn emit_relocatable_object() {
+    use wasmparser::RelocationType::*;
+
+    let wasm = object().emit_wasm();
+    let (code, data, segments) = relocs(&wasm);
+    assert_eq!(
+        code,
+        vec![
+            reloc(GlobalIndexLeb, 2, 0, 0),
+            reloc(FunctionIndexLeb, 1, 0, 0),
+            reloc(TypeIndexLeb, 0, 0, 0),
+            reloc(TableNumberLeb, 3, 0, 0),
+            reloc(MemoryAddrLeb, 4, 4, 4),
+        ]
+    );
+    assert_eq!(data, vec![reloc(MemoryAddrI32, 4, 4, 4)]);
+    assert_eq!(
+        segments,
+        vec![".bss.buf".to_string(), ".data.1".to_string()]
+    );
+}
+
+#[test]
+fn relocations_follow_transformed_code() {
+    use wasmparser::RelocationType::*;
+
+    let wasm = object().emit_wasm();
+    let mut module = Module::from_buffer(&wasm).unwrap();
+
+    let linking = module.linking.as_ref().unwrap();
+    assert_eq!(linking.symbols.len(), 6);
+    assert_eq!(linking.symbols[0].name(), Some("f"));
+    assert_eq!(linking.code_relocs.len(), 5);
+    assert_eq!(linking.data_relocs.len(), 1);
+    let buf = match &linking.symbols[4].kind {
+        SymbolKind::Data {
+            definition: Some(def),
+            ..
+        } => def.data,
+        kind => panic!("unexpected symbol {:?}", kind),
+    };
+    assert_eq!(linking.segments[0].data, buf);
+
+    // Add a new function, called from the start of `f` after some arithmetic
+    // that shifts every existing immediate.
+    let helper =
+        FunctionBuilder::new(&mut module.types, &[], &[]).finish(vec![], &mut module.funcs);
+    let f = module.funcs.by_name("f").unwrap();
+    let body = module.funcs.get_mut(f).kind.unwrap_local_mut();
+    let entry = body.entry_block();
+    body.block_mut(entry).instrs.splice(
+        0..0,
+        [
+            (
+                walrus::ir::Const {
+                    value: Value::I32(1 << 20),
+                }
+                .into(),
+                InstrLocId::default(),
+            ),
+            (
+                walrus::ir::Const {
+                    value: Value::I32(1),
+                }
+                .into(),
+                InstrLocId::default(),
+            ),
+            (
+                walrus::ir::Binop {
+                    op: BinaryOp::I32Add,
+                }
+                .into(),
+                InstrLocId::default(),
+            ),
+            (walrus::ir::Drop {}.into(), InstrLocId::default()),
+            (
+                walrus::ir::Call { func: helper }.into(),
+                InstrLocId::default(),
+            ),
+        ],
+    );
+
+    let wasm = module.emit_wasm();
+    let (code, data, _) = relocs(&wasm);
+    assert_eq!(
+        code,
+        vec![
+            reloc(FunctionIndexLeb, 6, 0, 2),
+            reloc(GlobalIndexLeb, 2, 0, 0),
+            reloc(FunctionIndexLeb, 1, 0, 0),
+            reloc(TypeIndexLeb, 0, 0, 0),
+            reloc(TableNumberLeb, 3, 0, 0),
+            reloc(MemoryAddrLeb, 4, 4, 4),
+        ]
+    );
+    assert_eq!(data, vec![reloc(MemoryAddrI32, 4, 4, 4)]);
+
+    // The new function got a local symbol, and reparsing keeps everything.
+    let module = Module::from_buffer(&wasm).unwrap();
+    let linking = module.linking.as_ref().unwrap();
+    assert_eq!(linking.symbols.len(), 7);
+    assert_eq!(linking.symbols[6].flags, Symbol::BINDING_LOCAL);
+    assert_eq!(linking.code_relocs.len(), 6);
+}
+
+/// An object compiled by rustc with debug info, see `linking/rust_debug.rs`.
+const RUST_DEBUG: &[u8] = include_bytes!("linking/rust_debug.o");
+
+type CustomRelocs =
+    BTreeMap<String, (Vec<u8>, Vec<(wasmparser::RelocationType, u32, String, i64)>)>;
+
+/// The payload and relocations of every custom section with relocations, by
+/// name. Symbols are given by name, so that relocations can be compared across
+/// symbol tables.
+fn custom_relocs(wasm: &[u8]) -> CustomRelocs {
+    let mut sections = Vec::new();
+    let mut symbols = Vec::new();
+    let mut relocs = Vec::new();
+    for payload in wasmparser::Parser::new(0).parse_all(wasm) {
+        match payload.unwrap() {
+            wasmparser::Payload::Version { .. }
+            | wasmparser::Payload::CodeSectionEntry(_)
+            | wasmparser::Payload::End(_) => {}
+            wasmparser::Payload::CustomSection(s) => {
+                sections.push(Some((s.name(), s.data())));
+                let reader = wasmparser::BinaryReader::new(s.data(), s.data_offset());
+                if s.name() == "linking" {
+                    let linking = wasmparser::LinkingSectionReader::new(reader).unwrap();
+                    for subsection in linking {
+                        if let wasmparser::Linking::SymbolTable(map) = subsection.unwrap() {
+                            symbols = map.into_iter().map(|s| s.unwrap()).collect();
+                        }
+                    }
+                } else if s.name().starts_with("reloc.") {
+                    let reloc = wasmparser::RelocSectionReader::new(reader).unwrap();
+                    let entries = reloc
+                        .entries()
+                        .into_iter()
+                        .map(|e| e.unwrap())
+                        .collect::<Vec<_>>();
+                    relocs.push((reloc.section_index(), entries));
+                }
+            }
+            _ => sections.push(None),
+        }
+    }
+
+    let mut result = CustomRelocs::new();
+    for (section, entries) in relocs {
+        let (name, data) = match sections[section as usize] {
+            Some(section) => section,
+            None => continue,
+        };
+        let entries = entries
+            .iter()
+            .map(|e| {
+                let symbol = match symbols[e.index as usize] {
+                    wasmparser::SymbolInfo::Section { section, .. } => {
+                        sections[section as usize].unwrap().0.to_string()
+                    }
+                    wasmparser::SymbolInfo::Data { name, .. } => name.to_string(),
+                    ref s => format!("{:?}", s),
+                };
+                (e.ty, e.offset, symbol, e.addend)
+            })
+            .collect();
+        result.insert(name.to_string(), (data.to_vec(), entries));
+    }
+    result
+}
+
+#[test]
+fn rustc_object_round_trips() {
+    let mut module = Module::from_buffer(RUST_DEBUG).unwrap();
+    let linking = module.linking.clone().unwrap();
+    assert!(!linking.code_relocs.is_empty());
+    assert!(!linking.data_relocs.is_empty());
+    assert!(linking
+        .custom_relocs
+        .iter()
+        .any(|r| r.section == ".debug_info" && r.ty == RelocationType::FunctionOffsetI32));
+    assert!(linking
+        .symbols
+        .iter()
+        .any(|s| matches!(&s.kind, SymbolKind::Section { section } if section == ".debug_str")));
+
+    let wasm = module.emit_wasm();
+    assert_eq!(relocs(&wasm), relocs(RUST_DEBUG));
+    let debug = custom_relocs(RUST_DEBUG);
+    assert!(debug.contains_key(".debug_info"));
+    assert_eq!(custom_relocs(&wasm), debug);
+
+    // Reparsing keeps every relocation.
+    let module = Module::from_buffer(&wasm).unwrap();
+    let reparsed = module.linking.as_ref().unwrap();
+    let names = |l: &ModuleLinking| {
+        l.symbols
+            .iter()
+            .map(|s| (s.flags, s.name().map(String::from)))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(names(reparsed), names(&linking));
+    assert_eq!(reparsed.custom_relocs, linking.custom_relocs);
+}
+
+#[test]
+fn symbols_of_removed_sections_are_dropped() {
+    let mut module = Module::from_buffer(RUST_DEBUG).unwrap();
+    module.customs.remove_raw(".debug_abbrev").unwrap();
+    let wasm = module.emit_wasm();
+    relocs(&wasm);
+
+    // Only relocations against the removed section are gone, and every other
+    // relocation still refers to the same symbol.
+    let mut expected = custom_relocs(RUST_DEBUG);
+    assert!(expected[".debug_info"]
+        .1
+        .iter()
+        .any(|r| r.2 == ".debug_abbrev"));
+    for (_, relocs) in expected.values_mut() {
+        relocs.retain(|r| r.2 != ".debug_abbrev");
+    }
+    expected.retain(|name, _| name != ".debug_abbrev");
+    assert_eq!(custom_relocs(&wasm), expected);
+
+    let module = Module::from_buffer(&wasm).unwrap();
+    let linking = module.linking.as_ref().unwrap();
+    assert!(linking
+        .symbols
+        .iter()
+        .all(|s| s.name() != Some(".debug_abbrev")));
+}
@@ -0,0 +1,56 @@
+// Source of `rust_debug.o`, built with
+//
+//     rustc +nightly --target wasm32-unknown-unknown --emit=obj -g \
+//         -C opt-level=1 -C panic=abort rust_debug.rs
+//
+// `no_core` keeps the object small while still giving it code, data and DWARF
+// relocations.
+
+#![feature(no_core, lang_items, auto_traits)]
+#![allow(internal_features)]
+#![no_core]
+#![crate_type = "lib"]
+
+#[lang = "pointee_sized"]
+pub trait PointeeSized {}
+#[lang = "meta_sized"]
+pub trait MetaSized: PointeeSized {}
+#[lang = "sized"]
+pub trait Sized: MetaSized {}
+#[lang = "sync"]
+pub unsafe auto trait Sync {}
+#[lang = "drop_glue"]
+fn drop_glue<T: PointeeSized>(_: *mut T) {}
+#[lang = "copy"]
+pub trait Copy {}
+impl Copy for i32 {}
+
+#[lang = "add"]
+pub trait Add<Rhs = Self> {
+    type Output;
+    fn add(self, rhs: Rhs) -> Self::Output;
+}
+impl Add for i32 {
+    type Output = i32;
+    fn add(self, rhs: i32) -> i32 {
+        self + rhs
+    }
+}
+
+static mut COUNTER: i32 = 0;
+static TABLE: [i32; 4] = [1, 2, 3, 5];
+#[no_mangle]
+pub static FIRST: &i32 = unsafe { &*(&raw const TABLE as *const i32) };
+
+#[no_mangle]
+pub fn bump(by: i32) -> i32 {
+    unsafe {
+        COUNTER = COUNTER + by;
+        COUNTER
+    }
+}
+
+#[no_mangle]
+pub fn nth(i: i32) -> i32 {
+    unsafe { *(&raw const TABLE as *const i32) + i }
+}
@@ -0,0 +1,78 @@
+//! Tests for building images of initial memory contents from data segments.
+
+use std::collections::HashMap;
+use walrus::ir::Value;
+use walrus_tests_utils::parse;
+
+#[test]
+fn segments_are_painted_in_order() {
+    let mut module = parse(
+        r#"
+        (module
+            (import "env" "base" (global $base i32))
+            (memory $a 1)
+            (memory $b 1)
+            (data (memory $a) (i32.const 8) "abcdef")
+            (data (memory $a) (i32.const 10) "XY")
+            (data (memory $a) (i32.const 100) "zz")
+            (data (memory $b) (global.get $base) "q")
+            (data "passive"))
+        "#,
+    );
+    // A custom page size of one byte.
+    let b = module.memories.iter_mut().nth(1).unwrap();
+    b.initial = 16;
+    b.page_size_log2 = Some(0);
+
+    let mut imports = HashMap::new();
+    imports.insert(module.globals.iter().next().unwrap().id(), Value::I32(15));
+    let images = module.memories.images(&module, &imports).unwrap();
+    let data = module.data.iter().map(|d| d.id()).collect::<Vec<_>>();
+
+    let a = &images[0];
+    assert_eq!(a.size, 65536);
+    assert_eq!(a.read(6, 10), b"\0\0abXYef\0\0");
+    let chunks = a
+        .chunks()
+        .map(|(range, data, bytes)| (range, data, bytes.to_vec()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        chunks,
+        [
+            (8..10, data[0], b"ab".to_vec()),
+            (10..12, data[1], b"XY".to_vec()),
+            (12..14, data[0], b"ef".to_vec()),
+            (100..102, data[2], b"zz".to_vec()),
+        ]
+    );
+    assert_eq!(a.overlaps(), [(data[0], data[1])]);
+
+    let b = &images[1];
+    assert_eq!(b.size, 16);
+    assert_eq!(b.read(14, 2), b"\0q");
+    assert!(b.overlaps().is_empty());
+}
+
+#[test]
+fn out_of_bounds_and_unknown_offsets() {
+    let module = parse(
+        r#"
+        (module
+            (memory 1)
+            (data (i32.const 65535) "ab"))
+        "#,
+    );
+    let err = module.memories.images(&module, &|_| None).unwrap_err();
+    assert!(format!("{:?}", err).contains("out of bounds"));
+
+    let module = parse(
+        r#"
+        (module
+            (import "env" "base" (global i32))
+            (memory 1)
+            (data (global.get 0) "ab"))
+        "#,
+    );
+    let err = module.memories.images(&module, &|_| None).unwrap_err();
+    assert!(format!("{:?}", err).contains("isn't constant"));
+}
@@ -0,0 +1,208 @@
+//! Tests for statically linking two modules.
+
+use walrus::interp::{DummyHost, Instance, Val};
+use walrus::{ExportItem, MergeOptions, Module};
+use walrus_tests_utils::{emit, parse};
+
+fn merge(main: &str, lib: &str, options: &MergeOptions) -> Module {
+    let mut module = parse(main);
+    module.merge(parse(lib), options).unwrap();
+    emit(&mut module);
+    module
+}
+
+#[test]
+fn resolves_imports_both_ways() {
+    let module = merge(
+        r#"
+        (module
+            (import "lib" "double" (func $double (param i32) (result i32)))
+            (import "env" "print" (func $print (param i32)))
+            (func (export "run") (param i32) (result i32)
+                local.get 0
+                call $double)
+            (func (export "inc") (param i32) (result i32)
+                local.get 0
+                i32.const 1
+                i32.add))
+        "#,
+        r#"
+        (module
+            (import "main" "inc" (func $inc (param i32) (result i32)))
+            (import "env" "print" (func $print (param i32)))
+            (global $calls (export "calls") (mut i32) (i32.const 0))
+            (func (export "double") (param i32) (result i32)
+                (local i32)
+                global.get $calls
+                i32.const 1
+                i32.add
+                global.set $calls
+                local.get 0
+                call $print
+                local.get 0
+                local.get 0
+                i32.add
+                local.tee 1
+                call $inc))
+        "#,
+        &MergeOptions {
+            other_name: Some("lib".to_string()),
+            self_name: Some("main".to_string()),
+            ..MergeOptions::default()
+        },
+    );
+    // Only the import of `env.print` is left, shared by both.
+    let imports = module.imports.iter().collect::<Vec<_>>();
+    assert_eq!(imports.len(), 1);
+    assert_eq!(
+        (&imports[0].module[..], &imports[0].name[..]),
+        ("env", "print")
+    );
+    let names = module
+        .exports
+        .iter()
+        .map(|e| &e.name[..])
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["run", "inc", "calls", "double"]);
+
+    let mut instance = Instance::new(&module, DummyHost::default()).unwrap();
+    let run = module.exports.get_func("run").unwrap();
+    assert_eq!(instance.call(run, &[Val::I32(5)]), Ok(vec![Val::I32(11)]));
+    assert_eq!(instance.host().calls.len(), 1);
+    let calls = module
+        .exports
+        .iter()
+        .find(|e| e.name == "calls")
+        .map(|e| match e.item {
+            ExportItem::Global(id) => id,
+            _ => unreachable!(),
+        })
+        .unwrap();
+    assert_eq!(instance.global(calls), Val::I32(1));
+}
+
+#[test]
+fn merges_memories_tables_and_start_functions() {
+    let module = merge(
+        r#"
+        (module
+            (import "lib" "get" (func $get (result i32)))
+            (memory 1)
+            (global $base (mut i32) (i32.const 0))
+            (func $init
+                call $get
+                global.set $base)
+            (start $init)
+            (func (export "run") (param i32) (result i32)
+                global.get $base
+                local.get 0
+                i32.add))
+        "#,
+        r#"
+        (module
+            (type $t (func (result i32)))
+            (memory 1)
+            (data (i32.const 0) "\2a")
+            (table 1 funcref)
+            (elem (i32.const 0) $load)
+            (global $ready (mut i32) (i32.const 0))
+            (func $load (result i32)
+                i32.const 0
+                i32.load8_u)
+            (func $init
+                i32.const 1
+                global.set $ready)
+            (start $init)
+            (func (export "get") (result i32)
+                global.get $ready
+                if (result i32)
+                    i32.const 0
+                    call_indirect (type $t)
+                else
+                    i32.const -1
+                end))
+        "#,
+        &MergeOptions {
+            hide_exports: true,
+            ..MergeOptions::default()
+        },
+    );
+    assert_eq!(module.imports.iter().count(), 0);
+    assert_eq!(module.memories.iter().count(), 2);
+    assert_eq!(module.exports.iter().count(), 1);
+    // `lib`'s start runs first, so `main`'s sees it's ready.
+    let mut instance = Instance::new(&module, DummyHost::default()).unwrap();
+    let run = module.exports.get_func("run").unwrap();
+    assert_eq!(instance.call(run, &[Val::I32(1)]), Ok(vec![Val::I32(43)]));
+}
+
+#[test]
+fn mismatches_are_errors() {
+    let main = r#"
+        (module
+            (import "lib" "f" (func (param i32)))
+            (func (export "run")))
+    "#;
+    let options = MergeOptions::default();
+    let mut module = parse(main);
+    let err = module
+        .merge(parse(r#"(module (func (export "f")))"#), &options)
+        .unwrap_err();
+    assert!(err.to_string().contains("`lib.f`"));
+
+    let mut module = parse(main);
+    let err = module
+        .merge(
+            parse(r#"(module (global (export "f") i32 (i32.const 0)))"#),
+            &options,
+        )
+        .unwrap_err();
+    assert!(err.to_string().contains("`lib.f`"));
+
+    let mut module = parse(main);
+    let err = module
+        .merge(parse(r#"(module (func (export "run")))"#), &options)
+        .unwrap_err();
+    assert!(err.to_string().contains("`run`"));
+    assert_eq!(module.funcs.iter().count(), 2);
+}
+
+#[test]
+fn tables_and_memories_must_fit_their_imports() {
+    let options = MergeOptions::default();
+    let merged = |main: &str, lib: &str| {
+        let mut module = parse(main);
+        module.merge(parse(lib), &options).map(|()| module)
+    };
+
+    // Exports may be larger than their imports need, and have a tighter
+    // maximum.
+    let module = merged(
+        r#"(module (import "lib" "mem" (memory 1 4)) (import "lib" "t" (table 1 funcref)))"#,
+        r#"(module (memory (export "mem") 2 3) (table (export "t") 5 5 funcref))"#,
+    )
+    .unwrap();
+    assert_eq!(module.imports.iter().count(), 0);
+
+    let main = r#"(module (import "lib" "mem" (memory 17 20)))"#;
+    for lib in [
+        r#"(module (memory (export "mem") 1 20))"#,
+        r#"(module (memory (export "mem") 17))"#,
+        r#"(module (memory (export "mem") 17 21))"#,
+    ] {
+        let err = merged(main, lib).err().unwrap();
+        assert!(err.to_string().contains("`lib.mem`"), "{}", lib);
+    }
+    let mut lib = parse(r#"(module (memory (export "mem") 17 20))"#);
+    lib.memories.iter_mut().next().unwrap().page_size_log2 = Some(0);
+    let err = parse(main).merge(lib, &options).unwrap_err();
+    assert!(err.to_string().contains("`lib.mem`"));
+
+    let err = merged(
+        r#"(module (import "lib" "t" (table 2 funcref)))"#,
+        r#"(module (table (export "t") 1 funcref))"#,
+    )
+    .err()
+    .unwrap();
+    assert!(err.to_string().contains("`lib.t`"));
+}
@@ -0,0 +1,212 @@
+//! Tests for metering the fuel a module uses.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use walrus::interp::{DummyHost, Host, Instance, Trap, Val};
+use walrus::ir::{Const, Drop, Instr, InstrLocId, LegacyCatch, Try, Value};
+use walrus::passes::metering::{self, Options};
+use walrus::{FunctionId, GlobalId, Import, Module, ValType};
+use walrus_tests_utils::{emit, instrument, parse};
+
+/// Counts down from its argument in a loop, which costs `4 + 8 * n` with the
+/// default costs: 1 for the loop, 3 to check whether it's done and 5 for
+/// every iteration.
+const COUNT: &str = r#"
+    (module
+        (func $count (export "count") (param i32)
+            loop
+                local.get 0
+                i32.eqz
+                br_if 1
+                local.get 0
+                i32.const 1
+                i32.sub
+                local.set 0
+                br 0
+            end)
+        (func (export "twice") (param i32)
+            local.get 0
+            call $count
+            local.get 0
+            call $count))
+"#;
+
+fn meter(wat: &str, options: &Options) -> (Module, GlobalId) {
+    instrument(wat, |module| metering::run(module, options))
+}
+
+/// Call the export `name` with `n`, and return the result and the fuel left.
+fn call<H: Host>(
+    module: &Module,
+    fuel: GlobalId,
+    instance: &mut Instance<H>,
+    name: &str,
+    n: i32,
+) -> (Result<Vec<Val>, Trap>, i64) {
+    let result = walrus_tests_utils::call(module, instance, name, &[Val::I32(n)]);
+    match instance.global(fuel) {
+        Val::I64(fuel) => (result, fuel),
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn loops_are_charged_per_iteration() {
+    let options = Options {
+        initial: 1000,
+        export: Some("fuel".to_string()),
+        ..Options::default()
+    };
+    let (module, fuel) = meter(COUNT, &options);
+    assert!(module.exports.get_exported_global(fuel).is_some());
+
+    for n in [0, 1, 10] {
+        let mut instance = Instance::new(&module, DummyHost::default()).unwrap();
+        let (result, left) = call(&module, fuel, &mut instance, "count", n);
+        assert_eq!(result, Ok(vec![]));
+        assert_eq!(left, 1000 - 4 - 8 * i64::from(n));
+    }
+
+    // Running out of fuel traps.
+    let mut instance = Instance::new(&module, DummyHost::default()).unwrap();
+    let (result, left) = call(&module, fuel, &mut instance, "count", 1000);
+    assert_eq!(result, Err(Trap::Unreachable));
+    assert!(left < 0);
+}
+
+#[test]
+fn out_of_fuel_hook() {
+    let options = Options {
+        initial: 20,
+        out_of_fuel: Some(("env".to_string(), "out_of_fuel".to_string())),
+        ..Options::default()
+    };
+    let (module, fuel) = meter(COUNT, &options);
+    let mut instance = Instance::new(&module, DummyHost::default()).unwrap();
+    let (result, left) = call(&module, fuel, &mut instance, "count", 3);
+    // The dummy hook doesn't refill the fuel, so it's called for every
+    // segment after the fuel goes negative: 20 covers the loop, two
+    // iterations and the next check, but not the third iteration or the
+    // final check.
+    assert_eq!(result, Ok(vec![]));
+    assert_eq!(left, 20 - 4 - 8 * 3);
+    assert_eq!(instance.host().calls.len(), 2);
+}
+
+/// Provides the fuel global, and nothing else.
+struct Fuel(i64);
+
+impl Host for Fuel {
+    fn call(&mut self, _: &Import, _: &[Val], _: &[ValType]) -> Result<Vec<Val>, Trap> {
+        unreachable!()
+    }
+
+    fn global(&mut self, import: &Import, _: ValType) -> Option<Val> {
+        assert_eq!((&import.module[..], &import.name[..]), ("env", "fuel"));
+        Some(Val::I64(self.0))
+    }
+}
+
+#[test]
+fn custom_costs_and_imported_fuel() {
+    let options = Options {
+        cost: Some(Arc::new(|instr: &Instr| match instr {
+            Instr::Call(_) => 100,
+            Instr::Br(_) => 1,
+            _ => 0,
+        })),
+        import: Some(("env".to_string(), "fuel".to_string())),
+        ..Options::default()
+    };
+    let (module, fuel) = meter(COUNT, &options);
+    let mut instance = Instance::new(&module, Fuel(1000)).unwrap();
+    let (result, left) = call(&module, fuel, &mut instance, "twice", 5);
+    assert_eq!(result, Ok(vec![]));
+    assert_eq!(left, 1000 - 2 * 100 - 2 * 5);
+
+    let mut module = parse(COUNT);
+    module.add_import_global("env", "fuel", ValType::I64, true, false);
+    assert!(metering::run(&mut module, &options).is_err());
+}
+
+#[test]
+fn costs_from_a_runtime_table() {
+    let (module, fuel) = instrument(COUNT, |module| {
+        let count = module.exports.get_func("count")?;
+        let prices: HashMap<FunctionId, u64> = [(count, 50)].into_iter().collect();
+        let options = Options {
+            initial: 1000,
+            cost: Some(Arc::new(move |instr: &Instr| match instr {
+                Instr::Call(call) => prices.get(&call.func).copied().unwrap_or(1),
+                _ => 0,
+            })),
+            ..Options::default()
+        };
+        metering::run(module, &options)
+    });
+    let mut instance = Instance::new(&module, DummyHost::default()).unwrap();
+    let (result, left) = call(&module, fuel, &mut instance, "twice", 5);
+    assert_eq!(result, Ok(vec![]));
+    assert_eq!(left, 1000 - 2 * 50);
+}
+
+#[test]
+fn legacy_try_blocks_and_rethrow_end_segments() {
+    let mut module = parse(
+        r#"
+        (module
+            (tag $e)
+            (func (export "run") (param i32)
+                try
+                    local.get 0
+                    if
+                        return
+                    end
+                    throw $e
+                catch_all
+                    rethrow 0
+                end
+                i32.const 1
+                drop))
+        "#,
+    );
+    // Parsing drops the dead code after `rethrow`, so add some back.
+    let (_, func) = module.funcs.iter_local_mut().next().unwrap();
+    let entry = func.entry_block();
+    let handler = match &func.block(entry).instrs[0].0 {
+        Instr::Try(Try { catches, .. }) => match catches[..] {
+            [LegacyCatch::CatchAll { handler }] => handler,
+            _ => unreachable!(),
+        },
+        _ => unreachable!(),
+    };
+    let dead: [Instr; 2] = [
+        Const {
+            value: Value::I32(1),
+        }
+        .into(),
+        Drop {}.into(),
+    ];
+    let dead = dead.map(|instr| (instr, InstrLocId::default()));
+    func.block_mut(handler).instrs.extend(dead);
+
+    let options = Options {
+        initial: 1000,
+        cost: Some(Arc::new(|instr: &Instr| match instr {
+            Instr::Const(_) => 1,
+            _ => 0,
+        })),
+        ..Options::default()
+    };
+    let fuel = metering::run(&mut module, &options).unwrap();
+    emit(&mut module);
+    // Neither the code after the `try`, which returning skips, nor the code
+    // after the `rethrow` runs.
+    let mut instance = Instance::new(&module, DummyHost::default()).unwrap();
+    let (result, left) = call(&module, fuel, &mut instance, "run", 1);
+    assert_eq!(result, Ok(vec![]));
+    assert_eq!(left, 1000);
+    let (result, left) = call(&module, fuel, &mut instance, "run", 0);
+    assert!(matches!(result, Err(Trap::UncaughtException(_))));
+    assert_eq!(left, 1000);
+}
@@ -0,0 +1,272 @@
+//! Tests for merging several memories into one.
+
+use walrus::interp::Val;
+use walrus::passes::multi_memory_lowering::{self, Options};
+use walrus::{ConstExpr, DataKind, Module};
+use walrus_tests_utils::{body, outcomes, parse, print};
+
+fn lower(wat: &str, options: &Options) -> (Module, String) {
+    let mut module = parse(wat);
+    multi_memory_lowering::run(&mut module, options).unwrap();
+    let text = print(&mut module);
+    (module, text)
+}
+
+const WAT: &str = r#"
+    (module
+        (memory $a (export "memory") 1 2)
+        (memory $b 1 3)
+        (memory $c 0)
+        (global $g i32 (i32.const 16))
+        (data (memory $a) (i32.const 8) "a")
+        (data (memory $b) (i32.const 8) "b")
+        (data (memory $c) (global.get $g) "")
+        (func $load (export "load") (param i32) (result i32)
+            local.get 0
+            i32.load $b offset=4)
+        (func $store (export "store") (param i32 i64)
+            local.get 0
+            local.get 1
+            i64.store $c)
+        (func $size (export "size") (result i32)
+            memory.size $b)
+        (func $grow (export "grow") (param i32) (result i32)
+            local.get 0
+            memory.grow $c)
+        (func $copy (export "copy") (param i32 i32 i32)
+            local.get 0
+            local.get 1
+            local.get 2
+            memory.copy $a $b))
+"#;
+
+#[test]
+fn memories_are_merged_into_the_first() {
+    let (module, text) = lower(WAT, &Options::default());
+    let memory = module.memories.iter().next().unwrap();
+    assert_eq!(module.memories.iter().count(), 1);
+    // `$a` and `$b` take their maximum sizes, 2 and 3 pages, before `$c`.
+    assert_eq!(memory.initial, 5);
+    assert_eq!(memory.maximum, None);
+    assert_eq!(memory.data_segments.len(), 3);
+
+    let offsets = module
+        .data
+        .iter()
+        .map(|data| match &data.kind {
+            DataKind::Active { memory: m, offset } => {
+                assert_eq!(*m, memory.id());
+                offset.clone()
+            }
+            DataKind::Passive => panic!("passive segment"),
+        })
+        .collect::<Vec<_>>();
+    assert!(matches!(
+        offsets[0],
+        ConstExpr::Value(walrus::ir::Value::I32(8))
+    ));
+    assert!(matches!(
+        offsets[1],
+        ConstExpr::Value(walrus::ir::Value::I32(0x2_0008))
+    ));
+    assert!(matches!(offsets[2], ConstExpr::Extended(_)));
+
+    assert!(body(&text, "load").contains("i32.const 131072"));
+    assert!(body(&text, "load").contains("unreachable"));
+    assert!(body(&text, "store").contains("i32.const 327680"));
+    assert!(body(&text, "size").contains("global.get"));
+    assert!(!body(&text, "size").contains("memory.size"));
+    // Only the last memory grows the merged memory.
+    assert!(body(&text, "grow").contains("memory.grow"));
+    assert!(body(&text, "copy").contains("memory.copy"));
+}
+
+#[test]
+fn bounds_checks_can_be_disabled() {
+    let options = Options {
+        bounds_checks: false,
+    };
+    let (_, text) = lower(WAT, &options);
+    assert!(!body(&text, "load").contains("unreachable"));
+    assert!(body(&text, "load").contains("i32.const 131072"));
+}
+
+#[test]
+fn single_memories_are_left_alone() {
+    let wat = r#"(module (memory 1) (func (export "f") (result i32) memory.size))"#;
+    let mut module = parse(wat);
+    let before = module.emit_wasm();
+    multi_memory_lowering::run(&mut module, &Options::default()).unwrap();
+    assert_eq!(module.emit_wasm(), before);
+}
+
+#[test]
+fn unmergeable_memories_are_rejected() {
+    for wat in [
+        // Only the last memory may grow without a maximum.
+        "(module (memory 1) (memory 1 2))",
+        "(module (import \"env\" \"memory\" (memory 1 1)) (memory 1 1))",
+        "(module (memory 1 1) (memory (export \"second\") 1 1))",
+        "(module (memory 1 1) (memory 1 1) (data (memory 1) (i32.const 65535) \"ab\"))",
+    ] {
+        let mut module = parse(wat);
+        assert!(multi_memory_lowering::run(&mut module, &Options::default()).is_err());
+        assert_eq!(module.memories.iter().count(), 2);
+    }
+}
+
+#[test]
+fn one_byte_pages_reach_the_whole_address_space() {
+    let mut module = Module::default();
+    module.memories.add_local(false, true, 1, Some(1), Some(0));
+    module.memories.add_local(false, true, 1, None, Some(0));
+    multi_memory_lowering::run(&mut module, &Options::default()).unwrap();
+    let memory = module.memories.iter().next().unwrap();
+    assert_eq!((memory.initial, memory.maximum), (2, None));
+
+    let mut module = Module::default();
+    module
+        .memories
+        .add_local(false, true, 1, Some(u64::MAX), Some(0));
+    module.memories.add_local(false, true, 1, Some(2), Some(0));
+    assert!(multi_memory_lowering::run(&mut module, &Options::default()).is_err());
+    assert_eq!(module.memories.iter().count(), 2);
+}
+
+const ACCESSES: &str = r#"
+    (module
+        (memory $a 1 2)
+        (memory $b 1 3)
+        (memory $c 0)
+        (data (memory $a) (i32.const 8) "ab")
+        (data (memory $b) (i32.const 8) "cd")
+        (func (export "load_a") (param i32) (result i32)
+            local.get 0
+            i32.load16_u $a)
+        (func (export "load_b") (param i32) (result i32)
+            local.get 0
+            i32.load16_u $b offset=1)
+        (func (export "load_c") (param i32) (result i32)
+            local.get 0
+            i32.load8_u $c)
+        (func (export "store_b") (param i32 i32)
+            local.get 0
+            local.get 1
+            i32.store16 $b)
+        (func (export "store_c") (param i32 i32)
+            local.get 0
+            local.get 1
+            i32.store8 $c)
+        (func (export "size_b") (result i32)
+            memory.size $b)
+        (func (export "grow_b") (param i32) (result i32)
+            local.get 0
+            memory.grow $b)
+        (func (export "grow_c") (param i32) (result i32)
+            local.get 0
+            memory.grow $c)
+        (func (export "copy") (param i32 i32 i32)
+            local.get 0
+            local.get 1
+            local.get 2
+            memory.copy $b $a))
+"#;
+
+#[test]
+fn lowered_accesses_behave_the_same() {
+    let i32s = |vals: &[i32]| vals.iter().map(|v| Val::I32(*v)).collect::<Vec<_>>();
+    let calls = [
+        ("load_a", i32s(&[8])),
+        ("load_b", i32s(&[7])),
+        // The end of `$a`, and past it into `$b`'s region.
+        ("load_a", i32s(&[65534])),
+        ("load_a", i32s(&[65535])),
+        ("load_b", i32s(&[65533])),
+        ("load_b", i32s(&[-1])),
+        ("store_b", i32s(&[100, 0x4241])),
+        ("load_b", i32s(&[99])),
+        // `$c` starts out empty.
+        ("load_c", i32s(&[0])),
+        ("store_c", i32s(&[0, 7])),
+        ("grow_c", i32s(&[2])),
+        ("store_c", i32s(&[0x1_ffff, 7])),
+        ("load_c", i32s(&[0x1_ffff])),
+        ("load_c", i32s(&[0x2_0000])),
+        // `$b` may only grow up to its maximum, in its own region.
+        ("grow_b", i32s(&[1])),
+        ("size_b", vec![]),
+        ("load_b", i32s(&[0x1_fffd])),
+        ("grow_b", i32s(&[2])),
+        ("grow_b", i32s(&[-1])),
+        ("grow_b", i32s(&[1])),
+        ("size_b", vec![]),
+        ("load_c", i32s(&[0x1_ffff])),
+        // Copies are checked against both memories.
+        ("copy", i32s(&[200, 8, 2])),
+        ("load_b", i32s(&[199])),
+        ("copy", i32s(&[0, 65535, 2])),
+        ("copy", i32s(&[3 * 65536 - 1, 0, 2])),
+        ("copy", i32s(&[0, 0, 0])),
+    ];
+    let calls = calls
+        .iter()
+        .map(|(name, args)| (*name, &args[..]))
+        .collect::<Vec<_>>();
+
+    let expected = outcomes(&parse(ACCESSES), &calls);
+    let (lowered, _) = lower(ACCESSES, &Options::default());
+    assert_eq!(outcomes(&lowered, &calls), expected);
+
+    let results = expected
+        .iter()
+        .map(|outcome| match outcome.as_deref() {
+            Some([Val::I32(v)]) => Some(*v),
+            Some([]) => Some(0),
+            None => None,
+            _ => unreachable!(),
+        })
+        .collect::<Vec<_>>();
+    #[rustfmt::skip]
+    assert_eq!(
+        results,
+        [
+            Some(0x6261), Some(0x6463), Some(0), None, Some(0), None,
+            Some(0), Some(0x4241),
+            None, None, Some(0), Some(0), Some(7), None,
+            Some(1), Some(2), Some(0), Some(-1), Some(-1), Some(2), Some(3), Some(7),
+            Some(0), Some(0x6261), None, None, Some(0),
+        ]
+    );
+}
+
+#[test]
+fn huge_64_bit_grows_fail() {
+    let wat = r#"
+        (module
+            (memory $a i64 1 2)
+            (memory $b i64 0)
+            (func (export "grow_a") (param i64) (result i64)
+                local.get 0
+                memory.grow $a)
+            (func (export "size_a") (result i64)
+                memory.size $a))
+    "#;
+    let calls: [(&str, &[Val]); 4] = [
+        ("grow_a", &[Val::I64(-1)]),
+        ("grow_a", &[Val::I64(i64::MAX)]),
+        ("grow_a", &[Val::I64(1)]),
+        ("size_a", &[]),
+    ];
+    let expected = outcomes(&parse(wat), &calls);
+    let (lowered, _) = lower(wat, &Options::default());
+    assert_eq!(outcomes(&lowered, &calls), expected);
+    assert_eq!(
+        expected,
+        [
+            Some(vec![Val::I64(-1)]),
+            Some(vec![Val::I64(-1)]),
+            Some(vec![Val::I64(1)]),
+            Some(vec![Val::I64(2)]),
+        ]
+    );
+}
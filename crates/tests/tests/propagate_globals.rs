@@ -0,0 +1,135 @@
+//! Tests for propagating constant globals and removing the unused ones.
+
+use walrus::passes::propagate_globals;
+use walrus::{ConstExpr, ConstOp, DataKind, Module, ModuleLinking};
+use walrus_tests_utils::{parse, print};
+
+fn run(wat: &str) -> (Module, String) {
+    let mut module = parse(wat);
+    propagate_globals::run(&mut module);
+    let text = print(&mut module);
+    (module, text)
+}
+
+fn global_names(module: &Module) -> Vec<&str> {
+    module
+        .globals
+        .iter()
+        .map(|g| g.name.as_deref().unwrap())
+        .collect()
+}
+
+#[test]
+fn constants_are_propagated() {
+    let (module, text) = run(r#"
+        (module
+            (import "env" "base" (global $base i32))
+            (global $unwritten (mut i32) (i32.const 7))
+            (global $derived i64 (i64.const 40))
+            (global $seven i32 (i32.const 7))
+            (global $sum i32 (i32.add (global.get $seven) (i32.const 1)))
+            (global $offset i32 (global.get $base))
+            (global $counter (mut i32) (i32.const 0))
+            (global $exported (mut i32) (i32.const 3))
+            (export "exported" (global $exported))
+            (memory 1)
+            (data (global.get $sum) "x")
+            (func (export "f") (result i32 i64 i32 i32)
+                global.get $counter
+                i32.const 1
+                i32.add
+                global.set $counter
+                global.get $unwritten
+                global.get $derived
+                global.get $offset
+                global.get $exported))
+        "#);
+
+    // Only globals that are still read, or are imported or exported, remain.
+    assert_eq!(
+        global_names(&module),
+        ["base", "offset", "counter", "exported"]
+    );
+    assert!(text.contains("i32.const 7"));
+    assert!(text.contains("i64.const 40"));
+    assert!(text.contains("(data (;0;) (i32.const 8)"), "{}", text);
+    assert!(module.globals.iter().all(|g| {
+        let name = g.name.as_deref().unwrap();
+        g.mutable == (name == "counter" || name == "exported")
+    }));
+}
+
+#[test]
+fn extended_const_exprs_are_substituted() {
+    let (module, _) = run(r#"
+        (module
+            (global $four i32 (i32.const 4))
+            (memory 1)
+            (data (i32.add (global.get $four) (i32.const 8)) "x"))
+        "#);
+
+    assert!(global_names(&module).is_empty());
+    let data = module.data.iter().next().unwrap();
+    let offset = match &data.kind {
+        DataKind::Active { offset, .. } => offset,
+        DataKind::Passive => panic!("data became passive"),
+    };
+    match offset {
+        ConstExpr::Extended(ops) => assert!(matches!(
+            ops[..],
+            [ConstOp::I32Const(4), ConstOp::I32Const(8), ConstOp::I32Add]
+        )),
+        _ => panic!("unexpected offset {:?}", offset),
+    }
+}
+
+#[test]
+fn exported_mutable_globals_stay_mutable() {
+    let (module, text) = run(r#"
+        (module
+            (global $exported (export "exported") (mut i32) (i32.const 3))
+            (func (export "f") (result i32)
+                global.get $exported))
+        "#);
+
+    assert_eq!(global_names(&module), ["exported"]);
+    assert!(module.globals.iter().all(|g| g.mutable));
+    assert!(text.contains("global.get $exported"), "{}", text);
+}
+
+#[test]
+fn globals_read_only_from_const_exprs_are_kept() {
+    let (module, _) = run(r#"
+        (module
+            (import "env" "base" (global $base i32))
+            (global $data i32 (global.get $base))
+            (global $elem i32 (i32.add (global.get $base) (i32.const 1)))
+            (global $next i32 (i32.add (global.get $elem) (i32.const 1)))
+            (memory 1)
+            (data (global.get $data) "x")
+            (table 4 funcref)
+            (elem (global.get $next) func $f)
+            (func $f))
+        "#);
+
+    assert_eq!(global_names(&module), ["base", "data", "elem", "next"]);
+}
+
+#[test]
+fn relocatable_modules_are_left_alone() {
+    let mut module = parse(
+        r#"
+        (module
+            (global $unwritten (mut i32) (i32.const 7))
+            (global $unread i32 (i32.const 1))
+            (func (export "f") (result i32)
+                global.get $unwritten))
+        "#,
+    );
+    module.linking = Some(ModuleLinking::default());
+    let before = module.emit_wasm();
+    propagate_globals::run(&mut module);
+    assert_eq!(module.emit_wasm(), before);
+    assert_eq!(global_names(&module), ["unwritten", "unread"]);
+    assert!(module.globals.iter().next().unwrap().mutable);
+}
@@ -0,0 +1,134 @@
+use walrus::interp::{DummyHost, Instance, Trap};
+use walrus::passes::reduce;
+use walrus::Module;
+
+fn divides_by_zero(wasm: &[u8]) -> bool {
+    let module = Module::from_buffer(wasm).unwrap();
+    let Ok(main) = module.exports.get_func("main") else {
+        return false;
+    };
+    let mut instance = match Instance::new(&module, DummyHost::default()) {
+        Ok(instance) => instance,
+        Err(_) => return false,
+    };
+    instance.set_fuel(Some(100_000));
+    instance.call(main, &[]) == Err(Trap::IntegerDivideByZero)
+}
+
+#[test]
+fn reduces_to_the_interesting_part() {
+    let wasm = wat::parse_str(
+        r#"
+        (module
+            (import "env" "log" (func $log (param i32)))
+            (memory (export "memory") 1)
+            (data (i32.const 0) "some data that nothing needs")
+            (global $g (mut i32) (i32.const 0))
+            (table 2 funcref)
+            (elem (i32.const 0) func $helper $unrelated)
+            (func $helper (param i32) (result i32)
+                local.get 0
+                i32.const 1
+                i32.add
+                call $log
+                i32.const 0)
+            (func $unrelated (export "unrelated") (param i32) (result i32)
+                local.get 0
+                i32.load
+                global.get $g
+                i32.add)
+            (func (export "main") (result i32)
+                (local i32)
+                i32.const 10
+                global.set $g
+                i32.const 0
+                call $unrelated
+                drop
+                i32.const 7
+                i32.const 3
+                call $helper
+                i32.div_u
+                local.tee 0
+                local.get 0
+                i32.add)
+            (start $start)
+            (func $start
+                i32.const 1
+                call $log))
+        "#,
+    )
+    .unwrap();
+    assert!(divides_by_zero(&wasm));
+
+    let reduced = reduce::run(&wasm, divides_by_zero).unwrap();
+    assert!(divides_by_zero(&reduced));
+    assert!(
+        reduced.len() < wasm.len() / 2,
+        "{}",
+        wasmprinter::print_bytes(&reduced).unwrap()
+    );
+
+    let module = Module::from_buffer(&reduced).unwrap();
+    assert_eq!(module.exports.iter().count(), 1);
+    assert_eq!(module.funcs.iter().count(), 1);
+    assert!(module.imports.iter().next().is_none());
+    assert!(module.start.is_none());
+    assert!(module.data.iter().next().is_none());
+}
+
+#[test]
+fn shrinks_data_segments() {
+    let wasm = wat::parse_str(
+        r#"
+        (module
+            (memory 1)
+            (data (i32.const 0) "ab and a lot more that doesn't matter at all"))
+        "#,
+    )
+    .unwrap();
+    let starts_with_ab = |wasm: &[u8]| {
+        let module = Module::from_buffer(wasm).unwrap();
+        let found = module.data.iter().any(|d| d.value.starts_with(b"ab"));
+        found
+    };
+    let reduced = reduce::run(&wasm, starts_with_ab).unwrap();
+    let module = Module::from_buffer(&reduced).unwrap();
+    let data = module.data.iter().next().unwrap();
+    assert!(data.value.starts_with(b"ab"));
+    assert!(data.value.len() < 4);
+}
+
+#[test]
+fn keeps_data_segments_code_refers_to() {
+    let wasm = wat::parse_str(
+        r#"
+        (module
+            (memory 1)
+            (data (i32.const 0) "")
+            (func (export "f") data.drop 0))
+        "#,
+    )
+    .unwrap();
+    let drops_data = |wasm: &[u8]| {
+        wasmprinter::print_bytes(wasm)
+            .unwrap()
+            .contains("data.drop")
+    };
+    let reduced = reduce::run(&wasm, drops_data).unwrap();
+    assert!(drops_data(&reduced));
+}
+
+#[test]
+fn the_original_must_be_interesting() {
+    let wasm = wat::parse_str("(module)").unwrap();
+    assert!(reduce::run(&wasm, |_| false).is_err());
+}
+
+#[test]
+fn never_grows_the_module() {
+    // Truncating the body replaces `nop` with `unreachable`, which is no
+    // smaller, so nothing can be kept even though everything is interesting.
+    let wasm = wat::parse_str(r#"(module (func (export "f") nop))"#).unwrap();
+    let reduced = reduce::run(&wasm, |reduced| reduced.len() >= wasm.len()).unwrap();
+    assert_eq!(reduced, wasm);
+}
@@ -0,0 +1,87 @@
+//! Tests for baking a snapshot of initialized state into a module.
+
+use walrus::ir::Value;
+use walrus::{ConstExpr, DataKind, GlobalKind, Snapshot};
+use walrus_tests_utils::{emit, parse};
+
+const WAT: &str = r#"
+    (module
+        (memory (export "memory") 1)
+        (global $counter (mut i32) (i32.const 0))
+        (global $scale f64 (f64.const 1))
+        (data $kept (i32.const 0) "old")
+        (data $dropped (i32.const 16) "tmp")
+        (func $init (export "wizer.initialize")
+            i32.const 1
+            global.set $counter)
+        (func (export "run") (result i32)
+            data.drop $dropped
+            global.get $counter)
+        (start $init))
+    "#;
+
+#[test]
+fn snapshot_replaces_initial_state() {
+    let mut module = parse(WAT);
+    let init = module.funcs.by_name("init").unwrap();
+    let memory = module.memories.iter().next().unwrap().id();
+    let globals = module.globals.iter().map(|g| g.id()).collect::<Vec<_>>();
+
+    let mut contents = vec![0; 65536 + 10];
+    contents[100..103].copy_from_slice(b"new");
+    contents[65536 + 4] = 7;
+    let snapshot = Snapshot {
+        memories: vec![(memory, contents.clone())],
+        globals: vec![(globals[0], Value::I32(42))],
+    };
+    module.apply_snapshot(&snapshot, Some(init)).unwrap();
+
+    // The memory grew to fit, and holds only the snapshot.
+    assert_eq!(module.memories.get(memory).initial, 2);
+    let image = &module.memories.images(&module, &|_| None).unwrap()[0];
+    assert_eq!(image.read(0, contents.len()), contents);
+    let active = module
+        .data
+        .iter()
+        .filter(|d| !d.is_passive())
+        .map(|d| d.value.len())
+        .collect::<Vec<_>>();
+    assert_eq!(active, [65536 + 4 - 100 + 1]);
+
+    // The segment used by `data.drop` is still there, but empty.
+    let passive = module.data.iter().find(|d| d.is_passive()).unwrap();
+    assert!(passive.value.is_empty());
+    assert!(matches!(passive.kind, DataKind::Passive));
+
+    match &module.globals.get(globals[0]).kind {
+        GlobalKind::Local(ConstExpr::Value(Value::I32(42))) => {}
+        kind => panic!("unexpected initializer {:?}", kind),
+    }
+    assert!(matches!(
+        module.globals.get(globals[1]).kind,
+        GlobalKind::Local(ConstExpr::Value(Value::F64(_)))
+    ));
+
+    assert!(module.start.is_none());
+    assert!(module.funcs.by_name("init").is_none());
+    assert!(module.exports.iter().all(|e| e.name != "wizer.initialize"));
+
+    emit(&mut module);
+}
+
+#[test]
+fn mismatched_snapshots_are_rejected() {
+    let mut module = parse(WAT);
+    let memory = module.memories.iter().next().unwrap().id();
+    let counter = module.globals.iter().next().unwrap().id();
+
+    let snapshot = Snapshot {
+        memories: vec![(memory, b"data".to_vec())],
+        globals: vec![(counter, Value::I64(1))],
+    };
+    let err = module.apply_snapshot(&snapshot, None).unwrap_err();
+    assert!(format!("{:?}", err).contains("doesn't match the type"));
+    // Nothing changed.
+    assert_eq!(module.data.iter().count(), 2);
+    assert!(module.start.is_some());
+}
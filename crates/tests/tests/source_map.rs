@@ -0,0 +1,203 @@
+//! Tests for remapping JS-style source maps through code transformations.
+
+use walrus::ir::{BinaryOp, Binop, Const, Value};
+use walrus::{Module, SourceMap, SourceMappingUrl};
+
+/// Byte offsets of every instruction in each function body of `wasm`.
+fn instruction_offsets(wasm: &[u8]) -> Vec<Vec<usize>> {
+    let mut ret = Vec::new();
+    for payload in wasmparser::Parser::new(0).parse_all(wasm) {
+        if let wasmparser::Payload::CodeSectionEntry(body) = payload.unwrap() {
+            let mut reader = body.get_operators_reader().unwrap();
+            let mut offsets = Vec::new();
+            while !reader.eof() {
+                offsets.push(reader.original_position());
+                reader.read().unwrap();
+            }
+            ret.push(offsets);
+        }
+    }
+    ret
+}
+
+fn wasm() -> Vec<u8> {
+    wat::parse_str(
+        r#"
+        (module
+            (func $add (export "add") (param i32 i32) (result i32)
+                local.get 0
+                local.get 1
+                i32.add)
+            (func $twice (export "twice") (param i32) (result i32)
+                local.get 0
+                local.get 0
+                call $add)
+            (@custom "sourceMappingURL" "\0capp.wasm.map"))
+        "#,
+    )
+    .unwrap()
+}
+
+#[test]
+fn source_mapping_url_round_trips() {
+    let mut module = Module::from_buffer(&wasm()).unwrap();
+    assert_eq!(
+        module.source_mapping_url,
+        Some(SourceMappingUrl {
+            url: "app.wasm.map".to_string()
+        })
+    );
+    assert!(module.customs.iter().next().is_none());
+
+    module.source_mapping_url.as_mut().unwrap().url = "out.wasm.map".to_string();
+    let wasm = module.emit_wasm();
+    let module = Module::from_buffer(&wasm).unwrap();
+    assert_eq!(module.source_mapping_url.unwrap().url, "out.wasm.map");
+}
+
+#[test]
+fn json_round_trips() {
+    let json = r#"{
+        "version": 3,
+        "file": "app.wasm",
+        "sources": ["a.c", "b\"é😀.c"],
+        "sourcesContent": [null, "int b;\n"],
+        "names": ["add", "sub"],
+        "mappings": "gBAAA,EACAC;,CAAA",
+        "x_ignored": {"nested": [1, 2.5, true]}
+    }"#;
+    let err = SourceMap::parse(json).unwrap_err();
+    assert!(format!("{:?}", err).contains("single generated line"));
+
+    let json = json.replace(";,", ",");
+    let map = SourceMap::parse(&json).unwrap();
+    assert_eq!(map.file.as_deref(), Some("app.wasm"));
+    assert_eq!(map.sources[1], "b\"\u{e9}\u{1f600}.c");
+    assert_eq!(map.sources_content, [None, Some("int b;\n".to_string())]);
+    let generated = map.mappings.iter().map(|m| m.generated).collect::<Vec<_>>();
+    assert_eq!(generated, [16, 18, 19]);
+    let second = map.mappings[1].original.unwrap();
+    assert_eq!((second.source, second.line, second.column), (0, 1, 0));
+    assert_eq!(second.name, Some(1));
+    assert_eq!(map.mappings[2].original.unwrap().name, None);
+    assert_eq!(SourceMap::parse(&map.to_json()).unwrap(), map);
+
+    let err = SourceMap::parse(&json.replace("EACAC", "EACAE")).unwrap_err();
+    assert!(format!("{:?}", err).contains("out of bounds name"));
+}
+
+#[test]
+fn remap_through_transform() {
+    let input = wasm();
+    let offsets = instruction_offsets(&input);
+
+    // One mapping per instruction, each on its own line of `lib.c`.
+    let mappings = offsets
+        .iter()
+        .flatten()
+        .enumerate()
+        .map(|(line, offset)| walrus::SourceMapping {
+            generated: *offset,
+            original: Some(walrus::OriginalLocation {
+                source: 0,
+                line: line as u32,
+                column: 0,
+                name: None,
+            }),
+        })
+        .collect();
+    let map = SourceMap {
+        sources: vec!["lib.c".to_string()],
+        mappings,
+        ..Default::default()
+    };
+    let map = SourceMap::parse(&map.to_json()).unwrap();
+
+    // Insert `i32.const 0; i32.add` before the call in `twice`, which shifts
+    // every instruction after it.
+    let mut module = Module::from_buffer(&input).unwrap();
+    let twice = module.funcs.by_name("twice").unwrap();
+    let func = module.funcs.get_mut(twice).kind.unwrap_local_mut();
+    let entry = func.entry_block();
+    let instrs = &mut func.block_mut(entry).instrs;
+    instrs.insert(
+        2,
+        (
+            Const {
+                value: Value::I32(0),
+            }
+            .into(),
+            Default::default(),
+        ),
+    );
+    instrs.insert(
+        3,
+        (
+            Binop {
+                op: BinaryOp::I32Add,
+            }
+            .into(),
+            Default::default(),
+        ),
+    );
+
+    let (output, remapped) = module.emit_wasm_with_source_map(&map);
+    let new_offsets = instruction_offsets(&output);
+    let lines = remapped
+        .mappings
+        .iter()
+        .map(|m| (m.generated, m.original.unwrap().line))
+        .collect::<Vec<_>>();
+
+    // Functions are emitted largest first, so `twice` now comes before
+    // `add`. The new instructions in `twice` have no mapping, and everything
+    // else keeps its original line.
+    let (twice, add) = (&new_offsets[0], &new_offsets[1]);
+    let expected = [
+        (twice[0], 4),
+        (twice[1], 5),
+        (twice[4], 6),
+        (twice[5], 7),
+        (add[0], 0),
+        (add[1], 1),
+        (add[2], 2),
+        (add[3], 3),
+    ];
+    assert_eq!(lines, expected);
+    assert_eq!(remapped.sources, map.sources);
+    assert_eq!(
+        SourceMap::parse(&remapped.to_json()).unwrap(),
+        remapped,
+        "remapped map should serialize"
+    );
+}
+
+#[test]
+fn offsets_no_instruction_can_have() {
+    let mapping = |generated| walrus::SourceMapping {
+        generated,
+        original: None,
+    };
+    let map = SourceMap {
+        mappings: vec![mapping(1), mapping(u32::MAX as usize)],
+        ..Default::default()
+    };
+    let err = SourceMap::parse(&map.to_json()).unwrap_err();
+    assert!(format!("{:?}", err).contains("reserved offset"));
+
+    let map = SourceMap {
+        mappings: vec![mapping(1), mapping(1 << 32)],
+        ..Default::default()
+    };
+    let err = SourceMap::parse(&map.to_json()).unwrap_err();
+    assert!(format!("{:?}", err).contains("out of range"));
+
+    // Maps built by hand are only checked when remapped.
+    let mut module = Module::from_buffer(&wasm()).unwrap();
+    let map = SourceMap {
+        mappings: vec![mapping(u32::MAX as usize), mapping(usize::MAX)],
+        ..Default::default()
+    };
+    let (_, remapped) = module.emit_wasm_with_source_map(&map);
+    assert!(remapped.mappings.is_empty());
+}
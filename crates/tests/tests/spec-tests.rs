@@ -1,9 +1,13 @@
 #![allow(dead_code)]
 use anyhow::{bail, Context};
+use serde_json::Value;
+use std::collections::HashMap;
 use std::fs;
 use std::path::Path;
 use std::process::Command;
 use tempfile::TempDir;
+use walrus::interp::{Host, Instance, Ref, Trap, Val};
+use walrus::{ExportItem, Import, Module, ModuleConfig, ValType};
 
 #[derive(serde::Deserialize, serde::Serialize)]
 struct Test {
@@ -74,7 +78,7 @@ fn run(wast: &Path) -> Result<(), anyhow::Error> {
 
     let mut should_not_parse = vec![];
     let mut non_deterministic = vec![];
-    for command in test.commands {
+    for command in &test.commands {
         let filename = match command.get("filename") {
             Some(name) => name.as_str().unwrap().to_string(),
             None => continue,
@@ -148,6 +152,12 @@ fn run(wast: &Path) -> Result<(), anyhow::Error> {
             non_deterministic
         ));
     }
+    if interpreted(proposal) {
+        let failed = interpret(tempdir.path(), &test.commands, &config)?;
+        if !failed.is_empty() {
+            message.push_str(&format!("interpreter failed at line: {:?}", failed));
+        }
+    }
     if !message.is_empty() {
         panic!("{}", message);
     }
@@ -187,6 +197,217 @@ fn run(wast: &Path) -> Result<(), anyhow::Error> {
     Ok(())
 }
 
+/// Whether walrus's interpreter supports everything `proposal`'s tests use,
+/// beyond what it reports as unsupported.
+fn interpreted(proposal: Option<&str>) -> bool {
+    match proposal {
+        None => true,
+        Some(proposal) => [
+            "exception-handling",
+            "function-references",
+            "memory64",
+            "tail-call",
+        ]
+        .contains(&proposal),
+    }
+}
+
+/// Provides the imports of the `spectest` module.
+struct SpecTest;
+
+impl Host for SpecTest {
+    fn call(&mut self, _: &Import, _: &[Val], results: &[ValType]) -> Result<Vec<Val>, Trap> {
+        // Only the `print*` functions, which return nothing.
+        Ok(results.iter().map(|ty| Val::default(*ty)).collect())
+    }
+
+    fn global(&mut self, import: &Import, _: ValType) -> Option<Val> {
+        match &import.name[..] {
+            "global_i32" => Some(Val::I32(666)),
+            "global_i64" => Some(Val::I64(666)),
+            "global_f32" => Some(Val::F32(666.6f32.to_bits())),
+            "global_f64" => Some(Val::F64(666.6f64.to_bits())),
+            _ => None,
+        }
+    }
+}
+
+/// Run the commands that call into modules, such as `assert_return` and
+/// `assert_trap`, with walrus's interpreter, and return the lines of those
+/// that fail.
+///
+/// Modules that import anything but `spectest`, and everything after a call
+/// the interpreter can't run, are skipped, since the state they'd see isn't
+/// known.
+fn interpret(dir: &Path, commands: &[Value], config: &ModuleConfig) -> anyhow::Result<Vec<u64>> {
+    // Instances borrow their modules, so parse them all first.
+    let mut modules = Vec::new();
+    for command in commands {
+        if command["type"] == "module" {
+            modules.push(match command["filename"].as_str() {
+                Some(filename) if filename.ends_with(".wasm") => {
+                    let module = config.parse(&fs::read(dir.join(filename))?)?;
+                    Some(module).filter(|m| m.imports.iter().all(|i| i.module == "spectest"))
+                }
+                _ => None,
+            });
+        }
+    }
+
+    let mut modules = modules.iter();
+    let mut instances = Vec::new();
+    let mut names = HashMap::new();
+    let mut failed = Vec::new();
+    for command in commands {
+        let line = command["line"].as_u64().unwrap();
+        let kind = command["type"].as_str().unwrap();
+        match kind {
+            "module" => {
+                let instance = modules.next().unwrap().as_ref().and_then(|module| {
+                    match Instance::new(module, SpecTest) {
+                        Ok(instance) => Some((module, instance)),
+                        Err(Trap::Unsupported(_)) => None,
+                        Err(_) => {
+                            failed.push(line);
+                            None
+                        }
+                    }
+                });
+                if let Some(name) = command["name"].as_str() {
+                    names.insert(name, instances.len());
+                }
+                instances.push(instance);
+                continue;
+            }
+            // Modules whose instantiation traps.
+            "assert_uninstantiable" | "assert_trap" if command.get("filename").is_some() => {
+                let filename = command["filename"].as_str().unwrap();
+                if !filename.ends_with(".wasm") {
+                    continue;
+                }
+                let module = config.parse(&fs::read(dir.join(filename))?)?;
+                if module.imports.iter().any(|i| i.module != "spectest") {
+                    continue;
+                }
+                if Instance::new(&module, SpecTest).is_ok() {
+                    failed.push(line);
+                }
+                continue;
+            }
+            "action" | "assert_return" | "assert_trap" | "assert_exhaustion"
+            | "assert_exception" => {}
+            // Modules that shouldn't validate, and names for imports.
+            "register" => continue,
+            _ if command.get("filename").is_some() => continue,
+            // Anything else may define a module the interpreter doesn't know
+            // of, so stop interpreting the commands after it.
+            _ => {
+                instances.push(None);
+                continue;
+            }
+        }
+
+        let action = &command["action"];
+        let index = match action["module"].as_str() {
+            Some(name) => names.get(name).copied(),
+            None => instances.len().checked_sub(1),
+        };
+        let Some(slot) = index.map(|i| &mut instances[i]) else {
+            continue;
+        };
+        let Some((module, instance)) = slot else {
+            continue;
+        };
+        let outcome = match invoke(module, instance, action) {
+            Some(Err(Trap::Unsupported(_))) | None => {
+                *slot = None;
+                continue;
+            }
+            Some(outcome) => outcome,
+        };
+        let ok = match (kind, outcome) {
+            ("action", outcome) => outcome.is_ok(),
+            ("assert_return", Ok(results)) => {
+                let expected = command["expected"].as_array().unwrap();
+                // Results that can't be checked, such as `v128`s, count as
+                // matching.
+                expected.len() == results.len()
+                    && expected
+                        .iter()
+                        .zip(&results)
+                        .all(|(expected, actual)| matches(expected, actual).unwrap_or(true))
+            }
+            ("assert_return", Err(_)) => false,
+            (_, outcome) => outcome.is_err(),
+        };
+        if !ok {
+            failed.push(line);
+        }
+    }
+    Ok(failed)
+}
+
+/// Run `action` on `instance`, or return `None` if its arguments can't be
+/// represented.
+fn invoke(
+    module: &Module,
+    instance: &mut Instance<SpecTest>,
+    action: &Value,
+) -> Option<Result<Vec<Val>, Trap>> {
+    let field = action["field"].as_str().unwrap();
+    let export = module.exports.iter().find(|e| e.name == field).unwrap();
+    match (action["type"].as_str().unwrap(), export.item) {
+        ("invoke", ExportItem::Function(func)) => {
+            let args = action["args"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(val)
+                .collect::<Option<Vec<_>>>()?;
+            Some(instance.call(func, &args))
+        }
+        ("get", ExportItem::Global(global)) => Some(Ok(vec![instance.global(global)])),
+        _ => None,
+    }
+}
+
+/// The value `value` describes, or `None` if the interpreter can't represent
+/// it.
+fn val(value: &Value) -> Option<Val> {
+    let bits = value["value"].as_str()?;
+    Some(match value["type"].as_str()? {
+        "i32" => Val::I32(bits.parse::<u32>().ok()? as i32),
+        "i64" => Val::I64(bits.parse::<u64>().ok()? as i64),
+        "f32" => Val::F32(bits.parse().ok()?),
+        "f64" => Val::F64(bits.parse().ok()?),
+        "externref" if bits != "null" => Val::Ref(Ref::Extern(bits.parse().ok()?)),
+        _ if bits == "null" => Val::Ref(Ref::Null),
+        _ => return None,
+    })
+}
+
+/// Whether `actual` is what `expected` describes, or `None` if that can't be
+/// checked.
+fn matches(expected: &Value, actual: &Val) -> Option<bool> {
+    let ty = expected["type"].as_str()?;
+    let bits = expected["value"].as_str();
+    Some(match (ty, bits, actual) {
+        ("f32", Some("nan:canonical"), Val::F32(bits)) => bits & 0x7fff_ffff == 0x7fc0_0000,
+        ("f32", Some("nan:arithmetic"), Val::F32(bits)) => bits & 0x7fc0_0000 == 0x7fc0_0000,
+        ("f64", Some("nan:canonical"), Val::F64(bits)) => {
+            bits & 0x7fff_ffff_ffff_ffff == 0x7ff8_0000_0000_0000
+        }
+        ("f64", Some("nan:arithmetic"), Val::F64(bits)) => {
+            bits & 0x7ff8_0000_0000_0000 == 0x7ff8_0000_0000_0000
+        }
+        ("i32" | "i64" | "f32" | "f64", Some(_), actual) => val(expected)? == *actual,
+        // A non-null reference of any kind.
+        (_, None, Val::Ref(reference)) => *reference != Ref::Null,
+        (_, Some(_), Val::Ref(_)) => val(expected)? == *actual,
+        _ => return None,
+    })
+}
+
 fn run_spectest_interp(cwd: &Path, extra_args: &[&str]) -> Result<(), anyhow::Error> {
     let output = Command::new("spectest-interp")
         .current_dir(cwd)
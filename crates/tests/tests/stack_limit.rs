@@ -0,0 +1,137 @@
+//! Tests for limiting the stack height.
+
+use walrus::interp::{Trap, Val};
+use walrus::passes::stack_limit::{self, Options};
+use walrus::{GlobalId, Module};
+use walrus_tests_utils::{instrument, trace};
+
+fn limited(wat: &str, options: &Options) -> (Module, GlobalId) {
+    instrument(wat, |module| stack_limit::run(module, options))
+}
+
+/// Call the export `name` with `arg`, and return the result and the stack
+/// height afterwards.
+fn call(module: &Module, height: GlobalId, name: &str, arg: i32) -> (Result<Vec<Val>, Trap>, i32) {
+    let (result, instance) = trace(module, name, &[Val::I32(arg)]);
+    match instance.global(height) {
+        Val::I32(height) => (result, height),
+        _ => unreachable!(),
+    }
+}
+
+const RECURSE: &str = r#"
+    (module
+        (func $double (export "double") (param i32) (result i32)
+            local.get 0
+            local.get 0
+            i32.add)
+        (func $recurse (export "recurse") (param i32) (result i32)
+            local.get 0
+            i32.eqz
+            if
+                i32.const 0
+                return
+            end
+            local.get 0
+            i32.const 1
+            i32.sub
+            call $recurse
+            i32.const 1
+            i32.add))
+"#;
+
+#[test]
+fn deep_recursion_traps() {
+    // `double` has 1 local and 2 operands at most.
+    for (limit, result) in [(2, Err(Trap::Unreachable)), (3, Ok(vec![Val::I32(4)]))] {
+        let options = Options {
+            limit,
+            ..Options::default()
+        };
+        let (module, height) = limited(RECURSE, &options);
+        assert_eq!(call(&module, height, "double", 2).0, result);
+    }
+
+    let options = Options {
+        limit: 1000,
+        export: Some("stack_height".to_string()),
+        ..Options::default()
+    };
+    let (module, height) = limited(RECURSE, &options);
+    assert!(module.exports.get_exported_global(height).is_some());
+    assert_eq!(
+        call(&module, height, "recurse", 10),
+        (Ok(vec![Val::I32(10)]), 0)
+    );
+    assert_eq!(
+        call(&module, height, "recurse", 1000).0,
+        Err(Trap::Unreachable)
+    );
+}
+
+#[test]
+fn every_exit_refunds_the_frame() {
+    let (module, height) = limited(
+        r#"
+        (module
+            (import "env" "log" (func $log (param i32)))
+            (func $exits (export "exits") (param i32) (result i32)
+                local.get 0
+                call $log
+                local.get 0
+                local.get 0
+                br_if 0
+                drop
+                i32.const 1
+                local.get 0
+                i32.eqz
+                br_if 0
+                return)
+            (func (export "tail") (param i32) (result i32)
+                local.get 0
+                return_call $exits))
+        "#,
+        &Options {
+            limit: 100,
+            ..Options::default()
+        },
+    );
+    // The import is called through a thunk.
+    assert_eq!(module.funcs.iter().count(), 4);
+    for arg in [0, 1] {
+        assert_eq!(call(&module, height, "exits", arg).1, 0);
+        assert_eq!(call(&module, height, "tail", arg).1, 0);
+    }
+}
+
+#[test]
+fn exceptions_refund_the_frames_they_unwind() {
+    let wat = r#"
+        (module
+            (tag $e (param i32))
+            (func $throw (param i32)
+                local.get 0
+                throw $e)
+            (func (export "catch") (param i32)
+                block (result i32)
+                    try_table (catch $e 0)
+                        local.get 0
+                        call $throw
+                    end
+                    unreachable
+                end
+                drop))
+    "#;
+    let mut options = Options {
+        limit: 100,
+        ..Options::default()
+    };
+    let (module, height) = limited(wat, &options);
+    let (result, leaked) = call(&module, height, "catch", 0);
+    assert_eq!(result, Ok(vec![]));
+    assert!(leaked > 0);
+
+    options.exceptions = true;
+    let (module, height) = limited(wat, &options);
+    assert_eq!(call(&module, height, "catch", 0), (Ok(vec![]), 0));
+}
@@ -0,0 +1,96 @@
+//! Tests for table images and resolving indirect call targets.
+
+use walrus::ir::{CallIndirect, Instr};
+use walrus::{Module, TableImage, TableSlot};
+use walrus_tests_utils::parse;
+
+fn call_indirect(module: &Module, name: &str) -> CallIndirect {
+    let func = module.funcs.by_name(name).unwrap();
+    let func = module.funcs.get(func).kind.unwrap_local();
+    func.block(func.entry_block())
+        .instrs
+        .iter()
+        .find_map(|(instr, _)| match instr {
+            Instr::CallIndirect(call) => Some(call.clone()),
+            _ => None,
+        })
+        .unwrap()
+}
+
+const WAT: &str = r#"
+    (module
+        (type $unary (func (param i32) (result i32)))
+        (table 8 funcref)
+        (global $fn funcref (ref.func $c))
+        (func $a (type $unary) local.get 0)
+        (func $b (type $unary) local.get 0)
+        (func $c (result i32) i32.const 0)
+        (elem (i32.const 1) func $a $b $c)
+        (elem (i32.const 3) funcref (ref.func $a) (ref.null func) (global.get $fn))
+        (func $dispatch (export "dispatch") (param i32) (result i32)
+            local.get 0
+            local.get 0
+            call_indirect (type $unary)))
+"#;
+
+#[test]
+fn main_table_image() {
+    let module = parse(WAT);
+    let image = TableImage::main(&module, &|_| None).unwrap().unwrap();
+    let [a, b, c] = ["a", "b", "c"].map(|name| module.funcs.by_name(name).unwrap());
+    assert_eq!(
+        image.slots,
+        [
+            TableSlot::Null,
+            TableSlot::Func(a),
+            TableSlot::Func(b),
+            TableSlot::Func(a),
+            TableSlot::Null,
+            TableSlot::Func(c),
+            TableSlot::Null,
+            TableSlot::Null,
+        ]
+    );
+    assert!(image.is_complete());
+
+    // `$c` has the wrong type to be called.
+    let call = call_indirect(&module, "dispatch");
+    assert_eq!(image.possible_targets(&module, &call), [a, b]);
+}
+
+#[test]
+fn imported_tables_are_unknown() {
+    let module = parse(
+        r#"
+        (module
+            (import "env" "table" (table 2 funcref))
+            (func $f)
+            (elem (i32.const 1) func $f))
+        "#,
+    );
+    let images = module.tables.images(&module, &|_| None).unwrap();
+    let f = module.funcs.by_name("f").unwrap();
+    assert_eq!(images[0].slots, [TableSlot::Unknown, TableSlot::Func(f)]);
+    assert!(!images[0].is_complete());
+}
+
+#[test]
+fn out_of_bounds_segments() {
+    let module = parse(
+        r#"
+        (module
+            (table 2 funcref)
+            (func $f)
+            (elem (i32.const 1) func $f $f))
+        "#,
+    );
+    let err = module.tables.images(&module, &|_| None).unwrap_err();
+    assert!(format!("{:?}", err).contains("out of bounds"));
+}
+
+#[test]
+fn huge_tables_are_errors() {
+    let module = parse("(module (table 0xffff_ffff funcref))");
+    let err = module.tables.images(&module, &|_| None).unwrap_err();
+    assert!(format!("{:?}", err).contains("more than a table image can hold"));
+}
@@ -0,0 +1,45 @@
+//! Reduce a wasm file while a command behaves the same on it, like
+//! `wasm-reduce`.
+//!
+//! ```text
+//! cargo run --example reduce -- input.wasm output.wasm engine --flag
+//! ```
+//!
+//! Each candidate module is written to `output.wasm.candidate` and passed as
+//! the last argument to the command. It's kept if the command exits with the
+//! same status as on `input.wasm`, such as crashing with the same signal.
+
+use std::path::Path;
+use std::process::{Command, ExitStatus};
+
+fn main() -> anyhow::Result<()> {
+    env_logger::init();
+    let mut args = std::env::args().skip(1);
+    let usage = "usage: reduce <input.wasm> <output.wasm> <command> [args...]";
+    let input = args.next().ok_or_else(|| anyhow::anyhow!(usage))?;
+    let output = args.next().ok_or_else(|| anyhow::anyhow!(usage))?;
+    let program = args.next().ok_or_else(|| anyhow::anyhow!(usage))?;
+    let args = args.collect::<Vec<_>>();
+
+    let run = |path: &Path| -> std::io::Result<ExitStatus> {
+        Command::new(&program)
+            .args(&args)
+            .arg(path)
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .status()
+    };
+    let expected = run(Path::new(&input))?;
+    println!("the command exits with {} on {}", expected, input);
+
+    let candidate = format!("{}.candidate", output);
+    let wasm = std::fs::read(&input)?;
+    let reduced = walrus::passes::reduce::run(&wasm, |wasm| {
+        std::fs::write(&candidate, wasm).expect("failed to write the candidate");
+        run(Path::new(&candidate)).is_ok_and(|status| status == expected)
+    })?;
+    std::fs::remove_file(&candidate).ok();
+    std::fs::write(&output, &reduced)?;
+    println!("reduced {} bytes to {} bytes", wasm.len(), reduced.len());
+    Ok(())
+}
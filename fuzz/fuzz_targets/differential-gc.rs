@@ -0,0 +1,14 @@
+#![no_main]
+
+#[macro_use]
+extern crate libfuzzer_sys;
+
+use walrus_fuzz_utils::Differential;
+
+fuzz_target!(|data: &[u8]| {
+    let differential = Differential::new(walrus::passes::gc::run);
+    if let Err(e) = differential.run_one(data) {
+        walrus_fuzz_utils::print_err(&e);
+        panic!("Found an error! {}", e);
+    }
+});
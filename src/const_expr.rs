@@ -4,9 +4,11 @@ use crate::emit::EmitContext;
 use crate::ir::Value;
 use crate::parse::IndicesToIds;
 use crate::ty::TypeId;
-use crate::{FunctionId, GlobalId, Result};
+use crate::{FunctionId, GlobalId, GlobalKind, Module, Result};
 use crate::{HeapType, RefType};
-use anyhow::bail;
+use anyhow::{bail, Context};
+use std::collections::HashMap;
+use std::hash::BuildHasher;
 
 /// A constant which is produced in WebAssembly, typically used in global
 /// initializers or element/data offsets.
@@ -78,8 +80,125 @@ pub enum ConstOp {
     ExternConvertAny,
 }
 
+/// Supplies the values of imported globals when evaluating a `ConstExpr`.
+pub trait GlobalResolver {
+    /// Returns the value of the imported global `global`, or `None` if it
+    /// isn't known.
+    fn resolve(&self, global: GlobalId) -> Option<Value>;
+}
+
+impl<F> GlobalResolver for F
+where
+    F: Fn(GlobalId) -> Option<Value>,
+{
+    fn resolve(&self, global: GlobalId) -> Option<Value> {
+        self(global)
+    }
+}
+
+impl<S: BuildHasher> GlobalResolver for HashMap<GlobalId, Value, S> {
+    fn resolve(&self, global: GlobalId) -> Option<Value> {
+        self.get(&global).copied()
+    }
+}
+
 impl ConstExpr {
-    pub(crate) fn eval(init: &wasmparser::ConstExpr, ids: &IndicesToIds) -> Result<ConstExpr> {
+    /// Computes the value this expression produces in `module`.
+    ///
+    /// `global.get` of an immutable local global evaluates that global's
+    /// initializer, and of an imported global asks `imports` for its value.
+    ///
+    /// Returns `Ok(None)` if the value can't be known statically: when it
+    /// depends on a mutable local global or an import that `imports` doesn't
+    /// know, or when it is a reference, which `Value` can't represent.
+    /// Returns an error if the expression is malformed, for example if it
+    /// doesn't leave exactly one value of the right types on the stack.
+    pub fn eval(&self, module: &Module, imports: &dyn GlobalResolver) -> Result<Option<Value>> {
+        self.eval_in(module, imports, &mut Vec::new())
+    }
+
+    /// Evaluates this expression, where `active` is the chain of globals whose
+    /// initializers are being evaluated, to catch cycles.
+    fn eval_in(
+        &self,
+        module: &Module,
+        imports: &dyn GlobalResolver,
+        active: &mut Vec<GlobalId>,
+    ) -> Result<Option<Value>> {
+        let ops = match self {
+            ConstExpr::Value(v) => return Ok(Some(*v)),
+            ConstExpr::Global(g) => return eval_global(*g, module, imports, active),
+            ConstExpr::RefNull(_) | ConstExpr::RefFunc(_) => return Ok(None),
+            ConstExpr::Extended(ops) => ops,
+        };
+
+        let mut stack: Vec<Option<Value>> = Vec::new();
+        for op in ops {
+            let value = match op {
+                ConstOp::I32Const(v) => Some(Value::I32(*v)),
+                ConstOp::I64Const(v) => Some(Value::I64(*v)),
+                ConstOp::F32Const(v) => Some(Value::F32(*v)),
+                ConstOp::F64Const(v) => Some(Value::F64(*v)),
+                ConstOp::V128Const(v) => Some(Value::V128(*v)),
+                ConstOp::GlobalGet(g) => eval_global(*g, module, imports, active)?,
+                ConstOp::I32Add | ConstOp::I32Sub | ConstOp::I32Mul => {
+                    let (b, a) = (stack.pop(), stack.pop());
+                    match (a, b) {
+                        (Some(Some(Value::I32(a))), Some(Some(Value::I32(b)))) => {
+                            Some(Value::I32(match op {
+                                ConstOp::I32Add => a.wrapping_add(b),
+                                ConstOp::I32Sub => a.wrapping_sub(b),
+                                _ => a.wrapping_mul(b),
+                            }))
+                        }
+                        (Some(None), Some(_)) | (Some(_), Some(None)) => None,
+                        _ => bail!("`{:?}` needs two i32 operands", op),
+                    }
+                }
+                ConstOp::I64Add | ConstOp::I64Sub | ConstOp::I64Mul => {
+                    let (b, a) = (stack.pop(), stack.pop());
+                    match (a, b) {
+                        (Some(Some(Value::I64(a))), Some(Some(Value::I64(b)))) => {
+                            Some(Value::I64(match op {
+                                ConstOp::I64Add => a.wrapping_add(b),
+                                ConstOp::I64Sub => a.wrapping_sub(b),
+                                _ => a.wrapping_mul(b),
+                            }))
+                        }
+                        (Some(None), Some(_)) | (Some(_), Some(None)) => None,
+                        _ => bail!("`{:?}` needs two i64 operands", op),
+                    }
+                }
+                // Everything else produces a reference, and no constant
+                // operation turns a reference back into a number, so the
+                // result can't be a `Value`.
+                ConstOp::RefNull(_)
+                | ConstOp::RefFunc(_)
+                | ConstOp::RefI31
+                | ConstOp::StructNew(_)
+                | ConstOp::StructNewDefault(_)
+                | ConstOp::ArrayNew(_)
+                | ConstOp::ArrayNewDefault(_)
+                | ConstOp::ArrayNewFixed { .. }
+                | ConstOp::AnyConvertExtern
+                | ConstOp::ExternConvertAny => return Ok(None),
+            };
+            stack.push(value);
+        }
+
+        match stack.as_slice() {
+            [value] => Ok(*value),
+            _ => bail!(
+                "constant expression leaves {} values on the stack, expected 1",
+                stack.len()
+            ),
+        }
+    }
+
+    pub(crate) fn from_wasmparser(
+        init: &wasmparser::ConstExpr,
+        ids: &IndicesToIds,
+    ) -> Result<ConstExpr> {
         use wasmparser::Operator::*;
         let mut reader = init.get_operators_reader();
         let mut ops = Vec::new();
@@ -246,6 +365,31 @@ impl ConstExpr {
     }
 }
 
+fn eval_global(
+    global: GlobalId,
+    module: &Module,
+    imports: &dyn GlobalResolver,
+    active: &mut Vec<GlobalId>,
+) -> Result<Option<Value>> {
+    let g = module.globals.get(global);
+    match &g.kind {
+        GlobalKind::Import(_) => Ok(imports.resolve(global)),
+        // A mutable global may have been changed by the time it is read.
+        GlobalKind::Local(_) if g.mutable => Ok(None),
+        GlobalKind::Local(init) => {
+            if active.contains(&global) {
+                bail!("global initializers refer to each other in a cycle");
+            }
+            active.push(global);
+            let value = init
+                .eval_in(module, imports, active)
+                .with_context(|| format!("failed to evaluate the initializer of {:?}", global))?;
+            active.pop();
+            Ok(value)
+        }
+    }
+}
+
 pub(crate) fn v128_to_u128(value: &wasmparser::V128) -> u128 {
     let n = value.bytes();
     (n[0] as u128)
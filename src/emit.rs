@@ -4,6 +4,7 @@
 
 use crate::ir::Local;
 use crate::map::{IdHashMap, IdHashSet};
+use crate::module::EmittedReloc;
 use crate::{CodeTransform, Global, GlobalId, Memory, MemoryId, Module, Table, TableId};
 use crate::{Data, DataId, Element, ElementId, Function, FunctionId};
 use crate::{Tag, TagId, Type, TypeId};
@@ -14,6 +15,7 @@ pub struct EmitContext<'a> {
     pub wasm_module: wasm_encoder::Module,
     pub locals: IdHashMap<Function, IdHashSet<Local>>,
     pub code_transform: CodeTransform,
+    pub code_relocs: Vec<EmittedReloc>,
 }
 
 /// Anything that can be lowered to raw wasm structures.
@@ -105,6 +107,11 @@ define_get_index! {
 }
 
 impl IdsToIndices {
+    /// Get the index for the given global, if it has been emitted.
+    pub(crate) fn find_global_index(&self, id: GlobalId) -> Option<u32> {
+        self.globals.get(&id).copied()
+    }
+
     /// Sets the data index to the specified value
     pub(crate) fn set_data_index(&mut self, id: DataId, idx: u32) {
         self.data.insert(id, idx);
@@ -0,0 +1,1240 @@
+//! A reference interpreter for walrus IR.
+//!
+//! This executes a `Module`'s functions directly from their `Instr` trees, so
+//! that a module's behaviour can be compared before and after a transformation
+//! without emitting it and handing it to an external engine.
+//!
+//! It covers the MVP, multi-value, sign extension, non-trapping float to int
+//! conversions, bulk memory, reference types, typed function references,
+//! memory64 and table64, tail calls, and both the legacy and the current
+//! exception handling proposals. Anything else, such as SIMD beyond `v128`
+//! loads, stores and constants, atomics, and GC, stops execution with
+//! `Trap::Unsupported`.
+//!
+//! The interpreter keeps its own stacks rather than recursing, so deep wasm
+//! recursion can't overflow the host's stack; instead calls nest at most
+//! `Instance::set_max_call_depth` deep.
+
+mod numeric;
+
+use crate::ir::*;
+use crate::map::IdHashMap;
+use crate::{
+    ConstExpr, ConstOp, DataKind, ElementItems, ElementKind, ExportItem, FunctionId, FunctionKind,
+    GlobalId, GlobalKind, Import, LocalFunction, LocalId, MemoryId, Module, TableId, TagId, TypeId,
+    ValType,
+};
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
+
+/// The most bytes a memory may grow to, beyond which `memory.grow` fails.
+const MEMORY_LIMIT: u64 = 1 << 30;
+
+/// The most elements a table may grow to.
+const TABLE_LIMIT: u64 = 1 << 24;
+
+/// A value on the interpreter's stack, or in a local, global or table.
+///
+/// Floats are kept as their bits, so that values compare equal exactly when
+/// they are the same, NaNs included.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Val {
+    /// A 32-bit integer.
+    I32(i32),
+    /// A 64-bit integer.
+    I64(i64),
+    /// The bits of a 32-bit float.
+    F32(u32),
+    /// The bits of a 64-bit float.
+    F64(u64),
+    /// A 128-bit vector.
+    V128(u128),
+    /// A reference.
+    Ref(Ref),
+}
+
+/// A reference value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Ref {
+    /// A null reference, of any type.
+    Null,
+    /// A reference to a function.
+    Func(FunctionId),
+    /// A host reference, which the interpreter only passes around.
+    Extern(u32),
+    /// A reference to an exception, which `Instance::exception` looks up.
+    Exn(u32),
+}
+
+impl Val {
+    /// The default value of a local or global of type `ty`: zero, or null.
+    pub fn default(ty: ValType) -> Val {
+        match ty {
+            ValType::I32 => Val::I32(0),
+            ValType::I64 => Val::I64(0),
+            ValType::F32 => Val::F32(0),
+            ValType::F64 => Val::F64(0),
+            ValType::V128 => Val::V128(0),
+            ValType::Ref(_) => Val::Ref(Ref::Null),
+        }
+    }
+
+    fn from_value(value: Value) -> Val {
+        match value {
+            Value::I32(v) => Val::I32(v),
+            Value::I64(v) => Val::I64(v),
+            Value::F32(v) => Val::F32(v.to_bits()),
+            Value::F64(v) => Val::F64(v.to_bits()),
+            Value::V128(v) => Val::V128(v),
+        }
+    }
+
+    /// This value as an address or index into a 32-bit or 64-bit memory or
+    /// table.
+    fn address(self) -> u64 {
+        match self {
+            Val::I32(v) => u64::from(v as u32),
+            Val::I64(v) => v as u64,
+            other => panic!("expected an address, found {:?}", other),
+        }
+    }
+
+    fn i32(self) -> i32 {
+        match self {
+            Val::I32(v) => v,
+            other => panic!("expected an i32, found {:?}", other),
+        }
+    }
+
+    fn reference(self) -> Ref {
+        match self {
+            Val::Ref(r) => r,
+            other => panic!("expected a reference, found {:?}", other),
+        }
+    }
+
+    /// The low bytes of this value's bits, little-endian.
+    fn bits(self) -> u128 {
+        match self {
+            Val::I32(v) => u128::from(v as u32),
+            Val::I64(v) => u128::from(v as u64),
+            Val::F32(v) => v.into(),
+            Val::F64(v) => v.into(),
+            Val::V128(v) => v,
+            Val::Ref(_) => panic!("references can't be stored to memory"),
+        }
+    }
+}
+
+/// An address or index of the given width.
+fn address(value: u64, sixty_four: bool) -> Val {
+    if sixty_four {
+        Val::I64(value as i64)
+    } else {
+        Val::I32(value as i32)
+    }
+}
+
+/// A thrown exception.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Exception {
+    /// The tag it was thrown with.
+    pub tag: TagId,
+    /// The values it carries.
+    pub values: Vec<Val>,
+}
+
+/// Why execution stopped abnormally.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Trap {
+    /// An `unreachable` instruction was executed.
+    Unreachable,
+    /// A memory access was out of bounds.
+    MemoryOutOfBounds,
+    /// A table access was out of bounds.
+    TableOutOfBounds,
+    /// An indirect call went through a null table element.
+    UninitializedElement,
+    /// An indirect call's function has the wrong type.
+    IndirectCallTypeMismatch,
+    /// A null reference was used where a non-null one is needed.
+    NullReference,
+    /// Integer arithmetic or a float to integer conversion overflowed.
+    IntegerOverflow,
+    /// An integer was divided by zero.
+    IntegerDivideByZero,
+    /// A NaN was converted to an integer.
+    InvalidConversionToInteger,
+    /// Calls nested deeper than the instance allows.
+    CallStackExhausted,
+    /// The instance ran out of fuel.
+    OutOfFuel,
+    /// An exception was thrown and not caught.
+    UncaughtException(Exception),
+    /// The host failed.
+    Host(String),
+    /// The interpreter doesn't support something the module does.
+    Unsupported(String),
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Trap::Unreachable => write!(f, "unreachable"),
+            Trap::MemoryOutOfBounds => write!(f, "out of bounds memory access"),
+            Trap::TableOutOfBounds => write!(f, "out of bounds table access"),
+            Trap::UninitializedElement => write!(f, "uninitialized element"),
+            Trap::IndirectCallTypeMismatch => write!(f, "indirect call type mismatch"),
+            Trap::NullReference => write!(f, "null reference"),
+            Trap::IntegerOverflow => write!(f, "integer overflow"),
+            Trap::IntegerDivideByZero => write!(f, "integer divide by zero"),
+            Trap::InvalidConversionToInteger => write!(f, "invalid conversion to integer"),
+            Trap::CallStackExhausted => write!(f, "call stack exhausted"),
+            Trap::OutOfFuel => write!(f, "out of fuel"),
+            Trap::UncaughtException(e) => write!(f, "uncaught exception with {:?}", e.tag),
+            Trap::Host(message) => write!(f, "host error: {}", message),
+            Trap::Unsupported(what) => write!(f, "unsupported: {}", what),
+        }
+    }
+}
+
+impl std::error::Error for Trap {}
+
+/// Provides a module's imports.
+///
+/// Imported memories and tables are always fresh ones of their minimum size.
+pub trait Host {
+    /// Call the imported function `import`, whose results have the types
+    /// `results`.
+    fn call(
+        &mut self,
+        import: &Import,
+        args: &[Val],
+        results: &[ValType],
+    ) -> Result<Vec<Val>, Trap>;
+
+    /// The value of the imported global `import`, or `None` for the default
+    /// value of its type.
+    fn global(&mut self, import: &Import, ty: ValType) -> Option<Val> {
+        let _ = (import, ty);
+        None
+    }
+}
+
+/// A `Host` whose imported functions do nothing but return default values,
+/// and which records every call made to them.
+#[derive(Clone, Debug, Default)]
+pub struct DummyHost {
+    /// The module and name of each imported function called, with its
+    /// arguments, in order.
+    pub calls: Vec<(String, String, Vec<Val>)>,
+}
+
+impl Host for DummyHost {
+    fn call(
+        &mut self,
+        import: &Import,
+        args: &[Val],
+        results: &[ValType],
+    ) -> Result<Vec<Val>, Trap> {
+        self.calls
+            .push((import.module.clone(), import.name.clone(), args.to_vec()));
+        Ok(results.iter().map(|ty| Val::default(*ty)).collect())
+    }
+}
+
+struct MemoryInstance {
+    bytes: Vec<u8>,
+    page_size: u64,
+    /// The most pages it may grow to.
+    maximum: u64,
+    memory64: bool,
+}
+
+struct TableInstance {
+    elements: Vec<Val>,
+    maximum: u64,
+    table64: bool,
+}
+
+/// An instantiated module, ready to run its functions.
+pub struct Instance<'a, H> {
+    module: &'a Module,
+    host: H,
+    memories: IdHashMap<crate::Memory, MemoryInstance>,
+    tables: IdHashMap<crate::Table, TableInstance>,
+    globals: IdHashMap<crate::Global, Val>,
+    /// The contents of the data segments that haven't been dropped.
+    data: IdHashMap<crate::Data, &'a [u8]>,
+    /// The same for element segments.
+    elements: IdHashMap<crate::Element, Vec<Val>>,
+    exceptions: Vec<Exception>,
+    fuel: Option<u64>,
+    max_call_depth: usize,
+}
+
+impl<H> fmt::Debug for Instance<'_, H> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Instance")
+            .field("fuel", &self.fuel)
+            .field("max_call_depth", &self.max_call_depth)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A reason to stop executing instructions in order.
+enum Unwind {
+    Trap(Trap),
+    /// The exception with this index in `Instance::exceptions` was thrown.
+    Throw(u32),
+}
+
+impl From<Trap> for Unwind {
+    fn from(trap: Trap) -> Unwind {
+        Unwind::Trap(trap)
+    }
+}
+
+/// The state of a call into the instance.
+#[derive(Default)]
+struct Machine<'a> {
+    values: Vec<Val>,
+    labels: Vec<Label<'a>>,
+    frames: Vec<Frame<'a>>,
+}
+
+struct Frame<'a> {
+    func: &'a LocalFunction,
+    results: usize,
+    locals: HashMap<LocalId, Val>,
+    /// The index of the label of the function's body.
+    body: usize,
+}
+
+/// An instruction sequence being executed.
+struct Label<'a> {
+    seq: &'a InstrSeq,
+    /// The index of the next instruction to execute.
+    pc: usize,
+    /// The height of the value stack below the sequence's parameters.
+    height: usize,
+    kind: LabelKind<'a>,
+}
+
+#[derive(Clone, Copy)]
+enum LabelKind<'a> {
+    /// A function's body.
+    Body,
+    Block,
+    Loop,
+    /// The body of a legacy `try`.
+    Try(&'a [LegacyCatch]),
+    /// A legacy `catch` handling this exception.
+    Catch(u32),
+    TryTable(&'a [TryTableCatch]),
+}
+
+impl Machine<'_> {
+    fn pop(&mut self) -> Val {
+        self.values.pop().expect("value stack underflow")
+    }
+
+    fn pop_n(&mut self, n: usize) -> Vec<Val> {
+        self.values.split_off(self.values.len() - n)
+    }
+}
+
+impl<'a, H: Host> Instance<'a, H> {
+    /// Instantiate `module`, taking its imports from `host`.
+    ///
+    /// This initializes globals, memories and tables, applies active data
+    /// and element segments, and runs the start function, any of which may
+    /// trap.
+    pub fn new(module: &'a Module, host: H) -> Result<Instance<'a, H>, Trap> {
+        Instance::with_fuel(module, host, None)
+    }
+
+    /// Like `new`, but with the fuel limit of `set_fuel` already in place
+    /// while the start function runs.
+    pub fn with_fuel(
+        module: &'a Module,
+        host: H,
+        fuel: Option<u64>,
+    ) -> Result<Instance<'a, H>, Trap> {
+        let mut instance = Instance {
+            module,
+            host,
+            memories: Default::default(),
+            tables: Default::default(),
+            globals: Default::default(),
+            data: Default::default(),
+            elements: Default::default(),
+            exceptions: Vec::new(),
+            fuel,
+            max_call_depth: 1000,
+        };
+
+        for global in module.globals.iter() {
+            let value = match &global.kind {
+                GlobalKind::Import(import) => instance
+                    .host
+                    .global(module.imports.get(*import), global.ty)
+                    .unwrap_or(Val::default(global.ty)),
+                GlobalKind::Local(init) => instance.eval(init)?,
+            };
+            instance.globals.insert(global.id(), value);
+        }
+
+        for memory in module.memories.iter() {
+            let page_size = 1u64 << memory.page_size_log2.unwrap_or(16);
+            let limit = if memory.memory64 { u64::MAX } else { 1 << 32 } / page_size;
+            let bytes = memory.initial.saturating_mul(page_size);
+            if bytes > MEMORY_LIMIT {
+                return Err(Trap::Unsupported(format!(
+                    "{} bytes of initial memory",
+                    bytes
+                )));
+            }
+            instance.memories.insert(
+                memory.id(),
+                MemoryInstance {
+                    bytes: vec![0; bytes as usize],
+                    page_size,
+                    maximum: memory.maximum.unwrap_or(limit).min(limit),
+                    memory64: memory.memory64,
+                },
+            );
+        }
+
+        for table in module.tables.iter() {
+            let init = match &table.init {
+                Some(init) => instance.eval(init)?,
+                None => Val::Ref(Ref::Null),
+            };
+            if table.initial > TABLE_LIMIT {
+                return Err(Trap::Unsupported(format!(
+                    "{} initial table elements",
+                    table.initial
+                )));
+            }
+            let limit = if table.table64 {
+                u64::MAX
+            } else {
+                u32::MAX.into()
+            };
+            instance.tables.insert(
+                table.id(),
+                TableInstance {
+                    elements: vec![init; table.initial as usize],
+                    maximum: table.maximum.unwrap_or(limit).min(limit),
+                    table64: table.table64,
+                },
+            );
+        }
+
+        for elem in module.elements.iter() {
+            let items = match &elem.items {
+                ElementItems::Functions(funcs) => {
+                    funcs.iter().map(|f| Val::Ref(Ref::Func(*f))).collect()
+                }
+                ElementItems::Expressions(_, exprs) => exprs
+                    .iter()
+                    .map(|expr| instance.eval(expr))
+                    .collect::<Result<Vec<_>, _>>()?,
+            };
+            instance.elements.insert(elem.id(), items);
+        }
+        for data in module.data.iter() {
+            instance.data.insert(data.id(), &data.value);
+        }
+
+        // Active and declared segments are dropped once they've been applied.
+        for elem in module.elements.iter() {
+            match &elem.kind {
+                ElementKind::Passive => continue,
+                ElementKind::Declared => {}
+                ElementKind::Active { table, offset } => {
+                    let offset = instance.eval(offset)?.address();
+                    let items = instance.elements[&elem.id()].clone();
+                    let table = instance.tables.get_mut(table).unwrap();
+                    let range = range(table.elements.len(), offset, items.len() as u64)
+                        .ok_or(Trap::TableOutOfBounds)?;
+                    table.elements[range].copy_from_slice(&items);
+                }
+            }
+            instance.elements.remove(&elem.id());
+        }
+        for data in module.data.iter() {
+            if let DataKind::Active { memory, offset } = &data.kind {
+                let offset = instance.eval(offset)?.address();
+                let memory = instance.memories.get_mut(memory).unwrap();
+                let range = range(memory.bytes.len(), offset, data.value.len() as u64)
+                    .ok_or(Trap::MemoryOutOfBounds)?;
+                memory.bytes[range].copy_from_slice(&data.value);
+                instance.data.remove(&data.id());
+            }
+        }
+
+        if let Some(start) = module.start {
+            instance.call(start, &[])?;
+        }
+        Ok(instance)
+    }
+
+    /// Call `func` with `args`, returning its results.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `args` don't match `func`'s parameters, or if the module
+    /// isn't valid.
+    pub fn call(&mut self, func: FunctionId, args: &[Val]) -> Result<Vec<Val>, Trap> {
+        let mut m = Machine::default();
+        m.values.extend_from_slice(args);
+        match self.enter(&mut m, func) {
+            Ok(()) => {}
+            Err(Unwind::Trap(trap)) => return Err(trap),
+            Err(Unwind::Throw(exn)) => {
+                return Err(Trap::UncaughtException(
+                    self.exceptions[exn as usize].clone(),
+                ))
+            }
+        }
+        while let Some(label) = m.labels.last_mut() {
+            let Some((instr, _)) = label.seq.instrs.get(label.pc) else {
+                self.end(&mut m);
+                continue;
+            };
+            label.pc += 1;
+            if let Some(fuel) = &mut self.fuel {
+                if *fuel == 0 {
+                    return Err(Trap::OutOfFuel);
+                }
+                *fuel -= 1;
+            }
+            match self.step(&mut m, instr) {
+                Ok(()) => {}
+                Err(Unwind::Trap(trap)) => return Err(trap),
+                Err(Unwind::Throw(exn)) => self.throw(&mut m, exn)?,
+            }
+        }
+        Ok(m.values)
+    }
+
+    /// Call each exported function, in order, with default arguments, and
+    /// return its name and outcome.
+    ///
+    /// This is how `wasm-interp --run-all-exports` exercises a module.
+    pub fn run_exports(&mut self) -> Vec<(String, Result<Vec<Val>, Trap>)> {
+        let module = self.module;
+        let mut outcomes = Vec::new();
+        for export in module.exports.iter() {
+            if let ExportItem::Function(func) = export.item {
+                let ty = module.funcs.get(func).ty();
+                let args = module
+                    .types
+                    .params(ty)
+                    .iter()
+                    .map(|ty| Val::default(*ty))
+                    .collect::<Vec<_>>();
+                outcomes.push((export.name.clone(), self.call(func, &args)));
+            }
+        }
+        outcomes
+    }
+
+    /// Limit the number of instructions further calls may execute in total,
+    /// or lift the limit with `None`. There is no limit by default.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    /// The fuel left, if it is limited.
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    /// Limit how deeply calls may nest. Defaults to 1000.
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        self.max_call_depth = depth;
+    }
+
+    /// The host providing this instance's imports.
+    pub fn host(&self) -> &H {
+        &self.host
+    }
+
+    /// The host providing this instance's imports, mutably.
+    pub fn host_mut(&mut self) -> &mut H {
+        &mut self.host
+    }
+
+    /// The current contents of `memory`.
+    pub fn memory(&self, memory: MemoryId) -> &[u8] {
+        &self.memories[&memory].bytes
+    }
+
+    /// The current elements of `table`.
+    pub fn table(&self, table: TableId) -> &[Val] {
+        &self.tables[&table].elements
+    }
+
+    /// The current value of `global`.
+    pub fn global(&self, global: GlobalId) -> Val {
+        self.globals[&global]
+    }
+
+    /// The exception that `Ref::Exn(exn)` refers to.
+    pub fn exception(&self, exn: u32) -> &Exception {
+        &self.exceptions[exn as usize]
+    }
+
+    fn eval(&self, expr: &ConstExpr) -> Result<Val, Trap> {
+        let ops = match expr {
+            ConstExpr::Value(value) => return Ok(Val::from_value(*value)),
+            ConstExpr::Global(global) => return Ok(self.globals[global]),
+            ConstExpr::RefNull(_) => return Ok(Val::Ref(Ref::Null)),
+            ConstExpr::RefFunc(func) => return Ok(Val::Ref(Ref::Func(*func))),
+            ConstExpr::Extended(ops) => ops,
+        };
+        let mut stack = Vec::new();
+        for op in ops {
+            let binop = match op {
+                ConstOp::I32Const(v) => {
+                    stack.push(Val::I32(*v));
+                    continue;
+                }
+                ConstOp::I64Const(v) => {
+                    stack.push(Val::I64(*v));
+                    continue;
+                }
+                ConstOp::F32Const(v) => {
+                    stack.push(Val::F32(v.to_bits()));
+                    continue;
+                }
+                ConstOp::F64Const(v) => {
+                    stack.push(Val::F64(v.to_bits()));
+                    continue;
+                }
+                ConstOp::V128Const(v) => {
+                    stack.push(Val::V128(*v));
+                    continue;
+                }
+                ConstOp::GlobalGet(global) => {
+                    stack.push(self.globals[global]);
+                    continue;
+                }
+                ConstOp::RefNull(_) => {
+                    stack.push(Val::Ref(Ref::Null));
+                    continue;
+                }
+                ConstOp::RefFunc(func) => {
+                    stack.push(Val::Ref(Ref::Func(*func)));
+                    continue;
+                }
+                ConstOp::I32Add => BinaryOp::I32Add,
+                ConstOp::I32Sub => BinaryOp::I32Sub,
+                ConstOp::I32Mul => BinaryOp::I32Mul,
+                ConstOp::I64Add => BinaryOp::I64Add,
+                ConstOp::I64Sub => BinaryOp::I64Sub,
+                ConstOp::I64Mul => BinaryOp::I64Mul,
+                other => return Err(Trap::Unsupported(format!("{:?}", other))),
+            };
+            let b = stack.pop().unwrap();
+            let a = stack.pop().unwrap();
+            stack.push(numeric::binop(binop, a, b)?);
+        }
+        Ok(stack.pop().unwrap())
+    }
+
+    /// The numbers of parameters and results of a sequence of type `ty`.
+    fn arity(&self, ty: InstrSeqType) -> (usize, usize) {
+        match ty {
+            InstrSeqType::Simple(None) => (0, 0),
+            InstrSeqType::Simple(Some(_)) => (0, 1),
+            InstrSeqType::MultiValue(ty) => {
+                let (params, results) = self.module.types.params_results(ty);
+                (params.len(), results.len())
+            }
+        }
+    }
+
+    /// Start executing `seq` of the current function.
+    fn push_label(&self, m: &mut Machine<'a>, seq: InstrSeqId, kind: LabelKind<'a>) {
+        let seq = m.frames.last().unwrap().func.block(seq);
+        let (params, _) = self.arity(seq.ty);
+        m.labels.push(Label {
+            seq,
+            pc: 0,
+            height: m.values.len() - params,
+            kind,
+        });
+    }
+
+    /// Call `func` with the arguments on top of the stack.
+    fn enter(&mut self, m: &mut Machine<'a>, func: FunctionId) -> Result<(), Unwind> {
+        let module = self.module;
+        let function = module.funcs.get(func);
+        let (params, results) = module.types.params_results(function.ty());
+        let args = m.pop_n(params.len());
+        match &function.kind {
+            FunctionKind::Local(local) => {
+                if m.frames.len() >= self.max_call_depth {
+                    return Err(Trap::CallStackExhausted.into());
+                }
+                m.frames.push(Frame {
+                    func: local,
+                    results: results.len(),
+                    locals: local.args.iter().copied().zip(args).collect(),
+                    body: m.labels.len(),
+                });
+                self.push_label(m, local.entry_block(), LabelKind::Body);
+            }
+            FunctionKind::Import(import) => {
+                let import = module.imports.get(import.import);
+                let values = self.host.call(import, &args, results)?;
+                if values.len() != results.len() {
+                    return Err(Trap::Host(format!(
+                        "`{}.{}` returned {} values instead of {}",
+                        import.module,
+                        import.name,
+                        values.len(),
+                        results.len()
+                    ))
+                    .into());
+                }
+                m.values.extend(values);
+            }
+            FunctionKind::Uninitialized(_) => unreachable!(),
+        }
+        Ok(())
+    }
+
+    /// Tail call `func`, replacing the current function's frame.
+    fn tail_call(&mut self, m: &mut Machine<'a>, func: FunctionId) -> Result<(), Unwind> {
+        let ty = self.module.funcs.get(func).ty();
+        let args = m.pop_n(self.module.types.params(ty).len());
+        let frame = m.frames.pop().unwrap();
+        m.values.truncate(m.labels[frame.body].height);
+        m.labels.truncate(frame.body);
+        m.values.extend(args);
+        self.enter(m, func)
+    }
+
+    /// The function that an indirect call through `table` with type `ty`
+    /// calls, given the index on top of the stack.
+    fn indirect_callee(
+        &self,
+        m: &mut Machine<'a>,
+        table: TableId,
+        ty: TypeId,
+    ) -> Result<FunctionId, Trap> {
+        let index = m.pop().address();
+        let table = &self.tables[&table];
+        let element = usize::try_from(index)
+            .ok()
+            .and_then(|i| table.elements.get(i))
+            .ok_or(Trap::TableOutOfBounds)?;
+        let func = match element.reference() {
+            Ref::Func(func) => func,
+            Ref::Null => return Err(Trap::UninitializedElement),
+            _ => return Err(Trap::IndirectCallTypeMismatch),
+        };
+        let types = &self.module.types;
+        let actual = self.module.funcs.get(func).ty();
+        if types.params_results(actual) != types.params_results(ty) {
+            return Err(Trap::IndirectCallTypeMismatch);
+        }
+        Ok(func)
+    }
+
+    /// Finish executing the innermost sequence.
+    fn end(&mut self, m: &mut Machine<'a>) {
+        let label = m.labels.last().unwrap();
+        if let LabelKind::Body = label.kind {
+            self.ret(m);
+        } else {
+            m.labels.pop();
+        }
+    }
+
+    /// Return from the current function.
+    fn ret(&mut self, m: &mut Machine<'a>) {
+        let frame = m.frames.pop().unwrap();
+        let results = m.pop_n(frame.results);
+        m.values.truncate(m.labels[frame.body].height);
+        m.values.extend(results);
+        m.labels.truncate(frame.body);
+    }
+
+    /// Branch to the enclosing sequence `target`.
+    fn branch(&mut self, m: &mut Machine<'a>, target: InstrSeqId) {
+        let body = m.frames.last().unwrap().body;
+        let i = (body..m.labels.len())
+            .rev()
+            .find(|i| m.labels[*i].seq.id() == target)
+            .expect("branch to a sequence that isn't executing");
+        let label = &m.labels[i];
+        if let LabelKind::Body = label.kind {
+            return self.ret(m);
+        }
+        let (params, results) = self.arity(label.seq.ty);
+        let looping = matches!(label.kind, LabelKind::Loop);
+        let arity = if looping { params } else { results };
+        let height = label.height;
+        let values = m.pop_n(arity);
+        m.values.truncate(height);
+        m.values.extend(values);
+        if looping {
+            m.labels.truncate(i + 1);
+            m.labels[i].pc = 0;
+        } else {
+            m.labels.truncate(i);
+        }
+    }
+
+    /// Unwind to the innermost handler of the exception `exn`.
+    fn throw(&mut self, m: &mut Machine<'a>, exn: u32) -> Result<(), Trap> {
+        let tag = self.exceptions[exn as usize].tag;
+        while let Some(label) = m.labels.last() {
+            let height = label.height;
+            match label.kind {
+                LabelKind::Body => {
+                    m.values.truncate(height);
+                    m.labels.pop();
+                    m.frames.pop();
+                    continue;
+                }
+                LabelKind::Try(catches) => {
+                    for catch in catches {
+                        let (handler, payload) = match catch {
+                            LegacyCatch::Catch { tag: t, handler } if *t == tag => (*handler, true),
+                            LegacyCatch::CatchAll { handler } => (*handler, false),
+                            LegacyCatch::Catch { .. } => continue,
+                            // The labels between the `try` and its delegate
+                            // don't handle the exception, but the delegate
+                            // itself does.
+                            LegacyCatch::Delegate { relative_depth } => {
+                                let len = m.labels.len() - 1 - *relative_depth as usize;
+                                m.labels.truncate(len);
+                                break;
+                            }
+                        };
+                        m.labels.pop();
+                        m.values.truncate(height);
+                        if payload {
+                            let values = self.exceptions[exn as usize].values.clone();
+                            m.values.extend(values);
+                        }
+                        let seq = m.frames.last().unwrap().func.block(handler);
+                        m.labels.push(Label {
+                            seq,
+                            pc: 0,
+                            height,
+                            kind: LabelKind::Catch(exn),
+                        });
+                        return Ok(());
+                    }
+                    if !matches!(catches.last(), Some(LegacyCatch::Delegate { .. })) {
+                        m.labels.pop();
+                    }
+                    continue;
+                }
+                LabelKind::TryTable(catches) => {
+                    for catch in catches {
+                        let (target, payload, reference) = match *catch {
+                            TryTableCatch::Catch { tag: t, label } if t == tag => {
+                                (label, true, false)
+                            }
+                            TryTableCatch::CatchRef { tag: t, label } if t == tag => {
+                                (label, true, true)
+                            }
+                            TryTableCatch::CatchAll { label } => (label, false, false),
+                            TryTableCatch::CatchAllRef { label } => (label, false, true),
+                            _ => continue,
+                        };
+                        m.labels.pop();
+                        m.values.truncate(height);
+                        if payload {
+                            let values = self.exceptions[exn as usize].values.clone();
+                            m.values.extend(values);
+                        }
+                        if reference {
+                            m.values.push(Val::Ref(Ref::Exn(exn)));
+                        }
+                        self.branch(m, target);
+                        return Ok(());
+                    }
+                }
+                LabelKind::Block | LabelKind::Loop | LabelKind::Catch(_) => {}
+            }
+            m.labels.pop();
+        }
+        Err(Trap::UncaughtException(
+            self.exceptions[exn as usize].clone(),
+        ))
+    }
+
+    fn memory_range(&self, memory: MemoryId, start: u64, len: u64) -> Result<Range<usize>, Trap> {
+        range(self.memories[&memory].bytes.len(), start, len).ok_or(Trap::MemoryOutOfBounds)
+    }
+
+    fn table_range(&self, table: TableId, start: u64, len: u64) -> Result<Range<usize>, Trap> {
+        range(self.tables[&table].elements.len(), start, len).ok_or(Trap::TableOutOfBounds)
+    }
+
+    /// The bytes an access of `width` bytes with `arg` at the address on top
+    /// of the stack touches.
+    fn access(
+        &self,
+        m: &mut Machine<'a>,
+        memory: MemoryId,
+        arg: &MemArg,
+        width: u32,
+    ) -> Result<Range<usize>, Trap> {
+        let address = m.pop().address();
+        let start = address
+            .checked_add(arg.offset)
+            .ok_or(Trap::MemoryOutOfBounds)?;
+        self.memory_range(memory, start, width.into())
+    }
+
+    /// Execute one instruction that isn't the end of a sequence.
+    fn step(&mut self, m: &mut Machine<'a>, instr: &'a Instr) -> Result<(), Unwind> {
+        match instr {
+            Instr::Block(Block { seq }) => self.push_label(m, *seq, LabelKind::Block),
+            Instr::Loop(Loop { seq }) => self.push_label(m, *seq, LabelKind::Loop),
+            Instr::IfElse(IfElse {
+                consequent,
+                alternative,
+            }) => {
+                let seq = if m.pop().i32() != 0 {
+                    *consequent
+                } else {
+                    *alternative
+                };
+                self.push_label(m, seq, LabelKind::Block);
+            }
+            Instr::Br(Br { block }) => self.branch(m, *block),
+            Instr::BrIf(BrIf { block }) => {
+                if m.pop().i32() != 0 {
+                    self.branch(m, *block);
+                }
+            }
+            Instr::BrTable(BrTable { blocks, default }) => {
+                let index = m.pop().i32() as u32 as usize;
+                self.branch(m, *blocks.get(index).unwrap_or(default));
+            }
+            Instr::BrOnNull(BrOnNull { block }) => {
+                let r = m.pop();
+                if r.reference() == Ref::Null {
+                    self.branch(m, *block);
+                } else {
+                    m.values.push(r);
+                }
+            }
+            Instr::BrOnNonNull(BrOnNonNull { block }) => {
+                let r = m.pop();
+                if r.reference() != Ref::Null {
+                    m.values.push(r);
+                    self.branch(m, *block);
+                }
+            }
+            Instr::Return(Return {}) => self.ret(m),
+            Instr::Unreachable(Unreachable {}) => return Err(Trap::Unreachable.into()),
+            Instr::Drop(Drop {}) => {
+                m.pop();
+            }
+            Instr::Select(Select { .. }) => {
+                let condition = m.pop().i32();
+                let b = m.pop();
+                let a = m.pop();
+                m.values.push(if condition != 0 { a } else { b });
+            }
+
+            Instr::Call(Call { func }) => self.enter(m, *func)?,
+            Instr::ReturnCall(ReturnCall { func }) => self.tail_call(m, *func)?,
+            Instr::CallIndirect(CallIndirect { ty, table }) => {
+                let func = self.indirect_callee(m, *table, *ty)?;
+                self.enter(m, func)?;
+            }
+            Instr::ReturnCallIndirect(ReturnCallIndirect { ty, table }) => {
+                let func = self.indirect_callee(m, *table, *ty)?;
+                self.tail_call(m, func)?;
+            }
+            Instr::CallRef(CallRef { .. }) => match m.pop().reference() {
+                Ref::Func(func) => self.enter(m, func)?,
+                _ => return Err(Trap::NullReference.into()),
+            },
+            Instr::ReturnCallRef(ReturnCallRef { .. }) => match m.pop().reference() {
+                Ref::Func(func) => self.tail_call(m, func)?,
+                _ => return Err(Trap::NullReference.into()),
+            },
+
+            Instr::LocalGet(LocalGet { local }) => {
+                let value = match m.frames.last().unwrap().locals.get(local) {
+                    Some(value) => *value,
+                    None => Val::default(self.module.locals.get(*local).ty()),
+                };
+                m.values.push(value);
+            }
+            Instr::LocalSet(LocalSet { local }) => {
+                let value = m.pop();
+                m.frames.last_mut().unwrap().locals.insert(*local, value);
+            }
+            Instr::LocalTee(LocalTee { local }) => {
+                let value = *m.values.last().unwrap();
+                m.frames.last_mut().unwrap().locals.insert(*local, value);
+            }
+            Instr::GlobalGet(GlobalGet { global }) => m.values.push(self.globals[global]),
+            Instr::GlobalSet(GlobalSet { global }) => {
+                let value = m.pop();
+                self.globals.insert(*global, value);
+            }
+
+            Instr::Const(Const { value }) => m.values.push(Val::from_value(*value)),
+            Instr::Unop(Unop { op }) => {
+                let a = m.pop();
+                m.values.push(numeric::unop(*op, a)?);
+            }
+            Instr::Binop(Binop { op }) => {
+                let b = m.pop();
+                let a = m.pop();
+                m.values.push(numeric::binop(*op, a, b)?);
+            }
+
+            Instr::Load(Load { memory, kind, arg }) => {
+                let range = self.access(m, *memory, arg, kind.width())?;
+                let bits = le(&self.memories[memory].bytes[range]);
+                let value = match kind {
+                    LoadKind::I32 { atomic: false } => Val::I32(bits as i32),
+                    LoadKind::I64 { atomic: false } => Val::I64(bits as i64),
+                    LoadKind::F32 => Val::F32(bits as u32),
+                    LoadKind::F64 => Val::F64(bits as u64),
+                    LoadKind::V128 => Val::V128(bits),
+                    LoadKind::I32_8 {
+                        kind: ExtendedLoad::SignExtend,
+                    } => Val::I32((bits as i8).into()),
+                    LoadKind::I32_16 {
+                        kind: ExtendedLoad::SignExtend,
+                    } => Val::I32((bits as i16).into()),
+                    LoadKind::I64_8 {
+                        kind: ExtendedLoad::SignExtend,
+                    } => Val::I64((bits as i8).into()),
+                    LoadKind::I64_16 {
+                        kind: ExtendedLoad::SignExtend,
+                    } => Val::I64((bits as i16).into()),
+                    LoadKind::I64_32 {
+                        kind: ExtendedLoad::SignExtend,
+                    } => Val::I64((bits as i32).into()),
+                    LoadKind::I32_8 {
+                        kind: ExtendedLoad::ZeroExtend,
+                    }
+                    | LoadKind::I32_16 {
+                        kind: ExtendedLoad::ZeroExtend,
+                    } => Val::I32(bits as i32),
+                    LoadKind::I64_8 {
+                        kind: ExtendedLoad::ZeroExtend,
+                    }
+                    | LoadKind::I64_16 {
+                        kind: ExtendedLoad::ZeroExtend,
+                    }
+                    | LoadKind::I64_32 {
+                        kind: ExtendedLoad::ZeroExtend,
+                    } => Val::I64(bits as i64),
+                    _ => return Err(Trap::Unsupported("atomic loads".into()).into()),
+                };
+                m.values.push(value);
+            }
+            Instr::Store(Store { memory, kind, arg }) => {
+                if let StoreKind::I32 { atomic: true }
+                | StoreKind::I64 { atomic: true }
+                | StoreKind::I32_8 { atomic: true }
+                | StoreKind::I32_16 { atomic: true }
+                | StoreKind::I64_8 { atomic: true }
+                | StoreKind::I64_16 { atomic: true }
+                | StoreKind::I64_32 { atomic: true } = kind
+                {
+                    return Err(Trap::Unsupported("atomic stores".into()).into());
+                }
+                let bits = m.pop().bits();
+                let range = self.access(m, *memory, arg, kind.width())?;
+                let width = range.len();
+                let bytes = &mut self.memories.get_mut(memory).unwrap().bytes[range];
+                bytes.copy_from_slice(&bits.to_le_bytes()[..width]);
+            }
+            Instr::MemorySize(MemorySize { memory }) => {
+                let memory = &self.memories[memory];
+                let pages = memory.bytes.len() as u64 / memory.page_size;
+                m.values.push(address(pages, memory.memory64));
+            }
+            Instr::MemoryGrow(MemoryGrow { memory }) => {
+                let delta = m.pop().address();
+                let memory = self.memories.get_mut(memory).unwrap();
+                let pages = memory.bytes.len() as u64 / memory.page_size;
+                let result = match pages.checked_add(delta) {
+                    Some(new)
+                        if new <= memory.maximum && new * memory.page_size <= MEMORY_LIMIT =>
+                    {
+                        memory.bytes.resize((new * memory.page_size) as usize, 0);
+                        pages
+                    }
+                    _ => u64::MAX,
+                };
+                m.values.push(address(result, memory.memory64));
+            }
+            Instr::MemoryFill(MemoryFill { memory }) => {
+                let len = m.pop().address();
+                let value = m.pop().i32() as u8;
+                let start = m.pop().address();
+                let range = self.memory_range(*memory, start, len)?;
+                self.memories.get_mut(memory).unwrap().bytes[range].fill(value);
+            }
+            Instr::MemoryCopy(MemoryCopy { src, dst }) => {
+                let len = m.pop().address();
+                let from = m.pop().address();
+                let to = m.pop().address();
+                let from = self.memory_range(*src, from, len)?;
+                let to = self.memory_range(*dst, to, len)?;
+                if src == dst {
+                    let bytes = &mut self.memories.get_mut(dst).unwrap().bytes;
+                    bytes.copy_within(from, to.start);
+                } else {
+                    let bytes = self.memories[src].bytes[from].to_vec();
+                    self.memories.get_mut(dst).unwrap().bytes[to].copy_from_slice(&bytes);
+                }
+            }
+            Instr::MemoryInit(MemoryInit { memory, data }) => {
+                let len = m.pop().i32() as u32 as u64;
+                let from = m.pop().i32() as u32 as u64;
+                let to = m.pop().address();
+                let segment = self.data.get(data).copied().unwrap_or(&[]);
+                let from = range(segment.len(), from, len).ok_or(Trap::MemoryOutOfBounds)?;
+                let to = self.memory_range(*memory, to, len)?;
+                self.memories.get_mut(memory).unwrap().bytes[to].copy_from_slice(&segment[from]);
+            }
+            Instr::DataDrop(DataDrop { data }) => {
+                self.data.remove(data);
+            }
+
+            Instr::TableGet(TableGet { table }) => {
+                let index = m.pop().address();
+                let range = self.table_range(*table, index, 1)?;
+                m.values.push(self.tables[table].elements[range.start]);
+            }
+            Instr::TableSet(TableSet { table }) => {
+                let value = m.pop();
+                let index = m.pop().address();
+                let range = self.table_range(*table, index, 1)?;
+                self.tables.get_mut(table).unwrap().elements[range.start] = value;
+            }
+            Instr::TableSize(TableSize { table }) => {
+                let table = &self.tables[table];
+                m.values
+                    .push(address(table.elements.len() as u64, table.table64));
+            }
+            Instr::TableGrow(TableGrow { table }) => {
+                let delta = m.pop().address();
+                let init = m.pop();
+                let table = self.tables.get_mut(table).unwrap();
+                let len = table.elements.len() as u64;
+                let result = match len.checked_add(delta) {
+                    Some(new) if new <= table.maximum && new <= TABLE_LIMIT => {
+                        table.elements.resize(new as usize, init);
+                        len
+                    }
+                    _ => u64::MAX,
+                };
+                m.values.push(address(result, table.table64));
+            }
+            Instr::TableFill(TableFill { table }) => {
+                let len = m.pop().address();
+                let value = m.pop();
+                let start = m.pop().address();
+                let range = self.table_range(*table, start, len)?;
+                self.tables.get_mut(table).unwrap().elements[range].fill(value);
+            }
+            Instr::TableCopy(TableCopy { src, dst }) => {
+                let len = m.pop().address();
+                let from = m.pop().address();
+                let to = m.pop().address();
+                let from = self.table_range(*src, from, len)?;
+                let to = self.table_range(*dst, to, len)?;
+                let elements = self.tables[src].elements[from].to_vec();
+                self.tables.get_mut(dst).unwrap().elements[to].copy_from_slice(&elements);
+            }
+            Instr::TableInit(TableInit { table, elem }) => {
+                let len = m.pop().i32() as u32 as u64;
+                let from = m.pop().i32() as u32 as u64;
+                let to = m.pop().address();
+                let segment = self.elements.get(elem).map_or(&[][..], |e| &e[..]);
+                let from = range(segment.len(), from, len).ok_or(Trap::TableOutOfBounds)?;
+                let items = segment[from].to_vec();
+                let to = self.table_range(*table, to, len)?;
+                self.tables.get_mut(table).unwrap().elements[to].copy_from_slice(&items);
+            }
+            Instr::ElemDrop(ElemDrop { elem }) => {
+                self.elements.remove(elem);
+            }
+
+            Instr::RefNull(RefNull { .. }) => m.values.push(Val::Ref(Ref::Null)),
+            Instr::RefIsNull(RefIsNull {}) => {
+                let null = m.pop().reference() == Ref::Null;
+                m.values.push(Val::I32(null.into()));
+            }
+            Instr::RefFunc(RefFunc { func }) => m.values.push(Val::Ref(Ref::Func(*func))),
+            Instr::RefAsNonNull(RefAsNonNull {}) => {
+                if m.values.last().unwrap().reference() == Ref::Null {
+                    return Err(Trap::NullReference.into());
+                }
+            }
+
+            Instr::Throw(Throw { tag }) => {
+                let ty = self.module.tags.get(*tag).ty();
+                let values = m.pop_n(self.module.types.params(ty).len());
+                self.exceptions.push(Exception { tag: *tag, values });
+                return Err(Unwind::Throw(self.exceptions.len() as u32 - 1));
+            }
+            Instr::ThrowRef(ThrowRef {}) => match m.pop().reference() {
+                Ref::Exn(exn) => return Err(Unwind::Throw(exn)),
+                _ => return Err(Trap::NullReference.into()),
+            },
+            Instr::Rethrow(Rethrow { relative_depth }) => {
+                let label = &m.labels[m.labels.len() - 1 - *relative_depth as usize];
+                match label.kind {
+                    LabelKind::Catch(exn) => return Err(Unwind::Throw(exn)),
+                    _ => panic!("rethrow outside of a catch"),
+                }
+            }
+            Instr::Try(Try { seq, catches }) => self.push_label(m, *seq, LabelKind::Try(catches)),
+            Instr::TryTable(TryTable { seq, catches }) => {
+                self.push_label(m, *seq, LabelKind::TryTable(catches))
+            }
+
+            Instr::AtomicFence(AtomicFence {}) => {}
+            other => {
+                let name = format!("{:?}", other);
+                let name = name.split([' ', '(']).next().unwrap_or_default();
+                return Err(Trap::Unsupported(name.to_string()).into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The range of `len` items at `start` in a space of `size` items, if it
+/// fits.
+fn range(size: usize, start: u64, len: u64) -> Option<Range<usize>> {
+    let end = start.checked_add(len)?;
+    if end > size as u64 {
+        return None;
+    }
+    Some(start as usize..end as usize)
+}
+
+/// Read up to 16 bytes as a little-endian integer.
+fn le(bytes: &[u8]) -> u128 {
+    let mut buf = [0; 16];
+    buf[..bytes.len()].copy_from_slice(bytes);
+    u128::from_le_bytes(buf)
+}
@@ -0,0 +1,286 @@
+//! The numeric instructions: arithmetic, comparisons and conversions.
+
+use super::{Trap, Val};
+use crate::ir::{BinaryOp, UnaryOp};
+
+pub(super) fn unop(op: UnaryOp, v: Val) -> Result<Val, Trap> {
+    use UnaryOp::*;
+    Ok(match (op, v) {
+        (I32Eqz, Val::I32(a)) => bool(a == 0),
+        (I32Clz, Val::I32(a)) => Val::I32(a.leading_zeros() as i32),
+        (I32Ctz, Val::I32(a)) => Val::I32(a.trailing_zeros() as i32),
+        (I32Popcnt, Val::I32(a)) => Val::I32(a.count_ones() as i32),
+        (I64Eqz, Val::I64(a)) => bool(a == 0),
+        (I64Clz, Val::I64(a)) => Val::I64(a.leading_zeros().into()),
+        (I64Ctz, Val::I64(a)) => Val::I64(a.trailing_zeros().into()),
+        (I64Popcnt, Val::I64(a)) => Val::I64(a.count_ones().into()),
+
+        // The sign operations only touch the sign bit, even of NaNs.
+        (F32Abs, Val::F32(a)) => Val::F32(a & !(1 << 31)),
+        (F32Neg, Val::F32(a)) => Val::F32(a ^ (1 << 31)),
+        (F32Ceil, Val::F32(a)) => f32(f32::from_bits(a).ceil()),
+        (F32Floor, Val::F32(a)) => f32(f32::from_bits(a).floor()),
+        (F32Trunc, Val::F32(a)) => f32(f32::from_bits(a).trunc()),
+        (F32Nearest, Val::F32(a)) => f32(f32::from_bits(a).round_ties_even()),
+        (F32Sqrt, Val::F32(a)) => f32(f32::from_bits(a).sqrt()),
+        (F64Abs, Val::F64(a)) => Val::F64(a & !(1 << 63)),
+        (F64Neg, Val::F64(a)) => Val::F64(a ^ (1 << 63)),
+        (F64Ceil, Val::F64(a)) => f64(f64::from_bits(a).ceil()),
+        (F64Floor, Val::F64(a)) => f64(f64::from_bits(a).floor()),
+        (F64Trunc, Val::F64(a)) => f64(f64::from_bits(a).trunc()),
+        (F64Nearest, Val::F64(a)) => f64(f64::from_bits(a).round_ties_even()),
+        (F64Sqrt, Val::F64(a)) => f64(f64::from_bits(a).sqrt()),
+
+        (I32WrapI64, Val::I64(a)) => Val::I32(a as i32),
+        (I64ExtendSI32, Val::I32(a)) => Val::I64(a.into()),
+        (I64ExtendUI32, Val::I32(a)) => Val::I64((a as u32).into()),
+        (I32Extend8S, Val::I32(a)) => Val::I32((a as i8).into()),
+        (I32Extend16S, Val::I32(a)) => Val::I32((a as i16).into()),
+        (I64Extend8S, Val::I64(a)) => Val::I64((a as i8).into()),
+        (I64Extend16S, Val::I64(a)) => Val::I64((a as i16).into()),
+        (I64Extend32S, Val::I64(a)) => Val::I64((a as i32).into()),
+
+        // Truncations trap on NaN and on values outside of the target's
+        // range, given as the exclusive bounds of the truncated value.
+        (I32TruncSF32, Val::F32(a)) => {
+            Val::I32(trunc(f32::from_bits(a).into(), -2147483649.0, 2147483648.0)? as i32)
+        }
+        (I32TruncUF32, Val::F32(a)) => {
+            Val::I32(trunc(f32::from_bits(a).into(), -1.0, 4294967296.0)? as u32 as i32)
+        }
+        (I32TruncSF64, Val::F64(a)) => {
+            Val::I32(trunc(f64::from_bits(a), -2147483649.0, 2147483648.0)? as i32)
+        }
+        (I32TruncUF64, Val::F64(a)) => {
+            Val::I32(trunc(f64::from_bits(a), -1.0, 4294967296.0)? as u32 as i32)
+        }
+        (I64TruncSF32, Val::F32(a)) => Val::I64(trunc(
+            f32::from_bits(a).into(),
+            -9223372036854777856.0,
+            9223372036854775808.0,
+        )? as i64),
+        (I64TruncUF32, Val::F32(a)) => {
+            Val::I64(trunc(f32::from_bits(a).into(), -1.0, 18446744073709551616.0)? as u64 as i64)
+        }
+        (I64TruncSF64, Val::F64(a)) => Val::I64(trunc(
+            f64::from_bits(a),
+            -9223372036854777856.0,
+            9223372036854775808.0,
+        )? as i64),
+        (I64TruncUF64, Val::F64(a)) => {
+            Val::I64(trunc(f64::from_bits(a), -1.0, 18446744073709551616.0)? as u64 as i64)
+        }
+
+        // Rust's float to integer casts saturate, and turn NaN into zero.
+        (I32TruncSSatF32, Val::F32(a)) => Val::I32(f32::from_bits(a) as i32),
+        (I32TruncUSatF32, Val::F32(a)) => Val::I32(f32::from_bits(a) as u32 as i32),
+        (I32TruncSSatF64, Val::F64(a)) => Val::I32(f64::from_bits(a) as i32),
+        (I32TruncUSatF64, Val::F64(a)) => Val::I32(f64::from_bits(a) as u32 as i32),
+        (I64TruncSSatF32, Val::F32(a)) => Val::I64(f32::from_bits(a) as i64),
+        (I64TruncUSatF32, Val::F32(a)) => Val::I64(f32::from_bits(a) as u64 as i64),
+        (I64TruncSSatF64, Val::F64(a)) => Val::I64(f64::from_bits(a) as i64),
+        (I64TruncUSatF64, Val::F64(a)) => Val::I64(f64::from_bits(a) as u64 as i64),
+
+        (F32ConvertSI32, Val::I32(a)) => f32(a as f32),
+        (F32ConvertUI32, Val::I32(a)) => f32(a as u32 as f32),
+        (F32ConvertSI64, Val::I64(a)) => f32(a as f32),
+        (F32ConvertUI64, Val::I64(a)) => f32(a as u64 as f32),
+        (F32DemoteF64, Val::F64(a)) => f32(f64::from_bits(a) as f32),
+        (F64ConvertSI32, Val::I32(a)) => f64(a.into()),
+        (F64ConvertUI32, Val::I32(a)) => f64((a as u32).into()),
+        (F64ConvertSI64, Val::I64(a)) => f64(a as f64),
+        (F64ConvertUI64, Val::I64(a)) => f64(a as u64 as f64),
+        (F64PromoteF32, Val::F32(a)) => f64(f32::from_bits(a).into()),
+
+        (I32ReinterpretF32, Val::F32(a)) => Val::I32(a as i32),
+        (I64ReinterpretF64, Val::F64(a)) => Val::I64(a as i64),
+        (F32ReinterpretI32, Val::I32(a)) => Val::F32(a as u32),
+        (F64ReinterpretI64, Val::I64(a)) => Val::F64(a as u64),
+
+        (op, _) => return Err(Trap::Unsupported(format!("{:?}", op))),
+    })
+}
+
+pub(super) fn binop(op: BinaryOp, a: Val, b: Val) -> Result<Val, Trap> {
+    use BinaryOp::*;
+    Ok(match (op, a, b) {
+        (I32Eq, Val::I32(a), Val::I32(b)) => bool(a == b),
+        (I32Ne, Val::I32(a), Val::I32(b)) => bool(a != b),
+        (I32LtS, Val::I32(a), Val::I32(b)) => bool(a < b),
+        (I32LtU, Val::I32(a), Val::I32(b)) => bool((a as u32) < (b as u32)),
+        (I32GtS, Val::I32(a), Val::I32(b)) => bool(a > b),
+        (I32GtU, Val::I32(a), Val::I32(b)) => bool((a as u32) > (b as u32)),
+        (I32LeS, Val::I32(a), Val::I32(b)) => bool(a <= b),
+        (I32LeU, Val::I32(a), Val::I32(b)) => bool((a as u32) <= (b as u32)),
+        (I32GeS, Val::I32(a), Val::I32(b)) => bool(a >= b),
+        (I32GeU, Val::I32(a), Val::I32(b)) => bool((a as u32) >= (b as u32)),
+        (I64Eq, Val::I64(a), Val::I64(b)) => bool(a == b),
+        (I64Ne, Val::I64(a), Val::I64(b)) => bool(a != b),
+        (I64LtS, Val::I64(a), Val::I64(b)) => bool(a < b),
+        (I64LtU, Val::I64(a), Val::I64(b)) => bool((a as u64) < (b as u64)),
+        (I64GtS, Val::I64(a), Val::I64(b)) => bool(a > b),
+        (I64GtU, Val::I64(a), Val::I64(b)) => bool((a as u64) > (b as u64)),
+        (I64LeS, Val::I64(a), Val::I64(b)) => bool(a <= b),
+        (I64LeU, Val::I64(a), Val::I64(b)) => bool((a as u64) <= (b as u64)),
+        (I64GeS, Val::I64(a), Val::I64(b)) => bool(a >= b),
+        (I64GeU, Val::I64(a), Val::I64(b)) => bool((a as u64) >= (b as u64)),
+
+        (F32Eq | F32Ne | F32Lt | F32Gt | F32Le | F32Ge, Val::F32(a), Val::F32(b)) => {
+            let (a, b) = (f32::from_bits(a), f32::from_bits(b));
+            bool(match op {
+                F32Eq => a == b,
+                F32Ne => a != b,
+                F32Lt => a < b,
+                F32Gt => a > b,
+                F32Le => a <= b,
+                _ => a >= b,
+            })
+        }
+        (F64Eq | F64Ne | F64Lt | F64Gt | F64Le | F64Ge, Val::F64(a), Val::F64(b)) => {
+            let (a, b) = (f64::from_bits(a), f64::from_bits(b));
+            bool(match op {
+                F64Eq => a == b,
+                F64Ne => a != b,
+                F64Lt => a < b,
+                F64Gt => a > b,
+                F64Le => a <= b,
+                _ => a >= b,
+            })
+        }
+
+        (I32Add, Val::I32(a), Val::I32(b)) => Val::I32(a.wrapping_add(b)),
+        (I32Sub, Val::I32(a), Val::I32(b)) => Val::I32(a.wrapping_sub(b)),
+        (I32Mul, Val::I32(a), Val::I32(b)) => Val::I32(a.wrapping_mul(b)),
+        (I32DivS, Val::I32(a), Val::I32(b)) => {
+            if b == 0 {
+                return Err(Trap::IntegerDivideByZero);
+            }
+            Val::I32(a.checked_div(b).ok_or(Trap::IntegerOverflow)?)
+        }
+        (I32DivU, Val::I32(a), Val::I32(b)) => Val::I32(
+            (a as u32)
+                .checked_div(b as u32)
+                .ok_or(Trap::IntegerDivideByZero)? as i32,
+        ),
+        (I32RemS, Val::I32(a), Val::I32(b)) => {
+            if b == 0 {
+                return Err(Trap::IntegerDivideByZero);
+            }
+            Val::I32(a.wrapping_rem(b))
+        }
+        (I32RemU, Val::I32(a), Val::I32(b)) => Val::I32(
+            (a as u32)
+                .checked_rem(b as u32)
+                .ok_or(Trap::IntegerDivideByZero)? as i32,
+        ),
+        (I32And, Val::I32(a), Val::I32(b)) => Val::I32(a & b),
+        (I32Or, Val::I32(a), Val::I32(b)) => Val::I32(a | b),
+        (I32Xor, Val::I32(a), Val::I32(b)) => Val::I32(a ^ b),
+        (I32Shl, Val::I32(a), Val::I32(b)) => Val::I32(a.wrapping_shl(b as u32)),
+        (I32ShrS, Val::I32(a), Val::I32(b)) => Val::I32(a.wrapping_shr(b as u32)),
+        (I32ShrU, Val::I32(a), Val::I32(b)) => Val::I32((a as u32).wrapping_shr(b as u32) as i32),
+        (I32Rotl, Val::I32(a), Val::I32(b)) => Val::I32(a.rotate_left(b as u32 % 32)),
+        (I32Rotr, Val::I32(a), Val::I32(b)) => Val::I32(a.rotate_right(b as u32 % 32)),
+
+        (I64Add, Val::I64(a), Val::I64(b)) => Val::I64(a.wrapping_add(b)),
+        (I64Sub, Val::I64(a), Val::I64(b)) => Val::I64(a.wrapping_sub(b)),
+        (I64Mul, Val::I64(a), Val::I64(b)) => Val::I64(a.wrapping_mul(b)),
+        (I64DivS, Val::I64(a), Val::I64(b)) => {
+            if b == 0 {
+                return Err(Trap::IntegerDivideByZero);
+            }
+            Val::I64(a.checked_div(b).ok_or(Trap::IntegerOverflow)?)
+        }
+        (I64DivU, Val::I64(a), Val::I64(b)) => Val::I64(
+            (a as u64)
+                .checked_div(b as u64)
+                .ok_or(Trap::IntegerDivideByZero)? as i64,
+        ),
+        (I64RemS, Val::I64(a), Val::I64(b)) => {
+            if b == 0 {
+                return Err(Trap::IntegerDivideByZero);
+            }
+            Val::I64(a.wrapping_rem(b))
+        }
+        (I64RemU, Val::I64(a), Val::I64(b)) => Val::I64(
+            (a as u64)
+                .checked_rem(b as u64)
+                .ok_or(Trap::IntegerDivideByZero)? as i64,
+        ),
+        (I64And, Val::I64(a), Val::I64(b)) => Val::I64(a & b),
+        (I64Or, Val::I64(a), Val::I64(b)) => Val::I64(a | b),
+        (I64Xor, Val::I64(a), Val::I64(b)) => Val::I64(a ^ b),
+        (I64Shl, Val::I64(a), Val::I64(b)) => Val::I64(a.wrapping_shl(b as u32)),
+        (I64ShrS, Val::I64(a), Val::I64(b)) => Val::I64(a.wrapping_shr(b as u32)),
+        (I64ShrU, Val::I64(a), Val::I64(b)) => Val::I64((a as u64).wrapping_shr(b as u32) as i64),
+        (I64Rotl, Val::I64(a), Val::I64(b)) => Val::I64(a.rotate_left((b as u64 % 64) as u32)),
+        (I64Rotr, Val::I64(a), Val::I64(b)) => Val::I64(a.rotate_right((b as u64 % 64) as u32)),
+
+        (F32Add, Val::F32(a), Val::F32(b)) => f32(f32::from_bits(a) + f32::from_bits(b)),
+        (F32Sub, Val::F32(a), Val::F32(b)) => f32(f32::from_bits(a) - f32::from_bits(b)),
+        (F32Mul, Val::F32(a), Val::F32(b)) => f32(f32::from_bits(a) * f32::from_bits(b)),
+        (F32Div, Val::F32(a), Val::F32(b)) => f32(f32::from_bits(a) / f32::from_bits(b)),
+        (F32Min, Val::F32(a), Val::F32(b)) => {
+            f32(min(f32::from_bits(a).into(), f32::from_bits(b).into()) as f32)
+        }
+        (F32Max, Val::F32(a), Val::F32(b)) => {
+            f32(max(f32::from_bits(a).into(), f32::from_bits(b).into()) as f32)
+        }
+        (F32Copysign, Val::F32(a), Val::F32(b)) => Val::F32((a & !(1 << 31)) | (b & (1 << 31))),
+        (F64Add, Val::F64(a), Val::F64(b)) => f64(f64::from_bits(a) + f64::from_bits(b)),
+        (F64Sub, Val::F64(a), Val::F64(b)) => f64(f64::from_bits(a) - f64::from_bits(b)),
+        (F64Mul, Val::F64(a), Val::F64(b)) => f64(f64::from_bits(a) * f64::from_bits(b)),
+        (F64Div, Val::F64(a), Val::F64(b)) => f64(f64::from_bits(a) / f64::from_bits(b)),
+        (F64Min, Val::F64(a), Val::F64(b)) => f64(min(f64::from_bits(a), f64::from_bits(b))),
+        (F64Max, Val::F64(a), Val::F64(b)) => f64(max(f64::from_bits(a), f64::from_bits(b))),
+        (F64Copysign, Val::F64(a), Val::F64(b)) => Val::F64((a & !(1 << 63)) | (b & (1 << 63))),
+
+        (op, _, _) => return Err(Trap::Unsupported(format!("{:?}", op))),
+    })
+}
+
+fn bool(b: bool) -> Val {
+    Val::I32(b.into())
+}
+
+fn f32(f: f32) -> Val {
+    Val::F32(f.to_bits())
+}
+
+fn f64(f: f64) -> Val {
+    Val::F64(f.to_bits())
+}
+
+/// Truncate `f` towards zero, trapping unless it is strictly between `low`
+/// and `high`.
+fn trunc(f: f64, low: f64, high: f64) -> Result<f64, Trap> {
+    if f.is_nan() {
+        return Err(Trap::InvalidConversionToInteger);
+    }
+    if f <= low || f >= high {
+        return Err(Trap::IntegerOverflow);
+    }
+    Ok(f.trunc())
+}
+
+/// `min` as wasm defines it: NaN if either operand is, and `-0` below `+0`.
+fn min(a: f64, b: f64) -> f64 {
+    if a.is_nan() || b.is_nan() {
+        f64::NAN
+    } else if a == b {
+        // Only zeros of opposite signs compare equal and differ.
+        f64::from_bits(a.to_bits() | b.to_bits())
+    } else {
+        a.min(b)
+    }
+}
+
+fn max(a: f64, b: f64) -> f64 {
+    if a.is_nan() || b.is_nan() {
+        f64::NAN
+    } else if a == b {
+        f64::from_bits(a.to_bits() & b.to_bits())
+    } else {
+        a.max(b)
+    }
+}
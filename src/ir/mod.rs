@@ -1591,6 +1591,18 @@ impl LoadKind {
             F32 | F64 | V128 => false,
         }
     }
+
+    /// Returns the type of the value loaded
+    pub(crate) fn ty(&self) -> ValType {
+        use self::LoadKind::*;
+        match self {
+            I32 { .. } | I32_8 { .. } | I32_16 { .. } => ValType::I32,
+            I64 { .. } | I64_8 { .. } | I64_16 { .. } | I64_32 { .. } => ValType::I64,
+            F32 => ValType::F32,
+            F64 => ValType::F64,
+            V128 => ValType::V128,
+        }
+    }
 }
 
 impl ExtendedLoad {
@@ -1647,6 +1659,18 @@ impl StoreKind {
             F32 | F64 | V128 => false,
         }
     }
+
+    /// Returns the type of the value stored
+    pub(crate) fn ty(&self) -> ValType {
+        use self::StoreKind::*;
+        match self {
+            I32 { .. } | I32_8 { .. } | I32_16 { .. } => ValType::I32,
+            I64 { .. } | I64_8 { .. } | I64_16 { .. } | I64_32 { .. } => ValType::I64,
+            F32 => ValType::F32,
+            F64 => ValType::F64,
+            V128 => ValType::V128,
+        }
+    }
 }
 
 /// Arguments to memory operations, containing a constant offset from a dynamic
@@ -1695,6 +1719,15 @@ impl AtomicWidth {
             I64 => 8,
         }
     }
+
+    /// Returns the type of the value operated on
+    pub(crate) fn ty(&self) -> ValType {
+        use self::AtomicWidth::*;
+        match self {
+            I32 | I32_8 | I32_16 => ValType::I32,
+            I64 | I64_8 | I64_16 | I64_32 => ValType::I64,
+        }
+    }
 }
 
 impl Instr {
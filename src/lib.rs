@@ -42,6 +42,8 @@ pub mod dot;
 mod emit;
 mod error;
 mod function_builder;
+#[cfg(feature = "interp")]
+pub mod interp;
 pub mod ir;
 mod map;
 mod module;
@@ -50,7 +52,7 @@ pub mod passes;
 mod tombstone_arena;
 mod ty;
 
-pub use crate::const_expr::{ConstExpr, ConstOp};
+pub use crate::const_expr::{ConstExpr, ConstOp, GlobalResolver};
 pub use crate::emit::IdsToIndices;
 pub use crate::error::{ErrorKind, Result};
 pub use crate::function_builder::{FunctionBuilder, InstrSeqBuilder};
@@ -0,0 +1,61 @@
+//! The `build_id` custom section, giving a module a stable identity.
+//!
+//! See <https://github.com/WebAssembly/tool-conventions/blob/main/BuildId.md>.
+
+use crate::emit::{Emit, EmitContext};
+use crate::error::Result;
+use crate::module::Module;
+use anyhow::bail;
+#[cfg(feature = "build-id")]
+use sha2::{Digest, Sha256};
+
+/// The `build_id` custom section.
+///
+/// Tools such as crash reporters and debuggers use this to match a module up
+/// with its debug information. With the `build-id` feature, enable
+/// `ModuleConfig::generate_build_id` to have one computed from the emitted
+/// module instead of keeping the one read from the input.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BuildId {
+    /// The raw identifier bytes.
+    pub id: Vec<u8>,
+}
+
+#[cfg(feature = "build-id")]
+impl BuildId {
+    /// The number of bytes in a generated build id.
+    const GENERATED_LEN: usize = 16;
+
+    /// Generate a build id from the contents of a module, which is the leading
+    /// bytes of the SHA-256 digest of `wasm`.
+    pub(crate) fn from_contents(wasm: &[u8]) -> BuildId {
+        BuildId {
+            id: Sha256::digest(wasm)[..Self::GENERATED_LEN].to_vec(),
+        }
+    }
+}
+
+impl Module {
+    pub(crate) fn parse_build_id(&mut self, data: &[u8], offset: usize) -> Result<()> {
+        let mut reader = wasmparser::BinaryReader::new(data, offset);
+        let len = reader.read_var_u32()? as usize;
+        let id = reader.read_bytes(len)?.to_vec();
+        if !reader.eof() {
+            bail!("trailing bytes after the build id");
+        }
+        self.build_id = Some(BuildId { id });
+        Ok(())
+    }
+}
+
+impl Emit for BuildId {
+    fn emit(&self, cx: &mut EmitContext) {
+        log::debug!("emit build_id section");
+        let mut data = Vec::new();
+        wasm_encoder::Encode::encode(self.id.as_slice(), &mut data);
+        cx.wasm_module.section(&wasm_encoder::CustomSection {
+            name: "build_id".into(),
+            data: data.into(),
+        });
+    }
+}
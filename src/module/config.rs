@@ -22,6 +22,7 @@ pub struct ModuleConfig {
     pub(crate) skip_producers_section: bool,
     pub(crate) skip_name_section: bool,
     pub(crate) preserve_code_transform: bool,
+    pub(crate) generate_build_id: bool,
     pub(crate) on_parse: Option<OnParseFn>,
     pub(crate) on_instr_loc: Option<OnInstrLocFn>,
 }
@@ -38,6 +39,7 @@ impl Clone for ModuleConfig {
             skip_producers_section: self.skip_producers_section,
             skip_name_section: self.skip_name_section,
             preserve_code_transform: self.preserve_code_transform,
+            generate_build_id: self.generate_build_id,
 
             // ... and this is left empty.
             on_parse: None,
@@ -58,6 +60,7 @@ impl fmt::Debug for ModuleConfig {
             ref skip_producers_section,
             ref skip_name_section,
             ref preserve_code_transform,
+            ref generate_build_id,
             ref on_parse,
             ref on_instr_loc,
         } = self;
@@ -73,6 +76,7 @@ impl fmt::Debug for ModuleConfig {
             .field("skip_producers_section", skip_producers_section)
             .field("skip_name_section", skip_name_section)
             .field("preserve_code_transform", preserve_code_transform)
+            .field("generate_build_id", generate_build_id)
             .field("on_parse", &on_parse.as_ref().map(|_| ".."))
             .field("on_instr_loc", &on_instr_loc.as_ref().map(|_| ".."))
             .finish()
@@ -88,9 +92,9 @@ impl ModuleConfig {
     /// Sets a flag to whether DWARF debug sections are generated for this
     /// module.
     ///
-    /// By default this flag is `false`. Note that any emitted DWARF is
-    /// currently wildly incorrect and buggy, and is also larger than the wasm
-    /// itself!
+    /// By default this flag is `false`. Code addresses, range lists, location
+    /// lists and `DW_OP_WASM_location` expressions are translated to match the
+    /// emitted code, and anything describing removed code or locals is dropped.
     pub fn generate_dwarf(&mut self, generate: bool) -> &mut ModuleConfig {
         self.generate_dwarf = generate;
         // generate_dwarf implies preserve_code_transform
@@ -230,6 +234,23 @@ impl ModuleConfig {
         self
     }
 
+    /// Sets a flag to whether a fresh `build_id` section is generated when
+    /// emitting this module.
+    ///
+    /// The id is a hash of every other emitted section, so identical output
+    /// always gets the same id. It replaces any id read from the input, and
+    /// `Module::build_id` is updated to match after emission.
+    ///
+    /// By default this flag is `false`, and the module's existing `build_id`,
+    /// if any, is emitted as is.
+    ///
+    /// Only available with the `build-id` feature.
+    #[cfg(feature = "build-id")]
+    pub fn generate_build_id(&mut self, generate: bool) -> &mut ModuleConfig {
+        self.generate_build_id = generate;
+        self
+    }
+
     /// Parses an in-memory WebAssembly file into a `Module` using this
     /// configuration.
     pub fn parse(&self, wasm: &[u8]) -> Result<Module> {
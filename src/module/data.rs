@@ -99,6 +99,11 @@ impl ModuleData {
         self.arena.iter().map(|(_, f)| f)
     }
 
+    /// Get a mutable reference to this module's data segments.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Data> {
+        self.arena.iter_mut().map(|(_, f)| f)
+    }
+
     /// Add a data segment
     pub fn add(&mut self, kind: DataKind, value: Vec<u8>) -> DataId {
         let id = self.arena.next_id();
@@ -217,7 +222,7 @@ impl Module {
                     let memory = self.memories.get_mut(memory_id);
                     memory.data_segments.insert(data.id);
 
-                    let offset = ConstExpr::eval(&offset_expr, ids)
+                    let offset = ConstExpr::from_wasmparser(&offset_expr, ids)
                         .with_context(|| format!("failed to evaluate the offset of data {}", i))?;
 
                     if memory.memory64 {
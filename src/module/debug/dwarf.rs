@@ -17,6 +17,22 @@ pub enum AddressSearchPreference {
 
 pub(crate) static DEAD_CODE: u64 = 0xFFFFFFFF;
 
+/// A wasm local or global referenced by `DW_OP_WASM_location`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum WasmLocation {
+    Local(u32),
+    Global(u32),
+}
+
+enum ConvertedExpression {
+    /// The expression can't be rewritten here, keep the one converted by
+    /// `write::Dwarf::from`.
+    Unchanged,
+    Converted(write::Expression),
+    /// The expression refers to something that no longer exists.
+    Unavailable,
+}
+
 /// DWARF convertion context
 pub(crate) struct ConvertContext<'a, R: Reader<Offset = usize>> {
     /// Source DWARF debug data
@@ -31,6 +47,12 @@ pub(crate) struct ConvertContext<'a, R: Reader<Offset = usize>> {
     /// If the address is mapped in transformed wasm binary, the address should be wrapped in Option::Some.
     /// If the address is not mapped, None should be returned.
     pub convert_address: &'a dyn Fn(u64, AddressSearchPreference) -> Option<write::Address>,
+
+    /// Wasm location conversion function.
+    /// First argument is an original address of the code the location is
+    /// used in, which identifies the function that locals belong to.
+    /// Returns the new index of the local or global, or None if it was removed.
+    pub convert_wasm_location: &'a dyn Fn(Option<u64>, WasmLocation) -> Option<WasmLocation>,
 }
 
 impl<'a, R> ConvertContext<'a, R>
@@ -43,6 +65,7 @@ where
         strings: &'a mut write::StringTable,
         line_strings: &'a mut write::LineStringTable,
         convert_address: &'a dyn Fn(u64, AddressSearchPreference) -> Option<write::Address>,
+        convert_wasm_location: &'a dyn Fn(Option<u64>, WasmLocation) -> Option<WasmLocation>,
     ) -> Self {
         ConvertContext {
             debug_str,
@@ -50,39 +73,156 @@ where
             strings,
             line_strings,
             convert_address,
+            convert_wasm_location,
         }
     }
 
-    pub(crate) fn convert_high_pc(
+    /// Convert the code addresses, range lists, location lists and location
+    /// expressions of every entry in a unit.
+    ///
+    /// `unit` must have been converted from `from_unit` by `write::Dwarf::from`
+    /// so that its entries are in the same order. The unit's range and
+    /// location list tables are rebuilt from scratch.
+    pub(crate) fn convert_entries(
         &self,
-        from_unit: &mut gimli::read::EntriesCursor<R>,
+        from_dwarf: &read::Dwarf<R>,
+        from_unit: &read::Unit<R>,
         unit: &mut DebuggingInformationCursor,
     ) {
-        while let (Ok(Some((_, from_debug_entry))), Some(debug_entry)) =
-            (from_unit.next_dfs(), unit.next_dfs())
+        unit.unit().ranges = write::RangeListTable::default();
+        unit.unit().locations = write::LocationListTable::default();
+        let encoding = from_unit.encoding();
+
+        let mut from_entries = from_unit.entries();
+        // The original address of the code enclosing each entry on the path
+        // to the current one, used to find the function that owns locals.
+        let mut scopes: Vec<Option<u64>> = Vec::new();
+        let mut depth = 0;
+        while let (Ok(Some((delta, from_entry))), Some(_)) =
+            (from_entries.next_dfs(), unit.next_dfs())
         {
-            let low_pc = from_debug_entry
-                .attr_value(constants::DW_AT_low_pc)
-                .expect("low_pc");
-            let high_pc = from_debug_entry
-                .attr_value(constants::DW_AT_high_pc)
-                .expect("high_pc");
-
-            if let (Some(AttributeValue::Addr(low_addr)), Some(AttributeValue::Udata(offset))) =
-                (low_pc, high_pc)
-            {
-                let new_low_pc =
-                    (self.convert_address)(low_addr, AddressSearchPreference::InclusiveFunctionEnd);
-                let new_high_pc = (self.convert_address)(
-                    low_addr + offset,
+            depth += delta;
+            scopes.truncate(depth as usize);
+            let mut scope = scopes.last().copied().flatten();
+
+            let mut low_pc = None;
+            let mut attrs = from_entry.attrs();
+            while let Ok(Some(attr)) = attrs.next() {
+                let value = match attr.value() {
+                    AttributeValue::DebugAddrIndex(index) => {
+                        match from_dwarf.address(from_unit, index) {
+                            Ok(address) => AttributeValue::Addr(address),
+                            Err(_) => continue,
+                        }
+                    }
+                    value => value,
+                };
+                match (attr.name(), value) {
+                    (constants::DW_AT_low_pc, AttributeValue::Addr(address)) => {
+                        low_pc = Some(address);
+                        scope = Some(address);
+                        let new_address = self
+                            .convert_pc(address, AddressSearchPreference::ExclusiveFunctionEnd)
+                            .unwrap_or(DEAD_CODE);
+                        unit.current().unwrap().set(
+                            constants::DW_AT_low_pc,
+                            write::AttributeValue::Address(write::Address::Constant(new_address)),
+                        );
+                    }
+                    (constants::DW_AT_ranges, value) => {
+                        // Every range list was dropped from the unit above, so
+                        // any that can't be converted must go too.
+                        match self.convert_ranges(from_dwarf, from_unit, value) {
+                            Some((ranges, begin)) => {
+                                scope = begin.or(scope);
+                                let id = unit.unit().ranges.add(ranges);
+                                unit.current()
+                                    .unwrap()
+                                    .set(attr.name(), write::AttributeValue::RangeListRef(id));
+                            }
+                            None => unit.current().unwrap().delete(attr.name()),
+                        }
+                    }
+                    _ => {}
+                }
+            }
+
+            // `high_pc` is usually an offset from `low_pc`, and needs both to
+            // have been converted first.
+            if let Some(low_pc) = low_pc {
+                self.convert_high_pc(from_entry, low_pc, unit);
+            }
+
+            // Now that the scope of this entry is known, fix up the locations
+            // it describes.
+            let mut attrs = from_entry.attrs();
+            while let Ok(Some(attr)) = attrs.next() {
+                match attr.value() {
+                    AttributeValue::Exprloc(expression) => {
+                        match self.convert_expression(expression, encoding, scope) {
+                            ConvertedExpression::Unchanged => {}
+                            ConvertedExpression::Converted(expression) => {
+                                unit.current()
+                                    .unwrap()
+                                    .set(attr.name(), write::AttributeValue::Exprloc(expression));
+                            }
+                            ConvertedExpression::Unavailable => {
+                                unit.current().unwrap().delete(attr.name());
+                            }
+                        }
+                    }
+                    value @ (AttributeValue::LocationListsRef(_)
+                    | AttributeValue::DebugLocListsIndex(_)) => {
+                        match self.convert_locations(from_dwarf, from_unit, value) {
+                            Some(locations) => {
+                                let id = unit.unit().locations.add(locations);
+                                unit.current()
+                                    .unwrap()
+                                    .set(attr.name(), write::AttributeValue::LocationListRef(id));
+                            }
+                            None => unit.current().unwrap().delete(attr.name()),
+                        }
+                    }
+                    _ => {}
+                }
+            }
+
+            scopes.push(scope);
+        }
+    }
+
+    fn convert_high_pc(
+        &self,
+        from_entry: &read::DebuggingInformationEntry<R>,
+        low_pc: u64,
+        unit: &mut DebuggingInformationCursor,
+    ) {
+        let high_pc = match from_entry.attr_value(constants::DW_AT_high_pc) {
+            Ok(Some(high_pc)) => high_pc,
+            _ => return,
+        };
+        let new_low_pc = self.convert_pc(low_pc, AddressSearchPreference::ExclusiveFunctionEnd);
+        let entry = unit.current().unwrap();
+        match high_pc {
+            AttributeValue::Addr(high_pc) => {
+                let new_high_pc = self
+                    .convert_pc(high_pc, AddressSearchPreference::InclusiveFunctionEnd)
+                    .unwrap_or(DEAD_CODE);
+                entry.set(
+                    constants::DW_AT_high_pc,
+                    write::AttributeValue::Address(write::Address::Constant(new_high_pc)),
+                );
+            }
+            high_pc => {
+                let Some(offset) = high_pc.udata_value() else {
+                    return;
+                };
+                let new_high_pc = self.convert_pc(
+                    low_pc + offset,
                     AddressSearchPreference::InclusiveFunctionEnd,
                 );
-                if let (
-                    Some(write::Address::Constant(new_low_pc)),
-                    Some(write::Address::Constant(new_high_pc)),
-                ) = (new_low_pc, new_high_pc)
-                {
-                    debug_entry.set(
+                if let (Some(new_low_pc), Some(new_high_pc)) = (new_low_pc, new_high_pc) {
+                    entry.set(
                         constants::DW_AT_high_pc,
                         write::AttributeValue::Udata(new_high_pc.saturating_sub(new_low_pc)),
                     );
@@ -91,6 +231,199 @@ where
         }
     }
 
+    /// Convert a code address, keeping the special addresses 0 and
+    /// `DEAD_CODE` as they are.
+    fn convert_pc(&self, address: u64, search_preference: AddressSearchPreference) -> Option<u64> {
+        if address == 0 || address == DEAD_CODE {
+            return Some(address);
+        }
+        match (self.convert_address)(address, search_preference) {
+            Some(write::Address::Constant(address)) => Some(address),
+            _ => None,
+        }
+    }
+
+    /// Convert the start and end of a range of code, returning `None` if the
+    /// range no longer exists.
+    fn convert_range(&self, range: read::Range) -> Option<(write::Address, write::Address)> {
+        if range.begin >= range.end {
+            return None;
+        }
+        let begin = self.convert_pc(range.begin, AddressSearchPreference::ExclusiveFunctionEnd)?;
+        let end = self.convert_pc(range.end, AddressSearchPreference::InclusiveFunctionEnd)?;
+        if begin >= end || begin == 0 || end == DEAD_CODE {
+            return None;
+        }
+        Some((
+            write::Address::Constant(begin),
+            write::Address::Constant(end),
+        ))
+    }
+
+    /// Convert a range list attribute, also returning the original start of
+    /// its first range.
+    fn convert_ranges(
+        &self,
+        from_dwarf: &read::Dwarf<R>,
+        from_unit: &read::Unit<R>,
+        value: AttributeValue<R>,
+    ) -> Option<(write::RangeList, Option<u64>)> {
+        let offset = from_dwarf.attr_ranges_offset(from_unit, value).ok()??;
+        let mut from_ranges = from_dwarf.ranges(from_unit, offset).ok()?;
+        let mut ranges = Vec::new();
+        if needs_base_address_reset(from_unit) {
+            ranges.push(write::Range::BaseAddress {
+                address: write::Address::Constant(0),
+            });
+        }
+        let mut first = None;
+        while let Ok(Some(range)) = from_ranges.next() {
+            first = first.or(Some(range.begin));
+            if let Some((begin, end)) = self.convert_range(range) {
+                ranges.push(write::Range::StartEnd { begin, end });
+            }
+        }
+        Some((write::RangeList(ranges), first))
+    }
+
+    /// Convert a location list attribute, along with the location
+    /// expressions in it.
+    fn convert_locations(
+        &self,
+        from_dwarf: &read::Dwarf<R>,
+        from_unit: &read::Unit<R>,
+        value: AttributeValue<R>,
+    ) -> Option<write::LocationList> {
+        let mut from_locations = from_dwarf.attr_locations(from_unit, value).ok()??;
+        let mut locations = Vec::new();
+        if needs_base_address_reset(from_unit) {
+            locations.push(write::Location::BaseAddress {
+                address: write::Address::Constant(0),
+            });
+        }
+        while let Ok(Some(location)) = from_locations.next() {
+            let Some((begin, end)) = self.convert_range(location.range) else {
+                continue;
+            };
+            let data = match self.convert_expression(
+                location.data.clone(),
+                from_unit.encoding(),
+                Some(location.range.begin),
+            ) {
+                ConvertedExpression::Converted(data) => data,
+                ConvertedExpression::Unavailable => continue,
+                ConvertedExpression::Unchanged => {
+                    match write::Expression::from(
+                        location.data,
+                        from_unit.encoding(),
+                        Some(from_dwarf),
+                        Some(from_unit),
+                        None,
+                        &|address| Some(write::Address::Constant(address)),
+                    ) {
+                        Ok(data) => data,
+                        Err(_) => continue,
+                    }
+                }
+            };
+            locations.push(write::Location::StartEnd { begin, end, data });
+        }
+        Some(write::LocationList(locations))
+    }
+
+    /// Rewrite the wasm locals and globals a location expression refers to,
+    /// copying everything else verbatim.
+    ///
+    /// `DW_OP_addr` refers to linear memory rather than code in wasm, so
+    /// unlike `write::Expression::from` it is left alone.
+    fn convert_expression(
+        &self,
+        expression: read::Expression<R>,
+        encoding: Encoding,
+        scope: Option<u64>,
+    ) -> ConvertedExpression {
+        let mut bytes = expression.0;
+        let mut out = Vec::new();
+        let mut resized = false;
+        let mut branches = false;
+        while !bytes.is_empty() {
+            let start = bytes.clone();
+            let op = match read::Operation::parse(&mut bytes, encoding) {
+                Ok(op) => op,
+                Err(_) => return ConvertedExpression::Unchanged,
+            };
+            let len = start.len() - bytes.len();
+            let raw = match start
+                .clone()
+                .split(len)
+                .and_then(|r| Ok(r.to_slice()?.into_owned()))
+            {
+                Ok(raw) => raw,
+                Err(_) => return ConvertedExpression::Unchanged,
+            };
+            let location = match op {
+                read::Operation::WasmLocal { index } => WasmLocation::Local(index),
+                read::Operation::WasmGlobal { index } => WasmLocation::Global(index),
+                read::Operation::Skip { .. } | read::Operation::Bra { .. } => {
+                    branches = true;
+                    out.extend_from_slice(&raw);
+                    continue;
+                }
+                // These refer to other entries in `.debug_info` or to
+                // `.debug_addr`, and can't be copied as-is.
+                read::Operation::Call { .. }
+                | read::Operation::ImplicitPointer { .. }
+                | read::Operation::EntryValue { .. }
+                | read::Operation::ParameterRef { .. }
+                | read::Operation::AddressIndex { .. }
+                | read::Operation::ConstantIndex { .. }
+                | read::Operation::TypedLiteral { .. } => return ConvertedExpression::Unchanged,
+                read::Operation::RegisterOffset { base_type, .. }
+                | read::Operation::Deref { base_type, .. }
+                | read::Operation::Convert { base_type }
+                | read::Operation::Reinterpret { base_type }
+                    if base_type.0 != 0 =>
+                {
+                    return ConvertedExpression::Unchanged
+                }
+                _ => {
+                    out.extend_from_slice(&raw);
+                    continue;
+                }
+            };
+            let Some(new_location) = (self.convert_wasm_location)(scope, location) else {
+                return ConvertedExpression::Unavailable;
+            };
+            // `DW_OP_WASM_location`, the kind of location, then its index.
+            out.extend_from_slice(&raw[..2]);
+            let index = match new_location {
+                WasmLocation::Local(i) | WasmLocation::Global(i) => i,
+            };
+            if raw[1] == 3 {
+                // A global index encoded as a fixed `u32`.
+                out.extend_from_slice(&index.to_le_bytes());
+            } else {
+                // Keep the width of the original LEB if possible, so that
+                // branches over this operation stay correct.
+                let width = len - 2;
+                let mut leb = Vec::new();
+                leb128::write::unsigned(&mut leb, index.into()).unwrap();
+                if leb.len() < width {
+                    let last = leb.len() - 1;
+                    leb[last] |= 0x80;
+                    leb.resize(width, 0x80);
+                    leb[width - 1] = 0;
+                }
+                resized |= leb.len() != width;
+                out.extend_from_slice(&leb);
+            }
+        }
+        if resized && branches {
+            return ConvertedExpression::Unavailable;
+        }
+        ConvertedExpression::Converted(write::Expression::raw(out))
+    }
+
     pub(crate) fn convert_unit_line_program(
         &mut self,
         from_unit: read::Unit<R>,
@@ -319,11 +652,17 @@ where
     }
 }
 
+/// Range and location lists before DWARF 5 are relative to the unit's base
+/// address, but converted lists are absolute.
+fn needs_base_address_reset<R: Reader<Offset = usize>>(unit: &read::Unit<R>) -> bool {
+    unit.low_pc != 0 && unit.encoding().version <= 4
+}
+
 #[cfg(test)]
 mod tests {
     use crate::module::debug::units::DebuggingInformationCursor;
 
-    use super::AddressSearchPreference;
+    use super::{AddressSearchPreference, WasmLocation};
     use gimli::*;
     use std::cell::RefCell;
 
@@ -403,6 +742,7 @@ mod tests {
                 &mut strings,
                 &mut line_strings,
                 &convert_address,
+                &|_, location| Some(location),
             );
             convert_context
                 .convert_line_program(incomplete_debug_line)
@@ -448,6 +788,7 @@ mod tests {
                 &mut strings,
                 &mut line_strings,
                 &convert_address,
+                &|_, location| Some(location),
             );
             let converted_program = convert_context
                 .convert_line_program(incomplete_debug_line)
@@ -494,6 +835,7 @@ mod tests {
                 &mut strings,
                 &mut line_strings,
                 &convert_address,
+                &|_, location| Some(location),
             );
             let converted_program = convert_context
                 .convert_line_program(incomplete_debug_line)
@@ -569,7 +911,6 @@ mod tests {
 
         let read_first_unit_header = read_dwarf.units().next().unwrap().unwrap();
         let read_first_unit = read_dwarf.unit(read_first_unit_header).unwrap();
-        let mut read_first_unit_entries = read_first_unit.entries();
 
         let convert_address = |address, _| -> Option<write::Address> {
             if address < 0x1050 {
@@ -588,6 +929,7 @@ mod tests {
             &mut strings,
             &mut line_strings,
             &convert_address,
+            &|_, location| Some(location),
         );
 
         let mut converted_dwarf = write::Dwarf::from(&read_dwarf, &|address| {
@@ -601,8 +943,11 @@ mod tests {
             let unit = converted_dwarf.units.get_mut(id);
             let mut write_unit_first_entries = DebuggingInformationCursor::new(unit);
 
-            convert_context
-                .convert_high_pc(&mut read_first_unit_entries, &mut write_unit_first_entries);
+            convert_context.convert_entries(
+                &read_dwarf,
+                &read_first_unit,
+                &mut write_unit_first_entries,
+            );
         }
 
         {
@@ -622,4 +967,186 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn test_convert_ranges_and_locations() {
+        let encoding = Encoding {
+            version: 4,
+            address_size: 4,
+            format: Format::Dwarf32,
+        };
+        let mut unit_table = write::UnitTable::default();
+        {
+            let mut unit = write::Unit::new(encoding, write::LineProgram::none());
+            let root = unit.root();
+            unit.get_mut(root).set(
+                DW_AT_low_pc,
+                write::AttributeValue::Address(write::Address::Constant(0x1000)),
+            );
+
+            let ranges = unit.ranges.add(write::RangeList(vec![
+                write::Range::OffsetPair {
+                    begin: 0,
+                    end: 0x10,
+                },
+                write::Range::OffsetPair {
+                    begin: 0x20,
+                    end: 0x30,
+                },
+            ]));
+            let subprogram = unit.add(root, DW_TAG_subprogram);
+            unit.get_mut(subprogram)
+                .set(DW_AT_ranges, write::AttributeValue::RangeListRef(ranges));
+            let mut frame_base = write::Expression::new();
+            frame_base.op_wasm_local(1);
+            unit.get_mut(subprogram)
+                .set(DW_AT_frame_base, write::AttributeValue::Exprloc(frame_base));
+
+            let mut local = write::Expression::new();
+            local.op_wasm_local(2);
+            let mut global = write::Expression::new();
+            global.op_wasm_global(0);
+            global.op(DW_OP_stack_value);
+            let locations = unit.locations.add(write::LocationList(vec![
+                write::Location::OffsetPair {
+                    begin: 0,
+                    end: 0x8,
+                    data: local,
+                },
+                write::Location::OffsetPair {
+                    begin: 0x20,
+                    end: 0x28,
+                    data: global,
+                },
+            ]));
+            let variable = unit.add(subprogram, DW_TAG_variable);
+            unit.get_mut(variable).set(
+                DW_AT_location,
+                write::AttributeValue::LocationListRef(locations),
+            );
+
+            let mut memory = write::Expression::new();
+            memory.op_addr(write::Address::Constant(0x400));
+            let static_variable = unit.add(root, DW_TAG_variable);
+            unit.get_mut(static_variable)
+                .set(DW_AT_location, write::AttributeValue::Exprloc(memory));
+
+            unit_table.add(unit);
+        }
+        let mut sections = write::Sections::new(write::EndianVec::new(LittleEndian));
+        unit_table
+            .write(
+                &mut sections,
+                &write::DebugLineStrOffsets::none(),
+                &write::DebugStrOffsets::none(),
+            )
+            .unwrap();
+
+        let read_dwarf = Dwarf {
+            debug_info: read::DebugInfo::new(sections.debug_info.slice(), LittleEndian),
+            debug_abbrev: read::DebugAbbrev::new(sections.debug_abbrev.slice(), LittleEndian),
+            ranges: RangeLists::new(
+                read::DebugRanges::new(sections.debug_ranges.slice(), LittleEndian),
+                Default::default(),
+            ),
+            locations: LocationLists::new(
+                read::DebugLoc::new(sections.debug_loc.slice(), LittleEndian),
+                Default::default(),
+            ),
+            ..Default::default()
+        };
+        let read_unit_header = read_dwarf.units().next().unwrap().unwrap();
+        let read_unit = read_dwarf.unit(read_unit_header).unwrap();
+
+        // Code after 0x1020 was removed, everything before moved by 0x10.
+        let convert_address = |address, _| -> Option<write::Address> {
+            if address < 0x1020 {
+                Some(write::Address::Constant(address + 0x10))
+            } else {
+                None
+            }
+        };
+        let convert_wasm_location = |scope: Option<u64>, location| {
+            assert!(scope.is_some());
+            match location {
+                WasmLocation::Local(i) => Some(WasmLocation::Local(i + 10)),
+                WasmLocation::Global(i) => Some(WasmLocation::Global(i + 1)),
+            }
+        };
+
+        let mut strings = write::StringTable::default();
+        let mut line_strings = write::LineStringTable::default();
+        let convert_context = crate::module::debug::ConvertContext::new(
+            &read_dwarf.debug_str,
+            &read_dwarf.debug_line_str,
+            &mut strings,
+            &mut line_strings,
+            &convert_address,
+            &convert_wasm_location,
+        );
+
+        let mut converted_dwarf = write::Dwarf::from(&read_dwarf, &|address| {
+            convert_address(address, AddressSearchPreference::InclusiveFunctionEnd)
+                .or(Some(write::Address::Constant(super::DEAD_CODE)))
+        })
+        .unwrap();
+        let id = converted_dwarf.units.id(0);
+        let unit = converted_dwarf.units.get_mut(id);
+        convert_context.convert_entries(
+            &read_dwarf,
+            &read_unit,
+            &mut DebuggingInformationCursor::new(unit),
+        );
+
+        let root = unit.root();
+        let subprogram = *unit.get(root).children().next().unwrap();
+        let static_variable = *unit.get(root).children().nth(1).unwrap();
+        let variable = *unit.get(subprogram).children().next().unwrap();
+        let base = write::Range::BaseAddress {
+            address: write::Address::Constant(0),
+        };
+
+        match unit.get(subprogram).get(DW_AT_ranges) {
+            Some(write::AttributeValue::RangeListRef(id)) => assert_eq!(
+                unit.ranges.get(*id).0,
+                [
+                    base,
+                    write::Range::StartEnd {
+                        begin: write::Address::Constant(0x1010),
+                        end: write::Address::Constant(0x1020),
+                    }
+                ]
+            ),
+            value => panic!("unexpected ranges {:?}", value),
+        }
+        assert_eq!(
+            unit.get(subprogram).get(DW_AT_frame_base),
+            Some(&write::AttributeValue::Exprloc(write::Expression::raw(
+                vec![0xed, 0x00, 11]
+            )))
+        );
+        match unit.get(variable).get(DW_AT_location) {
+            Some(write::AttributeValue::LocationListRef(id)) => assert_eq!(
+                unit.locations.get(*id).0,
+                [
+                    write::Location::BaseAddress {
+                        address: write::Address::Constant(0),
+                    },
+                    write::Location::StartEnd {
+                        begin: write::Address::Constant(0x1010),
+                        end: write::Address::Constant(0x1018),
+                        data: write::Expression::raw(vec![0xed, 0x00, 12]),
+                    }
+                ]
+            ),
+            value => panic!("unexpected locations {:?}", value),
+        }
+        // Linear memory addresses aren't code addresses.
+        assert_eq!(
+            unit.get(static_variable).get(DW_AT_location),
+            Some(&write::AttributeValue::Exprloc(write::Expression::raw(
+                vec![DW_OP_addr.0, 0x00, 0x04, 0x00, 0x00]
+            )))
+        );
+    }
 }
@@ -49,6 +49,19 @@ impl CodeAddressGenerator {
         }
     }
 
+    /// Find the function whose original code contains `address`.
+    pub(crate) fn find_function(&self, address: usize) -> Option<Id<Function>> {
+        let i = self
+            .address_convert_table
+            .partition_point(|(range, _)| range.start <= address);
+        let (range, id) = self.address_convert_table.get(i.checked_sub(1)?)?;
+        if address < range.end {
+            Some(*id)
+        } else {
+            None
+        }
+    }
+
     pub(crate) fn find_address(
         &self,
         address: usize,
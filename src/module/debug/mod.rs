@@ -1,27 +1,46 @@
 mod dwarf;
 mod expression;
+mod split;
 mod units;
 
 use crate::emit::{Emit, EmitContext};
-use crate::{CustomSection, Module, RawCustomSection};
+use crate::map::IdHashMap;
+use crate::parse::IndicesToIds;
+use crate::{CustomSection, Function, GlobalId, LocalId, Module, RawCustomSection};
 use gimli::*;
 
-use self::dwarf::{AddressSearchPreference, ConvertContext, DEAD_CODE};
+use self::dwarf::{AddressSearchPreference, ConvertContext, WasmLocation, DEAD_CODE};
 use self::expression::{CodeAddressConverter, CodeAddressGenerator};
 use self::units::DebuggingInformationCursor;
 
+pub use self::split::ExternalDebugInfo;
+
+/// Names and contents of converted DWARF sections.
+pub(crate) type DwarfSections = Vec<(&'static str, Vec<u8>)>;
+
 /// The DWARF debug section in input WebAssembly binary.
 #[derive(Debug, Default)]
 pub struct ModuleDebugData {
     /// DWARF debug data
     pub dwarf: read::Dwarf<Vec<u8>>,
+    /// The locals of each function in the original wasm binary, by index,
+    /// referenced by `DW_OP_WASM_location`.
+    pub(crate) locals: IdHashMap<Function, Vec<LocalId>>,
+    /// The globals in the original wasm binary, by index.
+    pub(crate) globals: Vec<GlobalId>,
 }
 
 impl Module {
     pub(crate) fn parse_debug_sections(
         &mut self,
         mut debug_sections: Vec<RawCustomSection>,
+        indices: &IndicesToIds,
     ) -> Result<()> {
+        if !debug_sections.is_empty() {
+            self.debug.locals = indices.locals().clone();
+            self.debug.globals = indices.globals().to_vec();
+        }
+
         let load_section = |id: gimli::SectionId| -> Result<Vec<u8>> {
             Ok(
                 match debug_sections
@@ -42,6 +61,19 @@ impl Module {
 
 impl Emit for ModuleDebugData {
     fn emit(&self, cx: &mut EmitContext) {
+        for (name, data) in self.convert_sections(cx) {
+            cx.wasm_module.section(&wasm_encoder::CustomSection {
+                name: name.into(),
+                data: data.into(),
+            });
+        }
+    }
+}
+
+impl ModuleDebugData {
+    /// Convert the DWARF sections to describe the code that has just been
+    /// emitted into `cx`, returning each non-empty section's name and data.
+    pub(crate) fn convert_sections(&self, cx: &EmitContext) -> DwarfSections {
         let address_generator = CodeAddressGenerator::new(&cx.module.funcs);
         let address_converter = CodeAddressConverter::new(&cx.code_transform);
 
@@ -55,6 +87,21 @@ impl Emit for ModuleDebugData {
                 .map(write::Address::Constant)
         };
 
+        let convert_wasm_location = |address: Option<u64>, location| match location {
+            WasmLocation::Local(index) => {
+                let func = address_generator.find_function(address? as usize)?;
+                let local = cx.module.debug.locals.get(&func)?.get(index as usize)?;
+                let index = cx.indices.locals.get(&func)?.get(local)?;
+                Some(WasmLocation::Local(*index))
+            }
+            WasmLocation::Global(index) => {
+                let global = cx.module.debug.globals.get(index as usize)?;
+                cx.indices
+                    .find_global_index(*global)
+                    .map(WasmLocation::Global)
+            }
+        };
+
         #[allow(deprecated)]
         let from_dwarf = cx
             .module
@@ -90,6 +137,7 @@ impl Emit for ModuleDebugData {
             &mut dwarf.strings,
             &mut dwarf.line_strings,
             &convert_address,
+            &convert_wasm_location,
         );
 
         for (from_id, id) in units {
@@ -97,12 +145,11 @@ impl Emit for ModuleDebugData {
                 from_dwarf.unit(from_id).expect("readable unit");
             let unit = dwarf.units.get_mut(id);
 
-            // perform high pc transformation of DWARF .debug_info
+            // perform address, range, and location transformation of DWARF .debug_info
             {
-                let mut from_entries = from_unit.entries();
                 let mut entries = DebuggingInformationCursor::new(unit);
 
-                convert_context.convert_high_pc(&mut from_entries, &mut entries);
+                convert_context.convert_entries(&from_dwarf, &from_unit, &mut entries);
             }
 
             // perform line program transformation
@@ -113,18 +160,17 @@ impl Emit for ModuleDebugData {
 
         let mut sections = write::Sections::new(write::EndianVec::new(gimli::LittleEndian));
         dwarf.write(&mut sections).expect("write failed");
+        let mut ret = Vec::new();
         sections
             .for_each(
                 |id: SectionId, data: &write::EndianVec<LittleEndian>| -> Result<()> {
                     if !data.slice().is_empty() {
-                        cx.wasm_module.section(&wasm_encoder::CustomSection {
-                            name: id.name().into(),
-                            data: data.slice().into(),
-                        });
+                        ret.push((id.name(), data.slice().to_vec()));
                     }
                     Ok(())
                 },
             )
             .expect("never");
+        ret
     }
 }
@@ -0,0 +1,75 @@
+//! Splitting DWARF out into a separate debug file.
+//!
+//! The layout follows the "External DWARF" convention at
+//! <https://github.com/WebAssembly/tool-conventions/blob/main/Debugging.md>.
+
+use crate::emit::{Emit, EmitContext};
+use crate::error::Result;
+use crate::Module;
+use anyhow::bail;
+use std::mem;
+
+/// The `external_debug_info` custom section, pointing at a separate file that
+/// holds this module's DWARF.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExternalDebugInfo {
+    /// Where to find the debug file, typically relative to the module.
+    pub url: String,
+}
+
+impl Module {
+    pub(crate) fn parse_external_debug_info(&mut self, data: &[u8], offset: usize) -> Result<()> {
+        let mut reader = wasmparser::BinaryReader::new(data, offset);
+        let url = reader.read_string()?.to_string();
+        if !reader.eof() {
+            bail!("trailing bytes after the external debug info URL");
+        }
+        self.external_debug_info = Some(ExternalDebugInfo { url });
+        Ok(())
+    }
+
+    /// Emit this module into an in-memory wasm buffer without any DWARF, along
+    /// with a separate debug file holding that DWARF.
+    ///
+    /// The DWARF is translated to describe the emitted code, just as it is
+    /// with `ModuleConfig::generate_dwarf`, regardless of that setting. The
+    /// debug file is a wasm module containing only custom sections: the DWARF
+    /// sections, and a copy of the `build_id` section if this module has one
+    /// (or `ModuleConfig::generate_build_id` is set) so that the two files can
+    /// be matched up. Set `external_debug_info` first
+    /// to have the stripped module point at the debug file.
+    pub fn emit_wasm_split_dwarf(&mut self) -> (Vec<u8>, Vec<u8>) {
+        let preserve = mem::replace(&mut self.config.preserve_code_transform, true);
+        let (wasm, _, dwarf) = self.emit_wasm_parts(true);
+        self.config.preserve_code_transform = preserve;
+
+        let mut debug = wasm_encoder::Module::new();
+        for (name, data) in &dwarf {
+            debug.section(&wasm_encoder::CustomSection {
+                name: (*name).into(),
+                data: data.into(),
+            });
+        }
+        if let Some(build_id) = &self.build_id {
+            let mut data = Vec::new();
+            wasm_encoder::Encode::encode(build_id.id.as_slice(), &mut data);
+            debug.section(&wasm_encoder::CustomSection {
+                name: "build_id".into(),
+                data: data.into(),
+            });
+        }
+        (wasm, debug.finish())
+    }
+}
+
+impl Emit for ExternalDebugInfo {
+    fn emit(&self, cx: &mut EmitContext) {
+        log::debug!("emit external_debug_info section");
+        let mut data = Vec::new();
+        wasm_encoder::Encode::encode(self.url.as_str(), &mut data);
+        cx.wasm_module.section(&wasm_encoder::CustomSection {
+            name: "external_debug_info".into(),
+            data: data.into(),
+        });
+    }
+}
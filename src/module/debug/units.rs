@@ -17,6 +17,10 @@ impl<'a> DebuggingInformationCursor<'a> {
         }
     }
 
+    pub fn unit(&mut self) -> &mut Unit {
+        self.unit
+    }
+
     pub fn current(&mut self) -> Option<&mut DebuggingInformationEntry> {
         if !self.entry_id_stack.is_empty() {
             Some(self.unit.get_mut(*self.entry_id_stack.last().unwrap()))
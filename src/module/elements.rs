@@ -137,12 +137,13 @@ impl Module {
                     let mut const_exprs = Vec::with_capacity(items.count() as usize);
                     for item in items {
                         let const_expr = item?;
-                        let expr = ConstExpr::eval(&const_expr, ids).with_context(|| {
-                            format!(
-                                "Failed to evaluate a const expr in element segment {}:\n{:?}",
-                                i, const_expr
-                            )
-                        })?;
+                        let expr =
+                            ConstExpr::from_wasmparser(&const_expr, ids).with_context(|| {
+                                format!(
+                                    "Failed to evaluate a const expr in element segment {}:\n{:?}",
+                                    i, const_expr
+                                )
+                            })?;
                         const_exprs.push(expr);
                     }
                     ElementItems::Expressions(ty, const_exprs)
@@ -163,9 +164,10 @@ impl Module {
                     let table = self.tables.get_mut(table_id);
                     table.elem_segments.insert(id);
 
-                    let offset = ConstExpr::eval(&offset_expr, ids).with_context(|| {
-                        format!("failed to evaluate the offset of element {}", i)
-                    })?;
+                    let offset =
+                        ConstExpr::from_wasmparser(&offset_expr, ids).with_context(|| {
+                            format!("failed to evaluate the offset of element {}", i)
+                        })?;
                     if table.table64 {
                         match offset {
                             ConstExpr::Value(Value::I64(_)) => {}
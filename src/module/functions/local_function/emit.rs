@@ -2,8 +2,11 @@ use crate::emit::IdsToIndices;
 use crate::ir::*;
 use crate::map::IdHashMap;
 use crate::module::functions::LocalFunction;
+use crate::module::linking::{self, EmittedRelocKind, IndexTarget};
 use crate::module::memories::MemoryId;
-use wasm_encoder::Instruction;
+use crate::module::{EmittedReloc, RelocRecorder, RelocationType};
+use std::collections::BTreeMap;
+use wasm_encoder::{Encode, Instruction};
 
 pub(crate) fn run(
     func: &LocalFunction,
@@ -11,6 +14,7 @@ pub(crate) fn run(
     local_indices: &IdHashMap<Local, u32>,
     encoder: &mut wasm_encoder::Function,
     map: Option<&mut Vec<(InstrLocId, usize)>>,
+    relocs: Option<&mut RelocRecorder>,
 ) {
     let v = &mut Emit {
         indices,
@@ -19,6 +23,8 @@ pub(crate) fn run(
         encoder,
         local_indices,
         map,
+        value_relocs: relocs.as_ref().map(|r| r.value_relocs),
+        relocs: Vec::new(),
         try_table_catches: IdHashMap::default(),
         legacy_catches: IdHashMap::default(),
         catch_parent: IdHashMap::default(),
@@ -28,6 +34,10 @@ pub(crate) fn run(
 
     debug_assert!(v.blocks.is_empty());
     debug_assert!(v.block_kinds.is_empty());
+
+    if let Some(relocs) = relocs {
+        relocs.relocs = std::mem::take(&mut v.relocs);
+    }
 }
 
 struct Emit<'a, 'instr> {
@@ -52,6 +62,13 @@ struct Emit<'a, 'instr> {
     // Encoded ExprId -> offset map.
     map: Option<&'a mut Vec<(InstrLocId, usize)>>,
 
+    // Set when emitting a relocatable object: the instructions whose constant
+    // or memory offset is relocated in the input.
+    value_relocs: Option<&'a BTreeMap<InstrLocId, RelocationType>>,
+
+    // Relocatable immediates emitted so far.
+    relocs: Vec<EmittedReloc>,
+
     // Store TryTable catches for emission
     try_table_catches: IdHashMap<InstrSeq, Vec<TryTableCatch>>,
 
@@ -116,6 +133,10 @@ impl<'instr> Visitor<'instr> for Emit<'_, 'instr> {
             self.blocks.push(seq.id());
             debug_assert_eq!(self.blocks.len(), self.block_kinds.len());
 
+            if self.value_relocs.is_some() {
+                self.encode_relocatable_try_table(seq.ty, &catches, &wasm_catches);
+                return;
+            }
             self.encoder.instruction(&Instruction::TryTable(
                 self.block_type(seq.ty),
                 std::borrow::Cow::Owned(wasm_catches),
@@ -203,7 +224,13 @@ impl<'instr> Visitor<'instr> for Emit<'_, 'instr> {
                                 LegacyCatch::Catch { tag, handler: _ } => {
                                     let tag_index = self.indices.get_tag_index(tag);
                                     self.block_kinds.push(BlockKind::Catch);
-                                    self.encoder.instruction(&Instruction::Catch(tag_index));
+                                    if self.value_relocs.is_some() {
+                                        let mut bytes = vec![0x07];
+                                        self.push_index(&mut bytes, IndexTarget::Tag(tag));
+                                        self.encoder.raw(bytes);
+                                    } else {
+                                        self.encoder.instruction(&Instruction::Catch(tag_index));
+                                    }
                                     return; // Don't emit End yet, we'll visit the handler next
                                 }
                                 LegacyCatch::CatchAll { handler: _ } => {
@@ -297,7 +324,7 @@ impl<'instr> Visitor<'instr> for Emit<'_, 'instr> {
             return;
         }
 
-        self.encoder.instruction(&match instr {
+        let instruction = match instr {
             Block(_) | Loop(_) | IfElse(_) | TryTable(_) | Try { .. } => unreachable!(),
 
             BrTable(e) => {
@@ -1163,7 +1190,10 @@ impl<'instr> Visitor<'instr> for Emit<'_, 'instr> {
             I64Sub128(_) => Instruction::I64Sub128,
             I64MulWideS(_) => Instruction::I64MulWideS,
             I64MulWideU(_) => Instruction::I64MulWideU,
-        });
+        };
+        if !self.encode_relocatable(instr, *instr_loc, &instruction) {
+            self.encoder.instruction(&instruction);
+        }
     }
 }
 
@@ -1186,6 +1216,177 @@ impl Emit<'_, '_> {
         }
     }
 
+    /// When emitting a relocatable object, encode `instruction` with its
+    /// relocatable immediates padded to their full width, and record where
+    /// they end up. Returns `false` if `instruction` still needs encoding.
+    fn encode_relocatable(
+        &mut self,
+        instr: &Instr,
+        loc: InstrLocId,
+        instruction: &Instruction,
+    ) -> bool {
+        use self::Instr::*;
+
+        let value_relocs = match self.value_relocs {
+            Some(value_relocs) => value_relocs,
+            None => return false,
+        };
+        let start = self.encoder.byte_len();
+        let mut bytes = Vec::new();
+
+        // Instructions whose last immediate is an index.
+        let last_index = match instr {
+            Call(e) => Some(IndexTarget::Function(e.func)),
+            ReturnCall(e) => Some(IndexTarget::Function(e.func)),
+            RefFunc(e) => Some(IndexTarget::Function(e.func)),
+            GlobalGet(e) => Some(IndexTarget::Global(e.global)),
+            GlobalSet(e) => Some(IndexTarget::Global(e.global)),
+            Throw(e) => Some(IndexTarget::Tag(e.tag)),
+            TableGet(e) => Some(IndexTarget::Table(e.table)),
+            TableSet(e) => Some(IndexTarget::Table(e.table)),
+            TableGrow(e) => Some(IndexTarget::Table(e.table)),
+            TableSize(e) => Some(IndexTarget::Table(e.table)),
+            TableFill(e) => Some(IndexTarget::Table(e.table)),
+            TableInit(e) => Some(IndexTarget::Table(e.table)),
+            _ => None,
+        };
+
+        if let Some(target) = last_index {
+            instruction.encode(&mut bytes);
+            let index = self.index_of(target);
+            bytes.truncate(bytes.len() - linking::uleb_len(index.into()));
+            self.push_index_at(&mut bytes, start, loc, target);
+        } else {
+            match instr {
+                CallIndirect(e) => {
+                    bytes.push(0x11);
+                    self.push_index_at(&mut bytes, start, loc, IndexTarget::Type(e.ty));
+                    self.push_index_at(&mut bytes, start, loc, IndexTarget::Table(e.table));
+                }
+                ReturnCallIndirect(e) => {
+                    bytes.push(0x13);
+                    self.push_index_at(&mut bytes, start, loc, IndexTarget::Type(e.ty));
+                    self.push_index_at(&mut bytes, start, loc, IndexTarget::Table(e.table));
+                }
+                TableCopy(e) => {
+                    bytes.extend([0xfc, 0x0e]);
+                    self.push_index_at(&mut bytes, start, loc, IndexTarget::Table(e.dst));
+                    self.push_index_at(&mut bytes, start, loc, IndexTarget::Table(e.src));
+                }
+                Const(c) => {
+                    let (value, width) = match (&c.value, value_relocs.get(&loc)) {
+                        (Value::I32(v), Some(ty)) if is_sleb(*ty) && ty.extent() == 5 => {
+                            (i64::from(*v), 5)
+                        }
+                        (Value::I64(v), Some(ty)) if is_sleb(*ty) && ty.extent() == 10 => (*v, 10),
+                        _ => return false,
+                    };
+                    instruction.encode(&mut bytes);
+                    bytes.truncate(bytes.len() - linking::sleb_len(value));
+                    self.push_value_at(&bytes, start, loc);
+                    bytes.extend(linking::padded_sleb(value, width));
+                }
+                _ => {
+                    let (offset, lane) = match (memarg_offset(instr), value_relocs.get(&loc)) {
+                        (Some(memarg), Some(ty)) if !is_sleb(*ty) => memarg,
+                        _ => return false,
+                    };
+                    let width = value_relocs[&loc].extent();
+                    instruction.encode(&mut bytes);
+                    let lane = lane.map(|_| bytes.pop().unwrap());
+                    bytes.truncate(bytes.len() - linking::uleb_len(offset));
+                    self.push_value_at(&bytes, start, loc);
+                    bytes.extend(linking::padded_uleb(offset, width));
+                    bytes.extend(lane);
+                }
+            }
+        }
+
+        self.encoder.raw(bytes);
+        true
+    }
+
+    /// Encode a `try_table` of a relocatable object, padding catch tags.
+    fn encode_relocatable_try_table(
+        &mut self,
+        ty: InstrSeqType,
+        catches: &[TryTableCatch],
+        wasm_catches: &[wasm_encoder::Catch],
+    ) {
+        let mut bytes = vec![0x1f];
+        self.block_type(ty).encode(&mut bytes);
+        catches.len().encode(&mut bytes);
+        for (catch, wasm_catch) in catches.iter().zip(wasm_catches) {
+            let (kind, label) = match wasm_catch {
+                wasm_encoder::Catch::One { label, .. } => (0x00, label),
+                wasm_encoder::Catch::OneRef { label, .. } => (0x01, label),
+                wasm_encoder::Catch::All { label } => (0x02, label),
+                wasm_encoder::Catch::AllRef { label } => (0x03, label),
+            };
+            bytes.push(kind);
+            match catch {
+                TryTableCatch::Catch { tag, .. } | TryTableCatch::CatchRef { tag, .. } => {
+                    self.push_index(&mut bytes, IndexTarget::Tag(*tag));
+                }
+                TryTableCatch::CatchAll { .. } | TryTableCatch::CatchAllRef { .. } => {}
+            }
+            label.encode(&mut bytes);
+        }
+        self.encoder.raw(bytes);
+    }
+
+    fn index_of(&self, target: IndexTarget) -> u32 {
+        match target {
+            IndexTarget::Function(id) => self.indices.get_func_index(id),
+            IndexTarget::Global(id) => self.indices.get_global_index(id),
+            IndexTarget::Table(id) => self.indices.get_table_index(id),
+            IndexTarget::Tag(id) => self.indices.get_tag_index(id),
+            IndexTarget::Type(id) => self.indices.get_type_index(id),
+        }
+    }
+
+    /// Append the padded index of `target` to `bytes`, which will be written
+    /// at the current position, for an immediate not tied to an instruction.
+    fn push_index(&mut self, bytes: &mut Vec<u8>, target: IndexTarget) {
+        let start = self.encoder.byte_len();
+        self.push_index_at(bytes, start, InstrLocId::default(), target);
+    }
+
+    /// Append the padded index of `target` to `bytes`, which will be written
+    /// at `start`, and record its relocation.
+    fn push_index_at(
+        &mut self,
+        bytes: &mut Vec<u8>,
+        start: usize,
+        loc: InstrLocId,
+        target: IndexTarget,
+    ) {
+        let ty = match target {
+            IndexTarget::Function(_) => RelocationType::FunctionIndexLeb,
+            IndexTarget::Global(_) => RelocationType::GlobalIndexLeb,
+            IndexTarget::Table(_) => RelocationType::TableNumberLeb,
+            IndexTarget::Tag(_) => RelocationType::EventIndexLeb,
+            IndexTarget::Type(_) => RelocationType::TypeIndexLeb,
+        };
+        self.relocs.push(EmittedReloc {
+            offset: start + bytes.len(),
+            loc,
+            kind: EmittedRelocKind::Index(ty, target),
+        });
+        let index = self.index_of(target);
+        bytes.extend(linking::padded_uleb(index.into(), 5));
+    }
+
+    /// Record a value relocation for the immediate about to be appended to
+    /// `bytes`, which will be written at `start`.
+    fn push_value_at(&mut self, bytes: &[u8], start: usize, loc: InstrLocId) {
+        self.relocs.push(EmittedReloc {
+            offset: start + bytes.len(),
+            loc,
+            kind: EmittedRelocKind::Value,
+        });
+    }
+
     fn memarg(&self, id: MemoryId, arg: &MemArg) -> wasm_encoder::MemArg {
         let memory_index = self.indices.get_memory_index(id);
         let MemArg { mut align, offset } = *arg;
@@ -1201,3 +1402,37 @@ impl Emit<'_, '_> {
         }
     }
 }
+
+/// Does this relocation type patch a signed LEB, i.e. an `i32.const` or
+/// `i64.const` immediate rather than a memory offset?
+fn is_sleb(ty: RelocationType) -> bool {
+    !matches!(
+        ty,
+        RelocationType::MemoryAddrLeb | RelocationType::MemoryAddrLeb64
+    )
+}
+
+/// The static offset of a memory access, and its lane if it has one.
+fn memarg_offset(instr: &Instr) -> Option<(u64, Option<u8>)> {
+    let (arg, lane) = match instr {
+        Instr::Load(e) => (e.arg, None),
+        Instr::Store(e) => (e.arg, None),
+        Instr::AtomicRmw(e) => (e.arg, None),
+        Instr::Cmpxchg(e) => (e.arg, None),
+        Instr::AtomicNotify(e) => (e.arg, None),
+        Instr::AtomicWait(e) => (e.arg, None),
+        Instr::LoadSimd(e) => match e.kind {
+            LoadSimdKind::V128Load8Lane(lane)
+            | LoadSimdKind::V128Load16Lane(lane)
+            | LoadSimdKind::V128Load32Lane(lane)
+            | LoadSimdKind::V128Load64Lane(lane)
+            | LoadSimdKind::V128Store8Lane(lane)
+            | LoadSimdKind::V128Store16Lane(lane)
+            | LoadSimdKind::V128Store32Lane(lane)
+            | LoadSimdKind::V128Store64Lane(lane) => (e.arg, Some(lane)),
+            _ => (e.arg, None),
+        },
+        _ => return None,
+    };
+    Some((arg.offset, lane))
+}
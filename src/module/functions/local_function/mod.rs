@@ -6,6 +6,7 @@ mod emit;
 use self::context::ValidationContext;
 use crate::emit::IdsToIndices;
 use crate::map::{IdHashMap, IdHashSet};
+use crate::module::RelocRecorder;
 use crate::parse::IndicesToIds;
 use crate::{ir::*, HeapType, RefType};
 use crate::{Data, DataId, FunctionBuilder, FunctionId, MemoryId, Module, Result, TypeId, ValType};
@@ -278,8 +279,9 @@ impl LocalFunction {
         local_indices: &IdHashMap<Local, u32>,
         dst: &mut wasm_encoder::Function,
         map: Option<&mut Vec<(InstrLocId, usize)>>,
+        relocs: Option<&mut RelocRecorder>,
     ) {
-        emit::run(self, indices, local_indices, dst, map)
+        emit::run(self, indices, local_indices, dst, map, relocs)
     }
 }
 
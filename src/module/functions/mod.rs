@@ -17,12 +17,12 @@ use crate::emit::{Emit, EmitContext};
 use crate::error::Result;
 use crate::ir::InstrLocId;
 use crate::module::imports::ImportId;
-use crate::module::Module;
+use crate::module::{Module, RelocRecorder};
 use crate::parse::IndicesToIds;
 use crate::tombstone_arena::{Id, Tombstone, TombstoneArena};
 use crate::ty::TypeId;
 use crate::ty::ValType;
-use crate::{ExportItem, FunctionBuilder, InstrSeqBuilder, LocalId, Memory, MemoryId};
+use crate::{ExportItem, FunctionBuilder, ImportKind, InstrSeqBuilder, LocalId, Memory, MemoryId};
 
 pub use self::local_function::LocalFunction;
 
@@ -181,6 +181,12 @@ impl ModuleFunctions {
         })
     }
 
+    /// Reserve an id for a local function whose body is filled in later.
+    pub(crate) fn add_uninitialized(&mut self, ty: TypeId) -> FunctionId {
+        self.arena
+            .alloc_with_id(|id| Function::new_uninitialized(id, ty))
+    }
+
     /// Gets a reference to a function given its id
     pub fn get(&self, id: FunctionId) -> &Function {
         &self.arena[id]
@@ -623,6 +629,114 @@ impl Module {
             bail!("cannot replace function [{fid:?}], it is not an imported function");
         }
     }
+
+    /// Wrap the function exported as `name` in a new function of type `ty`,
+    /// and export the new function in its place.
+    ///
+    /// The builder function is given the original function, an
+    /// [`InstrSeqBuilder`] for the body of the new one, and the new one's
+    /// arguments, so that it can convert them, call the original and then
+    /// convert its results. For example, to export a function that ignores
+    /// its argument in place of one that takes none,
+    ///
+    /// ```ignore
+    /// let ty = module.types.add(&[ValType::I32], &[]);
+    /// module.wrap_export("run", ty, |original, body, _args| {
+    ///     body.call(original);
+    /// })?;
+    /// ```
+    ///
+    /// The new function gets the name of the original, which stays in the
+    /// module for the new one to call. This function returns the function ID
+    /// of the new function.
+    pub fn wrap_export(
+        &mut self,
+        name: &str,
+        ty: TypeId,
+        builder_fn: impl FnOnce(FunctionId, &mut InstrSeqBuilder, &[LocalId]),
+    ) -> Result<FunctionId> {
+        let export = self
+            .exports
+            .iter()
+            .find(|e| e.name == name)
+            .with_context(|| format!("no export named `{name}`"))?;
+        let (export, original) = match export.item {
+            ExportItem::Function(fid) => (export.id(), fid),
+            _ => bail!("export `{name}` is not a function"),
+        };
+
+        let func = self.build_wrapper(ty, |body, args| builder_fn(original, body, args));
+        let new_fn_id = self.funcs.add_local(func);
+        self.funcs.get_mut(new_fn_id).name = self.funcs.get(original).name.clone();
+        self.exports.get_mut(export).item = ExportItem::Function(new_fn_id);
+        Ok(new_fn_id)
+    }
+
+    /// Change the type of the function imported from `module` as `name` to
+    /// `ty`, and replace its uses with a new function of its old type that
+    /// adapts it.
+    ///
+    /// The builder function is given the function that is now imported, an
+    /// [`InstrSeqBuilder`] for the body of the adapter, and the adapter's
+    /// arguments, so that it can convert them, call the import and then
+    /// convert its results. For example, to import a function that returns a
+    /// status code in place of one that returns nothing,
+    ///
+    /// ```ignore
+    /// let ty = module.types.add(&[], &[ValType::I32]);
+    /// module.wrap_import("env", "flush", ty, |import, body, _args| {
+    ///     body.call(import).drop();
+    /// })?;
+    /// ```
+    ///
+    /// The adapter keeps the original function ID, so every call, export and
+    /// table element that used the import now uses the adapter, and both keep
+    /// the original's name. This function returns the function ID of the new
+    /// import.
+    pub fn wrap_import(
+        &mut self,
+        module: &str,
+        name: &str,
+        ty: TypeId,
+        builder_fn: impl FnOnce(FunctionId, &mut InstrSeqBuilder, &[LocalId]),
+    ) -> Result<FunctionId> {
+        let import = self
+            .imports
+            .find(module, name)
+            .with_context(|| format!("no import named `{module}.{name}`"))?;
+        let original = match self.imports.get(import).kind {
+            ImportKind::Function(fid) => fid,
+            _ => bail!("import `{module}.{name}` is not a function"),
+        };
+
+        let new_fn_id = self.funcs.add_import(ty, import);
+        self.funcs.get_mut(new_fn_id).name = self.funcs.get(original).name.clone();
+        self.imports.get_mut(import).kind = ImportKind::Function(new_fn_id);
+
+        let original_ty = self.funcs.get(original).ty();
+        let adapter =
+            self.build_wrapper(original_ty, |body, args| builder_fn(new_fn_id, body, args));
+        self.funcs.get_mut(original).kind = FunctionKind::Local(adapter);
+        Ok(new_fn_id)
+    }
+
+    /// Build a local function of type `ty`, whose body is built by
+    /// `builder_fn` from its arguments.
+    fn build_wrapper(
+        &mut self,
+        ty: TypeId,
+        builder_fn: impl FnOnce(&mut InstrSeqBuilder, &[LocalId]),
+    ) -> LocalFunction {
+        let ty = self.types.get(ty);
+        let (params, results) = (ty.params().to_vec(), ty.results().to_vec());
+        let args = params
+            .iter()
+            .map(|ty| self.locals.add(*ty))
+            .collect::<Vec<_>>();
+        let mut builder = FunctionBuilder::new(&mut self.types, &params, &results);
+        builder_fn(&mut builder.func_body(), &args);
+        builder.local_func(args)
+    }
 }
 
 fn used_local_functions<'a>(cx: &mut EmitContext<'a>) -> Vec<(FunctionId, &'a LocalFunction, u64)> {
@@ -672,6 +786,7 @@ impl Emit for ModuleFunctions {
 
         let mut wasm_code_section = wasm_encoder::CodeSection::new();
         let generate_map = cx.module.config.preserve_code_transform;
+        let value_relocs = cx.module.linking.as_ref().map(|l| l.value_relocs());
 
         // Functions can typically take awhile to serialize, so serialize
         // everything in parallel. Afterwards we'll actually place all the
@@ -684,12 +799,17 @@ impl Emit for ModuleFunctions {
 
                 let (locals_types, used_locals, local_indices) =
                     func.emit_locals(cx.module, cx.indices);
+                let mut relocs = value_relocs.as_ref().map(|value_relocs| RelocRecorder {
+                    value_relocs,
+                    relocs: Vec::new(),
+                });
                 let mut wasm_function = wasm_encoder::Function::new(locals_types);
                 func.emit_instructions(
                     cx.indices,
                     &local_indices,
                     &mut wasm_function,
                     map.as_mut(),
+                    relocs.as_mut(),
                 );
                 wasm_function.encode(&mut wasm);
                 (
@@ -699,6 +819,7 @@ impl Emit for ModuleFunctions {
                     used_locals,
                     local_indices,
                     map,
+                    relocs.map(|r| r.relocs),
                 )
             })
             .collect::<Vec<_>>();
@@ -707,12 +828,12 @@ impl Emit for ModuleFunctions {
         cx.indices.locals.reserve(bytes.len());
 
         let mut offset_data = Vec::new();
-        for (wasm, byte_len, id, used_locals, local_indices, map) in bytes {
+        for (wasm, byte_len, id, used_locals, local_indices, map, relocs) in bytes {
             let leb_len = wasm.len() - byte_len;
             wasm_code_section.raw(&wasm[leb_len..]);
             cx.indices.locals.insert(id, local_indices);
             cx.locals.insert(id, used_locals);
-            offset_data.push((byte_len, id, map, leb_len));
+            offset_data.push((byte_len, id, map, relocs, leb_len));
         }
         cx.wasm_module.section(&wasm_code_section);
 
@@ -721,13 +842,17 @@ impl Emit for ModuleFunctions {
         let mut cur_offset = code_section_start_offset;
 
         // update the map afterwards based on final offset differences
-        for (byte_len, id, map, leb_len) in offset_data {
+        for (byte_len, id, map, relocs, leb_len) in offset_data {
             // (this assumes the leb encodes the same)
             let code_start_offset = cur_offset + leb_len;
             cur_offset += leb_len + byte_len;
             if let Some(map) = map {
                 collect_non_default_code_offsets(&mut instruction_map, code_start_offset, map);
             }
+            for mut reloc in relocs.into_iter().flatten() {
+                reloc.offset += code_start_offset;
+                cx.code_relocs.push(reloc);
+            }
             cx.code_transform.function_ranges.push((
                 id,
                 Range {
@@ -738,8 +863,11 @@ impl Emit for ModuleFunctions {
             ));
         }
         cx.code_transform.function_ranges.sort_by_key(|i| i.0);
-        // FIXME: code section start in DWARF debug information expects 2 bytes before actual code section start.
-        cx.code_transform.code_section_start = code_section_start_offset - 2;
+        // DWARF code addresses are relative to the start of the section's
+        // payload, which begins with the function count.
+        let mut count = Vec::new();
+        leb128::write::unsigned(&mut count, wasm_code_section.len().into()).unwrap();
+        cx.code_transform.code_section_start = code_section_start_offset - count.len();
         cx.code_transform.instruction_map = instruction_map.into_iter().collect();
     }
 }
@@ -915,4 +1043,87 @@ mod tests {
             "new local function has the right kind"
         );
     }
+
+    /// Running `wrap_export` should export a new function of the new type,
+    /// which calls the original
+    #[test]
+    fn wrap_export() {
+        let mut module = Module::default();
+
+        // Create original function, taking an i32
+        let arg = module.locals.add(ValType::I32);
+        let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[]);
+        builder.name("original".to_string());
+        builder.func_body().local_get(arg).drop();
+        let original_fn_id = builder.finish(vec![arg], &mut module.funcs);
+        let export_id = module.exports.add("run", original_fn_id);
+
+        // Export a function that takes no arguments instead
+        let ty = module.types.add(&[], &[]);
+        let new_fn_id = module
+            .wrap_export("run", ty, |original, body, args| {
+                assert_eq!(original, original_fn_id);
+                assert!(args.is_empty());
+                body.i32_const(7).call(original);
+            })
+            .expect("export wrapping worked");
+
+        assert_eq!(module.funcs.get(new_fn_id).ty(), ty);
+        assert_eq!(
+            module.funcs.get(new_fn_id).name.as_deref(),
+            Some("original")
+        );
+        assert!(matches!(
+            module.exports.get(export_id).item,
+            ExportItem::Function(fid) if fid == new_fn_id
+        ));
+        wasmparser::Validator::new()
+            .validate_all(&module.emit_wasm())
+            .expect("wrapped module is valid");
+
+        assert!(module.wrap_export("missing", ty, |_, _, _| {}).is_err());
+    }
+
+    /// Running `wrap_import` should import a function of the new type, and
+    /// turn the original into an adapter that calls it
+    #[test]
+    fn wrap_import() {
+        let mut module = Module::default();
+
+        // Import a function returning nothing, and call it
+        let old_ty = module.types.add(&[ValType::I32], &[]);
+        let (original_fn_id, import_id) = module.add_import_func("env", "log", old_ty);
+        module.funcs.get_mut(original_fn_id).name = Some("log".to_string());
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder.func_body().i32_const(1).call(original_fn_id);
+        let caller = builder.finish(vec![], &mut module.funcs);
+        module.exports.add("caller", caller);
+
+        // Import it returning a status instead, which the adapter drops
+        let new_ty = module.types.add(&[ValType::I32], &[ValType::I32]);
+        let new_fn_id = module
+            .wrap_import("env", "log", new_ty, |import, body, args| {
+                body.local_get(args[0]).call(import).drop();
+            })
+            .expect("import wrapping worked");
+
+        assert_eq!(module.funcs.get(new_fn_id).ty(), new_ty);
+        assert_eq!(module.funcs.get(new_fn_id).name.as_deref(), Some("log"));
+        assert_eq!(
+            module.imports.get_imported_func(new_fn_id).unwrap().id(),
+            import_id
+        );
+        assert_eq!(module.funcs.get(original_fn_id).ty(), old_ty);
+        assert!(matches!(
+            module.funcs.get(original_fn_id).kind,
+            FunctionKind::Local(_)
+        ));
+        wasmparser::Validator::new()
+            .validate_all(&module.emit_wasm())
+            .expect("wrapped module is valid");
+
+        assert!(module
+            .wrap_import("env", "missing", new_ty, |_, _, _| {})
+            .is_err());
+    }
 }
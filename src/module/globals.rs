@@ -111,6 +111,11 @@ impl ModuleGlobals {
     pub fn iter(&self) -> impl Iterator<Item = &Global> {
         self.arena.iter().map(|(_, f)| f)
     }
+
+    /// Get a mutable reference to this module's globals.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Global> {
+        self.arena.iter_mut().map(|(_, f)| f)
+    }
 }
 
 impl Module {
@@ -123,7 +128,7 @@ impl Module {
         log::debug!("parse global section");
         for g in section {
             let g = g?;
-            let init_expr = ConstExpr::eval(&g.init_expr, ids)?;
+            let init_expr = ConstExpr::from_wasmparser(&g.init_expr, ids)?;
             let id = self.globals.add_local(
                 ValType::from_wasmparser(&g.ty.content_type, ids, 0)?,
                 g.ty.mutable,
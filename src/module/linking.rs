@@ -0,0 +1,1196 @@
+//! Handling of the `linking` and `reloc.*` custom sections found in
+//! relocatable object files.
+//!
+//! Specified upstream at
+//! <https://github.com/WebAssembly/tool-conventions/blob/main/Linking.md>
+
+use crate::emit::{EmitContext, IdsToIndices};
+use crate::error::Result;
+use crate::ir::InstrLocId;
+use crate::map::IdHashSet;
+use crate::module::functions::FunctionKind;
+use crate::module::globals::GlobalKind;
+use crate::module::tags::TagKind;
+use crate::module::{Module, RawCustomSection};
+use crate::parse::IndicesToIds;
+use crate::{DataId, FunctionId, GlobalId, TableId, TagId, TypeId};
+use anyhow::bail;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Range;
+use wasmparser::BinaryReader;
+
+/// The linking metadata of a relocatable object file: the `linking` custom
+/// section together with the `reloc.*` sections.
+///
+/// Symbols refer to module items by id, and relocations are anchored to the
+/// instruction or data segment they patch, so both stay valid while the module
+/// is transformed. Relocation offsets are recomputed from scratch when the
+/// module is emitted.
+///
+/// Custom sections with relocations, such as the DWARF sections of objects
+/// compiled with debug info, are kept as raw custom sections whose bytes are
+/// not rewritten, so their relocations keep their original offsets.
+#[derive(Debug, Default, Clone)]
+pub struct ModuleLinking {
+    /// The symbol table.
+    ///
+    /// Relocations and init functions refer to symbols by their index in this
+    /// list, so new symbols should only ever be appended to it.
+    pub symbols: Vec<Symbol>,
+    /// Extra metadata about data segments.
+    pub segments: Vec<SegmentInfo>,
+    /// Constructor functions to run at startup.
+    pub init_funcs: Vec<InitFunc>,
+    /// COMDAT groups.
+    pub comdats: Vec<Comdat>,
+    /// Relocations applying to the code section.
+    pub code_relocs: Vec<CodeRelocation>,
+    /// Relocations applying to the data section.
+    pub data_relocs: Vec<DataRelocation>,
+    /// Relocations applying to custom sections.
+    pub custom_relocs: Vec<CustomRelocation>,
+}
+
+/// An entry in the symbol table of the `linking` section.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    /// The `WASM_SYM_*` flags of this symbol, see the associated constants.
+    pub flags: u32,
+    /// What this symbol refers to.
+    pub kind: SymbolKind,
+}
+
+impl Symbol {
+    /// This is a weak symbol.
+    pub const BINDING_WEAK: u32 = 0x1;
+    /// This is a local symbol, exclusive with `BINDING_WEAK`.
+    pub const BINDING_LOCAL: u32 = 0x2;
+    /// This is a hidden symbol.
+    pub const VISIBILITY_HIDDEN: u32 = 0x4;
+    /// This symbol is not defined in this object.
+    pub const UNDEFINED: u32 = 0x10;
+    /// This symbol is exported from the linked module.
+    pub const EXPORTED: u32 = 0x20;
+    /// This symbol has an explicit name rather than its import's name.
+    pub const EXPLICIT_NAME: u32 = 0x40;
+    /// The linker must keep this symbol even if it is unused.
+    pub const NO_STRIP: u32 = 0x80;
+    /// This symbol resides in thread local storage.
+    pub const TLS: u32 = 0x100;
+    /// This symbol represents an absolute address.
+    pub const ABSOLUTE: u32 = 0x200;
+
+    /// Is this symbol undefined in this object?
+    pub fn is_undefined(&self) -> bool {
+        self.flags & Symbol::UNDEFINED != 0
+    }
+
+    /// The name of this symbol, if it has one.
+    pub fn name(&self) -> Option<&str> {
+        match &self.kind {
+            SymbolKind::Function { name, .. }
+            | SymbolKind::Global { name, .. }
+            | SymbolKind::Tag { name, .. }
+            | SymbolKind::Table { name, .. } => name.as_deref(),
+            SymbolKind::Data { name, .. } => Some(name),
+            SymbolKind::Section { section } => Some(section),
+        }
+    }
+}
+
+/// The item a `Symbol` refers to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SymbolKind {
+    /// A function symbol.
+    Function {
+        /// The function.
+        func: FunctionId,
+        /// The symbol name, present for defined symbols and those with an
+        /// explicit name.
+        name: Option<String>,
+    },
+    /// A data symbol.
+    Data {
+        /// The symbol name.
+        name: String,
+        /// Where the symbol lives, if it is defined.
+        definition: Option<DataSymbolDefinition>,
+    },
+    /// A global symbol.
+    Global {
+        /// The global.
+        global: GlobalId,
+        /// The symbol name, present for defined symbols and those with an
+        /// explicit name.
+        name: Option<String>,
+    },
+    /// A symbol for a custom section, typically a DWARF section.
+    Section {
+        /// The name of the custom section.
+        section: String,
+    },
+    /// A tag (event) symbol.
+    Tag {
+        /// The tag.
+        tag: TagId,
+        /// The symbol name, present for defined symbols and those with an
+        /// explicit name.
+        name: Option<String>,
+    },
+    /// A table symbol.
+    Table {
+        /// The table.
+        table: TableId,
+        /// The symbol name, present for defined symbols and those with an
+        /// explicit name.
+        name: Option<String>,
+    },
+}
+
+/// The location of a defined data symbol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DataSymbolDefinition {
+    /// The segment containing the symbol.
+    pub data: DataId,
+    /// The offset of the symbol within the segment.
+    pub offset: u32,
+    /// The size of the symbol in bytes.
+    pub size: u32,
+}
+
+/// Extra metadata about a data segment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentInfo {
+    /// The segment this describes.
+    pub data: DataId,
+    /// The segment's name, e.g. `.rodata.str`.
+    pub name: String,
+    /// The required alignment of the segment, as a power of two.
+    pub alignment: u32,
+    /// The `WASM_SEG_FLAG_*` flags of this segment, see the associated
+    /// constants.
+    pub flags: u32,
+}
+
+impl SegmentInfo {
+    /// The segment only contains null-terminated strings.
+    pub const STRINGS: u32 = 0x1;
+    /// The segment contains thread-local data.
+    pub const TLS: u32 = 0x2;
+}
+
+/// A constructor function to be called at startup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InitFunc {
+    /// The priority; lower priorities run first.
+    pub priority: u32,
+    /// The index of the function symbol in the symbol table.
+    pub symbol: u32,
+}
+
+/// A COMDAT group: items that the linker keeps or discards together.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Comdat {
+    /// The name of this group.
+    pub name: String,
+    /// Flags, currently always zero.
+    pub flags: u32,
+    /// The members of this group.
+    pub members: Vec<ComdatMember>,
+}
+
+/// A member of a `Comdat` group.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ComdatMember {
+    /// A data segment.
+    Data(DataId),
+    /// A function.
+    Function(FunctionId),
+    /// A global.
+    Global(GlobalId),
+    /// A tag.
+    Tag(TagId),
+    /// A table.
+    Table(TableId),
+    /// A custom section, by name.
+    Section(String),
+}
+
+/// The `R_WASM_*` relocation types.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[allow(missing_docs)]
+#[repr(u8)]
+pub enum RelocationType {
+    FunctionIndexLeb = 0,
+    TableIndexSleb = 1,
+    TableIndexI32 = 2,
+    MemoryAddrLeb = 3,
+    MemoryAddrSleb = 4,
+    MemoryAddrI32 = 5,
+    TypeIndexLeb = 6,
+    GlobalIndexLeb = 7,
+    FunctionOffsetI32 = 8,
+    SectionOffsetI32 = 9,
+    EventIndexLeb = 10,
+    MemoryAddrRelSleb = 11,
+    TableIndexRelSleb = 12,
+    GlobalIndexI32 = 13,
+    MemoryAddrLeb64 = 14,
+    MemoryAddrSleb64 = 15,
+    MemoryAddrI64 = 16,
+    MemoryAddrRelSleb64 = 17,
+    TableIndexSleb64 = 18,
+    TableIndexI64 = 19,
+    TableNumberLeb = 20,
+    MemoryAddrTlsSleb = 21,
+    FunctionOffsetI64 = 22,
+    MemoryAddrLocrelI32 = 23,
+    TableIndexRelSleb64 = 24,
+    MemoryAddrTlsSleb64 = 25,
+    FunctionIndexI32 = 26,
+}
+
+impl RelocationType {
+    fn from_wasmparser(ty: wasmparser::RelocationType) -> RelocationType {
+        use wasmparser::RelocationType as W;
+        match ty {
+            W::FunctionIndexLeb => RelocationType::FunctionIndexLeb,
+            W::TableIndexSleb => RelocationType::TableIndexSleb,
+            W::TableIndexI32 => RelocationType::TableIndexI32,
+            W::MemoryAddrLeb => RelocationType::MemoryAddrLeb,
+            W::MemoryAddrSleb => RelocationType::MemoryAddrSleb,
+            W::MemoryAddrI32 => RelocationType::MemoryAddrI32,
+            W::TypeIndexLeb => RelocationType::TypeIndexLeb,
+            W::GlobalIndexLeb => RelocationType::GlobalIndexLeb,
+            W::FunctionOffsetI32 => RelocationType::FunctionOffsetI32,
+            W::SectionOffsetI32 => RelocationType::SectionOffsetI32,
+            W::EventIndexLeb => RelocationType::EventIndexLeb,
+            W::MemoryAddrRelSleb => RelocationType::MemoryAddrRelSleb,
+            W::TableIndexRelSleb => RelocationType::TableIndexRelSleb,
+            W::GlobalIndexI32 => RelocationType::GlobalIndexI32,
+            W::MemoryAddrLeb64 => RelocationType::MemoryAddrLeb64,
+            W::MemoryAddrSleb64 => RelocationType::MemoryAddrSleb64,
+            W::MemoryAddrI64 => RelocationType::MemoryAddrI64,
+            W::MemoryAddrRelSleb64 => RelocationType::MemoryAddrRelSleb64,
+            W::TableIndexSleb64 => RelocationType::TableIndexSleb64,
+            W::TableIndexI64 => RelocationType::TableIndexI64,
+            W::TableNumberLeb => RelocationType::TableNumberLeb,
+            W::MemoryAddrTlsSleb => RelocationType::MemoryAddrTlsSleb,
+            W::FunctionOffsetI64 => RelocationType::FunctionOffsetI64,
+            W::MemoryAddrLocrelI32 => RelocationType::MemoryAddrLocrelI32,
+            W::TableIndexRelSleb64 => RelocationType::TableIndexRelSleb64,
+            W::MemoryAddrTlsSleb64 => RelocationType::MemoryAddrTlsSleb64,
+            W::FunctionIndexI32 => RelocationType::FunctionIndexI32,
+        }
+    }
+
+    fn to_wasmparser(self) -> wasmparser::RelocationType {
+        wasmparser::RelocationType::try_from(self as u8).unwrap()
+    }
+
+    /// The number of bytes patched by this relocation.
+    pub fn extent(self) -> usize {
+        self.to_wasmparser().extent()
+    }
+
+    /// Does this relocation carry an addend?
+    pub fn has_addend(self) -> bool {
+        self.to_wasmparser().addend_kind() != wasmparser::RelocAddendKind::None
+    }
+
+    /// Is this the relocation of an index immediate (function, global, type,
+    /// tag or table index) in code?
+    ///
+    /// These are regenerated from the instructions themselves on emission.
+    pub fn is_index(self) -> bool {
+        matches!(
+            self,
+            RelocationType::FunctionIndexLeb
+                | RelocationType::TypeIndexLeb
+                | RelocationType::GlobalIndexLeb
+                | RelocationType::EventIndexLeb
+                | RelocationType::TableNumberLeb
+        )
+    }
+
+    /// Is this the relocation of an `i32.const`/`i64.const` value or of a
+    /// memory access offset in code?
+    pub fn is_code_value(self) -> bool {
+        matches!(
+            self,
+            RelocationType::TableIndexSleb
+                | RelocationType::MemoryAddrLeb
+                | RelocationType::MemoryAddrSleb
+                | RelocationType::MemoryAddrRelSleb
+                | RelocationType::TableIndexRelSleb
+                | RelocationType::MemoryAddrLeb64
+                | RelocationType::MemoryAddrSleb64
+                | RelocationType::MemoryAddrRelSleb64
+                | RelocationType::TableIndexSleb64
+                | RelocationType::MemoryAddrTlsSleb
+                | RelocationType::TableIndexRelSleb64
+                | RelocationType::MemoryAddrTlsSleb64
+        )
+    }
+}
+
+/// What a relocation's index refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelocationTarget {
+    /// An index into the symbol table.
+    Symbol(u32),
+    /// A type, used by `TypeIndexLeb` relocations.
+    Type(TypeId),
+}
+
+/// A relocation in the code section.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CodeRelocation {
+    /// The relocation type.
+    pub ty: RelocationType,
+    /// The instruction whose immediate is relocated.
+    pub instr: InstrLocId,
+    /// The symbol or type this relocation refers to.
+    pub target: RelocationTarget,
+    /// The addend, zero for relocation types without one.
+    pub addend: i64,
+}
+
+/// A relocation in the data section.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DataRelocation {
+    /// The relocation type.
+    pub ty: RelocationType,
+    /// The segment containing the relocated bytes.
+    pub data: DataId,
+    /// The offset of the relocated bytes within the segment.
+    pub offset: u32,
+    /// The index of the symbol in the symbol table.
+    pub symbol: u32,
+    /// The addend, zero for relocation types without one.
+    pub addend: i64,
+}
+
+/// A relocation in a custom section.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CustomRelocation {
+    /// The relocation type.
+    pub ty: RelocationType,
+    /// The name of the custom section containing the relocated bytes.
+    pub section: String,
+    /// The offset of the relocated bytes within the section's payload.
+    pub offset: u32,
+    /// The index of the symbol in the symbol table.
+    pub symbol: u32,
+    /// The addend, zero for relocation types without one.
+    pub addend: i64,
+}
+
+/// The item an index relocation refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum IndexTarget {
+    Function(FunctionId),
+    Global(GlobalId),
+    Table(TableId),
+    Tag(TagId),
+    Type(TypeId),
+}
+
+/// A relocatable immediate written by the function emitter.
+#[derive(Debug)]
+pub(crate) struct EmittedReloc {
+    /// The offset of the immediate, relative to the start of the function body
+    /// while emitting, and absolute once the code section is placed.
+    pub(crate) offset: usize,
+    /// The instruction the immediate belongs to, or the default id for
+    /// immediates of catch clauses.
+    pub(crate) loc: InstrLocId,
+    pub(crate) kind: EmittedRelocKind,
+}
+
+#[derive(Debug)]
+pub(crate) enum EmittedRelocKind {
+    /// An index immediate, which always gets a relocation.
+    Index(RelocationType, IndexTarget),
+    /// A constant or memory offset that had a relocation in the input.
+    Value,
+}
+
+/// State for emitting a function of a relocatable module.
+#[derive(Debug)]
+pub(crate) struct RelocRecorder<'a> {
+    /// Instructions with a value relocation, which must be emitted padded.
+    pub(crate) value_relocs: &'a BTreeMap<InstrLocId, RelocationType>,
+    /// Relocatable immediates emitted so far.
+    pub(crate) relocs: Vec<EmittedReloc>,
+}
+
+/// Encode `value` as an unsigned LEB128 padded to `width` bytes.
+pub(crate) fn padded_uleb(mut value: u64, width: usize) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(width);
+    for i in 0..width {
+        let mut byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if i + 1 < width {
+            byte |= 0x80;
+        }
+        bytes.push(byte);
+    }
+    bytes
+}
+
+/// Encode `value` as a signed LEB128 padded to `width` bytes.
+pub(crate) fn padded_sleb(mut value: i64, width: usize) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(width);
+    for i in 0..width {
+        let mut byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if i + 1 < width {
+            byte |= 0x80;
+        }
+        bytes.push(byte);
+    }
+    bytes
+}
+
+/// The length of the shortest unsigned LEB128 encoding of `value`.
+pub(crate) fn uleb_len(value: u64) -> usize {
+    let mut buf = Vec::new();
+    leb128::write::unsigned(&mut buf, value).unwrap()
+}
+
+/// The length of the shortest signed LEB128 encoding of `value`.
+pub(crate) fn sleb_len(value: i64) -> usize {
+    let mut buf = Vec::new();
+    leb128::write::signed(&mut buf, value).unwrap()
+}
+
+/// Sections of the input module needed to interpret the `linking` and
+/// `reloc.*` sections, gathered while parsing.
+#[derive(Default)]
+pub(crate) struct LinkingInput<'a> {
+    /// The name of every input section that is a custom section, by index.
+    pub(crate) sections: Vec<Option<&'a str>>,
+    /// The index of the code section.
+    pub(crate) code_section: Option<u32>,
+    /// The index and payload offset of the data section.
+    pub(crate) data_section: Option<(u32, usize)>,
+    /// The absolute range of every data segment's bytes.
+    pub(crate) data_segments: Vec<Range<usize>>,
+    /// The payload and payload offset of the `linking` section.
+    pub(crate) linking: Option<(&'a [u8], usize)>,
+    /// The name, payload and payload offset of each `reloc.*` section.
+    pub(crate) relocs: Vec<(&'a str, &'a [u8], usize)>,
+}
+
+impl Module {
+    /// Parse the `linking` and `reloc.*` sections, once all other sections and
+    /// function bodies have been parsed.
+    pub(crate) fn parse_linking(&mut self, input: LinkingInput, ids: &IndicesToIds) -> Result<()> {
+        let (data, offset) = match input.linking {
+            Some(linking) => linking,
+            None => {
+                // Relocations without a symbol table can't be interpreted, so
+                // just pass them through.
+                for (name, data, _) in input.relocs {
+                    self.customs.add(RawCustomSection {
+                        name: name.to_string(),
+                        data: data.to_vec(),
+                    });
+                }
+                return Ok(());
+            }
+        };
+        log::debug!("parse linking section");
+
+        let section_name = |index: u32| -> Result<String> {
+            match input.sections.get(index as usize) {
+                Some(Some(name)) => Ok(name.to_string()),
+                _ => bail!("section {} is not a custom section", index),
+            }
+        };
+
+        let mut linking = ModuleLinking::default();
+        let reader = wasmparser::LinkingSectionReader::new(BinaryReader::new(data, offset))?;
+        for subsection in reader {
+            match subsection? {
+                wasmparser::Linking::SymbolTable(symbols) => {
+                    for symbol in symbols {
+                        let symbol = match symbol? {
+                            wasmparser::SymbolInfo::Func { flags, index, name } => Symbol {
+                                flags: flags.bits(),
+                                kind: SymbolKind::Function {
+                                    func: ids.get_func(index)?,
+                                    name: name.map(|n| n.to_string()),
+                                },
+                            },
+                            wasmparser::SymbolInfo::Data {
+                                flags,
+                                name,
+                                symbol,
+                            } => Symbol {
+                                flags: flags.bits(),
+                                kind: SymbolKind::Data {
+                                    name: name.to_string(),
+                                    definition: match symbol {
+                                        Some(def) => Some(DataSymbolDefinition {
+                                            data: ids.get_data(def.index)?,
+                                            offset: def.offset,
+                                            size: def.size,
+                                        }),
+                                        None => None,
+                                    },
+                                },
+                            },
+                            wasmparser::SymbolInfo::Global { flags, index, name } => Symbol {
+                                flags: flags.bits(),
+                                kind: SymbolKind::Global {
+                                    global: ids.get_global(index)?,
+                                    name: name.map(|n| n.to_string()),
+                                },
+                            },
+                            wasmparser::SymbolInfo::Section { flags, section } => Symbol {
+                                flags: flags.bits(),
+                                kind: SymbolKind::Section {
+                                    section: section_name(section)?,
+                                },
+                            },
+                            wasmparser::SymbolInfo::Event { flags, index, name } => Symbol {
+                                flags: flags.bits(),
+                                kind: SymbolKind::Tag {
+                                    tag: ids.get_tag(index)?,
+                                    name: name.map(|n| n.to_string()),
+                                },
+                            },
+                            wasmparser::SymbolInfo::Table { flags, index, name } => Symbol {
+                                flags: flags.bits(),
+                                kind: SymbolKind::Table {
+                                    table: ids.get_table(index)?,
+                                    name: name.map(|n| n.to_string()),
+                                },
+                            },
+                        };
+                        linking.symbols.push(symbol);
+                    }
+                }
+                wasmparser::Linking::SegmentInfo(segments) => {
+                    for (i, segment) in segments.into_iter().enumerate() {
+                        let segment = segment?;
+                        linking.segments.push(SegmentInfo {
+                            data: ids.get_data(i as u32)?,
+                            name: segment.name.to_string(),
+                            alignment: segment.alignment,
+                            flags: segment.flags.bits(),
+                        });
+                    }
+                }
+                wasmparser::Linking::InitFuncs(funcs) => {
+                    for func in funcs {
+                        let func = func?;
+                        linking.init_funcs.push(InitFunc {
+                            priority: func.priority,
+                            symbol: func.symbol_index,
+                        });
+                    }
+                }
+                wasmparser::Linking::ComdatInfo(comdats) => {
+                    for comdat in comdats {
+                        let comdat = comdat?;
+                        let mut members = Vec::new();
+                        for member in comdat.symbols {
+                            let member = member?;
+                            members.push(match member.kind {
+                                wasmparser::ComdatSymbolKind::Data => {
+                                    ComdatMember::Data(ids.get_data(member.index)?)
+                                }
+                                wasmparser::ComdatSymbolKind::Func => {
+                                    ComdatMember::Function(ids.get_func(member.index)?)
+                                }
+                                wasmparser::ComdatSymbolKind::Global => {
+                                    ComdatMember::Global(ids.get_global(member.index)?)
+                                }
+                                wasmparser::ComdatSymbolKind::Event => {
+                                    ComdatMember::Tag(ids.get_tag(member.index)?)
+                                }
+                                wasmparser::ComdatSymbolKind::Table => {
+                                    ComdatMember::Table(ids.get_table(member.index)?)
+                                }
+                                wasmparser::ComdatSymbolKind::Section => {
+                                    ComdatMember::Section(section_name(member.index)?)
+                                }
+                            });
+                        }
+                        linking.comdats.push(Comdat {
+                            name: comdat.name.to_string(),
+                            flags: comdat.flags,
+                            members,
+                        });
+                    }
+                }
+                wasmparser::Linking::Unknown { ty, .. } => {
+                    log::warn!("unknown linking subsection {} ignored", ty)
+                }
+            }
+        }
+
+        // Every instruction's offset within the code section, used to anchor
+        // code relocations to the instruction containing them.
+        let mut instrs = self
+            .funcs
+            .iter_local()
+            .flat_map(|(_, f)| f.instruction_mapping.iter().copied())
+            .collect::<Vec<_>>();
+        instrs.sort_by_key(|(pos, _)| *pos);
+
+        for (name, data, offset) in input.relocs {
+            log::debug!("parse {} section", name);
+            let reader = wasmparser::RelocSectionReader::new(BinaryReader::new(data, offset))?;
+            let section = reader.section_index();
+            if Some(section) == input.code_section {
+                for entry in reader.entries() {
+                    let entry = entry?;
+                    let ty = RelocationType::from_wasmparser(entry.ty);
+                    let offset = entry.offset as usize;
+                    let i = instrs.partition_point(|(pos, _)| *pos <= offset);
+                    if i == 0 {
+                        bail!("relocation at {:#x} is outside of any function", offset);
+                    }
+                    let target = if ty == RelocationType::TypeIndexLeb {
+                        RelocationTarget::Type(ids.get_type(entry.index)?)
+                    } else {
+                        RelocationTarget::Symbol(entry.index)
+                    };
+                    linking.code_relocs.push(CodeRelocation {
+                        ty,
+                        instr: instrs[i - 1].1,
+                        target,
+                        addend: entry.addend,
+                    });
+                }
+            } else if let Some((_, start)) = input.data_section.filter(|(i, _)| *i == section) {
+                for entry in reader.entries() {
+                    let entry = entry?;
+                    let ty = RelocationType::from_wasmparser(entry.ty);
+                    let pos = start + entry.offset as usize;
+                    let i = input.data_segments.partition_point(|r| r.start <= pos);
+                    let segment = match i.checked_sub(1).map(|i| &input.data_segments[i]) {
+                        Some(segment) if pos + ty.extent() <= segment.end => segment,
+                        _ => bail!(
+                            "data relocation at {:#x} is outside of any segment",
+                            entry.offset
+                        ),
+                    };
+                    linking.data_relocs.push(DataRelocation {
+                        ty,
+                        data: ids.get_data(i as u32 - 1)?,
+                        offset: (pos - segment.start) as u32,
+                        symbol: entry.index,
+                        addend: entry.addend,
+                    });
+                }
+            } else {
+                let name = match input.sections.get(section as usize) {
+                    Some(Some(name)) => *name,
+                    _ => bail!("relocations for section {} are not supported", section),
+                };
+                if !self.customs.iter().any(|(_, s)| s.name() == name) {
+                    bail!(
+                        "relocations for custom section `{}` are not supported",
+                        name
+                    );
+                }
+                for entry in reader.entries() {
+                    let entry = entry?;
+                    linking.custom_relocs.push(CustomRelocation {
+                        ty: RelocationType::from_wasmparser(entry.ty),
+                        section: name.to_string(),
+                        offset: entry.offset,
+                        symbol: entry.index,
+                        addend: entry.addend,
+                    });
+                }
+            }
+        }
+
+        self.linking = Some(linking);
+        Ok(())
+    }
+}
+
+impl ModuleLinking {
+    /// Instructions with a value relocation, and its type.
+    pub(crate) fn value_relocs(&self) -> BTreeMap<InstrLocId, RelocationType> {
+        self.code_relocs
+            .iter()
+            .filter(|r| r.ty.is_code_value())
+            .map(|r| (r.instr, r.ty))
+            .collect()
+    }
+
+    fn symbol_target(symbol: &Symbol) -> Option<IndexTarget> {
+        match &symbol.kind {
+            SymbolKind::Function { func, .. } => Some(IndexTarget::Function(*func)),
+            SymbolKind::Global { global, .. } => Some(IndexTarget::Global(*global)),
+            SymbolKind::Tag { tag, .. } => Some(IndexTarget::Tag(*tag)),
+            SymbolKind::Table { table, .. } => Some(IndexTarget::Table(*table)),
+            SymbolKind::Data { .. } | SymbolKind::Section { .. } => None,
+        }
+    }
+
+    /// Emit the `linking` and `reloc.*` sections.
+    ///
+    /// This must run after every other section has been emitted, as section
+    /// symbols and relocation sections refer to earlier sections by index.
+    pub(crate) fn emit(&self, cx: &mut EmitContext, indices: &IdsToIndices) {
+        log::debug!("emit linking section");
+        let module = cx.module;
+        let layout = section_layout(cx.wasm_module.as_slice());
+        let section_index = |name: &str| {
+            layout
+                .iter()
+                .position(|s| s.name.as_deref() == Some(name))
+                .map(|i| i as u32)
+        };
+
+        // Symbols of custom sections that are no longer there are dropped,
+        // which shifts the index of every later symbol. Synthesized symbols
+        // are appended after all of the original ones.
+        let mut renumbered = Vec::with_capacity(self.symbols.len());
+        let mut next = 0;
+        for symbol in &self.symbols {
+            match &symbol.kind {
+                SymbolKind::Section { section } if section_index(section).is_none() => {
+                    log::warn!("dropping symbol of missing section `{}`", section);
+                    renumbered.push(None);
+                }
+                _ => {
+                    renumbered.push(Some(next));
+                    next += 1;
+                }
+            }
+        }
+        let dropped = self.symbols.len() as u32 - next;
+        let renumber = |symbol: u32| match renumbered.get(symbol as usize) {
+            Some(new) => *new,
+            None => Some(symbol - dropped),
+        };
+
+        let mut symbols = self.symbols.clone();
+        let mut defaults = HashMap::new();
+        for (i, symbol) in symbols.iter().enumerate() {
+            if let Some(target) = Self::symbol_target(symbol) {
+                defaults.entry(target).or_insert(i as u32);
+            }
+        }
+
+        // Regenerate code relocations from the emitted immediates, keeping the
+        // original symbol of an instruction whenever it still refers to the
+        // same item.
+        let mut originals: BTreeMap<InstrLocId, Vec<&CodeRelocation>> = BTreeMap::new();
+        for reloc in &self.code_relocs {
+            originals.entry(reloc.instr).or_default().push(reloc);
+        }
+        let code_start = layout
+            .iter()
+            .find(|s| s.id == 10)
+            .map(|s| s.payload.start)
+            .unwrap_or(0);
+        let mut code_relocs = Vec::new();
+        for emitted in &cx.code_relocs {
+            let offset = (emitted.offset - code_start) as u32;
+            let originals = originals.get(&emitted.loc).map(|v| &v[..]).unwrap_or(&[]);
+            match emitted.kind {
+                EmittedRelocKind::Index(ty, IndexTarget::Type(ty_id)) => {
+                    code_relocs.push((ty, offset, indices.get_type_index(ty_id), 0));
+                }
+                EmittedRelocKind::Index(ty, target) => {
+                    let original = originals.iter().find_map(|r| match r.target {
+                        RelocationTarget::Symbol(s)
+                            if r.ty == ty
+                                && symbols
+                                    .get(s as usize)
+                                    .and_then(Self::symbol_target)
+                                    .is_some_and(|t| t == target) =>
+                        {
+                            Some(s)
+                        }
+                        _ => None,
+                    });
+                    let symbol = match original {
+                        Some(symbol) => symbol,
+                        None => *defaults.entry(target).or_insert_with(|| {
+                            symbols.push(synthesize_symbol(module, indices, target));
+                            symbols.len() as u32 - 1
+                        }),
+                    };
+                    if let Some(symbol) = renumber(symbol) {
+                        code_relocs.push((ty, offset, symbol, 0));
+                    }
+                }
+                EmittedRelocKind::Value => {
+                    for reloc in originals.iter().filter(|r| r.ty.is_code_value()) {
+                        let symbol = match reloc.target {
+                            RelocationTarget::Symbol(symbol) => renumber(symbol),
+                            RelocationTarget::Type(_) => None,
+                        };
+                        match symbol {
+                            Some(symbol) => {
+                                code_relocs.push((reloc.ty, offset, symbol, reloc.addend))
+                            }
+                            None => log::warn!("dropping relocation against a dropped symbol"),
+                        }
+                    }
+                }
+            }
+        }
+
+        // Data relocations only need the new position of their segment.
+        let mut data_relocs = Vec::new();
+        let data_section = layout.iter().position(|s| s.id == 11);
+        if let Some(i) = data_section {
+            let payload = layout[i].payload.clone();
+            let segments = data_segment_ranges(cx.wasm_module.as_slice(), payload.clone());
+            let live = module.data.iter().map(|d| d.id()).collect::<IdHashSet<_>>();
+            for reloc in &self.data_relocs {
+                if !live.contains(&reloc.data) {
+                    log::warn!("dropping relocation in deleted data segment");
+                    continue;
+                }
+                let segment = &segments[indices.get_data_index(reloc.data) as usize];
+                let start = segment.start + reloc.offset as usize;
+                if start + reloc.ty.extent() > segment.end {
+                    log::warn!("dropping relocation past the end of its data segment");
+                    continue;
+                }
+                let symbol = match renumber(reloc.symbol) {
+                    Some(symbol) => symbol,
+                    None => {
+                        log::warn!("dropping relocation against a dropped symbol");
+                        continue;
+                    }
+                };
+                let offset = (start - payload.start) as u32;
+                data_relocs.push((reloc.ty, offset, symbol, reloc.addend));
+            }
+        }
+
+        // Custom sections are passed through unchanged, so their relocations
+        // only need the new index of their section.
+        let mut custom_relocs: BTreeMap<u32, Vec<_>> = BTreeMap::new();
+        for reloc in &self.custom_relocs {
+            let section = match section_index(&reloc.section) {
+                Some(section) => section,
+                None => {
+                    log::warn!("dropping relocation in missing section `{}`", reloc.section);
+                    continue;
+                }
+            };
+            let symbol = match renumber(reloc.symbol) {
+                Some(symbol) => symbol,
+                None => {
+                    log::warn!("dropping relocation against a dropped symbol");
+                    continue;
+                }
+            };
+            custom_relocs.entry(section).or_default().push((
+                reloc.ty,
+                reloc.offset,
+                symbol,
+                reloc.addend,
+            ));
+        }
+
+        let mut linking = Vec::new();
+        write_uleb(&mut linking, 2);
+
+        // WASM_SYMBOL_TABLE
+        let mut sub = Vec::new();
+        write_uleb(&mut sub, (symbols.len() - dropped as usize) as u64);
+        for symbol in &symbols {
+            let defined = !symbol.is_undefined();
+            let explicit = symbol.flags & Symbol::EXPLICIT_NAME != 0;
+            let (kind, index, name) = match &symbol.kind {
+                SymbolKind::Function { func, name } => (0, indices.get_func_index(*func), name),
+                SymbolKind::Global { global, name } => (2, indices.get_global_index(*global), name),
+                SymbolKind::Tag { tag, name } => (4, indices.get_tag_index(*tag), name),
+                SymbolKind::Table { table, name } => (5, indices.get_table_index(*table), name),
+                SymbolKind::Data { name, definition } => {
+                    sub.push(1);
+                    write_uleb(&mut sub, symbol.flags.into());
+                    write_str(&mut sub, name);
+                    if let Some(def) = definition.filter(|_| defined) {
+                        write_uleb(&mut sub, indices.get_data_index(def.data).into());
+                        write_uleb(&mut sub, def.offset.into());
+                        write_uleb(&mut sub, def.size.into());
+                    }
+                    continue;
+                }
+                SymbolKind::Section { section } => {
+                    // Symbols of missing sections were dropped above.
+                    if let Some(index) = section_index(section) {
+                        sub.push(3);
+                        write_uleb(&mut sub, symbol.flags.into());
+                        write_uleb(&mut sub, index.into());
+                    }
+                    continue;
+                }
+            };
+            sub.push(kind);
+            write_uleb(&mut sub, symbol.flags.into());
+            write_uleb(&mut sub, index.into());
+            if defined || explicit {
+                write_str(&mut sub, name.as_deref().unwrap_or(""));
+            }
+        }
+        write_subsection(&mut linking, 8, &sub);
+
+        // WASM_SEGMENT_INFO, which covers every segment in index order.
+        if module.data.iter().next().is_some() {
+            let mut sub = Vec::new();
+            write_uleb(&mut sub, module.data.iter().count() as u64);
+            for data in module.data.iter() {
+                match self.segments.iter().find(|s| s.data == data.id()) {
+                    Some(info) => {
+                        write_str(&mut sub, &info.name);
+                        write_uleb(&mut sub, info.alignment.into());
+                        write_uleb(&mut sub, info.flags.into());
+                    }
+                    None => {
+                        let name = match &data.name {
+                            Some(name) => name.clone(),
+                            None => format!(".data.{}", indices.get_data_index(data.id())),
+                        };
+                        write_str(&mut sub, &name);
+                        write_uleb(&mut sub, 0);
+                        write_uleb(&mut sub, 0);
+                    }
+                }
+            }
+            write_subsection(&mut linking, 5, &sub);
+        }
+
+        // WASM_INIT_FUNCS
+        let init_funcs = self
+            .init_funcs
+            .iter()
+            .filter_map(|func| Some((func.priority, renumber(func.symbol)?)))
+            .collect::<Vec<_>>();
+        if !init_funcs.is_empty() {
+            let mut sub = Vec::new();
+            write_uleb(&mut sub, init_funcs.len() as u64);
+            for (priority, symbol) in init_funcs {
+                write_uleb(&mut sub, priority.into());
+                write_uleb(&mut sub, symbol.into());
+            }
+            write_subsection(&mut linking, 6, &sub);
+        }
+
+        // WASM_COMDAT_INFO
+        if !self.comdats.is_empty() {
+            let mut sub = Vec::new();
+            write_uleb(&mut sub, self.comdats.len() as u64);
+            for comdat in &self.comdats {
+                write_str(&mut sub, &comdat.name);
+                write_uleb(&mut sub, comdat.flags.into());
+                let members = comdat
+                    .members
+                    .iter()
+                    .filter_map(|member| match member {
+                        ComdatMember::Data(id) => Some((0, indices.get_data_index(*id))),
+                        ComdatMember::Function(id) => Some((1, indices.get_func_index(*id))),
+                        ComdatMember::Global(id) => Some((2, indices.get_global_index(*id))),
+                        ComdatMember::Tag(id) => Some((3, indices.get_tag_index(*id))),
+                        ComdatMember::Table(id) => Some((4, indices.get_table_index(*id))),
+                        ComdatMember::Section(name) => match section_index(name) {
+                            Some(index) => Some((5, index)),
+                            None => {
+                                log::warn!("dropping comdat member of missing section `{}`", name);
+                                None
+                            }
+                        },
+                    })
+                    .collect::<Vec<_>>();
+                write_uleb(&mut sub, members.len() as u64);
+                for (kind, index) in members {
+                    sub.push(kind);
+                    write_uleb(&mut sub, index.into());
+                }
+            }
+            write_subsection(&mut linking, 7, &sub);
+        }
+
+        cx.wasm_module.section(&wasm_encoder::CustomSection {
+            name: "linking".into(),
+            data: linking.into(),
+        });
+
+        if let Some(code_index) = layout.iter().position(|s| s.id == 10) {
+            if !code_relocs.is_empty() {
+                emit_reloc_section(cx, "reloc.CODE", code_index as u32, code_relocs);
+            }
+        }
+        if let Some(data_index) = data_section {
+            if !data_relocs.is_empty() {
+                emit_reloc_section(cx, "reloc.DATA", data_index as u32, data_relocs);
+            }
+        }
+        for (section, relocs) in custom_relocs {
+            let name = format!(
+                "reloc.{}",
+                layout[section as usize].name.as_deref().unwrap()
+            );
+            emit_reloc_section(cx, &name, section, relocs);
+        }
+    }
+}
+
+/// Create a symbol for an item that is referenced from code but has no symbol
+/// yet, e.g. because a transformation introduced a new call.
+fn synthesize_symbol(module: &Module, indices: &IdsToIndices, target: IndexTarget) -> Symbol {
+    let (imported, name, fallback) = match target {
+        IndexTarget::Function(id) => {
+            let func = module.funcs.get(id);
+            let imported = matches!(func.kind, FunctionKind::Import(_));
+            (
+                imported,
+                &func.name,
+                format!("func{}", indices.get_func_index(id)),
+            )
+        }
+        IndexTarget::Global(id) => {
+            let global = module.globals.get(id);
+            let imported = matches!(global.kind, GlobalKind::Import(_));
+            (
+                imported,
+                &global.name,
+                format!("global{}", indices.get_global_index(id)),
+            )
+        }
+        IndexTarget::Table(id) => {
+            let table = module.tables.get(id);
+            let imported = table.import.is_some();
+            (
+                imported,
+                &table.name,
+                format!("table{}", indices.get_table_index(id)),
+            )
+        }
+        IndexTarget::Tag(id) => {
+            let tag = module.tags.get(id);
+            let imported = matches!(tag.kind, TagKind::Import(_));
+            (
+                imported,
+                &tag.name,
+                format!("tag{}", indices.get_tag_index(id)),
+            )
+        }
+        IndexTarget::Type(_) => unreachable!("types are not referenced through symbols"),
+    };
+    // Imports are named after the import itself; everything else becomes a
+    // local symbol so it can't clash with symbols of other objects.
+    let (flags, name) = if imported {
+        (Symbol::UNDEFINED, None)
+    } else {
+        let name = name
+            .clone()
+            .unwrap_or_else(|| format!("walrus.{}", fallback));
+        (Symbol::BINDING_LOCAL, Some(name))
+    };
+    let kind = match target {
+        IndexTarget::Function(func) => SymbolKind::Function { func, name },
+        IndexTarget::Global(global) => SymbolKind::Global { global, name },
+        IndexTarget::Table(table) => SymbolKind::Table { table, name },
+        IndexTarget::Tag(tag) => SymbolKind::Tag { tag, name },
+        IndexTarget::Type(_) => unreachable!(),
+    };
+    Symbol { flags, kind }
+}
+
+struct SectionLayout {
+    id: u8,
+    name: Option<String>,
+    payload: Range<usize>,
+}
+
+/// Find the id, custom section name and payload range of every section in an
+/// encoded module.
+fn section_layout(wasm: &[u8]) -> Vec<SectionLayout> {
+    let mut sections = Vec::new();
+    let mut pos = 8;
+    while pos < wasm.len() {
+        let id = wasm[pos];
+        let mut reader = &wasm[pos + 1..];
+        let size = leb128::read::unsigned(&mut reader).unwrap() as usize;
+        let start = wasm.len() - reader.len();
+        let name = if id == 0 {
+            let len = leb128::read::unsigned(&mut reader).unwrap() as usize;
+            let name_start = wasm.len() - reader.len();
+            Some(String::from_utf8_lossy(&wasm[name_start..name_start + len]).into_owned())
+        } else {
+            None
+        };
+        sections.push(SectionLayout {
+            id,
+            name,
+            payload: start..start + size,
+        });
+        pos = start + size;
+    }
+    sections
+}
+
+/// Find the absolute range of every segment's bytes in an encoded data section.
+fn data_segment_ranges(wasm: &[u8], payload: Range<usize>) -> Vec<Range<usize>> {
+    let reader = wasmparser::DataSectionReader::new(BinaryReader::new(
+        &wasm[payload.clone()],
+        payload.start,
+    ))
+    .unwrap();
+    reader
+        .into_iter()
+        .map(|data| {
+            let data = data.unwrap();
+            let start = data.data.as_ptr() as usize - wasm.as_ptr() as usize;
+            start..start + data.data.len()
+        })
+        .collect()
+}
+
+fn emit_reloc_section(
+    cx: &mut EmitContext,
+    name: &str,
+    section: u32,
+    mut relocs: Vec<(RelocationType, u32, u32, i64)>,
+) {
+    relocs.sort_by_key(|r| r.1);
+    let mut data = Vec::new();
+    write_uleb(&mut data, section.into());
+    write_uleb(&mut data, relocs.len() as u64);
+    for (ty, offset, index, addend) in relocs {
+        data.push(ty as u8);
+        write_uleb(&mut data, offset.into());
+        write_uleb(&mut data, index.into());
+        if ty.has_addend() {
+            leb128::write::signed(&mut data, addend).unwrap();
+        }
+    }
+    cx.wasm_module.section(&wasm_encoder::CustomSection {
+        name: name.into(),
+        data: data.into(),
+    });
+}
+
+fn write_uleb(buf: &mut Vec<u8>, value: u64) {
+    leb128::write::unsigned(buf, value).unwrap();
+}
+
+fn write_str(buf: &mut Vec<u8>, s: &str) {
+    write_uleb(buf, s.len() as u64);
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn write_subsection(buf: &mut Vec<u8>, id: u8, data: &[u8]) {
+    buf.push(id);
+    write_uleb(buf, data.len() as u64);
+    buf.extend_from_slice(data);
+}
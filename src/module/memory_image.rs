@@ -0,0 +1,182 @@
+//! Flattening active data segments into the initial contents of memories.
+
+use crate::ir::Value;
+use crate::{DataId, DataKind, GlobalResolver, Memory, MemoryId, Module, Result};
+use crate::{ModuleData, ModuleMemories};
+use anyhow::{bail, Context};
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+/// The contents of a memory right after instantiation, as initialized by its
+/// active data segments.
+///
+/// The image is sparse: it only holds the bytes written by data segments,
+/// and everything else is zero.
+#[derive(Clone, Debug)]
+pub struct MemoryImage {
+    /// The memory this is an image of.
+    pub memory: MemoryId,
+    /// The size of the memory in bytes, from its initial number of pages.
+    pub size: u64,
+    /// Initialized bytes, keyed by offset. These never overlap, and remember
+    /// the segment that wrote them.
+    chunks: BTreeMap<u64, (DataId, Vec<u8>)>,
+    overlaps: Vec<(DataId, DataId)>,
+}
+
+impl MemoryImage {
+    /// Create an empty image of `memory`.
+    ///
+    /// Imported memories may be larger than their declared initial size at
+    /// runtime, but the image only covers that declared size.
+    pub fn new(memory: &Memory) -> MemoryImage {
+        let page_size = 1u64 << memory.page_size_log2.unwrap_or(16);
+        MemoryImage {
+            memory: memory.id(),
+            size: memory.initial.saturating_mul(page_size),
+            chunks: BTreeMap::new(),
+            overlaps: Vec::new(),
+        }
+    }
+
+    /// Write `bytes` from the data segment `data` at `offset`, replacing
+    /// anything written there before just as instantiation would.
+    ///
+    /// Returns an error if the bytes don't fit in the memory.
+    pub fn write(&mut self, data: DataId, offset: u64, bytes: &[u8]) -> Result<()> {
+        let end = match offset.checked_add(bytes.len() as u64) {
+            Some(end) if end <= self.size => end,
+            _ => bail!(
+                "data segment {:?} at {:#x} with length {:#x} is out of bounds of a \
+                 {:#x}-byte memory",
+                data,
+                offset,
+                bytes.len(),
+                self.size
+            ),
+        };
+        if bytes.is_empty() {
+            return Ok(());
+        }
+
+        let overlapping = self
+            .chunks
+            .range(..end)
+            .rev()
+            .take_while(|(start, (_, old))| **start + old.len() as u64 > offset)
+            .map(|(start, _)| *start)
+            .collect::<Vec<_>>();
+        for start in overlapping {
+            let (old_data, old) = self.chunks.remove(&start).unwrap();
+            if !self.overlaps.contains(&(old_data, data)) {
+                self.overlaps.push((old_data, data));
+            }
+            let old_end = start + old.len() as u64;
+            if start < offset {
+                let prefix = old[..(offset - start) as usize].to_vec();
+                self.chunks.insert(start, (old_data, prefix));
+            }
+            if old_end > end {
+                let suffix = old[(end - start) as usize..].to_vec();
+                self.chunks.insert(end, (old_data, suffix));
+            }
+        }
+        self.chunks.insert(offset, (data, bytes.to_vec()));
+        Ok(())
+    }
+
+    /// Read `len` bytes at `offset`, with anything uninitialized reading as
+    /// zero.
+    pub fn read(&self, offset: u64, len: usize) -> Vec<u8> {
+        let mut ret = vec![0; len];
+        let end = offset.saturating_add(len as u64);
+        for (range, _, bytes) in self.chunks() {
+            if range.end <= offset {
+                continue;
+            }
+            if range.start >= end {
+                break;
+            }
+            let from = range.start.max(offset);
+            let to = range.end.min(end);
+            ret[(from - offset) as usize..(to - offset) as usize].copy_from_slice(
+                &bytes[(from - range.start) as usize..(to - range.start) as usize],
+            );
+        }
+        ret
+    }
+
+    /// Iterate over the initialized parts of this image in address order,
+    /// along with the data segment each came from.
+    ///
+    /// Adjacent parts are not merged, so a segment that was partially
+    /// overwritten shows up as the pieces of it that remain.
+    pub fn chunks(&self) -> impl Iterator<Item = (Range<u64>, DataId, &[u8])> + '_ {
+        self.chunks
+            .iter()
+            .map(|(start, (data, bytes))| (*start..*start + bytes.len() as u64, *data, &bytes[..]))
+    }
+
+    /// Pairs of data segments where the second overwrote some of the first,
+    /// in the order they were found.
+    pub fn overlaps(&self) -> &[(DataId, DataId)] {
+        &self.overlaps
+    }
+}
+
+impl ModuleData {
+    /// Paint every active data segment targeting `image`'s memory into it, in
+    /// the order instantiation would.
+    ///
+    /// Offsets are evaluated with `ConstExpr::eval`, using `imports` for the
+    /// values of imported globals. Returns an error if an offset can't be
+    /// evaluated or a segment is out of bounds.
+    pub fn paint(
+        &self,
+        module: &Module,
+        imports: &dyn GlobalResolver,
+        image: &mut MemoryImage,
+    ) -> Result<()> {
+        for data in self.iter() {
+            let offset = match &data.kind {
+                DataKind::Active { memory, offset } if *memory == image.memory => offset,
+                _ => continue,
+            };
+            let offset = match offset
+                .eval(module, imports)
+                .with_context(|| format!("failed to evaluate the offset of {:?}", data.id()))?
+            {
+                Some(Value::I32(offset)) => u64::from(offset as u32),
+                Some(Value::I64(offset)) => offset as u64,
+                Some(v) => bail!(
+                    "data segment {:?} has a non-integer offset {}",
+                    data.id(),
+                    v
+                ),
+                None => bail!("the offset of data segment {:?} isn't constant", data.id()),
+            };
+            image.write(data.id(), offset, &data.value)?;
+        }
+        Ok(())
+    }
+}
+
+impl ModuleMemories {
+    /// Build an image of the initial contents of each memory, in the same
+    /// order as `iter`.
+    ///
+    /// See `ModuleData::paint` for how segments are placed.
+    pub fn images(
+        &self,
+        module: &Module,
+        imports: &dyn GlobalResolver,
+    ) -> Result<Vec<MemoryImage>> {
+        self.iter()
+            .map(|memory| {
+                let mut image = MemoryImage::new(memory);
+                module.data.paint(module, imports, &mut image)?;
+                Ok(image)
+            })
+            .collect()
+    }
+}
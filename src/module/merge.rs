@@ -0,0 +1,770 @@
+//! Statically linking two modules into one.
+
+use crate::ir::*;
+use crate::map::IdHashMap;
+use crate::{
+    ArrayType, CompositeType, ConstExpr, ConstOp, Data, DataId, DataKind, Element, ElementId,
+    ElementItems, ElementKind, ExportItem, FieldType, Function, FunctionBuilder, FunctionId,
+    FunctionKind, FunctionType, Global, GlobalId, GlobalKind, HeapType, ImportId, ImportKind,
+    LocalFunction, Memory, MemoryId, Module, ModuleExports, ModuleImports, ModuleTypes,
+    RawCustomSection, RefType, Result, StorageType, StructType, Table, TableId, Tag, TagId,
+    TagKind, Type, TypeId, ValType,
+};
+use anyhow::bail;
+use std::mem;
+
+/// Options for `Module::merge`.
+#[derive(Clone, Debug, Default)]
+pub struct MergeOptions {
+    /// The module name that `self` imports `other`'s exports from. If `None`,
+    /// each of `self`'s imports is resolved against `other`'s export of the
+    /// same name, whatever module it's imported from.
+    pub other_name: Option<String>,
+    /// The module name that `other` imports `self`'s exports from, in the
+    /// same way.
+    pub self_name: Option<String>,
+    /// Don't export `other`'s exports from the merged module. They still
+    /// resolve `self`'s imports.
+    pub hide_exports: bool,
+}
+
+impl Module {
+    /// Statically link `other` into this module.
+    ///
+    /// Every item of `other` is copied into this module with a new id. Types
+    /// are deduplicated against this module's types, except for those in
+    /// explicit or self-referencing recursion groups. Imports of either
+    /// module that name an export of the other are resolved to the exported
+    /// item and removed; `other`'s remaining imports are added to this
+    /// module, reusing this module's identical imports.
+    ///
+    /// The merged module keeps the memories and tables of both, so merging
+    /// two modules that each define a memory needs multi-memory, or
+    /// `passes::multi_memory_lowering` afterwards. If both modules have a
+    /// `start` function, the merged module's calls `other`'s and then this
+    /// module's. `other`'s `producers` are added to this module's, and its raw
+    /// custom sections are appended to this module's of the same name. Its
+    /// DWARF and other typed custom sections are dropped.
+    ///
+    /// Returns an error if either module is relocatable, if both export the
+    /// same name, or if an import is resolved to an item of a different kind
+    /// or type. Tables and memories must also be at least as large as their
+    /// imports need, have a maximum within the imports' maximum, and have the
+    /// same page size. This module is only changed by having `other`'s types
+    /// added then.
+    pub fn merge(&mut self, mut other: Module, options: &MergeOptions) -> Result<()> {
+        if self.linking.is_some() || other.linking.is_some() {
+            bail!("cannot merge relocatable modules");
+        }
+        if !options.hide_exports {
+            for export in other.exports.iter() {
+                if self.exports.iter().any(|e| e.name == export.name) {
+                    bail!("both modules export `{}`", export.name);
+                }
+            }
+        }
+
+        let mut remap = Remap::default();
+        remap.add_types(&mut self.types, &other.types);
+
+        // `self`'s imports resolved to `other`'s exports, and the other way
+        // around.
+        let imported = resolve(&self.imports, &other.exports, options.other_name.as_deref());
+        let exported = resolve(&other.imports, &self.exports, options.self_name.as_deref());
+        let identity = Remap::default();
+        for (id, item) in &imported {
+            let import = self.imports.get(*id);
+            let expected = identity.signature(self, &self.types, import_item(&import.kind));
+            if !remap
+                .signature(&other, &self.types, *item)
+                .satisfies(&expected)
+            {
+                bail!(
+                    "`{}.{}` is imported with a different type than it's exported with",
+                    import.module,
+                    import.name
+                );
+            }
+        }
+        for (id, item) in &exported {
+            let import = other.imports.get(*id);
+            let from = import_item(&import.kind);
+            let expected = remap.signature(&other, &self.types, from);
+            if !identity
+                .signature(self, &self.types, *item)
+                .satisfies(&expected)
+            {
+                bail!(
+                    "`{}.{}` is imported with a different type than it's exported with",
+                    import.module,
+                    import.name
+                );
+            }
+            remap.insert(from, *item);
+        }
+
+        for import in other.imports.iter() {
+            if exported.iter().any(|(id, _)| *id == import.id()) {
+                continue;
+            }
+            let from = import_item(&import.kind);
+            let signature = remap.signature(&other, &self.types, from);
+            let existing = self
+                .imports
+                .iter()
+                .find(|i| {
+                    i.module == import.module
+                        && i.name == import.name
+                        && identity.signature(self, &self.types, import_item(&i.kind)) == signature
+                })
+                .map(|i| import_item(&i.kind));
+            let to = existing.unwrap_or_else(|| self.add_import_like(&other, &remap, import.id()));
+            remap.insert(from, to);
+        }
+
+        // Add every local item first, so that they can all refer to each
+        // other, then fix up their references.
+        for func in other.funcs.iter() {
+            if let FunctionKind::Local(_) = func.kind {
+                let id = self.funcs.add_uninitialized(remap.ty(func.ty()));
+                self.funcs.get_mut(id).name = func.name.clone();
+                remap.funcs.insert(func.id(), id);
+            }
+        }
+        let mut tables = Vec::new();
+        for table in other.tables.iter().filter(|t| t.import.is_none()) {
+            let id = self.tables.add_local_with_init(
+                table.table64,
+                table.initial,
+                table.maximum,
+                remap.ref_type(table.element_ty),
+                table.init.clone(),
+            );
+            self.tables.get_mut(id).name = table.name.clone();
+            remap.tables.insert(table.id(), id);
+            tables.push(id);
+        }
+        for memory in other.memories.iter().filter(|m| m.import.is_none()) {
+            let id = self.memories.add_local(
+                memory.shared,
+                memory.memory64,
+                memory.initial,
+                memory.maximum,
+                memory.page_size_log2,
+            );
+            self.memories.get_mut(id).name = memory.name.clone();
+            remap.memories.insert(memory.id(), id);
+        }
+        let mut globals = Vec::new();
+        for global in other.globals.iter() {
+            if let GlobalKind::Local(init) = &global.kind {
+                let ty = remap.val_type(global.ty);
+                let id = self
+                    .globals
+                    .add_local(ty, global.mutable, global.shared, init.clone());
+                self.globals.get_mut(id).name = global.name.clone();
+                remap.globals.insert(global.id(), id);
+                globals.push(id);
+            }
+        }
+        for tag in other.tags.iter() {
+            if let TagKind::Local = tag.kind {
+                let id = self.tags.add(remap.ty(tag.ty));
+                self.tags.get_mut(id).name = tag.name.clone();
+                remap.tags.insert(tag.id(), id);
+            }
+        }
+        for local in other.locals.iter() {
+            let id = self.locals.add(remap.val_type(local.ty()));
+            self.locals.get_mut(id).name = local.name.clone();
+            remap.locals.insert(local.id(), id);
+        }
+
+        for id in tables {
+            if let Some(init) = &mut self.tables.get_mut(id).init {
+                remap.const_expr(init);
+            }
+        }
+        for id in globals {
+            if let GlobalKind::Local(init) = &mut self.globals.get_mut(id).kind {
+                remap.const_expr(init);
+            }
+        }
+        for data in other.data.iter_mut() {
+            let kind = remap.data_kind(&data.kind);
+            let id = self.data.add(kind, mem::take(&mut data.value));
+            if let DataKind::Active { memory, .. } = self.data.get(id).kind {
+                self.memories.get_mut(memory).data_segments.insert(id);
+            }
+            self.data.get_mut(id).name = data.name.take();
+            remap.data.insert(data.id(), id);
+        }
+        for element in other.elements.iter_mut() {
+            let kind = remap.element_kind(&element.kind);
+            let mut items = mem::replace(&mut element.items, ElementItems::Functions(Vec::new()));
+            remap.element_items(&mut items);
+            let id = self.elements.add(kind, items);
+            if let ElementKind::Active { table, .. } = self.elements.get(id).kind {
+                self.tables.get_mut(table).elem_segments.insert(id);
+            }
+            self.elements.get_mut(id).name = element.name.take();
+            remap.elements.insert(element.id(), id);
+        }
+        for func in other.funcs.iter_mut() {
+            let ty = func.ty();
+            let mut local = match mem::replace(&mut func.kind, FunctionKind::Uninitialized(ty)) {
+                FunctionKind::Local(local) => local,
+                _ => continue,
+            };
+            // These describe `other`'s code section, for its DWARF.
+            local.instruction_mapping.clear();
+            local.original_range = None;
+            remap.function(&mut local);
+            self.funcs.get_mut(remap.funcs[&func.id()]).kind = FunctionKind::Local(local);
+        }
+
+        if !options.hide_exports {
+            for export in other.exports.iter() {
+                self.exports.add(&export.name, remap.item(export.item));
+            }
+        }
+        if let Some(start) = other.start {
+            let start = remap.func(start);
+            self.start = Some(match self.start {
+                Some(then) => {
+                    let mut builder = FunctionBuilder::new(&mut self.types, &[], &[]);
+                    builder.func_body().call(start).call(then);
+                    builder.finish(Vec::new(), &mut self.funcs)
+                }
+                None => start,
+            });
+        }
+        for field in other.producers.fields() {
+            for value in field.values() {
+                self.producers
+                    .field(field.name(), value.name(), value.version());
+            }
+        }
+        let names = other
+            .customs
+            .iter()
+            .filter(|(_, s)| s.as_any().is::<RawCustomSection>())
+            .map(|(_, s)| s.name().to_string())
+            .collect::<Vec<_>>();
+        for name in names {
+            let raw = other.customs.remove_raw(&name).unwrap();
+            let existing = self.customs.iter_mut().find_map(|(_, s)| {
+                s.as_any_mut()
+                    .downcast_mut::<RawCustomSection>()
+                    .filter(|s| s.name == raw.name)
+            });
+            match existing {
+                Some(existing) => existing.data.extend(raw.data),
+                None => {
+                    self.customs.add(raw);
+                }
+            }
+        }
+
+        // Finally, point everything that used `self`'s resolved imports at
+        // what they resolved to, and remove them.
+        let mut resolved = Remap::default();
+        for (id, item) in &imported {
+            resolved.insert(import_item(&self.imports.get(*id).kind), remap.item(*item));
+        }
+        resolved.apply(self);
+        for (id, _) in imported {
+            match import_item(&self.imports.get(id).kind) {
+                ExportItem::Function(func) => self.funcs.delete(func),
+                ExportItem::Table(table) => {
+                    let segments = mem::take(&mut self.tables.get_mut(table).elem_segments);
+                    let to = resolved.table(table);
+                    self.tables.get_mut(to).elem_segments.extend(segments);
+                    self.tables.delete(table);
+                }
+                ExportItem::Memory(memory) => {
+                    let segments = mem::take(&mut self.memories.get_mut(memory).data_segments);
+                    let to = resolved.memory(memory);
+                    self.memories.get_mut(to).data_segments.extend(segments);
+                    self.memories.delete(memory);
+                }
+                ExportItem::Global(global) => self.globals.delete(global),
+                ExportItem::Tag(tag) => self.tags.delete(tag),
+            }
+            self.imports.delete(id);
+        }
+        Ok(())
+    }
+
+    /// Add an import of `other`'s import `id` to this module.
+    fn add_import_like(&mut self, other: &Module, remap: &Remap, id: ImportId) -> ExportItem {
+        let import = other.imports.get(id);
+        let (module, name) = (&import.module[..], &import.name[..]);
+        match import.kind {
+            ImportKind::Function(func) => {
+                let ty = remap.ty(other.funcs.get(func).ty());
+                let (id, _) = self.add_import_func(module, name, ty);
+                self.funcs.get_mut(id).name = other.funcs.get(func).name.clone();
+                ExportItem::Function(id)
+            }
+            ImportKind::Table(table) => {
+                let table = other.tables.get(table);
+                let ty = remap.ref_type(table.element_ty);
+                let (id, _) = self.add_import_table(
+                    module,
+                    name,
+                    table.table64,
+                    table.initial,
+                    table.maximum,
+                    ty,
+                );
+                ExportItem::Table(id)
+            }
+            ImportKind::Memory(memory) => {
+                let memory = other.memories.get(memory);
+                let (id, _) = self.add_import_memory(
+                    module,
+                    name,
+                    memory.shared,
+                    memory.memory64,
+                    memory.initial,
+                    memory.maximum,
+                    memory.page_size_log2,
+                );
+                ExportItem::Memory(id)
+            }
+            ImportKind::Global(global) => {
+                let global = other.globals.get(global);
+                let ty = remap.val_type(global.ty);
+                let (id, _) =
+                    self.add_import_global(module, name, ty, global.mutable, global.shared);
+                ExportItem::Global(id)
+            }
+            ImportKind::Tag(tag) => {
+                let ty = remap.ty(other.tags.get(tag).ty);
+                ExportItem::Tag(self.add_import_tag(module, name, ty).0)
+            }
+        }
+    }
+}
+
+/// Pair each of `imports` from `module`, or from any module if `None`, with
+/// the item of the export with the same name.
+fn resolve(
+    imports: &ModuleImports,
+    exports: &ModuleExports,
+    module: Option<&str>,
+) -> Vec<(ImportId, ExportItem)> {
+    imports
+        .iter()
+        .filter(|import| module.is_none_or(|module| import.module == module))
+        .filter_map(|import| {
+            let export = exports.iter().find(|e| e.name == import.name)?;
+            Some((import.id(), export.item))
+        })
+        .collect()
+}
+
+fn import_item(kind: &ImportKind) -> ExportItem {
+    match *kind {
+        ImportKind::Function(id) => ExportItem::Function(id),
+        ImportKind::Table(id) => ExportItem::Table(id),
+        ImportKind::Memory(id) => ExportItem::Memory(id),
+        ImportKind::Global(id) => ExportItem::Global(id),
+        ImportKind::Tag(id) => ExportItem::Tag(id),
+    }
+}
+
+/// What an import has to agree on with the item it's resolved to.
+#[derive(PartialEq)]
+enum Signature {
+    Function(Vec<ValType>, Vec<ValType>),
+    Table(bool, RefType, Limits),
+    /// Whether the memory is 64-bit and shared, and the log2 of its page size.
+    Memory(bool, bool, u32, Limits),
+    Global(ValType, bool),
+    Tag(Vec<ValType>, Vec<ValType>),
+}
+
+impl Signature {
+    /// Whether an item with this signature can be imported as `import`.
+    fn satisfies(&self, import: &Signature) -> bool {
+        match (self, import) {
+            (Signature::Table(a, b, limits), Signature::Table(c, d, import)) => {
+                (a, b) == (c, d) && limits.satisfies(import)
+            }
+            (Signature::Memory(a, b, c, limits), Signature::Memory(d, e, f, import)) => {
+                (a, b, c) == (d, e, f) && limits.satisfies(import)
+            }
+            _ => self == import,
+        }
+    }
+}
+
+/// The size limits of a table or memory.
+#[derive(PartialEq)]
+struct Limits {
+    initial: u64,
+    maximum: Option<u64>,
+}
+
+impl Limits {
+    /// Whether these limits are within those `import` allows.
+    fn satisfies(&self, import: &Limits) -> bool {
+        self.initial >= import.initial
+            && match import.maximum {
+                Some(max) => self.maximum.is_some_and(|m| m <= max),
+                None => true,
+            }
+    }
+}
+
+/// Maps the ids of one module's items to the ids of another's, or of the
+/// same module's. Ids that aren't mapped are left alone.
+#[derive(Default)]
+struct Remap {
+    types: IdHashMap<Type, TypeId>,
+    funcs: IdHashMap<Function, FunctionId>,
+    tables: IdHashMap<Table, TableId>,
+    memories: IdHashMap<Memory, MemoryId>,
+    globals: IdHashMap<Global, GlobalId>,
+    tags: IdHashMap<Tag, TagId>,
+    data: IdHashMap<Data, DataId>,
+    elements: IdHashMap<Element, ElementId>,
+    locals: IdHashMap<Local, LocalId>,
+}
+
+impl Remap {
+    /// Add each of `other`'s types to `types`.
+    fn add_types(&mut self, types: &mut ModuleTypes, other: &ModuleTypes) {
+        for group in other.rec_groups() {
+            let recursive = group.is_explicit
+                || group.types.iter().any(|id| {
+                    let mut referenced = Vec::new();
+                    other.get(*id).referenced_types(&mut referenced);
+                    referenced.contains(id)
+                });
+            if recursive {
+                types.add_rec_group(group.types.len(), |ids| {
+                    self.types
+                        .extend(group.types.iter().copied().zip(ids.iter().copied()));
+                    group
+                        .types
+                        .iter()
+                        .map(|id| {
+                            let ty = other.get(*id);
+                            let supertype = ty.supertype.map(|s| self.ty(s));
+                            (self.composite(ty.kind()), ty.is_final, supertype)
+                        })
+                        .collect()
+                });
+                continue;
+            }
+            for id in &group.types {
+                let ty = other.get(*id);
+                let new = if ty.is_for_function_entry() {
+                    types.add_entry_ty(&self.val_types(ty.results()))
+                } else {
+                    let supertype = ty.supertype.map(|s| self.ty(s));
+                    types.add_composite(self.composite(ty.kind()), ty.is_final, supertype)
+                };
+                self.types.insert(*id, new);
+            }
+        }
+    }
+
+    fn insert(&mut self, from: ExportItem, to: ExportItem) {
+        match (from, to) {
+            (ExportItem::Function(from), ExportItem::Function(to)) => {
+                self.funcs.insert(from, to);
+            }
+            (ExportItem::Table(from), ExportItem::Table(to)) => {
+                self.tables.insert(from, to);
+            }
+            (ExportItem::Memory(from), ExportItem::Memory(to)) => {
+                self.memories.insert(from, to);
+            }
+            (ExportItem::Global(from), ExportItem::Global(to)) => {
+                self.globals.insert(from, to);
+            }
+            (ExportItem::Tag(from), ExportItem::Tag(to)) => {
+                self.tags.insert(from, to);
+            }
+            _ => unreachable!("items of different kinds"),
+        }
+    }
+
+    /// The signature of `module`'s `item`, in terms of `types`.
+    fn signature(&self, module: &Module, types: &ModuleTypes, item: ExportItem) -> Signature {
+        let func_type = |ty: TypeId| {
+            let (params, results) = types.params_results(self.ty(ty));
+            (params.to_vec(), results.to_vec())
+        };
+        match item {
+            ExportItem::Function(id) => {
+                let (params, results) = func_type(module.funcs.get(id).ty());
+                Signature::Function(params, results)
+            }
+            ExportItem::Table(id) => {
+                let table = module.tables.get(id);
+                let limits = Limits {
+                    initial: table.initial,
+                    maximum: table.maximum,
+                };
+                Signature::Table(table.table64, self.ref_type(table.element_ty), limits)
+            }
+            ExportItem::Memory(id) => {
+                let memory = module.memories.get(id);
+                let limits = Limits {
+                    initial: memory.initial,
+                    maximum: memory.maximum,
+                };
+                let page_size_log2 = memory.page_size_log2.unwrap_or(16);
+                Signature::Memory(memory.memory64, memory.shared, page_size_log2, limits)
+            }
+            ExportItem::Global(id) => {
+                let global = module.globals.get(id);
+                Signature::Global(self.val_type(global.ty), global.mutable)
+            }
+            ExportItem::Tag(id) => {
+                let (params, results) = func_type(module.tags.get(id).ty);
+                Signature::Tag(params, results)
+            }
+        }
+    }
+
+    fn ty(&self, id: TypeId) -> TypeId {
+        self.types.get(&id).copied().unwrap_or(id)
+    }
+
+    fn func(&self, id: FunctionId) -> FunctionId {
+        self.funcs.get(&id).copied().unwrap_or(id)
+    }
+
+    fn table(&self, id: TableId) -> TableId {
+        self.tables.get(&id).copied().unwrap_or(id)
+    }
+
+    fn memory(&self, id: MemoryId) -> MemoryId {
+        self.memories.get(&id).copied().unwrap_or(id)
+    }
+
+    fn global(&self, id: GlobalId) -> GlobalId {
+        self.globals.get(&id).copied().unwrap_or(id)
+    }
+
+    fn item(&self, item: ExportItem) -> ExportItem {
+        match item {
+            ExportItem::Function(id) => ExportItem::Function(self.func(id)),
+            ExportItem::Table(id) => ExportItem::Table(self.table(id)),
+            ExportItem::Memory(id) => ExportItem::Memory(self.memory(id)),
+            ExportItem::Global(id) => ExportItem::Global(self.global(id)),
+            ExportItem::Tag(id) => ExportItem::Tag(self.tags.get(&id).copied().unwrap_or(id)),
+        }
+    }
+
+    fn heap_type(&self, ty: HeapType) -> HeapType {
+        match ty {
+            HeapType::Concrete(id) => HeapType::Concrete(self.ty(id)),
+            HeapType::Exact(id) => HeapType::Exact(self.ty(id)),
+            ty => ty,
+        }
+    }
+
+    fn ref_type(&self, ty: RefType) -> RefType {
+        RefType {
+            nullable: ty.nullable,
+            heap_type: self.heap_type(ty.heap_type),
+        }
+    }
+
+    fn val_type(&self, ty: ValType) -> ValType {
+        match ty {
+            ValType::Ref(ty) => ValType::Ref(self.ref_type(ty)),
+            ty => ty,
+        }
+    }
+
+    fn val_types(&self, tys: &[ValType]) -> Box<[ValType]> {
+        tys.iter().map(|ty| self.val_type(*ty)).collect()
+    }
+
+    fn field_type(&self, field: FieldType) -> FieldType {
+        let element_type = match field.element_type {
+            StorageType::Val(ty) => StorageType::Val(self.val_type(ty)),
+            packed => packed,
+        };
+        FieldType {
+            element_type,
+            mutable: field.mutable,
+        }
+    }
+
+    fn composite(&self, comp: &CompositeType) -> CompositeType {
+        match comp {
+            CompositeType::Function(f) => CompositeType::Function(FunctionType::new(
+                self.val_types(f.params()),
+                self.val_types(f.results()),
+            )),
+            CompositeType::Struct(s) => CompositeType::Struct(StructType {
+                fields: s.fields.iter().map(|f| self.field_type(*f)).collect(),
+            }),
+            CompositeType::Array(a) => CompositeType::Array(ArrayType {
+                field: self.field_type(a.field),
+            }),
+        }
+    }
+
+    fn const_expr(&self, expr: &mut ConstExpr) {
+        match expr {
+            ConstExpr::Value(_) => {}
+            ConstExpr::Global(global) => *global = self.global(*global),
+            ConstExpr::RefNull(ty) => *ty = self.ref_type(*ty),
+            ConstExpr::RefFunc(func) => *func = self.func(*func),
+            ConstExpr::Extended(ops) => {
+                for op in ops {
+                    match op {
+                        ConstOp::GlobalGet(global) => *global = self.global(*global),
+                        ConstOp::RefNull(ty) => *ty = self.ref_type(*ty),
+                        ConstOp::RefFunc(func) => *func = self.func(*func),
+                        ConstOp::StructNew(ty)
+                        | ConstOp::StructNewDefault(ty)
+                        | ConstOp::ArrayNew(ty)
+                        | ConstOp::ArrayNewDefault(ty)
+                        | ConstOp::ArrayNewFixed { ty, .. } => *ty = self.ty(*ty),
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+
+    fn data_kind(&self, kind: &DataKind) -> DataKind {
+        match kind {
+            DataKind::Active { memory, offset } => {
+                let mut offset = offset.clone();
+                self.const_expr(&mut offset);
+                DataKind::Active {
+                    memory: self.memory(*memory),
+                    offset,
+                }
+            }
+            DataKind::Passive => DataKind::Passive,
+        }
+    }
+
+    fn element_kind(&self, kind: &ElementKind) -> ElementKind {
+        match kind {
+            ElementKind::Active { table, offset } => {
+                let mut offset = offset.clone();
+                self.const_expr(&mut offset);
+                ElementKind::Active {
+                    table: self.table(*table),
+                    offset,
+                }
+            }
+            kind => kind.clone(),
+        }
+    }
+
+    fn element_items(&self, items: &mut ElementItems) {
+        match items {
+            ElementItems::Functions(funcs) => {
+                for func in funcs {
+                    *func = self.func(*func);
+                }
+            }
+            ElementItems::Expressions(ty, exprs) => {
+                *ty = self.ref_type(*ty);
+                for expr in exprs {
+                    self.const_expr(expr);
+                }
+            }
+        }
+    }
+
+    fn function(&mut self, func: &mut LocalFunction) {
+        let builder = func.builder_mut();
+        builder.ty = self.ty(builder.ty);
+        for arg in &mut func.args {
+            *arg = self.locals.get(arg).copied().unwrap_or(*arg);
+        }
+        let entry = func.entry_block();
+        dfs_pre_order_mut(self, func, entry);
+    }
+
+    /// Remap every reference to an item in `module`.
+    fn apply(&mut self, module: &mut Module) {
+        for (_, func) in module.funcs.iter_local_mut() {
+            self.function(func);
+        }
+        for table in module.tables.iter_mut() {
+            if let Some(init) = &mut table.init {
+                self.const_expr(init);
+            }
+        }
+        for global in module.globals.iter_mut() {
+            if let GlobalKind::Local(init) = &mut global.kind {
+                self.const_expr(init);
+            }
+        }
+        for data in module.data.iter_mut() {
+            data.kind = self.data_kind(&data.kind);
+        }
+        for element in module.elements.iter_mut() {
+            element.kind = self.element_kind(&element.kind);
+            self.element_items(&mut element.items);
+        }
+        for export in module.exports.iter_mut() {
+            export.item = self.item(export.item);
+        }
+        module.start = module.start.map(|start| self.func(start));
+    }
+}
+
+impl VisitorMut for Remap {
+    fn visit_instr_mut(&mut self, instr: &mut Instr, _: &mut InstrLocId) {
+        // Types the visitor doesn't visit.
+        match instr {
+            Instr::RefNull(RefNull { ty }) => *ty = self.ref_type(*ty),
+            Instr::Select(Select { ty: Some(ty) }) => *ty = self.val_type(*ty),
+            _ => {}
+        }
+    }
+
+    fn visit_local_id_mut(&mut self, local: &mut LocalId) {
+        *local = self.locals.get(local).copied().unwrap_or(*local);
+    }
+
+    fn visit_memory_id_mut(&mut self, memory: &mut MemoryId) {
+        *memory = self.memory(*memory);
+    }
+
+    fn visit_table_id_mut(&mut self, table: &mut TableId) {
+        *table = self.table(*table);
+    }
+
+    fn visit_global_id_mut(&mut self, global: &mut GlobalId) {
+        *global = self.global(*global);
+    }
+
+    fn visit_function_id_mut(&mut self, function: &mut FunctionId) {
+        *function = self.func(*function);
+    }
+
+    fn visit_data_id_mut(&mut self, data: &mut DataId) {
+        *data = self.data.get(data).copied().unwrap_or(*data);
+    }
+
+    fn visit_type_id_mut(&mut self, ty: &mut TypeId) {
+        *ty = self.ty(*ty);
+    }
+
+    fn visit_element_id_mut(&mut self, elem: &mut ElementId) {
+        *elem = self.elements.get(elem).copied().unwrap_or(*elem);
+    }
+
+    fn visit_tag_id_mut(&mut self, tag: &mut TagId) {
+        *tag = self.tags.get(tag).copied().unwrap_or(*tag);
+    }
+}
@@ -1,5 +1,6 @@
 //! A high-level API for manipulating wasm modules.
 
+mod build_id;
 mod config;
 mod custom;
 mod data;
@@ -9,9 +10,17 @@ mod exports;
 mod functions;
 mod globals;
 mod imports;
+mod linking;
 mod locals;
 mod memories;
+mod memory_image;
+mod merge;
 mod producers;
+mod snapshot;
+#[cfg(feature = "source-map")]
+mod source_map;
+mod source_mapping_url;
+mod table_image;
 mod tables;
 mod tags;
 mod types;
@@ -19,12 +28,14 @@ mod types;
 use crate::emit::{Emit, EmitContext, IdsToIndices};
 use crate::error::Result;
 pub use crate::ir::InstrLocId;
+pub use crate::module::build_id::BuildId;
 pub use crate::module::custom::{
     CustomSection, CustomSectionId, ModuleCustomSections, RawCustomSection, TypedCustomSectionId,
     UntypedCustomSectionId,
 };
 pub use crate::module::data::{Data, DataId, DataKind, ModuleData};
-pub use crate::module::debug::ModuleDebugData;
+use crate::module::debug::DwarfSections;
+pub use crate::module::debug::{ExternalDebugInfo, ModuleDebugData};
 pub use crate::module::elements::{Element, ElementId, ModuleElements};
 pub use crate::module::elements::{ElementItems, ElementKind};
 pub use crate::module::exports::{Export, ExportId, ExportItem, ModuleExports};
@@ -33,9 +44,21 @@ pub use crate::module::functions::{Function, FunctionId, ModuleFunctions};
 pub use crate::module::functions::{FunctionKind, ImportedFunction, LocalFunction};
 pub use crate::module::globals::{Global, GlobalId, GlobalKind, ModuleGlobals};
 pub use crate::module::imports::{Import, ImportId, ImportKind, ModuleImports};
+pub use crate::module::linking::{
+    CodeRelocation, Comdat, ComdatMember, CustomRelocation, DataRelocation, DataSymbolDefinition,
+    InitFunc, ModuleLinking, RelocationTarget, RelocationType, SegmentInfo, Symbol, SymbolKind,
+};
+pub(crate) use crate::module::linking::{EmittedReloc, LinkingInput, RelocRecorder};
 pub use crate::module::locals::ModuleLocals;
 pub use crate::module::memories::{Memory, MemoryId, ModuleMemories};
+pub use crate::module::memory_image::MemoryImage;
+pub use crate::module::merge::MergeOptions;
 pub use crate::module::producers::ModuleProducers;
+pub use crate::module::snapshot::Snapshot;
+#[cfg(feature = "source-map")]
+pub use crate::module::source_map::{OriginalLocation, SourceMap, SourceMapping};
+pub use crate::module::source_mapping_url::SourceMappingUrl;
+pub use crate::module::table_image::{TableImage, TableSlot};
 pub use crate::module::tables::{ModuleTables, Table, TableId};
 pub use crate::module::tags::{ModuleTags, Tag, TagId, TagKind};
 pub use crate::module::types::ModuleTypes;
@@ -73,10 +96,19 @@ pub struct Module {
     pub start: Option<FunctionId>,
     /// Representation of the eventual custom section, `producers`
     pub producers: ModuleProducers,
+    /// The `sourceMappingURL` custom section, if any.
+    pub source_mapping_url: Option<SourceMappingUrl>,
+    /// The `external_debug_info` custom section, if any.
+    pub external_debug_info: Option<ExternalDebugInfo>,
+    /// The `build_id` custom section, if any.
+    pub build_id: Option<BuildId>,
     /// Custom sections found in this module.
     pub customs: ModuleCustomSections,
     /// Dwarf debug data.
     pub debug: ModuleDebugData,
+    /// Linking metadata and relocations, present if this module is a
+    /// relocatable object file.
+    pub linking: Option<ModuleLinking>,
     /// The name of this module, used for debugging purposes in the `name`
     /// custom section.
     pub name: Option<String>,
@@ -154,12 +186,21 @@ impl Module {
 
         let mut local_functions = Vec::new();
         let mut debug_sections = Vec::new();
+        let mut linking = LinkingInput::default();
 
         let mut parser = Parser::new(0);
         parser.set_features(wasm_features);
 
         for payload in parser.parse_all(wasm) {
-            match payload? {
+            let payload = payload?;
+            // Relocations refer to sections by their index in the input.
+            let section_index = linking.sections.len() as u32;
+            match &payload {
+                Payload::Version { .. } | Payload::CodeSectionEntry(_) | Payload::End(_) => {}
+                Payload::CustomSection(s) => linking.sections.push(Some(s.name())),
+                _ => linking.sections.push(None),
+            }
+            match payload {
                 Payload::Version {
                     num,
                     encoding,
@@ -171,6 +212,12 @@ impl Module {
                     validator
                         .data_section(&s)
                         .context("failed to parse data section")?;
+                    linking.data_section = Some((section_index, s.range().start));
+                    for segment in s.clone() {
+                        let data = segment?.data;
+                        let start = data.as_ptr() as usize - wasm.as_ptr() as usize;
+                        linking.data_segments.push(start..start + data.len());
+                    }
                     ret.parse_data(s, &mut indices)?;
                 }
                 Payload::TypeSection(s) => {
@@ -232,6 +279,7 @@ impl Module {
                 Payload::CodeSectionStart { range, .. } => {
                     validator.code_section_start(&range)?;
                     ret.funcs.code_section_offset = range.start;
+                    linking.code_section = Some(section_index);
                 }
                 Payload::CodeSectionEntry(body) => {
                     let validator = validator
@@ -255,6 +303,21 @@ impl Module {
                                 ));
                             ret.parse_name_section(name_section_reader, &indices)
                         }
+                        "sourceMappingURL" => {
+                            ret.parse_source_mapping_url(s.data(), s.data_offset())
+                        }
+                        "external_debug_info" => {
+                            ret.parse_external_debug_info(s.data(), s.data_offset())
+                        }
+                        "build_id" => ret.parse_build_id(s.data(), s.data_offset()),
+                        "linking" => {
+                            linking.linking = Some((s.data(), s.data_offset()));
+                            continue;
+                        }
+                        name if name.starts_with("reloc.") => {
+                            linking.relocs.push((name, s.data(), s.data_offset()));
+                            continue;
+                        }
                         name => {
                             log::debug!("parsing custom section `{}`", name);
                             if name.starts_with(".debug") {
@@ -306,7 +369,19 @@ impl Module {
         )
         .context("failed to parse code section")?;
 
-        ret.parse_debug_sections(debug_sections)
+        // The DWARF of a relocatable object is only meaningful with its
+        // relocations applied, so it is kept as raw custom sections that
+        // `reloc.*` sections can keep referring to.
+        if linking.linking.is_some() {
+            for section in debug_sections.drain(..) {
+                ret.customs.add(section);
+            }
+        }
+
+        ret.parse_linking(linking, &indices)
+            .context("failed to parse linking section")?;
+
+        ret.parse_debug_sections(debug_sections, &indices)
             .context("failed to parse debug data section")?;
 
         ret.producers
@@ -445,6 +520,16 @@ impl Module {
 
     /// Emit this module into an in-memory wasm buffer.
     pub fn emit_wasm(&mut self) -> Vec<u8> {
+        self.emit_wasm_parts(false).0
+    }
+
+    /// Emit this module, returning the wasm along with the transformations
+    /// made to its code and, if `split_dwarf` is set, the DWARF sections that
+    /// were left out of it.
+    pub(crate) fn emit_wasm_parts(
+        &mut self,
+        split_dwarf: bool,
+    ) -> (Vec<u8>, CodeTransform, DwarfSections) {
         log::debug!("start emit");
 
         self.ensure_func_declarations();
@@ -459,6 +544,7 @@ impl Module {
             wasm_module: wasm_encoder::Module::new(),
             locals: Default::default(),
             code_transform: Default::default(),
+            code_relocs: Vec::new(),
         };
         self.types.emit(&mut cx);
         self.imports.emit(&mut cx);
@@ -486,7 +572,10 @@ impl Module {
             self.producers.emit(&mut cx);
         }
 
-        if self.config.generate_dwarf {
+        let mut dwarf = Vec::new();
+        if split_dwarf {
+            dwarf = self.debug.convert_sections(&cx);
+        } else if self.config.generate_dwarf {
             self.debug.emit(&mut cx);
         } else {
             log::debug!("skipping DWARF custom section");
@@ -495,7 +584,7 @@ impl Module {
         let indices = std::mem::take(cx.indices);
 
         for (_id, section) in customs.iter_mut() {
-            if section.name().starts_with(".debug") {
+            if section.name().starts_with(".debug") && self.linking.is_none() {
                 continue;
             }
 
@@ -511,7 +600,36 @@ impl Module {
             });
         }
 
+        if let Some(url) = &self.source_mapping_url {
+            url.emit(&mut cx);
+        }
+        if let Some(url) = &self.external_debug_info {
+            url.emit(&mut cx);
+        }
+
+        // Relocatable objects need their linking metadata last, after every
+        // section it may refer to.
+        if let Some(linking) = &self.linking {
+            linking.emit(&mut cx, &indices);
+        }
+
+        // The build id goes at the very end so that a generated one covers
+        // every other section.
+        #[cfg(feature = "build-id")]
+        let build_id = if self.config.generate_build_id {
+            Some(BuildId::from_contents(cx.wasm_module.as_slice()))
+        } else {
+            self.build_id.clone()
+        };
+        #[cfg(not(feature = "build-id"))]
+        let build_id = self.build_id.clone();
+        if let Some(build_id) = &build_id {
+            build_id.emit(&mut cx);
+        }
+
         let out = cx.wasm_module.finish();
+        let code_transform = cx.code_transform;
+        self.build_id = build_id;
         log::debug!("emission finished");
 
         // let mut validator = Validator::new();
@@ -520,7 +638,7 @@ impl Module {
         //     panic!("Unable to validate serialized output");
         // }
 
-        out
+        (out, code_transform, dwarf)
     }
 
     /// Returns an iterator over all functions in this module
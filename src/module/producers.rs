@@ -46,7 +46,7 @@ impl ModuleProducers {
         &self.fields
     }
 
-    fn field(&mut self, field_name: &str, name: &str, version: &str) {
+    pub(crate) fn field(&mut self, field_name: &str, name: &str, version: &str) {
         let new_value = ProducerValue {
             name: name.to_string(),
             version: version.to_string(),
@@ -0,0 +1,160 @@
+//! Baking the state left behind by an initialization function into a module,
+//! in the style of [Wizer](https://github.com/bytecodealliance/wizer).
+
+use crate::ir::Value;
+use crate::map::IdHashSet;
+use crate::passes::used::Used;
+use crate::{ConstExpr, Data, DataKind, ExportItem, FunctionId, GlobalId, GlobalKind, MemoryId};
+use crate::{Module, Result, ValType};
+use anyhow::bail;
+
+/// The state of a module instance after running its initialization, as
+/// captured by whichever runtime ran it.
+#[derive(Clone, Debug, Default)]
+pub struct Snapshot {
+    /// The full contents of each memory to snapshot. Memories may have grown
+    /// during initialization, in which case they are grown in the module to
+    /// match.
+    pub memories: Vec<(MemoryId, Vec<u8>)>,
+    /// The values to give to globals.
+    pub globals: Vec<(GlobalId, Value)>,
+}
+
+impl Module {
+    /// Make this module start out in the state described by `snapshot`, and
+    /// remove `init`, the function that produced that state.
+    ///
+    /// Each snapshotted memory gets a single active data segment holding its
+    /// contents without leading and trailing zeros, replacing its other active
+    /// segments. Active segments referenced by `memory.init` or `data.drop`
+    /// become empty passive segments instead, which behave the same as the
+    /// dropped segments they would be after instantiation. Run
+    /// `passes::data_segments` afterwards to split the snapshot at long runs
+    /// of zeros. Each snapshotted global gets its value as its initializer.
+    ///
+    /// `init` is removed from the exports and as the `start` function, and is
+    /// deleted if nothing else uses it.
+    ///
+    /// Tables and passive data segments are not snapshotted. Returns an error,
+    /// without changing anything, if the snapshot doesn't fit this module.
+    pub fn apply_snapshot(&mut self, snapshot: &Snapshot, init: Option<FunctionId>) -> Result<()> {
+        for (id, bytes) in &snapshot.memories {
+            let memory = self.memories.get(*id);
+            if memory.import.is_some() {
+                bail!("cannot snapshot imported memory {:?}", id);
+            }
+            if !memory.memory64 && bytes.len() as u64 > 1 << 32 {
+                bail!("snapshot of {:?} is too large for a 32-bit memory", id);
+            }
+            let pages = pages(bytes.len(), memory.page_size_log2);
+            if memory.maximum.is_some_and(|max| pages > max) {
+                bail!(
+                    "snapshot of {:?} is {} pages, more than its maximum",
+                    id,
+                    pages
+                );
+            }
+        }
+        for (id, value) in &snapshot.globals {
+            let global = self.globals.get(*id);
+            if let GlobalKind::Import(_) = global.kind {
+                bail!("cannot snapshot imported global {:?}", id);
+            }
+            let matches = matches!(
+                (global.ty, value),
+                (ValType::I32, Value::I32(_))
+                    | (ValType::I64, Value::I64(_))
+                    | (ValType::F32, Value::F32(_))
+                    | (ValType::F64, Value::F64(_))
+                    | (ValType::V128, Value::V128(_))
+            );
+            if !matches {
+                bail!(
+                    "snapshot value {} doesn't match the type of {:?}",
+                    value,
+                    id
+                );
+            }
+        }
+
+        let mut referenced = IdHashSet::default();
+        for (_, func) in self.funcs.iter_local() {
+            referenced.extend(func.used_data_segments());
+        }
+        for (id, bytes) in &snapshot.memories {
+            self.snapshot_memory(*id, bytes, &referenced);
+        }
+        for (id, value) in &snapshot.globals {
+            self.globals.get_mut(*id).kind = GlobalKind::Local(ConstExpr::Value(*value));
+        }
+
+        if let Some(init) = init {
+            if self.start == Some(init) {
+                self.start = None;
+            }
+            let exports = self
+                .exports
+                .iter()
+                .filter(|e| matches!(e.item, ExportItem::Function(f) if f == init))
+                .map(|e| e.id())
+                .collect::<Vec<_>>();
+            for export in exports {
+                self.exports.delete(export);
+            }
+            if !Used::new(self).funcs.contains(&init) {
+                self.funcs.delete(init);
+            }
+        }
+        Ok(())
+    }
+
+    fn snapshot_memory(&mut self, id: MemoryId, bytes: &[u8], referenced: &IdHashSet<Data>) {
+        let segments = self
+            .data
+            .iter()
+            .filter(|d| matches!(d.kind, DataKind::Active { memory, .. } if memory == id))
+            .map(|d| d.id())
+            .collect::<Vec<_>>();
+        for segment in segments {
+            self.memories.get_mut(id).data_segments.remove(&segment);
+            if referenced.contains(&segment) {
+                let data = self.data.get_mut(segment);
+                data.kind = DataKind::Passive;
+                data.value = Vec::new();
+            } else {
+                self.data.delete(segment);
+            }
+        }
+
+        let memory = self.memories.get_mut(id);
+        memory.initial = memory
+            .initial
+            .max(pages(bytes.len(), memory.page_size_log2));
+        let memory64 = memory.memory64;
+
+        let start = match bytes.iter().position(|b| *b != 0) {
+            Some(start) => start,
+            None => return,
+        };
+        let end = bytes.iter().rposition(|b| *b != 0).unwrap() + 1;
+        let offset = if memory64 {
+            Value::I64(start as i64)
+        } else {
+            Value::I32(start as i32)
+        };
+        let data = self.data.add(
+            DataKind::Active {
+                memory: id,
+                offset: ConstExpr::Value(offset),
+            },
+            bytes[start..end].to_vec(),
+        );
+        self.memories.get_mut(id).data_segments.insert(data);
+    }
+}
+
+/// The number of pages needed to hold `len` bytes.
+fn pages(len: usize, page_size_log2: Option<u32>) -> u64 {
+    let page_size = 1u64 << page_size_log2.unwrap_or(16);
+    (len as u64).div_ceil(page_size)
+}
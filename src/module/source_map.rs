@@ -0,0 +1,311 @@
+//! Reading, writing and remapping JS-style source maps for wasm.
+//!
+//! Source maps for wasm use a single generated line, and the generated column
+//! of every mapping is the byte offset of an instruction in the binary. See
+//! <https://sourcemaps.info/spec.html> for the format itself.
+
+use crate::error::Result;
+use crate::ir::InstrLocId;
+use crate::module::{CodeTransform, Module};
+use anyhow::{bail, Context};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// A version 3 source map describing a wasm binary.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SourceMap {
+    /// The name of the generated file this map is associated with.
+    pub file: Option<String>,
+    /// A prefix prepended to every entry in `sources`.
+    pub source_root: Option<String>,
+    /// The original sources referenced by the mappings.
+    pub sources: Vec<String>,
+    /// The contents of each source, if embedded in the map. Either empty or
+    /// the same length as `sources`.
+    pub sources_content: Vec<Option<String>>,
+    /// Symbol names referenced by the mappings.
+    pub names: Vec<String>,
+    /// The mappings, sorted by their generated offset.
+    pub mappings: Vec<SourceMapping>,
+}
+
+/// A single segment of a source map's `mappings`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SourceMapping {
+    /// The byte offset in the wasm binary of the instruction this mapping
+    /// applies to.
+    pub generated: usize,
+    /// The original location, if this mapping has one.
+    pub original: Option<OriginalLocation>,
+}
+
+/// A position in one of a source map's original sources.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OriginalLocation {
+    /// Index into `SourceMap::sources`.
+    pub source: u32,
+    /// Zero-based line in the source.
+    pub line: u32,
+    /// Zero-based column in the source.
+    pub column: u32,
+    /// Index into `SourceMap::names`, if any.
+    pub name: Option<u32>,
+}
+
+impl SourceMap {
+    /// Parse a source map from its JSON representation.
+    pub fn parse(json: &str) -> Result<SourceMap> {
+        let value = serde_json::from_str(json).context("invalid source map JSON")?;
+        let fields = match value {
+            Value::Object(fields) => fields,
+            _ => bail!("source map is not a JSON object"),
+        };
+        let mut map = SourceMap::default();
+        let mut mappings = None;
+        for (key, value) in fields {
+            match (key.as_str(), value) {
+                ("version", Value::Number(n)) if n.as_u64() == Some(3) => {}
+                ("version", _) => bail!("only version 3 source maps are supported"),
+                ("file", Value::String(s)) => map.file = Some(s),
+                ("sourceRoot", Value::String(s)) => map.source_root = Some(s),
+                ("sources", value) => map.sources = strings(value, "sources")?,
+                ("names", value) => map.names = strings(value, "names")?,
+                ("sourcesContent", Value::Array(values)) => {
+                    map.sources_content = values
+                        .into_iter()
+                        .map(|v| match v {
+                            Value::String(s) => Ok(Some(s)),
+                            Value::Null => Ok(None),
+                            _ => bail!("`sourcesContent` must contain strings or null"),
+                        })
+                        .collect::<Result<_>>()?;
+                }
+                ("mappings", Value::String(s)) => mappings = Some(s),
+                ("sections", _) => bail!("indexed source maps are not supported"),
+                (key, _) => log::debug!("ignoring source map field `{}`", key),
+            }
+        }
+        let mappings = mappings.context("source map has no `mappings`")?;
+        map.mappings = decode_mappings(&mappings)?;
+        for m in map.mappings.iter().filter_map(|m| m.original) {
+            if m.source as usize >= map.sources.len() {
+                bail!("mapping refers to out of bounds source {}", m.source);
+            }
+            if m.name.is_some_and(|n| n as usize >= map.names.len()) {
+                bail!("mapping refers to out of bounds name");
+            }
+        }
+        map.mappings.sort_by_key(|m| m.generated);
+        Ok(map)
+    }
+
+    /// Serialize this source map to JSON.
+    pub fn to_json(&self) -> String {
+        // Written field by field to keep the conventional field order.
+        let string = |s: &str| Value::from(s).to_string();
+        let mut out = String::from("{\"version\":3");
+        if let Some(file) = &self.file {
+            out.push_str(",\"file\":");
+            out.push_str(&string(file));
+        }
+        if let Some(root) = &self.source_root {
+            out.push_str(",\"sourceRoot\":");
+            out.push_str(&string(root));
+        }
+        out.push_str(",\"sources\":");
+        out.push_str(&Value::from(&self.sources[..]).to_string());
+        if !self.sources_content.is_empty() {
+            out.push_str(",\"sourcesContent\":");
+            out.push_str(&Value::from(self.sources_content.clone()).to_string());
+        }
+        out.push_str(",\"names\":");
+        out.push_str(&Value::from(&self.names[..]).to_string());
+        out.push_str(",\"mappings\":");
+        out.push_str(&string(&encode_mappings(&self.mappings)));
+        out.push('}');
+        out
+    }
+
+    /// Translate this source map, written against the binary this module was
+    /// parsed from, into one for the binary that produced `transform`.
+    ///
+    /// Mappings are matched to instructions by their original offset, so this
+    /// relies on instruction locations being the default input offsets.
+    /// Mappings for instructions that no longer exist are dropped, as are
+    /// those at offsets no instruction can have, which `parse` rejects.
+    pub fn remap(&self, transform: &CodeTransform) -> SourceMap {
+        let originals = self
+            .mappings
+            .iter()
+            .filter_map(|m| {
+                let generated = u32::try_from(m.generated).ok().filter(|g| *g != u32::MAX)?;
+                Some((InstrLocId::new(generated), m.original))
+            })
+            .collect::<BTreeMap<_, _>>();
+        let mut mappings = transform
+            .instruction_map
+            .iter()
+            .filter_map(|(loc, generated)| {
+                originals.get(loc).map(|original| SourceMapping {
+                    generated: *generated,
+                    original: *original,
+                })
+            })
+            .collect::<Vec<_>>();
+        mappings.sort_by_key(|m| m.generated);
+        mappings.dedup_by_key(|m| m.generated);
+        SourceMap {
+            mappings,
+            ..self.clone()
+        }
+    }
+}
+
+impl Module {
+    /// Emit this module into an in-memory wasm buffer, along with `source_map`
+    /// translated to describe the emitted code.
+    ///
+    /// `source_map` must describe the binary this module was parsed from.
+    pub fn emit_wasm_with_source_map(&mut self, source_map: &SourceMap) -> (Vec<u8>, SourceMap) {
+        let preserve = std::mem::replace(&mut self.config.preserve_code_transform, true);
+        let (wasm, transform, _) = self.emit_wasm_parts(false);
+        self.config.preserve_code_transform = preserve;
+        (wasm, source_map.remap(&transform))
+    }
+}
+
+fn strings(value: Value, field: &str) -> Result<Vec<String>> {
+    match value {
+        Value::Array(values) => values
+            .into_iter()
+            .map(|v| match v {
+                Value::String(s) => Ok(s),
+                // Missing sources are allowed by the spec, and get an empty
+                // name here.
+                Value::Null => Ok(String::new()),
+                _ => bail!("`{}` must contain strings", field),
+            })
+            .collect(),
+        _ => bail!("`{}` must be an array", field),
+    }
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn decode_mappings(mappings: &str) -> Result<Vec<SourceMapping>> {
+    let mut ret = Vec::new();
+    let mut fields = [0i64; 5];
+    for (line, segments) in mappings.split(';').enumerate() {
+        // The generated column resets on each line, everything else is
+        // relative to the previous segment.
+        fields[0] = 0;
+        for segment in segments.split(',').filter(|s| !s.is_empty()) {
+            if line > 0 {
+                bail!("wasm source maps must have a single generated line");
+            }
+            let mut bytes = segment.bytes();
+            let mut n = 0;
+            while bytes.len() > 0 {
+                if n == fields.len() {
+                    bail!("source map segment `{}` has too many fields", segment);
+                }
+                fields[n] += decode_vlq(&mut bytes)?;
+                n += 1;
+            }
+            let field = |i: usize| -> Result<u32> {
+                u32::try_from(fields[i]).context("out of range value in source map mappings")
+            };
+            let original = match n {
+                1 => None,
+                4 | 5 => Some(OriginalLocation {
+                    source: field(1)?,
+                    line: field(2)?,
+                    column: field(3)?,
+                    name: if n == 5 { Some(field(4)?) } else { None },
+                }),
+                _ => bail!("source map segment `{}` has {} fields", segment, n),
+            };
+            // Instruction locations are `u32` offsets, the last of which
+            // is reserved.
+            let generated = match field(0)? {
+                u32::MAX => bail!("source map maps the reserved offset {:#x}", u32::MAX),
+                generated => generated as usize,
+            };
+            ret.push(SourceMapping {
+                generated,
+                original,
+            });
+        }
+    }
+    Ok(ret)
+}
+
+fn decode_vlq(bytes: &mut std::str::Bytes) -> Result<i64> {
+    let mut value = 0u64;
+    let mut shift = 0;
+    loop {
+        let byte = bytes.next().context("unterminated VLQ in source map")?;
+        let digit = BASE64
+            .iter()
+            .position(|b| *b == byte)
+            .with_context(|| format!("invalid base64 character `{}`", byte as char))?
+            as u64;
+        if shift > 60 {
+            bail!("VLQ in source map is too large");
+        }
+        value |= (digit & 0x1f) << shift;
+        shift += 5;
+        if digit & 0x20 == 0 {
+            break;
+        }
+    }
+    let magnitude = (value >> 1) as i64;
+    Ok(if value & 1 == 1 {
+        -magnitude
+    } else {
+        magnitude
+    })
+}
+
+fn encode_mappings(mappings: &[SourceMapping]) -> String {
+    let mut out = String::new();
+    let mut prev = [0i64; 5];
+    for (i, mapping) in mappings.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let mut field = |out: &mut String, i: usize, value: i64| {
+            encode_vlq(out, value - prev[i]);
+            prev[i] = value;
+        };
+        field(&mut out, 0, mapping.generated as i64);
+        if let Some(original) = mapping.original {
+            field(&mut out, 1, original.source.into());
+            field(&mut out, 2, original.line.into());
+            field(&mut out, 3, original.column.into());
+            if let Some(name) = original.name {
+                field(&mut out, 4, name.into());
+            }
+        }
+    }
+    out
+}
+
+fn encode_vlq(out: &mut String, value: i64) {
+    let mut value = if value < 0 {
+        (value.unsigned_abs() << 1) | 1
+    } else {
+        (value as u64) << 1
+    };
+    loop {
+        let mut digit = value & 0x1f;
+        value >>= 5;
+        if value != 0 {
+            digit |= 0x20;
+        }
+        out.push(BASE64[digit as usize] as char);
+        if value == 0 {
+            break;
+        }
+    }
+}
@@ -0,0 +1,39 @@
+//! The `sourceMappingURL` custom section, pointing at a JS-style source map
+//! for the module.
+
+use crate::emit::{Emit, EmitContext};
+use crate::error::Result;
+use crate::module::Module;
+use anyhow::bail;
+
+/// The `sourceMappingURL` custom section, pointing at the source map for this
+/// module.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceMappingUrl {
+    /// Where to find the source map, typically relative to the module.
+    pub url: String,
+}
+
+impl Module {
+    pub(crate) fn parse_source_mapping_url(&mut self, data: &[u8], offset: usize) -> Result<()> {
+        let mut reader = wasmparser::BinaryReader::new(data, offset);
+        let url = reader.read_string()?.to_string();
+        if !reader.eof() {
+            bail!("trailing bytes after the source map URL");
+        }
+        self.source_mapping_url = Some(SourceMappingUrl { url });
+        Ok(())
+    }
+}
+
+impl Emit for SourceMappingUrl {
+    fn emit(&self, cx: &mut EmitContext) {
+        log::debug!("emit sourceMappingURL section");
+        let mut data = Vec::new();
+        wasm_encoder::Encode::encode(self.url.as_str(), &mut data);
+        cx.wasm_module.section(&wasm_encoder::CustomSection {
+            name: "sourceMappingURL".into(),
+            data: data.into(),
+        });
+    }
+}
@@ -0,0 +1,190 @@
+//! Evaluating active element segments into the initial contents of tables.
+
+use crate::ir::{CallIndirect, Value};
+use crate::{ConstExpr, ElementItems, ElementKind, FunctionId, GlobalKind, GlobalResolver};
+use crate::{Module, ModuleElements, ModuleTables, Result, Table, TableId};
+use anyhow::{bail, Context};
+
+/// What a table slot holds right after instantiation.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TableSlot {
+    /// A null reference.
+    Null,
+    /// A reference to a function.
+    Func(FunctionId),
+    /// Something that can't be known statically, such as an imported
+    /// table's contents or a reference read from an imported global.
+    Unknown,
+}
+
+/// The contents of a table right after instantiation, as initialized by its
+/// initializer and active element segments.
+#[derive(Clone, Debug)]
+pub struct TableImage {
+    /// The table this is an image of.
+    pub table: TableId,
+    /// Every slot of the table, from its initial size.
+    pub slots: Vec<TableSlot>,
+}
+
+impl TableImage {
+    /// The most slots an image may have, since it holds every one of them.
+    pub const MAX_SLOTS: u64 = 1 << 20;
+
+    /// Create an image of `table` before any element segments are applied.
+    ///
+    /// Returns an error if the table's initial size is over `MAX_SLOTS`.
+    pub fn new(module: &Module, table: &Table) -> Result<TableImage> {
+        if table.initial > Self::MAX_SLOTS {
+            bail!(
+                "{:?} has {} slots, more than a table image can hold",
+                table.id(),
+                table.initial
+            );
+        }
+        let fill = if table.import.is_some() {
+            TableSlot::Unknown
+        } else {
+            match &table.init {
+                Some(init) => slot(module, init),
+                None => TableSlot::Null,
+            }
+        };
+        Ok(TableImage {
+            table: table.id(),
+            slots: vec![fill; table.initial as usize],
+        })
+    }
+
+    /// Build an image of the main function table, as found by
+    /// `ModuleTables::main_function_table`, if there is one.
+    pub fn main(module: &Module, imports: &dyn GlobalResolver) -> Result<Option<TableImage>> {
+        let table = match module.tables.main_function_table()? {
+            Some(table) => module.tables.get(table),
+            None => return Ok(None),
+        };
+        let mut image = TableImage::new(module, table)?;
+        module.elements.paint(module, imports, &mut image)?;
+        Ok(Some(image))
+    }
+
+    /// Whether every slot of this image is known.
+    pub fn is_complete(&self) -> bool {
+        !self.slots.contains(&TableSlot::Unknown)
+    }
+
+    /// The functions that `call` could reach through this table, in slot order
+    /// and without duplicates.
+    ///
+    /// Only functions whose type is exactly the type `call` expects are
+    /// included, since calling anything else traps. `Unknown` slots are
+    /// skipped, so check `is_complete` before treating this as exhaustive.
+    /// Returns nothing if `call` uses a different table.
+    pub fn possible_targets(&self, module: &Module, call: &CallIndirect) -> Vec<FunctionId> {
+        let mut targets = Vec::new();
+        if call.table != self.table {
+            return targets;
+        }
+        for slot in &self.slots {
+            if let TableSlot::Func(f) = slot {
+                if module.funcs.get(*f).ty() == call.ty && !targets.contains(f) {
+                    targets.push(*f);
+                }
+            }
+        }
+        targets
+    }
+}
+
+/// What a constant expression puts in a table slot.
+fn slot(module: &Module, expr: &ConstExpr) -> TableSlot {
+    match expr {
+        ConstExpr::RefNull(_) => TableSlot::Null,
+        ConstExpr::RefFunc(f) => TableSlot::Func(*f),
+        ConstExpr::Global(g) => {
+            let global = module.globals.get(*g);
+            match &global.kind {
+                GlobalKind::Local(init) if !global.mutable => slot(module, init),
+                _ => TableSlot::Unknown,
+            }
+        }
+        ConstExpr::Value(_) | ConstExpr::Extended(_) => TableSlot::Unknown,
+    }
+}
+
+impl ModuleElements {
+    /// Paint every active element segment targeting `image`'s table into it,
+    /// in the order instantiation would.
+    ///
+    /// Offsets are evaluated with `ConstExpr::eval`, using `imports` for the
+    /// values of imported globals. Returns an error if an offset can't be
+    /// evaluated or a segment is out of bounds.
+    pub fn paint(
+        &self,
+        module: &Module,
+        imports: &dyn GlobalResolver,
+        image: &mut TableImage,
+    ) -> Result<()> {
+        for elem in self.iter() {
+            let offset = match &elem.kind {
+                ElementKind::Active { table, offset } if *table == image.table => offset,
+                _ => continue,
+            };
+            let offset = match offset
+                .eval(module, imports)
+                .with_context(|| format!("failed to evaluate the offset of {:?}", elem.id()))?
+            {
+                Some(Value::I32(offset)) => u64::from(offset as u32),
+                Some(Value::I64(offset)) => offset as u64,
+                Some(v) => bail!(
+                    "element segment {:?} has a non-integer offset {}",
+                    elem.id(),
+                    v
+                ),
+                None => bail!(
+                    "the offset of element segment {:?} isn't constant",
+                    elem.id()
+                ),
+            };
+            let slots = match &elem.items {
+                ElementItems::Functions(funcs) => {
+                    funcs.iter().map(|f| TableSlot::Func(*f)).collect()
+                }
+                ElementItems::Expressions(_, exprs) => {
+                    exprs.iter().map(|e| slot(module, e)).collect::<Vec<_>>()
+                }
+            };
+            let len = image.slots.len() as u64;
+            match offset.checked_add(slots.len() as u64) {
+                Some(end) if end <= len => {
+                    image.slots[offset as usize..end as usize].copy_from_slice(&slots);
+                }
+                _ => bail!(
+                    "element segment {:?} at {} with length {} is out of bounds of a \
+                     table with {} slots",
+                    elem.id(),
+                    offset,
+                    slots.len(),
+                    len
+                ),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ModuleTables {
+    /// Build an image of the initial contents of each table, in the same
+    /// order as `iter`.
+    ///
+    /// See `ModuleElements::paint` for how segments are placed.
+    pub fn images(&self, module: &Module, imports: &dyn GlobalResolver) -> Result<Vec<TableImage>> {
+        self.iter()
+            .map(|table| {
+                let mut image = TableImage::new(module, table)?;
+                module.elements.paint(module, imports, &mut image)?;
+                Ok(image)
+            })
+            .collect()
+    }
+}
@@ -176,7 +176,7 @@ impl Module {
             let t = t?;
             let init = match t.init {
                 wasmparser::TableInit::RefNull => None,
-                wasmparser::TableInit::Expr(expr) => Some(ConstExpr::eval(&expr, ids)?),
+                wasmparser::TableInit::Expr(expr) => Some(ConstExpr::from_wasmparser(&expr, ids)?),
             };
             let id = self.tables.add_local_with_init(
                 t.ty.table64,
@@ -81,6 +81,17 @@ impl IndicesToIds {
         (list.len() as u32) - 1
     }
 
+    /// The globals of the original Wasm binary, in index order.
+    pub(crate) fn globals(&self) -> &[GlobalId] {
+        &self.globals
+    }
+
+    /// The locals of each function of the original Wasm binary, in index
+    /// order.
+    pub(crate) fn locals(&self) -> &IdHashMap<Function, Vec<LocalId>> {
+        &self.locals
+    }
+
     /// Gets the ID for a particular index
     pub fn get_local(&self, function: FunctionId, index: u32) -> Result<LocalId> {
         let locals = match self.locals.get(&function) {
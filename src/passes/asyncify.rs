@@ -0,0 +1,866 @@
+//! Lets code suspend while it calls certain imports, by unwinding the wasm
+//! call stack into linear memory and later rewinding it, in the style of
+//! binaryen's Asyncify.
+//!
+//! The instrumented module exports the same control functions as binaryen's:
+//!
+//! * `asyncify_start_unwind(data)` is called by an import that wants to
+//!   suspend, just before it returns. Every instrumented function on the stack
+//!   then saves its locals and where it was to the buffer that `data` points
+//!   to, and returns straight away.
+//! * `asyncify_stop_unwind()` is called by the embedder once the export it
+//!   called has returned.
+//! * `asyncify_start_rewind(data)` is called by the embedder to resume, before
+//!   calling the same export again. Every instrumented function restores its
+//!   locals, skips straight to the call it was in, and calls it again, down to
+//!   the import that suspended.
+//! * `asyncify_stop_rewind()` is called by that import, which then returns its
+//!   result as if it had never suspended.
+//! * `asyncify_get_state()` returns 0 normally, 1 while unwinding and 2 while
+//!   rewinding.
+//!
+//! `data` is the address of two `i32`s in memory: the address to save the
+//! next frame at, and the end of the space for frames. Running out of space
+//! traps.
+
+use crate::ir::*;
+use crate::map::{IdHashMap, IdHashSet};
+use crate::passes::instr_seqs;
+use crate::passes::stack_types::{self, Operands};
+use crate::InstrSeqBuilder;
+use crate::{ConstExpr, Function, FunctionBuilder, FunctionId, GlobalId, ImportKind};
+use crate::{LocalFunction, LocalId, MemoryId, Module, ModuleLocals, ModuleTypes, Result};
+use crate::{TypeId, ValType};
+use anyhow::{bail, Context};
+use std::collections::HashMap;
+use std::mem;
+
+const UNWINDING: i32 = 1;
+const REWINDING: i32 = 2;
+
+/// Options for `run`.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// The imported functions that may suspend, as `(module, name)` pairs.
+    /// Imports the module doesn't have are ignored.
+    pub imports: Vec<(String, String)>,
+    /// Assume that indirect calls never reach a function that suspends.
+    ///
+    /// Otherwise every function with an indirect call is instrumented, along
+    /// with its callers.
+    pub ignore_indirect: bool,
+    /// The memory holding the buffer that frames are saved to. Defaults to
+    /// the module's only memory.
+    pub memory: Option<MemoryId>,
+}
+
+/// The names of the functions that `run` exports.
+const EXPORTS: [&str; 5] = [
+    "asyncify_start_unwind",
+    "asyncify_stop_unwind",
+    "asyncify_start_rewind",
+    "asyncify_stop_rewind",
+    "asyncify_get_state",
+];
+
+/// Instrument every function of `module` that may call one of the imports in
+/// `options`, and export the functions that control unwinding and rewinding.
+///
+/// Instrumented functions spill the operand stack to locals around each call
+/// that may suspend, and save every local when unwinding. That means they
+/// can't keep references in locals or on the stack across those calls, tail
+/// call a function that may suspend, or make such a call inside a `try`
+/// block. Returns an error, before changing anything, if any does.
+pub fn run(module: &mut Module, options: &Options) -> Result<()> {
+    if module.linking.is_some() {
+        bail!("cannot asyncify a relocatable object");
+    }
+    for name in EXPORTS {
+        if module.exports.iter().any(|e| e.name == name) {
+            bail!("module already exports `{}`", name);
+        }
+    }
+    let memory = match options.memory {
+        Some(memory) => memory,
+        None => module.get_memory_id()?,
+    };
+    if module.memories.get(memory).memory64 {
+        bail!("cannot save frames to a 64-bit memory");
+    }
+
+    let suspending = suspending_functions(module, options);
+    let instrumented = module
+        .funcs
+        .iter_local()
+        .map(|(id, _)| id)
+        .filter(|id| suspending.contains(id))
+        .collect::<Vec<_>>();
+    log::debug!("instrumenting {} functions", instrumented.len());
+
+    // Find the types of the operands at every call and block, since any of
+    // them may need to be spilled.
+    let mut sites = Vec::new();
+    for &id in &instrumented {
+        let func = module.funcs.get(id).kind.unwrap_local();
+        for seq in instr_seqs(func, func.entry_block()) {
+            for (i, (instr, _)) in func.block(seq).instrs.iter().enumerate() {
+                if matches!(
+                    instr,
+                    Instr::Call(_)
+                        | Instr::CallIndirect(_)
+                        | Instr::CallRef(_)
+                        | Instr::Block(_)
+                        | Instr::Loop(_)
+                        | Instr::IfElse(_)
+                        | Instr::Try(_)
+                        | Instr::TryTable(_)
+                ) {
+                    sites.push((id, seq, i));
+                }
+            }
+        }
+    }
+    let mut operands = IdHashMap::<Function, HashMap<_, _>>::default();
+    for ((func, seq, i), types) in sites.iter().zip(stack_types::operands(module, &sites)?) {
+        operands.entry(*func).or_default().insert((*seq, *i), types);
+    }
+    let func_types = module
+        .funcs
+        .iter()
+        .map(|f| (f.id(), f.ty()))
+        .collect::<IdHashMap<Function, TypeId>>();
+
+    // Check everything before changing anything.
+    let no_operands = HashMap::new();
+    let mut points = IdHashMap::default();
+    for &id in &instrumented {
+        let func = module.funcs.get(id).kind.unwrap_local();
+        let mut finder = Points {
+            types: &module.types,
+            suspending: &suspending,
+            operands: operands.get(&id).unwrap_or(&no_operands),
+            ignore_indirect: options.ignore_indirect,
+            seqs: HashMap::new(),
+        };
+        check(module, func, &mut finder)
+            .with_context(|| format!("failed to asyncify {}", describe(module, id)))?;
+        points.insert(id, finder.seqs);
+    }
+
+    let state =
+        module
+            .globals
+            .add_local(ValType::I32, true, false, ConstExpr::Value(Value::I32(0)));
+    module.globals.get_mut(state).name = Some("__asyncify_state".to_string());
+    let data = module
+        .globals
+        .add_local(ValType::I32, true, false, ConstExpr::Value(Value::I32(0)));
+    module.globals.get_mut(data).name = Some("__asyncify_data".to_string());
+    for id in instrumented {
+        let func = module.funcs.get_mut(id).kind.unwrap_local_mut();
+        let unwind = func.builder_mut().dangling_instr_seq(None).id();
+        let index = module.locals.add(ValType::I32);
+        let mut instrument = Instrument {
+            types: &mut module.types,
+            locals: &mut module.locals,
+            func_types: &func_types,
+            operands: operands.get(&id).unwrap_or(&no_operands),
+            points: points.remove(&id).unwrap(),
+            state,
+            data,
+            memory,
+            unwind,
+            index,
+            calls: 0,
+        };
+        instrument.function(func);
+    }
+
+    add_control_functions(module, state, data, memory);
+    Ok(())
+}
+
+fn describe(module: &Module, id: FunctionId) -> String {
+    match &module.funcs.get(id).name {
+        Some(name) => format!("function `{}`", name),
+        None => format!("{:?}", id),
+    }
+}
+
+/// The suspending imports, and every function that may call one of them.
+fn suspending_functions(module: &Module, options: &Options) -> IdHashSet<Function> {
+    let mut suspending = IdHashSet::default();
+    for (module_name, name) in &options.imports {
+        match module.imports.find(module_name, name) {
+            Some(import) => match module.imports.get(import).kind {
+                ImportKind::Function(f) => {
+                    suspending.insert(f);
+                }
+                _ => log::debug!("import {}.{} isn't a function", module_name, name),
+            },
+            None => log::debug!("no import {}.{}", module_name, name),
+        }
+    }
+
+    let mut callers = IdHashMap::<Function, Vec<FunctionId>>::default();
+    let mut worklist = suspending.iter().copied().collect::<Vec<_>>();
+    for (id, func) in module.funcs.iter_local() {
+        let mut calls = Calls::default();
+        dfs_in_order(&mut calls, func, func.entry_block());
+        for callee in calls.direct {
+            callers.entry(callee).or_default().push(id);
+        }
+        if calls.indirect && !options.ignore_indirect && suspending.insert(id) {
+            worklist.push(id);
+        }
+    }
+    while let Some(f) = worklist.pop() {
+        for caller in callers.get(&f).into_iter().flatten() {
+            if suspending.insert(*caller) {
+                worklist.push(*caller);
+            }
+        }
+    }
+    suspending
+}
+
+/// Export the functions that control unwinding and rewinding.
+fn add_control_functions(module: &mut Module, state: GlobalId, data: GlobalId, memory: MemoryId) {
+    let check = |body: &mut InstrSeqBuilder| {
+        // Trap if the saved frames have overrun the buffer.
+        body.global_get(data)
+            .load(memory, LoadKind::I32 { atomic: false }, mem_arg(0, 4))
+            .global_get(data)
+            .load(memory, LoadKind::I32 { atomic: false }, mem_arg(4, 4))
+            .binop(BinaryOp::I32GtU)
+            .if_else(
+                None,
+                |then| {
+                    then.unreachable();
+                },
+                |_| {},
+            );
+    };
+
+    for (name, new_state) in [
+        ("asyncify_start_unwind", Some(UNWINDING)),
+        ("asyncify_stop_unwind", None),
+        ("asyncify_start_rewind", Some(REWINDING)),
+        ("asyncify_stop_rewind", None),
+    ] {
+        let params: &[ValType] = if new_state.is_some() {
+            &[ValType::I32]
+        } else {
+            &[]
+        };
+        let mut builder = FunctionBuilder::new(&mut module.types, params, &[]);
+        builder.name(name.to_string());
+        let mut args = Vec::new();
+        let mut body = builder.func_body();
+        body.i32_const(new_state.unwrap_or(0)).global_set(state);
+        if new_state.is_some() {
+            let arg = module.locals.add(ValType::I32);
+            body.local_get(arg).global_set(data);
+            args.push(arg);
+        }
+        check(&mut body);
+        let func = builder.finish(args, &mut module.funcs);
+        module.exports.add(name, func);
+    }
+
+    let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32]);
+    builder.name("asyncify_get_state".to_string());
+    builder.func_body().global_get(state);
+    let func = builder.finish(Vec::new(), &mut module.funcs);
+    module.exports.add("asyncify_get_state", func);
+}
+
+fn mem_arg(offset: u64, align: u32) -> MemArg {
+    MemArg { align, offset }
+}
+
+/// The number of bytes a value of type `ty` takes up in a frame.
+fn size(ty: ValType) -> u32 {
+    match ty {
+        ValType::I32 | ValType::F32 => 4,
+        ValType::I64 | ValType::F64 => 8,
+        ValType::V128 => 16,
+        ValType::Ref(_) => unreachable!(),
+    }
+}
+
+fn seq_type(types: &ModuleTypes, ty: InstrSeqType) -> (Vec<ValType>, Vec<ValType>) {
+    match ty {
+        InstrSeqType::Simple(result) => (Vec::new(), result.into_iter().collect()),
+        InstrSeqType::MultiValue(ty) => {
+            let (params, results) = types.params_results(ty);
+            (params.to_vec(), results.to_vec())
+        }
+    }
+}
+
+fn at(instr: impl Into<Instr>) -> (Instr, InstrLocId) {
+    (instr.into(), InstrLocId::default())
+}
+
+fn i32_const(value: i32) -> (Instr, InstrLocId) {
+    at(Const {
+        value: Value::I32(value),
+    })
+}
+
+fn binop(op: BinaryOp) -> (Instr, InstrLocId) {
+    at(Binop { op })
+}
+
+/// Returns an error if `func` can't be instrumented, and finds its
+/// suspension points otherwise.
+fn check(module: &Module, func: &LocalFunction, points: &mut Points) -> Result<()> {
+    let results = module.types.results(func.ty());
+    if results.iter().any(|ty| matches!(ty, ValType::Ref(_))) {
+        bail!("cannot return early from a function that returns a reference");
+    }
+    let mut used = Locals::default();
+    dfs_in_order(&mut used, func, func.entry_block());
+    used.locals.extend(func.args.iter().copied());
+    if used
+        .locals
+        .iter()
+        .any(|local| matches!(module.locals.get(*local).ty(), ValType::Ref(_)))
+    {
+        bail!("cannot save a reference-typed local");
+    }
+    points.seq(func, func.entry_block())?;
+    Ok(())
+}
+
+/// Finds a function's suspension points: the calls that may suspend, and the
+/// blocks containing them. Those in unreachable code are ignored.
+struct Points<'a> {
+    types: &'a ModuleTypes,
+    suspending: &'a IdHashSet<Function>,
+    operands: &'a HashMap<(InstrSeqId, usize), Operands>,
+    ignore_indirect: bool,
+    /// Which instructions of each sequence are suspension points.
+    seqs: HashMap<InstrSeqId, Vec<bool>>,
+}
+
+impl Points<'_> {
+    /// Whether `seq` contains a suspension point.
+    fn seq(&mut self, func: &LocalFunction, seq: InstrSeqId) -> Result<bool> {
+        if let Some(points) = self.seqs.get(&seq) {
+            return Ok(points.contains(&true));
+        }
+        let len = func.block(seq).instrs.len();
+        let mut points = Vec::with_capacity(len);
+        for index in 0..len {
+            points.push(self.is_point(func, seq, index)?);
+        }
+        let any = points.contains(&true);
+        self.seqs.insert(seq, points);
+        Ok(any)
+    }
+
+    fn is_point(&mut self, func: &LocalFunction, seq: InstrSeqId, index: usize) -> Result<bool> {
+        let instr = &func.block(seq).instrs[index].0;
+        let suspends = match instr {
+            Instr::Call(c) => self.suspending.contains(&c.func),
+            Instr::CallIndirect(_) | Instr::CallRef(_) => !self.ignore_indirect,
+            Instr::ReturnCall(c) if self.suspending.contains(&c.func) => {
+                bail!("cannot tail call a function that may suspend")
+            }
+            Instr::ReturnCallIndirect(_) | Instr::ReturnCallRef(_) if !self.ignore_indirect => {
+                bail!("cannot make an indirect tail call, which may suspend")
+            }
+            Instr::Block(Block { seq })
+            | Instr::Loop(Loop { seq })
+            | Instr::TryTable(TryTable { seq, .. }) => self.seq(func, *seq)?,
+            Instr::IfElse(IfElse {
+                consequent,
+                alternative,
+            }) => {
+                let consequent = self.seq(func, *consequent)?;
+                self.seq(func, *alternative)? || consequent
+            }
+            Instr::Try(Try { seq, catches }) => {
+                let mut any = self.seq(func, *seq)?;
+                for catch in catches {
+                    if let LegacyCatch::Catch { handler, .. } | LegacyCatch::CatchAll { handler } =
+                        catch
+                    {
+                        any |= self.seq(func, *handler)?;
+                    }
+                }
+                any
+            }
+            _ => false,
+        };
+        if !suspends {
+            return Ok(false);
+        }
+        match self.operands.get(&(seq, index)) {
+            Some(Operands::Known(_)) => {}
+            Some(Operands::Unreachable) | None => return Ok(false),
+            Some(Operands::References) => {
+                bail!("cannot keep a reference on the stack across a call that may suspend")
+            }
+        }
+        match instr {
+            Instr::Loop(Loop { seq })
+                if !seq_type(self.types, func.block(*seq).ty).0.is_empty() =>
+            {
+                bail!("cannot suspend inside a loop with parameters")
+            }
+            Instr::Try(_) | Instr::TryTable(_) => bail!("cannot suspend inside a `try` block"),
+            _ => Ok(true),
+        }
+    }
+}
+
+/// Instruments a single function.
+///
+/// Every instruction sequence containing a call that may suspend is split at
+/// those calls, and at the blocks containing them. Those are its suspension
+/// points. The operand stack is spilled into fresh locals before each one, so
+/// that the code between them starts and ends with an empty stack and can be
+/// skipped while rewinding. Each call that may suspend gets an index, stored
+/// in a local when unwinding, which rewinding uses to find its way back.
+struct Instrument<'a> {
+    types: &'a mut ModuleTypes,
+    locals: &'a mut ModuleLocals,
+    func_types: &'a IdHashMap<Function, TypeId>,
+    operands: &'a HashMap<(InstrSeqId, usize), Operands>,
+    /// Which instructions of each sequence are suspension points.
+    points: HashMap<InstrSeqId, Vec<bool>>,
+    state: GlobalId,
+    data: GlobalId,
+    memory: MemoryId,
+    /// The block that calls branch out of to unwind.
+    unwind: InstrSeqId,
+    /// The local holding the index of the call being unwound or rewound.
+    index: LocalId,
+    calls: u32,
+}
+
+impl Instrument<'_> {
+    /// Instrument `func`, whose body becomes:
+    ///
+    /// ```text
+    /// if rewinding
+    ///   restore every local from the last frame
+    /// end
+    /// block $unwind
+    ///   block (result ..)
+    ///     instrumented body
+    ///   end
+    ///   return
+    /// end
+    /// save every local to a new frame
+    /// zeros for the results
+    /// ```
+    fn function(&mut self, func: &mut LocalFunction) {
+        let results = self.types.results(func.ty()).to_vec();
+        let entry = func.entry_block();
+        let entry_ty = func.block(entry).ty;
+        self.seq(func, entry, &[]);
+        let ty = mem::replace(&mut func.block_mut(entry).ty, entry_ty);
+        let body = func.builder_mut().dangling_instr_seq(ty).id();
+        func.block_mut(body).instrs = mem::take(&mut func.block_mut(entry).instrs);
+        func.block_mut(self.unwind).instrs = vec![at(Block { seq: body }), at(Return {})];
+
+        let mut used = Locals::default();
+        dfs_in_order(&mut used, func, body);
+        used.locals.extend(func.args.iter().copied());
+        let mut locals = used.locals.into_iter().collect::<Vec<_>>();
+        // Largest first, so that everything is aligned if the frame is.
+        locals.sort_by_key(|l| (std::cmp::Reverse(size(self.locals.get(*l).ty())), *l));
+        let mut frame = Vec::with_capacity(locals.len());
+        let mut frame_size = 0;
+        for local in locals {
+            let ty = self.locals.get(local).ty();
+            frame.push((local, ty, frame_size));
+            frame_size += size(ty);
+        }
+
+        let builder = func.builder_mut();
+        let mut restore = builder.dangling_instr_seq(None);
+        self.bump(&mut restore, BinaryOp::I32Sub, frame_size);
+        for &(local, ty, offset) in &frame {
+            let kind = match ty {
+                ValType::I32 => LoadKind::I32 { atomic: false },
+                ValType::I64 => LoadKind::I64 { atomic: false },
+                ValType::F32 => LoadKind::F32,
+                ValType::F64 => LoadKind::F64,
+                _ => LoadKind::V128,
+            };
+            restore
+                .global_get(self.data)
+                .load(self.memory, LoadKind::I32 { atomic: false }, mem_arg(0, 4))
+                .load(self.memory, kind, mem_arg(offset.into(), size(ty)))
+                .local_set(local);
+        }
+        let restore = restore.id();
+        let nothing = builder.dangling_instr_seq(None).id();
+
+        let mut entry = builder.func_body();
+        entry
+            .global_get(self.state)
+            .i32_const(REWINDING)
+            .binop(BinaryOp::I32Eq)
+            .instr(IfElse {
+                consequent: restore,
+                alternative: nothing,
+            })
+            .instr(Block { seq: self.unwind })
+            // Trap rather than overrun the buffer.
+            .global_get(self.data)
+            .load(self.memory, LoadKind::I32 { atomic: false }, mem_arg(0, 4))
+            .i32_const(frame_size as i32)
+            .binop(BinaryOp::I32Add)
+            .global_get(self.data)
+            .load(self.memory, LoadKind::I32 { atomic: false }, mem_arg(4, 4))
+            .binop(BinaryOp::I32GtU)
+            .if_else(
+                None,
+                |then| {
+                    then.unreachable();
+                },
+                |_| {},
+            );
+        for &(local, ty, offset) in &frame {
+            let kind = match ty {
+                ValType::I32 => StoreKind::I32 { atomic: false },
+                ValType::I64 => StoreKind::I64 { atomic: false },
+                ValType::F32 => StoreKind::F32,
+                ValType::F64 => StoreKind::F64,
+                _ => StoreKind::V128,
+            };
+            entry
+                .global_get(self.data)
+                .load(self.memory, LoadKind::I32 { atomic: false }, mem_arg(0, 4))
+                .local_get(local)
+                .store(self.memory, kind, mem_arg(offset.into(), size(ty)));
+        }
+        self.bump(&mut entry, BinaryOp::I32Add, frame_size);
+        for ty in results {
+            entry.const_(match ty {
+                ValType::I32 => Value::I32(0),
+                ValType::I64 => Value::I64(0),
+                ValType::F32 => Value::F32(0.0),
+                ValType::F64 => Value::F64(0.0),
+                _ => Value::V128(0),
+            });
+        }
+    }
+
+    /// Move the address of the next frame by `frame_size` bytes.
+    fn bump(&self, seq: &mut InstrSeqBuilder, op: BinaryOp, frame_size: u32) {
+        seq.global_get(self.data)
+            .global_get(self.data)
+            .load(self.memory, LoadKind::I32 { atomic: false }, mem_arg(0, 4))
+            .i32_const(frame_size as i32)
+            .binop(op)
+            .store(self.memory, StoreKind::I32 { atomic: false }, mem_arg(0, 4));
+    }
+
+    /// Instrument `seq`, whose parameters have already been spilled to
+    /// `params`. Its code becomes:
+    ///
+    /// ```text
+    /// if not rewinding
+    ///   params and code up to the first point
+    ///   spill the stack
+    /// end
+    /// first point
+    /// if not rewinding
+    ///   unspilled stack and code up to the next point
+    ///   spill the stack
+    /// end
+    /// ...
+    /// if not rewinding (result ..)
+    ///   unspilled stack and the rest of the code
+    /// else
+    ///   unreachable
+    /// end
+    /// ```
+    fn seq(&mut self, func: &mut LocalFunction, seq: InstrSeqId, params: &[LocalId]) {
+        let points = self.points.remove(&seq).unwrap();
+        let (_, results) = seq_type(self.types, func.block(seq).ty);
+        let instrs = mem::take(&mut func.block_mut(seq).instrs);
+        func.block_mut(seq).ty = InstrSeqType::new(self.types, &[], &results);
+
+        let mut out = Vec::new();
+        let mut stretch = params
+            .iter()
+            .map(|&local| at(LocalGet { local }))
+            .collect::<Vec<_>>();
+        for (index, ((instr, loc), point)) in instrs.into_iter().zip(points).enumerate() {
+            if !point {
+                stretch.push((instr, loc));
+                continue;
+            }
+            let types = match &self.operands[&(seq, index)] {
+                Operands::Known(types) => types,
+                _ => unreachable!(),
+            };
+            let spilled = types
+                .iter()
+                .map(|ty| self.locals.add(*ty))
+                .collect::<Vec<_>>();
+            stretch.extend(spilled.iter().rev().map(|&local| at(LocalSet { local })));
+            self.skip_when_rewinding(func, &mut out, mem::take(&mut stretch));
+
+            let (consumed, results) = match instr {
+                Instr::Call(_) | Instr::CallIndirect(_) | Instr::CallRef(_) => {
+                    self.call(func, &mut out, (instr, loc), &spilled)
+                }
+                _ => self.block(func, &mut out, (instr, loc), &spilled),
+            };
+            let below = &spilled[..spilled.len() - consumed];
+            stretch.extend(below.iter().map(|&local| at(LocalGet { local })));
+            stretch.extend(results.iter().map(|&local| at(LocalGet { local })));
+        }
+
+        if !stretch.is_empty() || !results.is_empty() {
+            let ty = InstrSeqType::new(self.types, &[], &results);
+            let builder = func.builder_mut();
+            let consequent = builder.dangling_instr_seq(ty).id();
+            let alternative = builder.dangling_instr_seq(ty).unreachable().id();
+            func.block_mut(consequent).instrs = stretch;
+            out.extend([
+                at(GlobalGet { global: self.state }),
+                i32_const(REWINDING),
+                binop(BinaryOp::I32Ne),
+                at(IfElse {
+                    consequent,
+                    alternative,
+                }),
+            ]);
+        }
+        func.block_mut(seq).instrs = out;
+    }
+
+    /// Append `instrs`, which leave the stack as they found it, to `out` so
+    /// that they are skipped while rewinding.
+    fn skip_when_rewinding(
+        &mut self,
+        func: &mut LocalFunction,
+        out: &mut Vec<(Instr, InstrLocId)>,
+        instrs: Vec<(Instr, InstrLocId)>,
+    ) {
+        if instrs.is_empty() {
+            return;
+        }
+        let builder = func.builder_mut();
+        let consequent = builder.dangling_instr_seq(None).id();
+        let alternative = builder.dangling_instr_seq(None).id();
+        func.block_mut(consequent).instrs = instrs;
+        out.extend([
+            at(GlobalGet { global: self.state }),
+            i32_const(REWINDING),
+            binop(BinaryOp::I32Ne),
+            at(IfElse {
+                consequent,
+                alternative,
+            }),
+        ]);
+    }
+
+    /// Instrument a call that may suspend, whose operands have been spilled
+    /// to `spilled`. Returns how many of those it consumes, and the locals its
+    /// results are spilled to.
+    ///
+    /// ```text
+    /// if not rewinding or rewinding to this call
+    ///   arguments
+    ///   call
+    ///   spill the results
+    ///   if unwinding
+    ///     remember this call
+    ///     br $unwind
+    ///   end
+    /// end
+    /// ```
+    fn call(
+        &mut self,
+        func: &mut LocalFunction,
+        out: &mut Vec<(Instr, InstrLocId)>,
+        call: (Instr, InstrLocId),
+        spilled: &[LocalId],
+    ) -> (usize, Vec<LocalId>) {
+        let (ty, operands) = match &call.0 {
+            Instr::Call(c) => (self.func_types[&c.func], 0),
+            Instr::CallIndirect(c) => (c.ty, 1),
+            Instr::CallRef(c) => (c.ty, 1),
+            _ => unreachable!(),
+        };
+        let (params, results) = self.types.params_results(ty);
+        let consumed = params.len() + operands;
+        let results = results
+            .iter()
+            .map(|ty| self.locals.add(*ty))
+            .collect::<Vec<_>>();
+        let index = self.index;
+        let call_index = self.calls as i32;
+        self.calls += 1;
+
+        let builder = func.builder_mut();
+        let unwind = builder
+            .dangling_instr_seq(None)
+            .i32_const(call_index)
+            .local_set(index)
+            .br(self.unwind)
+            .id();
+        let nothing = builder.dangling_instr_seq(None).id();
+        let mut instrs = spilled[spilled.len() - consumed..]
+            .iter()
+            .map(|&local| at(LocalGet { local }))
+            .collect::<Vec<_>>();
+        instrs.push(call);
+        instrs.extend(results.iter().rev().map(|&local| at(LocalSet { local })));
+        instrs.extend([
+            at(GlobalGet { global: self.state }),
+            i32_const(UNWINDING),
+            binop(BinaryOp::I32Eq),
+            at(IfElse {
+                consequent: unwind,
+                alternative: nothing,
+            }),
+        ]);
+        let consequent = builder.dangling_instr_seq(None).id();
+        let alternative = builder.dangling_instr_seq(None).id();
+        func.block_mut(consequent).instrs = instrs;
+
+        out.extend([
+            at(GlobalGet { global: self.state }),
+            i32_const(REWINDING),
+            binop(BinaryOp::I32Ne),
+            at(LocalGet { local: index }),
+            i32_const(call_index),
+            binop(BinaryOp::I32Eq),
+            binop(BinaryOp::I32Or),
+            at(IfElse {
+                consequent,
+                alternative,
+            }),
+        ]);
+        (consumed, results)
+    }
+
+    /// Instrument a block containing suspension points, whose operands have
+    /// been spilled to `spilled`. Returns how many of those it consumes, and
+    /// the locals its results are spilled to.
+    ///
+    /// The block loses its parameters, which its body unspills instead.
+    ///
+    /// ```text
+    /// if not rewinding or rewinding to a call inside this block
+    ///   block
+    ///   spill the results
+    /// end
+    /// ```
+    fn block(
+        &mut self,
+        func: &mut LocalFunction,
+        out: &mut Vec<(Instr, InstrLocId)>,
+        block: (Instr, InstrLocId),
+        spilled: &[LocalId],
+    ) -> (usize, Vec<LocalId>) {
+        let first = self.calls as i32;
+        let mut instrs = Vec::new();
+        let (consumed, results) = match &block.0 {
+            Instr::Block(Block { seq }) | Instr::Loop(Loop { seq }) => {
+                let (params, results) = seq_type(self.types, func.block(*seq).ty);
+                self.seq(func, *seq, &spilled[spilled.len() - params.len()..]);
+                (params.len(), results)
+            }
+            Instr::IfElse(IfElse {
+                consequent,
+                alternative,
+            }) => {
+                let (params, results) = seq_type(self.types, func.block(*consequent).ty);
+                let (condition, spilled) = spilled.split_last().unwrap();
+                let params = &spilled[spilled.len() - params.len()..];
+                self.seq(func, *consequent, params);
+                self.seq(func, *alternative, params);
+                instrs.push(at(LocalGet { local: *condition }));
+                (params.len() + 1, results)
+            }
+            _ => unreachable!(),
+        };
+        let last = self.calls as i32 - 1;
+        let results = results
+            .into_iter()
+            .map(|ty| self.locals.add(ty))
+            .collect::<Vec<_>>();
+        instrs.push(block);
+        instrs.extend(results.iter().rev().map(|&local| at(LocalSet { local })));
+
+        let builder = func.builder_mut();
+        let consequent = builder.dangling_instr_seq(None).id();
+        let alternative = builder.dangling_instr_seq(None).id();
+        func.block_mut(consequent).instrs = instrs;
+        let index = self.index;
+        out.extend([
+            at(GlobalGet { global: self.state }),
+            i32_const(REWINDING),
+            binop(BinaryOp::I32Ne),
+            at(LocalGet { local: index }),
+            i32_const(first),
+            binop(BinaryOp::I32GeU),
+            at(LocalGet { local: index }),
+            i32_const(last),
+            binop(BinaryOp::I32LeU),
+            binop(BinaryOp::I32And),
+            binop(BinaryOp::I32Or),
+            at(IfElse {
+                consequent,
+                alternative,
+            }),
+        ]);
+        (consumed, results)
+    }
+}
+
+#[derive(Default)]
+struct Calls {
+    direct: Vec<FunctionId>,
+    indirect: bool,
+}
+
+impl<'instr> Visitor<'instr> for Calls {
+    fn visit_call(&mut self, instr: &Call) {
+        self.direct.push(instr.func);
+    }
+
+    fn visit_return_call(&mut self, instr: &ReturnCall) {
+        self.direct.push(instr.func);
+    }
+
+    fn visit_call_indirect(&mut self, _: &CallIndirect) {
+        self.indirect = true;
+    }
+
+    fn visit_return_call_indirect(&mut self, _: &ReturnCallIndirect) {
+        self.indirect = true;
+    }
+
+    fn visit_call_ref(&mut self, _: &CallRef) {
+        self.indirect = true;
+    }
+
+    fn visit_return_call_ref(&mut self, _: &ReturnCallRef) {
+        self.indirect = true;
+    }
+}
+
+#[derive(Default)]
+struct Locals {
+    locals: IdHashSet<crate::Local>,
+}
+
+impl<'instr> Visitor<'instr> for Locals {
+    fn visit_local_id(&mut self, local: &LocalId) {
+        self.locals.insert(*local);
+    }
+}
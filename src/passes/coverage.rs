@@ -0,0 +1,229 @@
+//! Counts how many times each basic block runs, for code coverage.
+//!
+//! A counter is incremented at the start of every instruction sequence, and
+//! after every instruction that can fall through after branching elsewhere or
+//! being branched out of: conditional branches, and blocks, loops, `if`s and
+//! `try_table`s. The counters are little-endian `i64`s, one after another in
+//! memory, and the returned `Coverage` says which code each one counts.
+
+use crate::ir::*;
+use crate::passes::instr_seqs;
+use crate::{FunctionId, LocalFunction, MemoryId, Module, Result};
+use anyhow::bail;
+use std::mem;
+
+/// Options for `run`.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// The memory to keep the counters in, from `address` onwards. If `None`,
+    /// a new memory is added just for the counters, which needs the
+    /// multi-memory proposal if the module already has a memory.
+    pub memory: Option<MemoryId>,
+    /// The address of the first counter.
+    pub address: u64,
+    /// Export the memory holding the counters with this name.
+    pub export: Option<String>,
+}
+
+/// Where `run` put the counters, and what each of them counts.
+#[derive(Clone, Debug)]
+pub struct Coverage {
+    /// The memory holding the counters.
+    pub memory: MemoryId,
+    /// The address of the first counter.
+    pub address: u64,
+    /// Each counter, in the order they're laid out in memory.
+    pub counters: Vec<Counter>,
+}
+
+/// The code that a counter counts.
+#[derive(Clone, Copy, Debug)]
+pub struct Counter {
+    /// The function the counter is in.
+    pub func: FunctionId,
+    /// The instruction sequence the counter is in.
+    pub seq: InstrSeqId,
+    /// The location of the first instruction the counter counts, which is
+    /// the offset of its bytecode if the function was parsed from a wasm
+    /// file. `None` if it counts an empty sequence.
+    pub loc: Option<InstrLocId>,
+}
+
+impl Coverage {
+    /// Read the counters out of the contents of their memory.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the memory is too small to hold them.
+    pub fn counts(&self, memory: &[u8]) -> Vec<u64> {
+        let start = self.address as usize;
+        let end = start + self.counters.len() * 8;
+        memory[start..end]
+            .chunks(8)
+            .map(|count| u64::from_le_bytes(count.try_into().unwrap()))
+            .collect()
+    }
+}
+
+/// Add coverage counters to every local function of `module`.
+///
+/// Returns an error, before changing anything, if the counters don't fit in
+/// the initial size of `options.memory`.
+pub fn run(module: &mut Module, options: &Options) -> Result<Coverage> {
+    let funcs = module
+        .funcs
+        .iter_local()
+        .map(|(id, _)| id)
+        .collect::<Vec<_>>();
+
+    let mut counters = Vec::new();
+    for id in &funcs {
+        let func = module.funcs.get(*id).kind.unwrap_local();
+        for seq in instr_seqs(func, func.entry_block()) {
+            let instrs = &func.block(seq).instrs;
+            let mut counter = |loc| {
+                counters.push(Counter {
+                    func: *id,
+                    seq,
+                    loc,
+                })
+            };
+            counter(instrs.first().map(|(_, loc)| *loc));
+            for pair in instrs.windows(2) {
+                if falls_through(&pair[0].0) {
+                    counter(Some(pair[1].1));
+                }
+            }
+        }
+    }
+
+    let size = counters.len() as u64 * 8;
+    let memory = match options.memory {
+        Some(memory) => {
+            let memory = module.memories.get(memory);
+            let page_size = 1 << memory.page_size_log2.unwrap_or(16);
+            if options.address + size > memory.initial * page_size {
+                bail!(
+                    "{} bytes of counters don't fit in memory at {}",
+                    size,
+                    options.address
+                );
+            }
+            memory.id()
+        }
+        None => {
+            let pages = (options.address + size).div_ceil(1 << 16);
+            module
+                .memories
+                .add_local(false, false, pages, Some(pages), None)
+        }
+    };
+    if let Some(name) = &options.export {
+        module.exports.add(name, memory);
+    }
+
+    let increment = Increment {
+        memory,
+        memory64: module.memories.get(memory).memory64,
+        address: options.address,
+    };
+    let mut next = 0;
+    for id in funcs {
+        let func = module.funcs.get_mut(id).kind.unwrap_local_mut();
+        instrument(func, &increment, &mut next);
+    }
+    debug_assert_eq!(next, counters.len());
+
+    Ok(Coverage {
+        memory,
+        address: options.address,
+        counters,
+    })
+}
+
+/// Whether execution can continue after `instr` from somewhere other than the
+/// instruction before it, so it needs a counter of its own.
+fn falls_through(instr: &Instr) -> bool {
+    matches!(
+        instr,
+        Instr::BrIf(_)
+            | Instr::BrOnNull(_)
+            | Instr::BrOnNonNull(_)
+            | Instr::BrOnCast(_)
+            | Instr::BrOnCastFail(_)
+            | Instr::Block(_)
+            | Instr::Loop(_)
+            | Instr::IfElse(_)
+            | Instr::Try(_)
+            | Instr::TryTable(_)
+    )
+}
+
+struct Increment {
+    memory: MemoryId,
+    memory64: bool,
+    address: u64,
+}
+
+impl Increment {
+    /// Increment the counter at `index`.
+    fn push(&self, index: usize, instrs: &mut Vec<(Instr, InstrLocId)>) {
+        let memory = self.memory;
+        let arg = MemArg {
+            align: 8,
+            offset: self.address + index as u64 * 8,
+        };
+        let address = if self.memory64 {
+            Value::I64(0)
+        } else {
+            Value::I32(0)
+        };
+        let increment: [Instr; 6] = [
+            Const { value: address }.into(),
+            Const { value: address }.into(),
+            Load {
+                memory,
+                kind: LoadKind::I64 { atomic: false },
+                arg,
+            }
+            .into(),
+            Const {
+                value: Value::I64(1),
+            }
+            .into(),
+            Binop {
+                op: BinaryOp::I64Add,
+            }
+            .into(),
+            Store {
+                memory,
+                kind: StoreKind::I64 { atomic: false },
+                arg,
+            }
+            .into(),
+        ];
+        instrs.extend(increment.map(|instr| (instr, InstrLocId::default())));
+    }
+}
+
+/// Add increments of the counters from `next` onwards to `func`, in the same
+/// order `run` laid them out.
+fn instrument(func: &mut LocalFunction, increment: &Increment, next: &mut usize) {
+    for seq in instr_seqs(func, func.entry_block()) {
+        let block = func.block_mut(seq);
+        let original = mem::take(&mut block.instrs);
+        let mut instrs = Vec::with_capacity(original.len() + 6);
+        increment.push(*next, &mut instrs);
+        *next += 1;
+        let len = original.len();
+        for (i, (instr, loc)) in original.into_iter().enumerate() {
+            let counted = falls_through(&instr) && i + 1 < len;
+            instrs.push((instr, loc));
+            if counted {
+                increment.push(*next, &mut instrs);
+                *next += 1;
+            }
+        }
+        func.block_mut(seq).instrs = instrs;
+    }
+}
@@ -0,0 +1,292 @@
+//! Merges, trims and splits active data segments.
+//!
+//! Compilers tend to emit lots of small active data segments, often padded
+//! with zeros that a fresh memory already holds. This pass rebuilds the active
+//! segments of each memory from the memory image they produce, so that every
+//! run of non-zero bytes lives in as few segments as possible.
+
+use crate::ir::Value;
+use crate::map::IdHashSet;
+use crate::{ConstExpr, Data, DataId, DataKind, MemoryId, MemoryImage, Module};
+use std::ops::Range;
+
+/// Options for `run`.
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// The most data segments the module may have afterwards.
+    ///
+    /// Web engines reject modules with more than 100,000 data segments, which
+    /// is the default. Segments are merged across gaps, smallest first, until
+    /// the module fits.
+    pub max_segments: usize,
+    /// The shortest run of zeros worth splitting a segment at.
+    ///
+    /// Each segment costs a few bytes for its header and offset, so shorter
+    /// runs of zeros are kept inline, and segments separated by shorter gaps
+    /// are merged.
+    pub min_zero_run: usize,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            max_segments: 100_000,
+            min_zero_run: 16,
+        }
+    }
+}
+
+/// Rewrite the active data segments of `module` according to `options`.
+///
+/// A memory's segments are only rewritten if all of its active segments have
+/// constant offsets and fit within its initial size. Those segments are then
+/// replaced with new ones, while passive segments, and active segments
+/// referenced by `memory.init` or `data.drop`, keep their `DataId`s and
+/// contents. The new segments don't merge across the bytes that referenced
+/// segments leave in memory.
+///
+/// Imported memories may already hold data, so their segments are only merged
+/// where they touch or overlap, and zeros are left alone.
+///
+/// Relocatable objects are left untouched, since their linking metadata
+/// describes the original segments.
+pub fn run(module: &mut Module, options: &Options) {
+    if module.linking.is_some() {
+        return;
+    }
+
+    let mut referenced = IdHashSet::default();
+    for (_, func) in module.funcs.iter_local() {
+        referenced.extend(func.used_data_segments());
+    }
+
+    let mut rewrites = Vec::new();
+    for memory in module.memories.iter() {
+        let (pinned, segments): (Vec<_>, Vec<_>) = active_segments(module, memory.id())
+            .into_iter()
+            .partition(|d| referenced.contains(d));
+        if segments.is_empty() {
+            continue;
+        }
+        let mut image = MemoryImage::new(memory);
+        if let Err(e) = module.data.paint(module, &|_| None, &mut image) {
+            log::debug!("not rewriting data segments of {:?}: {:?}", memory.id(), e);
+            continue;
+        }
+        let fresh = memory.import.is_none();
+        let pinned = Pinned {
+            ranges: pinned.iter().map(|d| range(module, *d)).collect(),
+            segments: pinned,
+        };
+        let pieces = pieces(&image, fresh, options.min_zero_run, &pinned);
+        rewrites.push(Rewrite {
+            memory: memory.id(),
+            memory64: memory.memory64,
+            fresh,
+            segments,
+            pinned,
+            pieces,
+        });
+    }
+
+    // Every segment that isn't rewritten counts against the limit, and the
+    // rest of it is shared out by merging the pieces of fresh memories.
+    let rewritten = rewrites.iter().map(|r| r.segments.len()).sum::<usize>();
+    let budget = options
+        .max_segments
+        .saturating_sub(module.data.iter().count() - rewritten);
+    let mut excess = rewrites
+        .iter()
+        .map(|r| r.pieces.len())
+        .sum::<usize>()
+        .saturating_sub(budget);
+    for rewrite in rewrites.iter_mut().filter(|r| r.fresh) {
+        if excess == 0 {
+            break;
+        }
+        let target = rewrite.pieces.len().saturating_sub(excess).max(1);
+        excess -= rewrite.pieces.len().saturating_sub(target);
+        merge_smallest_gaps(&mut rewrite.pieces, target, &rewrite.pinned);
+    }
+
+    for Rewrite {
+        memory,
+        memory64,
+        segments,
+        pieces,
+        ..
+    } in rewrites
+    {
+        log::debug!(
+            "rewriting {} data segments of {:?} into {}",
+            segments.len(),
+            memory,
+            pieces.len()
+        );
+        for id in segments {
+            module.data.delete(id);
+            module.memories.get_mut(memory).data_segments.remove(&id);
+        }
+        for (range, value) in pieces {
+            let offset = if memory64 {
+                Value::I64(range.start as i64)
+            } else {
+                Value::I32(range.start as i32)
+            };
+            let kind = DataKind::Active {
+                memory,
+                offset: ConstExpr::Value(offset),
+            };
+            let id = module.data.add(kind, value);
+            module.memories.get_mut(memory).data_segments.insert(id);
+        }
+    }
+}
+
+struct Rewrite {
+    memory: MemoryId,
+    memory64: bool,
+    /// Whether the memory starts out zeroed, rather than being imported.
+    fresh: bool,
+    /// The segments to replace.
+    segments: Vec<DataId>,
+    /// The segments to keep.
+    pinned: Pinned,
+    /// What to replace them with.
+    pieces: Vec<(Range<u64>, Vec<u8>)>,
+}
+
+/// Active segments that are referenced from code, and so are kept as they are.
+/// New segments are added after them, so they may overwrite their bytes, but
+/// mustn't zero bytes they leave in memory.
+struct Pinned {
+    segments: Vec<DataId>,
+    /// The bytes each segment covers.
+    ranges: Vec<Range<u64>>,
+}
+
+impl Pinned {
+    fn overlaps(&self, range: &Range<u64>) -> bool {
+        self.ranges
+            .iter()
+            .any(|r| r.start < range.end && range.start < r.end)
+    }
+
+    fn contains(&self, address: u64) -> bool {
+        self.ranges.iter().any(|r| r.contains(&address))
+    }
+}
+
+/// The bytes that the active segment `data` covers, whose offset has already
+/// been evaluated to paint it.
+fn range(module: &Module, data: DataId) -> Range<u64> {
+    let data = module.data.get(data);
+    let start = match &data.kind {
+        DataKind::Active { offset, .. } => match offset.eval(module, &|_| None) {
+            Ok(Some(Value::I32(offset))) => u64::from(offset as u32),
+            Ok(Some(Value::I64(offset))) => offset as u64,
+            _ => unreachable!(),
+        },
+        DataKind::Passive => unreachable!(),
+    };
+    start..start + data.value.len() as u64
+}
+
+fn active_segments(module: &Module, memory: MemoryId) -> Vec<DataId> {
+    module
+        .data
+        .iter()
+        .filter(|d| matches!(d.kind, DataKind::Active { memory: m, .. } if m == memory))
+        .map(Data::id)
+        .collect()
+}
+
+/// Split the contents of `image` into the pieces that segments should hold.
+fn pieces(
+    image: &MemoryImage,
+    fresh: bool,
+    min_zero_run: usize,
+    pinned: &Pinned,
+) -> Vec<(Range<u64>, Vec<u8>)> {
+    // First coalesce everything that touches, leaving out what pinned
+    // segments write themselves.
+    let mut runs: Vec<(Range<u64>, Vec<u8>)> = Vec::new();
+    let chunks = image
+        .chunks()
+        .filter(|(_, data, _)| !pinned.segments.contains(data));
+    for (range, _, bytes) in chunks {
+        match runs.last_mut() {
+            Some((last, value)) if last.end == range.start => {
+                last.end = range.end;
+                value.extend_from_slice(bytes);
+            }
+            _ => runs.push((range, bytes.to_vec())),
+        }
+    }
+    if !fresh {
+        return runs;
+    }
+
+    // Then split each run into its non-zero parts, separated by long runs of
+    // zeros, and merge those back together across short gaps. Zeros over a
+    // pinned segment have to be written.
+    let mut pieces: Vec<(Range<u64>, Vec<u8>)> = Vec::new();
+    for (range, value) in runs {
+        let keep = |i: usize| value[i] != 0 || pinned.contains(range.start + i as u64);
+        let mut i = 0;
+        while i < value.len() {
+            if !keep(i) {
+                i += 1;
+                continue;
+            }
+            let start = i;
+            while i < value.len() && keep(i) {
+                i += 1;
+            }
+            let piece = range.start + start as u64..range.start + i as u64;
+            match pieces.last_mut() {
+                Some((last, bytes))
+                    if piece.start - last.end < min_zero_run as u64
+                        && !pinned.overlaps(&(last.end..piece.start)) =>
+                {
+                    bytes.resize((piece.start - last.start) as usize, 0);
+                    bytes.extend_from_slice(&value[start..i]);
+                    last.end = piece.end;
+                }
+                _ => pieces.push((piece, value[start..i].to_vec())),
+            }
+        }
+    }
+    pieces
+}
+
+/// Merge `pieces` across the smallest gaps, filling them with zeros, until
+/// there are only `target` of them, or the only gaps left hold bytes of
+/// `pinned` segments.
+fn merge_smallest_gaps(pieces: &mut Vec<(Range<u64>, Vec<u8>)>, target: usize, pinned: &Pinned) {
+    if pieces.len() <= target {
+        return;
+    }
+    let mut gaps = (1..pieces.len())
+        .filter(|i| !pinned.overlaps(&(pieces[i - 1].0.end..pieces[*i].0.start)))
+        .map(|i| (pieces[i].0.start - pieces[i - 1].0.end, i))
+        .collect::<Vec<_>>();
+    gaps.sort();
+    let mut merge_into_previous = vec![false; pieces.len()];
+    for (_, i) in gaps.iter().take(pieces.len() - target) {
+        merge_into_previous[*i] = true;
+    }
+
+    let mut merged: Vec<(Range<u64>, Vec<u8>)> = Vec::with_capacity(target);
+    for (piece, merge) in pieces.drain(..).zip(merge_into_previous) {
+        match merged.last_mut() {
+            Some((last, bytes)) if merge => {
+                bytes.resize((piece.0.start - last.start) as usize, 0);
+                bytes.extend_from_slice(&piece.1);
+                last.end = piece.0.end;
+            }
+            _ => merged.push(piece),
+        }
+    }
+    *pieces = merged;
+}
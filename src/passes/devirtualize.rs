@@ -0,0 +1,337 @@
+//! Turns indirect calls through tables that never change into direct calls.
+//!
+//! A table that is neither imported nor exported, and that no instruction
+//! writes to, holds exactly what its element segments put there for the whole
+//! life of the instance. A `call_indirect` of a constant index into such a
+//! table always calls the same function, and other calls into it can only
+//! reach the functions of the right type in it.
+
+use crate::ir::*;
+use crate::map::{IdHashMap, IdHashSet};
+use crate::passes::{instr_seqs, Scratch};
+use crate::{ExportItem, FunctionId, LocalFunction, Module, ModuleLocals, ModuleTypes};
+use crate::{Table, TableId, TableImage, TableSlot, TypeId, ValType};
+use std::mem;
+
+/// Options for `run`.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// The most functions an indirect call with a non-constant index may
+    /// reach for it to be replaced with a `br_table` dispatching to direct
+    /// calls of each of them.
+    ///
+    /// Indices that don't reach one of those functions still go through the
+    /// original `call_indirect`, so that they trap just as before. Defaults to
+    /// zero, which disables this.
+    pub max_dispatch_targets: usize,
+}
+
+/// Devirtualize the indirect calls in `module` according to `options`.
+///
+/// `call_indirect` and `return_call_indirect` directly after a constant index
+/// become `call` and `return_call` when the slot holds a function of the
+/// right type. Calls that would trap are left alone.
+///
+/// Relocatable objects are left untouched, since their tables are only
+/// complete once linked.
+pub fn run(module: &mut Module, options: &Options) {
+    if module.linking.is_some() {
+        return;
+    }
+
+    let images = immutable_tables(module);
+    if images.is_empty() {
+        return;
+    }
+    let tables = images
+        .into_iter()
+        .map(|(id, image)| {
+            let table = Resolved::new(module, module.tables.get(id), image);
+            (id, table)
+        })
+        .collect::<IdHashMap<Table, Resolved>>();
+
+    let mut cx = Context {
+        tables: &tables,
+        types: &mut module.types,
+        locals: &mut module.locals,
+        scratch: Scratch::default(),
+        options,
+        devirtualized: 0,
+        dispatched: 0,
+    };
+    for (_, func) in module.funcs.iter_local_mut() {
+        cx.rewrite(func);
+    }
+    log::debug!(
+        "devirtualized {} indirect calls and added {} dispatches",
+        cx.devirtualized,
+        cx.dispatched
+    );
+}
+
+/// Images of every table whose contents never change after instantiation.
+fn immutable_tables(module: &Module) -> IdHashMap<Table, TableImage> {
+    let mut written = IdHashSet::default();
+    for export in module.exports.iter() {
+        if let ExportItem::Table(t) = export.item {
+            written.insert(t);
+        }
+    }
+    for (_, func) in module.funcs.iter_local() {
+        let mut visitor = TableWrites {
+            written: &mut written,
+        };
+        dfs_in_order(&mut visitor, func, func.entry_block());
+    }
+
+    let mut images = IdHashMap::default();
+    for table in module.tables.iter() {
+        if table.import.is_some() || written.contains(&table.id()) {
+            continue;
+        }
+        let image = TableImage::new(module, table).and_then(|mut image| {
+            module.elements.paint(module, &|_| None, &mut image)?;
+            Ok(image)
+        });
+        match image {
+            Ok(image) => {
+                images.insert(table.id(), image);
+            }
+            Err(e) => log::debug!("can't devirtualize calls through {:?}: {:?}", table.id(), e),
+        }
+    }
+    images
+}
+
+struct TableWrites<'a> {
+    written: &'a mut IdHashSet<Table>,
+}
+
+impl<'instr> Visitor<'instr> for TableWrites<'_> {
+    fn visit_table_set(&mut self, instr: &TableSet) {
+        self.written.insert(instr.table);
+    }
+
+    fn visit_table_grow(&mut self, instr: &TableGrow) {
+        self.written.insert(instr.table);
+    }
+
+    fn visit_table_fill(&mut self, instr: &TableFill) {
+        self.written.insert(instr.table);
+    }
+
+    fn visit_table_init(&mut self, instr: &TableInit) {
+        self.written.insert(instr.table);
+    }
+
+    fn visit_table_copy(&mut self, instr: &TableCopy) {
+        self.written.insert(instr.dst);
+    }
+}
+
+/// A table image with the type of every function in it.
+struct Resolved {
+    image: TableImage,
+    table64: bool,
+    types: Vec<Option<(FunctionId, TypeId)>>,
+}
+
+impl Resolved {
+    fn new(module: &Module, table: &Table, image: TableImage) -> Resolved {
+        let types = image
+            .slots
+            .iter()
+            .map(|slot| match slot {
+                TableSlot::Func(f) => Some((*f, module.funcs.get(*f).ty())),
+                TableSlot::Null | TableSlot::Unknown => None,
+            })
+            .collect();
+        Resolved {
+            image,
+            table64: table.table64,
+            types,
+        }
+    }
+
+    /// The function a call of type `ty` to slot `index` reaches, if it
+    /// doesn't trap.
+    fn target(&self, index: u64, ty: TypeId) -> Option<FunctionId> {
+        match self.types.get(usize::try_from(index).ok()?) {
+            Some(Some((f, f_ty))) if *f_ty == ty => Some(*f),
+            _ => None,
+        }
+    }
+}
+
+struct Context<'a> {
+    tables: &'a IdHashMap<Table, Resolved>,
+    types: &'a mut ModuleTypes,
+    locals: &'a mut ModuleLocals,
+    scratch: Scratch,
+    options: &'a Options,
+    devirtualized: usize,
+    dispatched: usize,
+}
+
+impl Context<'_> {
+    fn rewrite(&mut self, func: &mut LocalFunction) {
+        self.scratch = Scratch::default();
+        for seq in instr_seqs(func, func.entry_block()) {
+            let instrs = mem::take(&mut func.block_mut(seq).instrs);
+            let mut rewritten = Vec::with_capacity(instrs.len());
+            for (instr, loc) in instrs {
+                let (table, ty, tail) = match &instr {
+                    Instr::CallIndirect(c) => (c.table, c.ty, false),
+                    Instr::ReturnCallIndirect(c) => (c.table, c.ty, true),
+                    _ => {
+                        rewritten.push((instr, loc));
+                        continue;
+                    }
+                };
+                let resolved = match self.tables.get(&table) {
+                    Some(resolved) => resolved,
+                    None => {
+                        rewritten.push((instr, loc));
+                        continue;
+                    }
+                };
+
+                let index = match rewritten.last() {
+                    Some((Instr::Const(Const { value }), _)) => match (value, resolved.table64) {
+                        (Value::I32(i), false) => Some(u64::from(*i as u32)),
+                        (Value::I64(i), true) => Some(*i as u64),
+                        _ => None,
+                    },
+                    _ => None,
+                };
+                if let Some(func) = index.and_then(|i| resolved.target(i, ty)) {
+                    rewritten.pop();
+                    let call = if tail {
+                        ReturnCall { func }.into()
+                    } else {
+                        Call { func }.into()
+                    };
+                    rewritten.push((call, loc));
+                    self.devirtualized += 1;
+                    continue;
+                }
+
+                if index.is_none() && !tail && !resolved.table64 {
+                    if let Some(dispatch) = self.dispatch(func, resolved, table, ty, loc) {
+                        rewritten.extend(dispatch);
+                        self.dispatched += 1;
+                        continue;
+                    }
+                }
+                rewritten.push((instr, loc));
+            }
+            func.block_mut(seq).instrs = rewritten;
+        }
+    }
+
+    /// Build a replacement for a `call_indirect` of type `ty` through `table`
+    /// that branches on the index to a direct call of each possible target.
+    ///
+    /// ```text
+    /// local.set $index
+    /// block $done (param ..) (result ..)
+    ///   block $fallback (param ..) (result ..)
+    ///     block $target_n ...
+    ///       block $target_0
+    ///         local.get $index
+    ///         br_table $target_i.. $fallback
+    ///       end
+    ///       call $f_0
+    ///       br $done
+    ///     ...
+    ///   end
+    ///   local.get $index
+    ///   call_indirect
+    /// end
+    /// ```
+    fn dispatch(
+        &mut self,
+        func: &mut LocalFunction,
+        resolved: &Resolved,
+        table: TableId,
+        ty: TypeId,
+        loc: InstrLocId,
+    ) -> Option<Vec<(Instr, InstrLocId)>> {
+        if !resolved.image.is_complete() {
+            return None;
+        }
+        let call = CallIndirect { ty, table };
+        let candidates = resolved
+            .types
+            .iter()
+            .flatten()
+            .filter(|(_, f_ty)| *f_ty == ty)
+            .map(|(f, _)| *f);
+        let mut targets: Vec<FunctionId> = Vec::new();
+        for f in candidates {
+            if !targets.contains(&f) {
+                targets.push(f);
+            }
+        }
+        if targets.is_empty() || targets.len() > self.options.max_dispatch_targets {
+            return None;
+        }
+
+        let (params, results) = self.types.params_results(ty);
+        let (params, results) = (params.to_vec(), results.to_vec());
+        let pass_through = InstrSeqType::new(self.types, &params, &params);
+        let whole = InstrSeqType::new(self.types, &params, &results);
+        let index = self.scratch.local(self.locals, ValType::I32, 0);
+        let builder = func.builder_mut();
+
+        let done = builder.dangling_instr_seq(whole).id();
+        let mut blocks = Vec::with_capacity(targets.len() + 1);
+        blocks.push(builder.dangling_instr_seq(pass_through).id());
+        for (i, target) in targets.iter().enumerate() {
+            let inner = blocks[i];
+            let mut block = builder.dangling_instr_seq(pass_through);
+            block
+                .instr(Block { seq: inner })
+                .instr(Call { func: *target })
+                .instr(Br { block: done });
+            blocks.push(block.id());
+        }
+        let fallback = *blocks.last().unwrap();
+
+        let last = resolved
+            .types
+            .iter()
+            .rposition(|t| matches!(t, Some((_, f_ty)) if *f_ty == ty))
+            .unwrap();
+        let table_blocks = resolved.types[..=last]
+            .iter()
+            .map(|t| match t {
+                Some((f, f_ty)) if *f_ty == ty => {
+                    blocks[targets.iter().position(|t| t == f).unwrap()]
+                }
+                _ => fallback,
+            })
+            .collect();
+        builder
+            .instr_seq(blocks[0])
+            .local_get(index)
+            .instr(BrTable {
+                blocks: table_blocks,
+                default: fallback,
+            });
+        builder
+            .instr_seq(done)
+            .instr(Block { seq: fallback })
+            .local_get(index);
+        builder
+            .instr_seq(done)
+            .instrs_mut()
+            .push((call.into(), loc));
+
+        Some(vec![
+            (LocalSet { local: index }.into(), loc),
+            (Block { seq: done }.into(), loc),
+        ])
+    }
+}
@@ -0,0 +1,541 @@
+//! Switching memories and tables between 32-bit and 64-bit indices.
+//!
+//! Flipping a memory's or table's index type changes the type of every address
+//! or index operand and every size result of the instructions that use it, so
+//! each of those is wrapped in conversions that keep the code around it
+//! working with the types it did before.
+
+use crate::ir::*;
+use crate::passes::{instr_seqs, local_sets, Scratch};
+use crate::{ConstExpr, DataKind, ElementKind, LocalFunction, LocalId, MemoryId, Module};
+use crate::{ModuleLocals, Result, TableId, ValType};
+use anyhow::bail;
+use std::collections::HashMap;
+use std::mem;
+
+/// Make `memory` use 64-bit addresses if `memory64` is true, and 32-bit
+/// addresses otherwise.
+///
+/// Addresses that don't fit in 32 bits trap, as they would have gone out of
+/// bounds anyway, and growing by more pages than fit in 32 bits fails.
+/// Lowering clamps the memory's maximum size to what 32-bit addresses can
+/// reach, and raising clamps it so that sizes still fit in the 32 bits the
+/// code expects.
+///
+/// Returns an error, without changing anything, if the memory is imported or
+/// exported, as code outside the module would still use the old address type,
+/// if it starts out too large for 32-bit addresses, or if a data segment's
+/// offset isn't a constant.
+pub fn set_memory64(module: &mut Module, memory: MemoryId, memory64: bool) -> Result<()> {
+    let m = module.memories.get(memory);
+    if m.memory64 == memory64 {
+        return Ok(());
+    }
+    if m.import.is_some() {
+        bail!("cannot change the index type of imported {:?}", memory);
+    }
+    if module.exports.get_exported_memory(memory).is_some() {
+        bail!("cannot change the index type of exported {:?}", memory);
+    }
+    let limit = 1u64 << (32 - m.page_size_log2.unwrap_or(16));
+    if m.initial > limit {
+        bail!("{:?} is too large for 32-bit addresses", memory);
+    }
+    let mut offsets = Vec::new();
+    for data in module.data.iter() {
+        if let DataKind::Active { memory: m, offset } = &data.kind {
+            if *m == memory {
+                offsets.push(convert_offset(module, offset, memory64)?);
+            }
+        }
+    }
+
+    rewrite(module, Target::Memory(memory), memory64);
+    let mut offsets = offsets.into_iter();
+    for data in module.data.iter_mut() {
+        if let DataKind::Active { memory: m, offset } = &mut data.kind {
+            if *m == memory {
+                *offset = offsets.next().unwrap();
+            }
+        }
+    }
+    let m = module.memories.get_mut(memory);
+    m.memory64 = memory64;
+    m.maximum = Some(m.maximum.map_or(limit, |max| max.min(limit)));
+    Ok(())
+}
+
+/// Make `table` use 64-bit indices if `table64` is true, and 32-bit indices
+/// otherwise.
+///
+/// This converts indices and sizes the same way `set_memory64` converts
+/// addresses, and clamps the table's maximum size the same way.
+///
+/// Returns an error, without changing anything, if the table is imported or
+/// exported, if it starts out too large for 32-bit indices, or if an element
+/// segment's offset isn't a constant.
+pub fn set_table64(module: &mut Module, table: TableId, table64: bool) -> Result<()> {
+    let t = module.tables.get(table);
+    if t.table64 == table64 {
+        return Ok(());
+    }
+    if t.import.is_some() {
+        bail!("cannot change the index type of imported {:?}", table);
+    }
+    if module.exports.get_exported_table(table).is_some() {
+        bail!("cannot change the index type of exported {:?}", table);
+    }
+    let limit = u64::from(u32::MAX);
+    if t.initial > limit {
+        bail!("{:?} is too large for 32-bit indices", table);
+    }
+    let mut offsets = Vec::new();
+    for elem in module.elements.iter() {
+        if let ElementKind::Active { table: t, offset } = &elem.kind {
+            if *t == table {
+                offsets.push(convert_offset(module, offset, table64)?);
+            }
+        }
+    }
+
+    rewrite(module, Target::Table(table), table64);
+    let mut offsets = offsets.into_iter();
+    for elem in module.elements.iter_mut() {
+        if let ElementKind::Active { table: t, offset } = &mut elem.kind {
+            if *t == table {
+                *offset = offsets.next().unwrap();
+            }
+        }
+    }
+    let t = module.tables.get_mut(table);
+    t.table64 = table64;
+    t.maximum = Some(t.maximum.map_or(limit, |max| max.min(limit)));
+    Ok(())
+}
+
+/// Evaluate a segment's offset and give it the other index type.
+fn convert_offset(module: &Module, offset: &ConstExpr, to64: bool) -> Result<ConstExpr> {
+    let value = match offset.eval(module, &|_| None)? {
+        Some(Value::I32(v)) if to64 => Value::I64(i64::from(v as u32)),
+        Some(Value::I64(v)) if !to64 => match u32::try_from(v as u64) {
+            Ok(v) => Value::I32(v as i32),
+            Err(_) => bail!("segment offset {} doesn't fit in 32 bits", v as u64),
+        },
+        _ => bail!("segment offset isn't a known constant"),
+    };
+    Ok(ConstExpr::Value(value))
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Target {
+    Memory(MemoryId),
+    Table(TableId),
+}
+
+fn rewrite(module: &mut Module, target: Target, to64: bool) {
+    let memories = module
+        .memories
+        .iter()
+        .map(|m| (m.id(), m.memory64))
+        .collect::<HashMap<_, _>>();
+    let tables = module
+        .tables
+        .iter()
+        .map(|t| (t.id(), (t.table64, ValType::Ref(t.element_ty))))
+        .collect::<HashMap<_, _>>();
+    for (_, func) in module.funcs.iter_local_mut() {
+        let mut rewriter = Rewriter {
+            target,
+            to64,
+            memories: &memories,
+            tables: &tables,
+            locals: &mut module.locals,
+            scratch: Scratch::default(),
+        };
+        rewriter.function(func);
+    }
+}
+
+/// What happens to an instruction's result.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Output {
+    /// It isn't an index.
+    Unchanged,
+    /// It is a size in the target's index type.
+    Size,
+    /// It is a `grow`'s result, whose operand is the top one.
+    Grow,
+}
+
+struct Rewriter<'a> {
+    target: Target,
+    to64: bool,
+    /// Whether each memory used 64-bit addresses before the change.
+    memories: &'a HashMap<MemoryId, bool>,
+    /// The same for each table, with its element type.
+    tables: &'a HashMap<TableId, (bool, ValType)>,
+    locals: &'a mut ModuleLocals,
+    scratch: Scratch,
+}
+
+impl Rewriter<'_> {
+    fn function(&mut self, func: &mut LocalFunction) {
+        for seq in instr_seqs(func, func.entry_block()) {
+            let instrs = mem::take(&mut func.block_mut(seq).instrs);
+            let mut out = Vec::with_capacity(instrs.len());
+            for (instr, loc) in instrs {
+                self.instr(func, &mut out, instr, loc);
+            }
+            func.block_mut(seq).instrs = out;
+        }
+    }
+
+    /// The types an index into `target` has before and after the change.
+    fn index(&self, target: Target) -> (ValType, ValType) {
+        let before = match target {
+            Target::Memory(memory) => self.memories[&memory],
+            Target::Table(table) => self.tables[&table].0,
+        };
+        let after = if target == self.target {
+            self.to64
+        } else {
+            before
+        };
+        (address(before), address(after))
+    }
+
+    /// The types of the operands of `instr`, from the lowest one that may be
+    /// an index up to the top of the stack, before and after the change, and
+    /// what becomes of its result. Returns `None` for instructions the change
+    /// doesn't affect.
+    fn shape(&self, instr: &Instr) -> Option<(Vec<(ValType, ValType)>, Output)> {
+        let same = |ty| (ty, ty);
+        let mem = |memory| self.index(Target::Memory(memory));
+        let table = |table| self.index(Target::Table(table));
+        let element = |table| same(self.tables[&table].1);
+        let (targets, operands, output) = match instr {
+            Instr::Load(Load { memory, .. }) => (
+                vec![Target::Memory(*memory)],
+                vec![mem(*memory)],
+                Output::Unchanged,
+            ),
+            Instr::Store(Store { memory, kind, .. }) => {
+                let ty = kind.ty();
+                (
+                    vec![Target::Memory(*memory)],
+                    vec![mem(*memory), same(ty)],
+                    Output::Unchanged,
+                )
+            }
+            Instr::AtomicRmw(AtomicRmw { memory, width, .. }) => (
+                vec![Target::Memory(*memory)],
+                vec![mem(*memory), same(width.ty())],
+                Output::Unchanged,
+            ),
+            Instr::Cmpxchg(Cmpxchg { memory, width, .. }) => {
+                let ty = width.ty();
+                (
+                    vec![Target::Memory(*memory)],
+                    vec![mem(*memory), same(ty), same(ty)],
+                    Output::Unchanged,
+                )
+            }
+            Instr::AtomicNotify(AtomicNotify { memory, .. }) => (
+                vec![Target::Memory(*memory)],
+                vec![mem(*memory), same(ValType::I32)],
+                Output::Unchanged,
+            ),
+            Instr::AtomicWait(AtomicWait {
+                memory, sixty_four, ..
+            }) => {
+                let ty = if *sixty_four {
+                    ValType::I64
+                } else {
+                    ValType::I32
+                };
+                (
+                    vec![Target::Memory(*memory)],
+                    vec![mem(*memory), same(ty), same(ValType::I64)],
+                    Output::Unchanged,
+                )
+            }
+            Instr::LoadSimd(LoadSimd { memory, kind, .. }) => {
+                use LoadSimdKind::*;
+                let mut operands = vec![mem(*memory)];
+                if let V128Load8Lane(_) | V128Load16Lane(_) | V128Load32Lane(_)
+                | V128Load64Lane(_) | V128Store8Lane(_) | V128Store16Lane(_)
+                | V128Store32Lane(_) | V128Store64Lane(_) = kind
+                {
+                    operands.push(same(ValType::V128));
+                }
+                (vec![Target::Memory(*memory)], operands, Output::Unchanged)
+            }
+            Instr::MemorySize(MemorySize { memory }) => {
+                (vec![Target::Memory(*memory)], vec![], Output::Size)
+            }
+            Instr::MemoryGrow(MemoryGrow { memory }) => (
+                vec![Target::Memory(*memory)],
+                vec![mem(*memory)],
+                Output::Grow,
+            ),
+            Instr::MemoryFill(MemoryFill { memory }) => (
+                vec![Target::Memory(*memory)],
+                vec![mem(*memory), same(ValType::I32), mem(*memory)],
+                Output::Unchanged,
+            ),
+            Instr::MemoryCopy(MemoryCopy { src, dst }) => {
+                let (dst_ty, src_ty) = (mem(*dst), mem(*src));
+                (
+                    vec![Target::Memory(*dst), Target::Memory(*src)],
+                    vec![
+                        dst_ty,
+                        src_ty,
+                        (narrower(dst_ty.0, src_ty.0), narrower(dst_ty.1, src_ty.1)),
+                    ],
+                    Output::Unchanged,
+                )
+            }
+            Instr::MemoryInit(MemoryInit { memory, .. }) => (
+                vec![Target::Memory(*memory)],
+                vec![mem(*memory), same(ValType::I32), same(ValType::I32)],
+                Output::Unchanged,
+            ),
+            Instr::TableGet(TableGet { table: t }) => {
+                (vec![Target::Table(*t)], vec![table(*t)], Output::Unchanged)
+            }
+            Instr::TableSet(TableSet { table: t }) => (
+                vec![Target::Table(*t)],
+                vec![table(*t), element(*t)],
+                Output::Unchanged,
+            ),
+            Instr::TableSize(TableSize { table: t }) => {
+                (vec![Target::Table(*t)], vec![], Output::Size)
+            }
+            Instr::TableGrow(TableGrow { table: t }) => (
+                vec![Target::Table(*t)],
+                vec![element(*t), table(*t)],
+                Output::Grow,
+            ),
+            Instr::TableFill(TableFill { table: t }) => (
+                vec![Target::Table(*t)],
+                vec![table(*t), element(*t), table(*t)],
+                Output::Unchanged,
+            ),
+            Instr::TableCopy(TableCopy { src, dst }) => {
+                let (dst_ty, src_ty) = (table(*dst), table(*src));
+                (
+                    vec![Target::Table(*dst), Target::Table(*src)],
+                    vec![
+                        dst_ty,
+                        src_ty,
+                        (narrower(dst_ty.0, src_ty.0), narrower(dst_ty.1, src_ty.1)),
+                    ],
+                    Output::Unchanged,
+                )
+            }
+            Instr::TableInit(TableInit { table: t, .. }) => (
+                vec![Target::Table(*t)],
+                vec![table(*t), same(ValType::I32), same(ValType::I32)],
+                Output::Unchanged,
+            ),
+            Instr::CallIndirect(CallIndirect { table: t, .. })
+            | Instr::ReturnCallIndirect(ReturnCallIndirect { table: t, .. }) => {
+                (vec![Target::Table(*t)], vec![table(*t)], Output::Unchanged)
+            }
+            _ => return None,
+        };
+        if targets.contains(&self.target) {
+            Some((operands, output))
+        } else {
+            None
+        }
+    }
+
+    fn instr(
+        &mut self,
+        func: &mut LocalFunction,
+        out: &mut Vec<(Instr, InstrLocId)>,
+        instr: Instr,
+        loc: InstrLocId,
+    ) {
+        let (operands, output) = match self.shape(&instr) {
+            Some(shape) => shape,
+            None => return out.push((instr, loc)),
+        };
+        let mut instrs = Vec::new();
+
+        // An offset past 32 bits always goes out of bounds of a 32-bit
+        // memory, and can't be encoded for one.
+        if let Some(arg) = mem_arg(&instr) {
+            if !self.to64 && arg.offset > u64::from(u32::MAX) {
+                out.push((Unreachable {}.into(), loc));
+                return;
+            }
+        }
+
+        // Only the operands from the lowest one that changes type up need to
+        // be taken off the stack and converted, except that a narrowed `grow`
+        // branches on its delta, so takes all of its operands.
+        let narrowed_grow = output == Output::Grow && !self.to64;
+        let first = match narrowed_grow {
+            true => Some(0),
+            false => operands.iter().position(|(before, after)| before != after),
+        };
+        let spilled = match first {
+            Some(first) => &operands[first..],
+            None => &[][..],
+        };
+        let types = spilled
+            .iter()
+            .map(|(before, _)| *before)
+            .collect::<Vec<_>>();
+        let temps = self.scratch.locals(self.locals, &types);
+        instrs.extend(local_sets(&temps));
+
+        if narrowed_grow {
+            // Growing by more than 32 bits' worth fails instead of trapping.
+            let delta = *temps.last().unwrap();
+            let result = self.scratch.local(self.locals, ValType::I32, u32::MAX);
+            let builder = func.builder_mut();
+            let failed = builder.dangling_instr_seq(ValType::I64).i64_const(-1).id();
+            let mut grown = Vec::new();
+            for (&local, (before, after)) in temps.iter().zip(spilled) {
+                grown.push(LocalGet { local }.into());
+                convert(func, &mut grown, local, *before, *after);
+            }
+            grown.push(instr);
+            // `-1` means failure in either type, and any other size fits.
+            grown.extend::<[Instr; 7]>([
+                LocalTee { local: result }.into(),
+                Unop {
+                    op: UnaryOp::I64ExtendUI32,
+                }
+                .into(),
+                Const {
+                    value: Value::I64(-1),
+                }
+                .into(),
+                LocalGet { local: result }.into(),
+                Const {
+                    value: Value::I32(-1),
+                }
+                .into(),
+                Binop {
+                    op: BinaryOp::I32Ne,
+                }
+                .into(),
+                Select { ty: None }.into(),
+            ]);
+            let grown_seq = func.builder_mut().dangling_instr_seq(ValType::I64).id();
+            func.block_mut(grown_seq).instrs = grown.into_iter().map(|i| (i, loc)).collect();
+            instrs.extend::<[Instr; 4]>([
+                LocalGet { local: delta }.into(),
+                Const {
+                    value: Value::I64(u32::MAX.into()),
+                }
+                .into(),
+                Binop {
+                    op: BinaryOp::I64GtU,
+                }
+                .into(),
+                IfElse {
+                    consequent: failed,
+                    alternative: grown_seq,
+                }
+                .into(),
+            ]);
+        } else {
+            for (&local, (before, after)) in temps.iter().zip(spilled) {
+                instrs.push(LocalGet { local }.into());
+                convert(func, &mut instrs, local, *before, *after);
+            }
+            instrs.push(instr);
+            // Sizes fit in 32 bits, as raising an index type clamps the
+            // maximum size, and `-1` stays `-1` when wrapped.
+            if output != Output::Unchanged {
+                let op = if self.to64 {
+                    UnaryOp::I32WrapI64
+                } else {
+                    UnaryOp::I64ExtendUI32
+                };
+                instrs.push(Unop { op }.into());
+            }
+        }
+        out.extend(instrs.into_iter().map(|instr| (instr, loc)));
+    }
+}
+
+/// Push the conversion of the index in `local`, already on the stack, from
+/// `before` to `after`. Narrowing traps on indices that don't fit.
+fn convert(
+    func: &mut LocalFunction,
+    instrs: &mut Vec<Instr>,
+    local: LocalId,
+    before: ValType,
+    after: ValType,
+) {
+    match (before, after) {
+        (ValType::I32, ValType::I64) => instrs.push(
+            Unop {
+                op: UnaryOp::I64ExtendUI32,
+            }
+            .into(),
+        ),
+        (ValType::I64, ValType::I32) => {
+            let builder = func.builder_mut();
+            let consequent = builder.dangling_instr_seq(None).unreachable().id();
+            let alternative = builder.dangling_instr_seq(None).id();
+            instrs.extend::<[Instr; 5]>([
+                LocalGet { local }.into(),
+                Const {
+                    value: Value::I64(u32::MAX.into()),
+                }
+                .into(),
+                Binop {
+                    op: BinaryOp::I64GtU,
+                }
+                .into(),
+                IfElse {
+                    consequent,
+                    alternative,
+                }
+                .into(),
+                Unop {
+                    op: UnaryOp::I32WrapI64,
+                }
+                .into(),
+            ]);
+        }
+        _ => {}
+    }
+}
+
+fn mem_arg(instr: &Instr) -> Option<MemArg> {
+    match instr {
+        Instr::Load(Load { arg, .. })
+        | Instr::Store(Store { arg, .. })
+        | Instr::AtomicRmw(AtomicRmw { arg, .. })
+        | Instr::Cmpxchg(Cmpxchg { arg, .. })
+        | Instr::AtomicNotify(AtomicNotify { arg, .. })
+        | Instr::AtomicWait(AtomicWait { arg, .. })
+        | Instr::LoadSimd(LoadSimd { arg, .. }) => Some(*arg),
+        _ => None,
+    }
+}
+
+fn address(sixty_four: bool) -> ValType {
+    if sixty_four {
+        ValType::I64
+    } else {
+        ValType::I32
+    }
+}
+
+/// The type of a length shared by two index spaces.
+fn narrower(a: ValType, b: ValType) -> ValType {
+    if a == ValType::I32 || b == ValType::I32 {
+        ValType::I32
+    } else {
+        ValType::I64
+    }
+}
@@ -0,0 +1,320 @@
+//! Calls imported hooks on entry to and exit from every function, and
+//! optionally around every call, for profiling and tracing.
+//!
+//! Each function gets an id, which is passed to the hooks, and the returned
+//! `Manifest` maps ids back to function names.
+//!
+//! Every way out of a function body goes through a single exit point: the
+//! original body becomes a block, and `return`s, branches to the body and
+//! `try_table` catches that land on the body all branch to the end of that
+//! block instead, where the exit hook is called. Tail calls call the exit
+//! hook just before they leave.
+
+use crate::ir::*;
+use crate::map::IdHashMap;
+use crate::passes::instr_seqs;
+use crate::{Function, FunctionId, FunctionKind, LocalFunction, Module, RefType, Result, ValType};
+use anyhow::bail;
+use std::fmt;
+use std::mem;
+
+/// Options for `run`.
+///
+/// Each hook is an imported function, given as a `(module, name)` pair, that
+/// `run` adds to the module. Hooks that are `None` aren't called.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// Called with a function's id on entry to it, with type `[i32] -> []`.
+    pub entry: Option<(String, String)>,
+    /// Called with a function's id on every exit from it, with type
+    /// `[i32] -> []`.
+    pub exit: Option<(String, String)>,
+    /// Called with the ids of the caller and the callee before every call,
+    /// with type `[i32 i32] -> []`. The callee of an indirect call, or a call
+    /// through a reference, is `-1`.
+    pub before_call: Option<(String, String)>,
+    /// Called like `before_call` after every call returns. Tail calls don't
+    /// return, so it isn't called for them.
+    pub after_call: Option<(String, String)>,
+    /// Also call the exit hook when an exception unwinds out of a function,
+    /// by wrapping each body in a `try_table` that catches everything and
+    /// then rethrows it. This needs the exception handling proposal's
+    /// `exnref`.
+    pub exceptions: bool,
+}
+
+/// The ids `run` gave to functions.
+#[derive(Clone, Debug, Default)]
+pub struct Manifest {
+    /// The name of each function, indexed by its id. Functions without a
+    /// name are called `func{id}`, and imports without one `{module}.{name}`.
+    pub names: Vec<String>,
+    ids: IdHashMap<Function, u32>,
+}
+
+impl Manifest {
+    /// The id given to `func`, if it was in the module when it was
+    /// instrumented.
+    pub fn id(&self, func: FunctionId) -> Option<u32> {
+        self.ids.get(&func).copied()
+    }
+}
+
+impl fmt::Display for Manifest {
+    /// One line per function, with its id and then its name.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (id, name) in self.names.iter().enumerate() {
+            writeln!(f, "{}\t{}", id, name)?;
+        }
+        Ok(())
+    }
+}
+
+/// Instrument every local function of `module` with the hooks in `options`,
+/// and return the ids the hooks are called with.
+///
+/// Returns an error, before changing anything, if the module already imports
+/// one of the hooks.
+pub fn run(module: &mut Module, options: &Options) -> Result<Manifest> {
+    let hooks = [
+        (&options.entry, 1),
+        (&options.exit, 1),
+        (&options.before_call, 2),
+        (&options.after_call, 2),
+    ];
+    for (import_module, name) in hooks.iter().filter_map(|(hook, _)| hook.as_ref()) {
+        if module
+            .imports
+            .iter()
+            .any(|i| i.module == *import_module && i.name == *name)
+        {
+            bail!("module already imports `{}.{}`", import_module, name);
+        }
+    }
+
+    let mut manifest = Manifest::default();
+    for (id, func) in module.funcs.iter().enumerate() {
+        let name = match (&func.name, &func.kind) {
+            (Some(name), _) => name.clone(),
+            (None, FunctionKind::Import(import)) => {
+                let import = module.imports.get(import.import);
+                format!("{}.{}", import.module, import.name)
+            }
+            (None, _) => format!("func{}", id),
+        };
+        manifest.names.push(name);
+        manifest.ids.insert(func.id(), id as u32);
+    }
+
+    let [entry, exit, before_call, after_call] = hooks.map(|(hook, params)| {
+        let (import_module, name) = hook.as_ref()?;
+        let ty = module.types.add(&vec![ValType::I32; params], &[]);
+        Some(module.add_import_func(import_module, name, ty).0)
+    });
+    let hooks = Hooks {
+        entry,
+        exit,
+        before_call,
+        after_call,
+        ids: &manifest.ids,
+    };
+
+    let funcs = module
+        .funcs
+        .iter_local()
+        .map(|(id, _)| id)
+        .collect::<Vec<_>>();
+    for id in funcs {
+        let func = module.funcs.get_mut(id);
+        let results = module.types.results(func.ty()).to_vec();
+        let ty = InstrSeqType::new(&mut module.types, &[], &results);
+        let func = func.kind.unwrap_local_mut();
+        instrument(
+            func,
+            ty,
+            manifest.ids[&id] as i32,
+            &hooks,
+            options.exceptions,
+        );
+    }
+    Ok(manifest)
+}
+
+struct Hooks<'a> {
+    entry: Option<FunctionId>,
+    exit: Option<FunctionId>,
+    before_call: Option<FunctionId>,
+    after_call: Option<FunctionId>,
+    ids: &'a IdHashMap<Function, u32>,
+}
+
+impl Hooks<'_> {
+    fn call(&self, hook: Option<FunctionId>, args: &[i32], instrs: &mut Vec<(Instr, InstrLocId)>) {
+        if let Some(hook) = hook {
+            for arg in args {
+                let value = Value::I32(*arg);
+                instrs.push((Const { value }.into(), InstrLocId::default()));
+            }
+            instrs.push((Call { func: hook }.into(), InstrLocId::default()));
+        }
+    }
+}
+
+/// Instrument `func`, whose body produces results of type `ty`.
+fn instrument(
+    func: &mut LocalFunction,
+    ty: InstrSeqType,
+    id: i32,
+    hooks: &Hooks,
+    exceptions: bool,
+) {
+    for seq in instr_seqs(func, func.entry_block()) {
+        let block = func.block_mut(seq);
+        let mut instrs = Vec::with_capacity(block.instrs.len());
+        for (instr, loc) in mem::take(&mut block.instrs) {
+            match &instr {
+                Instr::Call(Call { func }) => {
+                    let callee = hooks.ids.get(func).map_or(-1, |id| *id as i32);
+                    hooks.call(hooks.before_call, &[id, callee], &mut instrs);
+                    instrs.push((instr, loc));
+                    hooks.call(hooks.after_call, &[id, callee], &mut instrs);
+                    continue;
+                }
+                Instr::CallIndirect(_) | Instr::CallRef(_) => {
+                    hooks.call(hooks.before_call, &[id, -1], &mut instrs);
+                    instrs.push((instr, loc));
+                    hooks.call(hooks.after_call, &[id, -1], &mut instrs);
+                    continue;
+                }
+                // `single_exit` calls the exit hook between these and the
+                // tail call.
+                Instr::ReturnCall(ReturnCall { func }) => {
+                    let callee = hooks.ids.get(func).map_or(-1, |id| *id as i32);
+                    hooks.call(hooks.before_call, &[id, callee], &mut instrs);
+                }
+                Instr::ReturnCallIndirect(_) | Instr::ReturnCallRef(_) => {
+                    hooks.call(hooks.before_call, &[id, -1], &mut instrs);
+                }
+                _ => {}
+            }
+            instrs.push((instr, loc));
+        }
+        func.block_mut(seq).instrs = instrs;
+    }
+
+    single_exit(
+        func,
+        ty,
+        exceptions,
+        |_, instrs| hooks.call(hooks.entry, &[id], instrs),
+        |instrs| hooks.call(hooks.exit, &[id], instrs),
+    );
+}
+
+/// Give `func`, whose body produces results of type `ty`, a single exit
+/// point, and add the instructions `entry` pushes at its start and those
+/// `exit` pushes on every way out of it.
+///
+/// With `exceptions`, `exit` is also run when an exception unwinds out of
+/// `func`, which needs `exnref`.
+pub(crate) fn single_exit(
+    func: &mut LocalFunction,
+    ty: InstrSeqType,
+    exceptions: bool,
+    entry: impl FnOnce(&mut LocalFunction, &mut Vec<(Instr, InstrLocId)>),
+    exit: impl Fn(&mut Vec<(Instr, InstrLocId)>),
+) {
+    // Move the original body into a block of its own, whose end is the
+    // function's single exit point.
+    let entry_block = func.entry_block();
+    let body = func.builder_mut().dangling_instr_seq(ty).id();
+    let instrs = mem::take(&mut func.block_mut(entry_block).instrs);
+    func.block_mut(body).instrs = instrs;
+
+    for seq in instr_seqs(func, body) {
+        let block = func.block_mut(seq);
+        let mut instrs = Vec::with_capacity(block.instrs.len());
+        for (mut instr, loc) in mem::take(&mut block.instrs) {
+            retarget(&mut instr, entry_block, body);
+            match instr {
+                Instr::Return(_) => {
+                    instrs.push((Br { block: body }.into(), loc));
+                    continue;
+                }
+                Instr::ReturnCall(_) | Instr::ReturnCallIndirect(_) | Instr::ReturnCallRef(_) => {
+                    exit(&mut instrs);
+                }
+                _ => {}
+            }
+            instrs.push((instr, loc));
+        }
+        func.block_mut(seq).instrs = instrs;
+    }
+
+    let mut instrs = Vec::new();
+    entry(func, &mut instrs);
+    if exceptions {
+        // block $caught (result exnref)
+        //   try_table $body (catch_all_ref $caught) ... end
+        //   exit
+        //   return
+        // end
+        // exit
+        // throw_ref
+        let caught = func
+            .builder_mut()
+            .dangling_instr_seq(ValType::Ref(RefType::EXNREF))
+            .id();
+        let mut caught_instrs = vec![(
+            TryTable {
+                seq: body,
+                catches: vec![TryTableCatch::CatchAllRef { label: caught }],
+            }
+            .into(),
+            InstrLocId::default(),
+        )];
+        exit(&mut caught_instrs);
+        caught_instrs.push((Return {}.into(), InstrLocId::default()));
+        func.block_mut(caught).instrs = caught_instrs;
+
+        instrs.push((Block { seq: caught }.into(), InstrLocId::default()));
+        exit(&mut instrs);
+        instrs.push((ThrowRef {}.into(), InstrLocId::default()));
+    } else {
+        instrs.push((Block { seq: body }.into(), InstrLocId::default()));
+        exit(&mut instrs);
+    }
+    func.block_mut(entry_block).instrs = instrs;
+}
+
+/// Make `instr` branch to `to` wherever it branched to `from`.
+fn retarget(instr: &mut Instr, from: InstrSeqId, to: InstrSeqId) {
+    let update = |block: &mut InstrSeqId| {
+        if *block == from {
+            *block = to;
+        }
+    };
+    match instr {
+        Instr::Br(Br { block })
+        | Instr::BrIf(BrIf { block })
+        | Instr::BrOnNull(BrOnNull { block })
+        | Instr::BrOnNonNull(BrOnNonNull { block })
+        | Instr::BrOnCast(BrOnCast { block, .. })
+        | Instr::BrOnCastFail(BrOnCastFail { block, .. }) => update(block),
+        Instr::BrTable(BrTable { blocks, default }) => {
+            blocks.iter_mut().for_each(update);
+            update(default);
+        }
+        Instr::TryTable(TryTable { catches, .. }) => {
+            for catch in catches {
+                match catch {
+                    TryTableCatch::Catch { label, .. }
+                    | TryTableCatch::CatchRef { label, .. }
+                    | TryTableCatch::CatchAll { label }
+                    | TryTableCatch::CatchAllRef { label } => update(label),
+                }
+            }
+        }
+        _ => {}
+    }
+}
@@ -0,0 +1,285 @@
+//! Calls imported hooks before every memory access, for sanitizers and
+//! other tools that check or trace how a module uses its memories.
+//!
+//! Each access is described to a hook by its dynamic address, its size in
+//! bytes, the static offset from its `MemArg` and the index of the memory it
+//! touches. Addresses and sizes are zero-extended to `i64`, so the same hooks
+//! work for both 32-bit and 64-bit memories.
+//!
+//! The operands of an instrumented instruction are spilled into scratch
+//! locals, the hooks are called, and then the operands are pushed again for
+//! the original instruction.
+
+use crate::ir::*;
+use crate::map::IdHashMap;
+use crate::passes::{instr_seqs, local_gets, local_sets, Scratch};
+use crate::{FunctionId, LocalFunction, Memory, MemoryId, Module, ModuleLocals, Result, ValType};
+use anyhow::bail;
+use std::mem;
+
+/// Options for `run`.
+///
+/// Each hook is an imported function, given as a `(module, name)` pair, that
+/// `run` adds to the module with type `[i64 i64 i64 i32] -> []`. It's called
+/// with the address, size, offset and memory index of an access. Hooks that
+/// are `None` aren't called.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// Called before every read from memory: loads, the reading half of
+    /// atomic read-modify-writes, and the source of `memory.copy`.
+    pub load: Option<(String, String)>,
+    /// Called before every write to memory: stores, the writing half of
+    /// atomic read-modify-writes, and the destinations of `memory.copy`,
+    /// `memory.fill` and `memory.init`.
+    pub store: Option<(String, String)>,
+}
+
+/// Instrument every memory access in the local functions of `module` with the
+/// hooks in `options`.
+///
+/// Returns an error, before changing anything, if the module already imports
+/// one of the hooks.
+pub fn run(module: &mut Module, options: &Options) -> Result<()> {
+    let hooks = [&options.load, &options.store];
+    for (import_module, name) in hooks.iter().filter_map(|hook| hook.as_ref()) {
+        if module
+            .imports
+            .iter()
+            .any(|i| i.module == *import_module && i.name == *name)
+        {
+            bail!("module already imports `{}.{}`", import_module, name);
+        }
+    }
+
+    let [load, store] = hooks.map(|hook| {
+        let (import_module, name) = hook.as_ref()?;
+        let params = [ValType::I64, ValType::I64, ValType::I64, ValType::I32];
+        let ty = module.types.add(&params, &[]);
+        Some(module.add_import_func(import_module, name, ty).0)
+    });
+    let mut memories = IdHashMap::default();
+    for (index, memory) in module.memories.iter().enumerate() {
+        memories.insert(memory.id(), (index as i32, memory.memory64));
+    }
+    let hooks = Hooks {
+        load,
+        store,
+        memories,
+    };
+
+    for (_, func) in module.funcs.iter_local_mut() {
+        instrument(func, &hooks, &mut module.locals);
+    }
+    Ok(())
+}
+
+struct Hooks {
+    load: Option<FunctionId>,
+    store: Option<FunctionId>,
+    /// The index of each memory and whether it's 64-bit.
+    memories: IdHashMap<Memory, (i32, bool)>,
+}
+
+/// A range of memory that an instruction accesses, whose address and size
+/// are given by the positions of its operands on the stack if they aren't
+/// constant.
+#[derive(Clone, Copy)]
+struct Access {
+    memory: MemoryId,
+    address: usize,
+    size: Size,
+    offset: u64,
+}
+
+#[derive(Clone, Copy)]
+enum Size {
+    Bytes(u32),
+    Operand(usize),
+}
+
+impl Access {
+    /// An access of `bytes` bytes at the address on the bottom of the stack.
+    fn at(memory: MemoryId, bytes: u32, arg: &MemArg) -> Access {
+        Access {
+            memory,
+            address: 0,
+            size: Size::Bytes(bytes),
+            offset: arg.offset,
+        }
+    }
+
+    /// An access whose size is the third operand, as for the bulk memory
+    /// instructions.
+    fn bulk(memory: MemoryId, address: usize) -> Access {
+        Access {
+            memory,
+            address,
+            size: Size::Operand(2),
+            offset: 0,
+        }
+    }
+}
+
+/// The operands an instruction takes, and what it reads and writes.
+type Accesses = (Vec<ValType>, Option<Access>, Option<Access>);
+
+impl Hooks {
+    fn address_type(&self, memory: MemoryId) -> ValType {
+        if self.memories[&memory].1 {
+            ValType::I64
+        } else {
+            ValType::I32
+        }
+    }
+
+    /// The operands `instr` takes and the memory it reads and writes, or
+    /// `None` if it doesn't access memory.
+    fn accesses(&self, instr: &Instr) -> Option<Accesses> {
+        Some(match instr {
+            Instr::Load(Load { memory, kind, arg }) => (
+                vec![self.address_type(*memory)],
+                Some(Access::at(*memory, kind.width(), arg)),
+                None,
+            ),
+            Instr::Store(Store { memory, kind, arg }) => (
+                vec![self.address_type(*memory), kind.ty()],
+                None,
+                Some(Access::at(*memory, kind.width(), arg)),
+            ),
+            Instr::LoadSimd(LoadSimd { memory, kind, arg }) => {
+                let (bytes, lane, store) = simd_access(kind);
+                let mut operands = vec![self.address_type(*memory)];
+                if lane {
+                    operands.push(ValType::V128);
+                }
+                let access = Some(Access::at(*memory, bytes, arg));
+                if store {
+                    (operands, None, access)
+                } else {
+                    (operands, access, None)
+                }
+            }
+            Instr::AtomicRmw(AtomicRmw {
+                memory, width, arg, ..
+            }) => {
+                let access = Some(Access::at(*memory, width.bytes(), arg));
+                let operands = vec![self.address_type(*memory), width.ty()];
+                (operands, access, access)
+            }
+            Instr::Cmpxchg(Cmpxchg { memory, width, arg }) => {
+                let access = Some(Access::at(*memory, width.bytes(), arg));
+                let value = width.ty();
+                let operands = vec![self.address_type(*memory), value, value];
+                (operands, access, access)
+            }
+            Instr::MemoryCopy(MemoryCopy { src, dst }) => {
+                let src_type = self.address_type(*src);
+                let dst_type = self.address_type(*dst);
+                // The length is only 64-bit when both memories are.
+                let len = if src_type == ValType::I64 && dst_type == ValType::I64 {
+                    ValType::I64
+                } else {
+                    ValType::I32
+                };
+                (
+                    vec![dst_type, src_type, len],
+                    Some(Access::bulk(*src, 1)),
+                    Some(Access::bulk(*dst, 0)),
+                )
+            }
+            Instr::MemoryFill(MemoryFill { memory }) => {
+                let address = self.address_type(*memory);
+                (
+                    vec![address, ValType::I32, address],
+                    None,
+                    Some(Access::bulk(*memory, 0)),
+                )
+            }
+            Instr::MemoryInit(MemoryInit { memory, .. }) => (
+                vec![self.address_type(*memory), ValType::I32, ValType::I32],
+                None,
+                Some(Access::bulk(*memory, 0)),
+            ),
+            _ => return None,
+        })
+    }
+}
+
+/// The number of bytes a SIMD load or store accesses, whether it also takes
+/// a vector operand for a lane, and whether it's a store.
+fn simd_access(kind: &LoadSimdKind) -> (u32, bool, bool) {
+    use LoadSimdKind::*;
+    match kind {
+        Splat8 => (1, false, false),
+        Splat16 => (2, false, false),
+        Splat32 | V128Load32Zero => (4, false, false),
+        Splat64 | V128Load64Zero => (8, false, false),
+        V128Load8x8S | V128Load8x8U | V128Load16x4S | V128Load16x4U | V128Load32x2S
+        | V128Load32x2U => (8, false, false),
+        V128Load8Lane(_) => (1, true, false),
+        V128Load16Lane(_) => (2, true, false),
+        V128Load32Lane(_) => (4, true, false),
+        V128Load64Lane(_) => (8, true, false),
+        V128Store8Lane(_) => (1, true, true),
+        V128Store16Lane(_) => (2, true, true),
+        V128Store32Lane(_) => (4, true, true),
+        V128Store64Lane(_) => (8, true, true),
+    }
+}
+
+fn instrument(func: &mut LocalFunction, hooks: &Hooks, locals: &mut ModuleLocals) {
+    // Scratch locals are shared by every instruction in the function, and
+    // allocated as the first instruction needing that many of a type does.
+    let mut scratch = Scratch::default();
+
+    for seq in instr_seqs(func, func.entry_block()) {
+        let block = func.block_mut(seq);
+        let mut instrs = Vec::with_capacity(block.instrs.len());
+        for (instr, loc) in mem::take(&mut block.instrs) {
+            let Some((operands, read, write)) = hooks.accesses(&instr) else {
+                instrs.push((instr, loc));
+                continue;
+            };
+            let checks = [(hooks.load, read), (hooks.store, write)]
+                .into_iter()
+                .filter_map(|(hook, access)| Some((hook?, access?)))
+                .collect::<Vec<_>>();
+            if checks.is_empty() {
+                instrs.push((instr, loc));
+                continue;
+            }
+
+            let temps = scratch.locals(locals, &operands);
+
+            let mut push = |instr: Instr| instrs.push((instr, InstrLocId::default()));
+            local_sets(&temps).for_each(&mut push);
+            // Push the operand at `index`, zero-extended to `i64`.
+            let push_i64 = |push: &mut dyn FnMut(Instr), index: usize| {
+                let local = temps[index];
+                push(LocalGet { local }.into());
+                if operands[index] == ValType::I32 {
+                    let op = UnaryOp::I64ExtendUI32;
+                    push(Unop { op }.into());
+                }
+            };
+            for (hook, access) in checks {
+                push_i64(&mut push, access.address);
+                match access.size {
+                    Size::Bytes(bytes) => {
+                        let value = Value::I64(bytes.into());
+                        push(Const { value }.into());
+                    }
+                    Size::Operand(index) => push_i64(&mut push, index),
+                }
+                let value = Value::I64(access.offset as i64);
+                push(Const { value }.into());
+                let value = Value::I32(hooks.memories[&access.memory].0);
+                push(Const { value }.into());
+                push(Call { func: hook }.into());
+            }
+            local_gets(&temps).for_each(&mut push);
+            instrs.push((instr, loc));
+        }
+        func.block_mut(seq).instrs = instrs;
+    }
+}
@@ -0,0 +1,212 @@
+//! Deterministic fuel metering, for running untrusted modules with a bounded
+//! amount of work.
+//!
+//! Every instruction sequence is split into straight-line segments, each of
+//! which ends with an instruction that branches, or that runs a nested
+//! sequence. The static cost of a segment is subtracted from an `i64` fuel
+//! global at its head, before any of it runs, and if the fuel goes negative
+//! an out-of-fuel hook is called, or the module traps.
+//!
+//! Branches back to a loop land on the head of the loop's body, which is
+//! charged again, so every iteration pays for itself.
+
+use crate::ir::*;
+use crate::passes::instr_seqs;
+use crate::{ConstExpr, FunctionId, GlobalId, LocalFunction, Module, Result, ValType};
+use anyhow::bail;
+use std::fmt;
+use std::mem;
+use std::sync::Arc;
+
+/// Type alias for the cost callback function.
+type CostFn = Arc<dyn Fn(&Instr) -> u64 + Send + Sync + 'static>;
+
+/// Options for `run`.
+#[derive(Clone, Default)]
+pub struct Options {
+    /// The cost of each instruction. Every instruction costs 1 if this is
+    /// `None`. The instructions of a nested sequence are charged when that
+    /// sequence runs, not as part of the instruction that contains them.
+    pub cost: Option<CostFn>,
+    /// Import the fuel global, which must be a mutable `i64`, from this
+    /// `(module, name)` pair, instead of adding a new one.
+    pub import: Option<(String, String)>,
+    /// The initial value of the fuel global, if it isn't imported.
+    pub initial: i64,
+    /// Export the fuel global with this name.
+    pub export: Option<String>,
+    /// Import a function with type `[] -> []` from this `(module, name)` pair,
+    /// and call it whenever the fuel goes negative. It may refill the fuel
+    /// and return, or not return at all. If `None`, the module traps with
+    /// `unreachable` instead.
+    pub out_of_fuel: Option<(String, String)>,
+}
+
+impl fmt::Debug for Options {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Destructure `self` so that we get compilation errors if we forget to
+        // add new fields to the debug here.
+        let Options {
+            ref cost,
+            ref import,
+            ref initial,
+            ref export,
+            ref out_of_fuel,
+        } = self;
+
+        f.debug_struct("Options")
+            .field("cost", &cost.as_ref().map(|_| ".."))
+            .field("import", import)
+            .field("initial", initial)
+            .field("export", export)
+            .field("out_of_fuel", out_of_fuel)
+            .finish()
+    }
+}
+
+/// Meter every local function of `module`, and return the fuel global.
+///
+/// Returns an error, before changing anything, if the module already imports
+/// the fuel global or the out-of-fuel hook.
+pub fn run(module: &mut Module, options: &Options) -> Result<GlobalId> {
+    for (import_module, name) in [&options.import, &options.out_of_fuel]
+        .into_iter()
+        .flatten()
+    {
+        if module
+            .imports
+            .iter()
+            .any(|i| i.module == *import_module && i.name == *name)
+        {
+            bail!("module already imports `{}.{}`", import_module, name);
+        }
+    }
+
+    let fuel = match &options.import {
+        Some((import_module, name)) => {
+            module
+                .add_import_global(import_module, name, ValType::I64, true, false)
+                .0
+        }
+        None => {
+            let init = ConstExpr::Value(Value::I64(options.initial));
+            module.globals.add_local(ValType::I64, true, false, init)
+        }
+    };
+    if let Some(name) = &options.export {
+        module.exports.add(name, fuel);
+    }
+    let out_of_fuel = options.out_of_fuel.as_ref().map(|(import_module, name)| {
+        let ty = module.types.add(&[], &[]);
+        module.add_import_func(import_module, name, ty).0
+    });
+
+    let meter = Meter {
+        fuel,
+        out_of_fuel,
+        cost: options.cost.as_deref().unwrap_or(&|_| 1),
+    };
+    for (_, func) in module.funcs.iter_local_mut() {
+        meter.instrument(func);
+    }
+    Ok(fuel)
+}
+
+struct Meter<'a> {
+    fuel: GlobalId,
+    out_of_fuel: Option<FunctionId>,
+    cost: &'a (dyn Fn(&Instr) -> u64 + Send + Sync),
+}
+
+impl Meter<'_> {
+    fn instrument(&self, func: &mut LocalFunction) {
+        for seq in instr_seqs(func, func.entry_block()) {
+            let original = mem::take(&mut func.block_mut(seq).instrs);
+            let mut instrs = Vec::with_capacity(original.len());
+            let mut rest = &original[..];
+            while !rest.is_empty() {
+                let len = rest
+                    .iter()
+                    .position(|(instr, _)| ends_segment(instr))
+                    .map_or(rest.len(), |i| i + 1);
+                let (segment, after) = rest.split_at(len);
+                let cost = segment.iter().fold(0u64, |cost, (instr, _)| {
+                    cost.saturating_add((self.cost)(instr))
+                });
+                if cost > 0 {
+                    self.charge(func, cost, &mut instrs);
+                }
+                instrs.extend_from_slice(segment);
+                rest = after;
+            }
+            func.block_mut(seq).instrs = instrs;
+        }
+    }
+
+    /// Subtract `cost` from the fuel, and run out if it goes negative.
+    fn charge(&self, func: &mut LocalFunction, cost: u64, instrs: &mut Vec<(Instr, InstrLocId)>) {
+        let consequent = func.builder_mut().dangling_instr_seq(None).id();
+        let alternative = func.builder_mut().dangling_instr_seq(None).id();
+        func.block_mut(consequent).instrs = vec![(
+            match self.out_of_fuel {
+                Some(func) => Call { func }.into(),
+                None => Unreachable {}.into(),
+            },
+            InstrLocId::default(),
+        )];
+
+        let global = self.fuel;
+        let cost = Value::I64(i64::try_from(cost).unwrap_or(i64::MAX));
+        let zero = Value::I64(0);
+        let charge: [Instr; 8] = [
+            GlobalGet { global }.into(),
+            Const { value: cost }.into(),
+            Binop {
+                op: BinaryOp::I64Sub,
+            }
+            .into(),
+            GlobalSet { global }.into(),
+            GlobalGet { global }.into(),
+            Const { value: zero }.into(),
+            Binop {
+                op: BinaryOp::I64LtS,
+            }
+            .into(),
+            IfElse {
+                consequent,
+                alternative,
+            }
+            .into(),
+        ];
+        instrs.extend(charge.map(|instr| (instr, InstrLocId::default())));
+    }
+}
+
+/// Whether `instr` is the last instruction of a straight-line segment,
+/// because it may not continue with the next instruction, or runs a nested
+/// sequence that's charged separately.
+fn ends_segment(instr: &Instr) -> bool {
+    matches!(
+        instr,
+        Instr::Block(_)
+            | Instr::Loop(_)
+            | Instr::IfElse(_)
+            | Instr::Try(_)
+            | Instr::TryTable(_)
+            | Instr::Br(_)
+            | Instr::BrIf(_)
+            | Instr::BrTable(_)
+            | Instr::BrOnNull(_)
+            | Instr::BrOnNonNull(_)
+            | Instr::BrOnCast(_)
+            | Instr::BrOnCastFail(_)
+            | Instr::Return(_)
+            | Instr::ReturnCall(_)
+            | Instr::ReturnCallIndirect(_)
+            | Instr::ReturnCallRef(_)
+            | Instr::Throw(_)
+            | Instr::ThrowRef(_)
+            | Instr::Rethrow(_)
+            | Instr::Unreachable(_)
+    )
+}
@@ -1,5 +1,83 @@
 //! Passes over whole modules or individual functions.
 
+pub mod asyncify;
+pub mod coverage;
+pub mod data_segments;
+pub mod devirtualize;
 pub mod gc;
-mod used;
+pub mod index_type;
+pub mod instrument;
+pub mod instrument_memory;
+pub mod metering;
+pub mod multi_memory_lowering;
+pub mod propagate_globals;
+pub mod reduce;
+pub mod stack_limit;
+pub(crate) mod stack_types;
+pub(crate) mod used;
 pub use self::used::Roots;
+
+use crate::ir::{dfs_in_order, Instr, InstrSeq, InstrSeqId, LocalGet, LocalSet, Visitor};
+use crate::{LocalFunction, LocalId, ModuleLocals, ValType};
+use std::collections::HashMap;
+
+/// Every instruction sequence reachable from `start` in `func`, in the order
+/// they're first entered.
+pub(crate) fn instr_seqs(func: &LocalFunction, start: InstrSeqId) -> Vec<InstrSeqId> {
+    #[derive(Default)]
+    struct InstrSeqs {
+        ids: Vec<InstrSeqId>,
+    }
+
+    impl<'instr> Visitor<'instr> for InstrSeqs {
+        fn start_instr_seq(&mut self, seq: &'instr InstrSeq) {
+            self.ids.push(seq.id());
+        }
+    }
+
+    let mut seqs = InstrSeqs::default();
+    dfs_in_order(&mut seqs, func, start);
+    seqs.ids
+}
+
+/// Locals that passes spill operands into while rewriting an instruction,
+/// reused by every instruction they rewrite.
+#[derive(Default)]
+pub(crate) struct Scratch {
+    locals: HashMap<(ValType, u32), LocalId>,
+}
+
+impl Scratch {
+    /// The `slot`th scratch local of type `ty`, added to `locals` the first
+    /// time it's asked for.
+    pub(crate) fn local(&mut self, locals: &mut ModuleLocals, ty: ValType, slot: u32) -> LocalId {
+        *self
+            .locals
+            .entry((ty, slot))
+            .or_insert_with(|| locals.add(ty))
+    }
+
+    /// A distinct scratch local for each of `types`.
+    pub(crate) fn locals(&mut self, locals: &mut ModuleLocals, types: &[ValType]) -> Vec<LocalId> {
+        let mut used = HashMap::new();
+        types
+            .iter()
+            .map(|&ty| {
+                let slot = used.entry(ty).or_insert(0);
+                *slot += 1;
+                self.local(locals, ty, *slot - 1)
+            })
+            .collect()
+    }
+}
+
+/// Instructions popping the top of the stack into `locals`, the last of them
+/// first.
+pub(crate) fn local_sets(locals: &[LocalId]) -> impl Iterator<Item = Instr> + '_ {
+    locals.iter().rev().map(|&local| LocalSet { local }.into())
+}
+
+/// Instructions pushing `locals` back onto the stack, the first of them first.
+pub(crate) fn local_gets(locals: &[LocalId]) -> impl Iterator<Item = Instr> + '_ {
+    locals.iter().map(|&local| LocalGet { local }.into())
+}
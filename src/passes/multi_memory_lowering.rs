@@ -0,0 +1,724 @@
+//! Lowers a module with several memories to one with a single memory.
+//!
+//! Each memory gets a fixed region of the first memory, one after the other,
+//! as large as its maximum size. Every memory instruction and active data
+//! segment is rebased into its memory's region, and each memory's current size
+//! is tracked in a global of its own, so that `memory.size` and `memory.grow`
+//! behave as before.
+
+use crate::ir::*;
+use crate::map::IdHashMap;
+use crate::passes::{instr_seqs, local_gets, local_sets, Scratch};
+use crate::{ConstExpr, ConstOp, DataKind, LocalFunction, LocalId, Memory, MemoryId, Module};
+use crate::{ModuleLocals, Result, ValType};
+use anyhow::bail;
+use std::mem;
+
+/// Options for `run`.
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// Whether to trap on accesses outside of the memory they were made to,
+    /// as they would have before.
+    ///
+    /// Without these checks, such accesses reach into the neighbouring
+    /// memory's region instead. Defaults to true.
+    pub bounds_checks: bool,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            bounds_checks: true,
+        }
+    }
+}
+
+/// Merge every memory of `module` into its first memory.
+///
+/// Memory `i` is placed at the sum of the maximum sizes of the memories before
+/// it, so every memory but the last must have a maximum size, and the first
+/// memory stays at address zero. All of those regions are allocated up front,
+/// and only the last memory grows the merged memory.
+///
+/// Returns an error, without changing anything, if the memories can't be
+/// merged: if any is imported or shared, if they differ in index type or page
+/// size, if any but the first is exported, or if they don't fit together.
+pub fn run(module: &mut Module, options: &Options) -> Result<()> {
+    let memories = module.memories.iter().map(Memory::id).collect::<Vec<_>>();
+    if memories.len() <= 1 {
+        return Ok(());
+    }
+    if module.linking.is_some() {
+        bail!("cannot merge the memories of a relocatable object");
+    }
+
+    let merged = memories[0];
+    let memory64 = module.memories.get(merged).memory64;
+    let page_size_log2 = module.memories.get(merged).page_size_log2.unwrap_or(16);
+    let address = if memory64 { ValType::I64 } else { ValType::I32 };
+    let mut regions = Vec::with_capacity(memories.len());
+    let mut base = 0u64;
+    for (i, &id) in memories.iter().enumerate() {
+        let memory = module.memories.get(id);
+        if memory.import.is_some() {
+            bail!("cannot merge imported {:?}", id);
+        }
+        // Each memory's size lives in a global, which threads don't share.
+        if memory.shared {
+            bail!("cannot merge shared {:?}", id);
+        }
+        if memory.memory64 != memory64 || memory.page_size_log2.unwrap_or(16) != page_size_log2 {
+            bail!(
+                "{:?} has a different index type or page size from {:?}",
+                id,
+                merged
+            );
+        }
+        if i > 0 && module.exports.get_exported_memory(id).is_some() {
+            bail!("cannot merge exported {:?} into another memory", id);
+        }
+        let last = i == memories.len() - 1;
+        let size = match memory.maximum {
+            Some(maximum) => maximum,
+            None if last => memory.initial,
+            None => bail!(
+                "{:?} needs a maximum size to be placed before another memory",
+                id
+            ),
+        };
+        regions.push((id, base, memory.initial, memory.maximum, last));
+        base = match base.checked_add(size) {
+            Some(base) => base,
+            None => bail!("memories need more than {} pages together", u64::MAX),
+        };
+    }
+    // The number of pages addresses can reach, which is 2^64 with 64-bit
+    // addresses and one-byte pages, so doesn't fit in a `u64`.
+    let limit = 1u128 << ((if memory64 { 64 } else { 32 }) - page_size_log2);
+    if u128::from(base) > limit {
+        bail!("memories need {} pages together, more than {}", base, limit);
+    }
+
+    // Active segments that didn't fit would have failed instantiation, so make
+    // sure they still would.
+    let page_size = 1u64 << page_size_log2;
+    for data in module.data.iter() {
+        if let DataKind::Active {
+            memory,
+            offset: ConstExpr::Value(offset),
+        } = &data.kind
+        {
+            let (_, _, initial, _, _) = regions.iter().find(|r| r.0 == *memory).unwrap();
+            let offset = match offset {
+                Value::I32(offset) => u64::from(*offset as u32),
+                Value::I64(offset) => *offset as u64,
+                _ => continue,
+            };
+            let fits = offset
+                .checked_add(data.value.len() as u64)
+                .is_some_and(|end| end <= initial * page_size);
+            if !fits {
+                bail!("{:?} doesn't fit in {:?}", data.id(), memory);
+            }
+        }
+    }
+
+    let mut layout = IdHashMap::default();
+    for (i, &(id, base, initial, maximum, last)) in regions.iter().enumerate() {
+        let initial = if memory64 {
+            Value::I64(initial as i64)
+        } else {
+            Value::I32(initial as i32)
+        };
+        let size = module
+            .globals
+            .add_local(address, true, false, ConstExpr::Value(initial));
+        module.globals.get_mut(size).name = Some(format!("__memory{}_size", i));
+        let capacity = match maximum {
+            Some(maximum) => maximum,
+            None => u64::try_from(limit - u128::from(base)).unwrap_or(u64::MAX),
+        };
+        layout.insert(
+            id,
+            Region {
+                base: base * page_size,
+                size,
+                capacity,
+                last,
+            },
+        );
+    }
+
+    for (_, func) in module.funcs.iter_local_mut() {
+        let mut lowering = Lowering {
+            regions: &layout,
+            merged,
+            address,
+            page_size_log2,
+            bounds_checks: options.bounds_checks,
+            locals: &mut module.locals,
+            scratch: Scratch::default(),
+        };
+        lowering.function(func);
+    }
+
+    for data in module.data.iter_mut() {
+        if let DataKind::Active { memory, offset } = &mut data.kind {
+            let base = layout[memory].base;
+            *memory = merged;
+            rebase(offset, base, memory64);
+        }
+    }
+
+    let (_, last_base, last_initial, last_maximum, _) = *regions.last().unwrap();
+    let mut segments = Vec::new();
+    for &id in &memories[1..] {
+        segments.extend(module.memories.get(id).data_segments.iter().copied());
+        module.memories.delete(id);
+    }
+    let memory = module.memories.get_mut(merged);
+    memory.data_segments.extend(segments);
+    memory.initial = last_base + last_initial;
+    memory.maximum = last_maximum.map(|maximum| last_base + maximum);
+    Ok(())
+}
+
+/// Add `base` to a data segment's offset.
+fn rebase(offset: &mut ConstExpr, base: u64, memory64: bool) {
+    if base == 0 {
+        return;
+    }
+    *offset = match (
+        mem::replace(offset, ConstExpr::Extended(Vec::new())),
+        memory64,
+    ) {
+        (ConstExpr::Value(Value::I32(v)), _) => {
+            ConstExpr::Value(Value::I32(v.wrapping_add(base as i32)))
+        }
+        (ConstExpr::Value(Value::I64(v)), _) => {
+            ConstExpr::Value(Value::I64(v.wrapping_add(base as i64)))
+        }
+        (ConstExpr::Global(g), false) => ConstExpr::Extended(vec![
+            ConstOp::GlobalGet(g),
+            ConstOp::I32Const(base as i32),
+            ConstOp::I32Add,
+        ]),
+        (ConstExpr::Global(g), true) => ConstExpr::Extended(vec![
+            ConstOp::GlobalGet(g),
+            ConstOp::I64Const(base as i64),
+            ConstOp::I64Add,
+        ]),
+        (ConstExpr::Extended(mut ops), false) => {
+            ops.extend([ConstOp::I32Const(base as i32), ConstOp::I32Add]);
+            ConstExpr::Extended(ops)
+        }
+        (ConstExpr::Extended(mut ops), true) => {
+            ops.extend([ConstOp::I64Const(base as i64), ConstOp::I64Add]);
+            ConstExpr::Extended(ops)
+        }
+        (other, _) => other,
+    };
+}
+
+/// Where a memory lives in the merged memory.
+struct Region {
+    /// The address its region starts at.
+    base: u64,
+    /// The global holding its current size, in pages.
+    size: crate::GlobalId,
+    /// The most pages it may grow to.
+    capacity: u64,
+    /// Whether it's the last memory, which grows the merged memory.
+    last: bool,
+}
+
+/// The length of a bounds-checked access.
+enum Len {
+    Const(u64),
+    Local(LocalId, ValType),
+}
+
+struct Lowering<'a> {
+    regions: &'a IdHashMap<Memory, Region>,
+    merged: MemoryId,
+    /// The type of addresses.
+    address: ValType,
+    page_size_log2: u32,
+    bounds_checks: bool,
+    locals: &'a mut ModuleLocals,
+    scratch: Scratch,
+}
+
+impl Lowering<'_> {
+    fn function(&mut self, func: &mut LocalFunction) {
+        for seq in instr_seqs(func, func.entry_block()) {
+            let instrs = mem::take(&mut func.block_mut(seq).instrs);
+            let mut out = Vec::with_capacity(instrs.len());
+            for (instr, loc) in instrs {
+                self.instr(func, &mut out, instr, loc);
+            }
+            func.block_mut(seq).instrs = out;
+        }
+    }
+
+    /// Pop operands of `types` off the stack into scratch locals.
+    fn spill(
+        &mut self,
+        out: &mut Vec<(Instr, InstrLocId)>,
+        loc: InstrLocId,
+        types: &[ValType],
+    ) -> Vec<LocalId> {
+        let temps = self.scratch.locals(self.locals, types);
+        out.extend(local_sets(&temps).map(|instr| (instr, loc)));
+        temps
+    }
+
+    fn instr(
+        &mut self,
+        func: &mut LocalFunction,
+        out: &mut Vec<(Instr, InstrLocId)>,
+        instr: Instr,
+        loc: InstrLocId,
+    ) {
+        let merged = self.merged;
+        match instr {
+            Instr::Load(Load { memory, kind, arg }) => {
+                let load = Load {
+                    memory: merged,
+                    kind,
+                    arg,
+                };
+                self.access(func, out, loc, memory, &[], extent(arg, kind.width()), load);
+            }
+            Instr::Store(Store { memory, kind, arg }) => {
+                let ty = kind.ty();
+                let store = Store {
+                    memory: merged,
+                    kind,
+                    arg,
+                };
+                self.access(
+                    func,
+                    out,
+                    loc,
+                    memory,
+                    &[ty],
+                    extent(arg, kind.width()),
+                    store,
+                );
+            }
+            Instr::AtomicRmw(AtomicRmw {
+                memory,
+                op,
+                width,
+                arg,
+            }) => {
+                let rmw = AtomicRmw {
+                    memory: merged,
+                    op,
+                    width,
+                    arg,
+                };
+                let ty = width.ty();
+                self.access(
+                    func,
+                    out,
+                    loc,
+                    memory,
+                    &[ty],
+                    extent(arg, width.bytes()),
+                    rmw,
+                );
+            }
+            Instr::Cmpxchg(Cmpxchg { memory, width, arg }) => {
+                let cmpxchg = Cmpxchg {
+                    memory: merged,
+                    width,
+                    arg,
+                };
+                let ty = width.ty();
+                let extent = extent(arg, width.bytes());
+                self.access(func, out, loc, memory, &[ty, ty], extent, cmpxchg);
+            }
+            Instr::AtomicNotify(AtomicNotify { memory, arg }) => {
+                let notify = AtomicNotify {
+                    memory: merged,
+                    arg,
+                };
+                self.access(
+                    func,
+                    out,
+                    loc,
+                    memory,
+                    &[ValType::I32],
+                    extent(arg, 4),
+                    notify,
+                );
+            }
+            Instr::AtomicWait(AtomicWait {
+                memory,
+                arg,
+                sixty_four,
+            }) => {
+                let (ty, width) = if sixty_four {
+                    (ValType::I64, 8)
+                } else {
+                    (ValType::I32, 4)
+                };
+                let wait = AtomicWait {
+                    memory: merged,
+                    arg,
+                    sixty_four,
+                };
+                let rest = [ty, ValType::I64];
+                self.access(func, out, loc, memory, &rest, extent(arg, width), wait);
+            }
+            Instr::LoadSimd(LoadSimd { memory, kind, arg }) => {
+                use LoadSimdKind::*;
+                let (width, rest): (u32, &[ValType]) = match kind {
+                    Splat8 => (1, &[]),
+                    Splat16 => (2, &[]),
+                    Splat32 | V128Load32Zero => (4, &[]),
+                    Splat64 | V128Load64Zero => (8, &[]),
+                    V128Load8x8S | V128Load8x8U | V128Load16x4S | V128Load16x4U | V128Load32x2S
+                    | V128Load32x2U => (8, &[]),
+                    V128Load8Lane(_) | V128Store8Lane(_) => (1, &[ValType::V128]),
+                    V128Load16Lane(_) | V128Store16Lane(_) => (2, &[ValType::V128]),
+                    V128Load32Lane(_) | V128Store32Lane(_) => (4, &[ValType::V128]),
+                    V128Load64Lane(_) | V128Store64Lane(_) => (8, &[ValType::V128]),
+                };
+                let load = LoadSimd {
+                    memory: merged,
+                    kind,
+                    arg,
+                };
+                self.access(func, out, loc, memory, rest, extent(arg, width), load);
+            }
+            Instr::MemorySize(MemorySize { memory }) => {
+                out.push((
+                    GlobalGet {
+                        global: self.regions[&memory].size,
+                    }
+                    .into(),
+                    loc,
+                ));
+            }
+            Instr::MemoryGrow(MemoryGrow { memory }) => self.grow(func, out, loc, memory),
+            Instr::MemoryFill(MemoryFill { memory }) => {
+                let temps = self.spill(out, loc, &[self.address, ValType::I32, self.address]);
+                let (dst, value, len) = (temps[0], temps[1], temps[2]);
+                self.check(func, out, loc, memory, dst, Len::Local(len, self.address));
+                self.rebased(out, loc, memory, dst);
+                push_gets(out, loc, &[value, len]);
+                out.push((MemoryFill { memory: merged }.into(), loc));
+            }
+            Instr::MemoryCopy(MemoryCopy { src, dst }) => {
+                let temps = self.spill(out, loc, &[self.address; 3]);
+                let (to, from, len) = (temps[0], temps[1], temps[2]);
+                self.check(func, out, loc, dst, to, Len::Local(len, self.address));
+                self.check(func, out, loc, src, from, Len::Local(len, self.address));
+                self.rebased(out, loc, dst, to);
+                self.rebased(out, loc, src, from);
+                push_gets(out, loc, &[len]);
+                let copy = MemoryCopy {
+                    src: merged,
+                    dst: merged,
+                };
+                out.push((copy.into(), loc));
+            }
+            Instr::MemoryInit(MemoryInit { memory, data }) => {
+                let temps = self.spill(out, loc, &[self.address, ValType::I32, ValType::I32]);
+                let (dst, offset, len) = (temps[0], temps[1], temps[2]);
+                self.check(func, out, loc, memory, dst, Len::Local(len, ValType::I32));
+                self.rebased(out, loc, memory, dst);
+                push_gets(out, loc, &[offset, len]);
+                let init = MemoryInit {
+                    memory: merged,
+                    data,
+                };
+                out.push((init.into(), loc));
+            }
+            instr => out.push((instr, loc)),
+        }
+    }
+
+    /// Rewrite an access to `memory` whose address is followed on the stack
+    /// by operands of the types in `rest`.
+    #[allow(clippy::too_many_arguments)]
+    fn access(
+        &mut self,
+        func: &mut LocalFunction,
+        out: &mut Vec<(Instr, InstrLocId)>,
+        loc: InstrLocId,
+        memory: MemoryId,
+        rest: &[ValType],
+        extent: u64,
+        instr: impl Into<Instr>,
+    ) {
+        let mut types = vec![self.address];
+        types.extend(rest);
+        let temps = self.spill(out, loc, &types);
+        let (address, rest) = (temps[0], &temps[1..]);
+        self.check(func, out, loc, memory, address, Len::Const(extent));
+        self.rebased(out, loc, memory, address);
+        push_gets(out, loc, rest);
+        out.push((instr.into(), loc));
+    }
+
+    /// Push the address in `address` rebased into `memory`'s region.
+    fn rebased(
+        &self,
+        out: &mut Vec<(Instr, InstrLocId)>,
+        loc: InstrLocId,
+        memory: MemoryId,
+        address: LocalId,
+    ) {
+        out.push((LocalGet { local: address }.into(), loc));
+        let base = self.regions[&memory].base;
+        if base != 0 {
+            out.push((self.address_const(base).into(), loc));
+            out.push((
+                self.address_op(BinaryOp::I32Add, BinaryOp::I64Add).into(),
+                loc,
+            ));
+        }
+    }
+
+    /// Trap unless `len` bytes at `address` are within `memory`'s current
+    /// size, without overflowing:
+    ///
+    /// ```text
+    /// address > size || size - address < len
+    /// ```
+    fn check(
+        &self,
+        func: &mut LocalFunction,
+        out: &mut Vec<(Instr, InstrLocId)>,
+        loc: InstrLocId,
+        memory: MemoryId,
+        address: LocalId,
+        len: Len,
+    ) {
+        if !self.bounds_checks {
+            return;
+        }
+        let size = self.regions[&memory].size;
+        let mut instrs: Vec<Instr> = Vec::new();
+        let widen = |instrs: &mut Vec<Instr>, ty: ValType| {
+            if ty == ValType::I32 {
+                instrs.push(
+                    Unop {
+                        op: UnaryOp::I64ExtendUI32,
+                    }
+                    .into(),
+                );
+            }
+        };
+        let size_in_bytes = |instrs: &mut Vec<Instr>| {
+            instrs.push(GlobalGet { global: size }.into());
+            widen(instrs, self.address);
+            instrs.push(
+                Const {
+                    value: Value::I64(self.page_size_log2.into()),
+                }
+                .into(),
+            );
+            instrs.push(
+                Binop {
+                    op: BinaryOp::I64Shl,
+                }
+                .into(),
+            );
+        };
+        instrs.push(LocalGet { local: address }.into());
+        widen(&mut instrs, self.address);
+        size_in_bytes(&mut instrs);
+        instrs.push(
+            Binop {
+                op: BinaryOp::I64GtU,
+            }
+            .into(),
+        );
+        size_in_bytes(&mut instrs);
+        instrs.push(LocalGet { local: address }.into());
+        widen(&mut instrs, self.address);
+        instrs.push(
+            Binop {
+                op: BinaryOp::I64Sub,
+            }
+            .into(),
+        );
+        match len {
+            Len::Const(len) => instrs.push(
+                Const {
+                    value: Value::I64(len as i64),
+                }
+                .into(),
+            ),
+            Len::Local(local, ty) => {
+                instrs.push(LocalGet { local }.into());
+                widen(&mut instrs, ty);
+            }
+        }
+        instrs.push(
+            Binop {
+                op: BinaryOp::I64LtU,
+            }
+            .into(),
+        );
+        instrs.push(
+            Binop {
+                op: BinaryOp::I32Or,
+            }
+            .into(),
+        );
+        let builder = func.builder_mut();
+        let consequent = builder.dangling_instr_seq(None).unreachable().id();
+        let alternative = builder.dangling_instr_seq(None).id();
+        instrs.push(
+            IfElse {
+                consequent,
+                alternative,
+            }
+            .into(),
+        );
+        out.extend(instrs.into_iter().map(|instr| (instr, loc)));
+    }
+
+    /// Rewrite a `memory.grow` of `memory`:
+    ///
+    /// ```text
+    /// local.set $delta
+    /// if delta > capacity - size (result ..)
+    ///   -1
+    /// else
+    ///   ;; only for the last memory
+    ///   local.get $delta
+    ///   memory.grow
+    ///   -1
+    ///   eq
+    ///   if (result ..)
+    ///     -1
+    ///   else
+    ///     size
+    ///     size + delta
+    ///     global.set $size
+    ///   end
+    /// end
+    /// ```
+    fn grow(
+        &mut self,
+        func: &mut LocalFunction,
+        out: &mut Vec<(Instr, InstrLocId)>,
+        loc: InstrLocId,
+        memory: MemoryId,
+    ) {
+        let region = &self.regions[&memory];
+        let (size, capacity, last) = (region.size, region.capacity, region.last);
+        let delta = self.scratch.local(self.locals, self.address, 0);
+        let add = self.address_op(BinaryOp::I32Add, BinaryOp::I64Add);
+        let failed = self.address_const(u64::MAX);
+        let widen = |instrs: &mut Vec<Instr>, address: ValType| {
+            if address == ValType::I32 {
+                instrs.push(
+                    Unop {
+                        op: UnaryOp::I64ExtendUI32,
+                    }
+                    .into(),
+                );
+            }
+        };
+
+        let grown: Vec<Instr> = vec![
+            GlobalGet { global: size }.into(),
+            GlobalGet { global: size }.into(),
+            LocalGet { local: delta }.into(),
+            add.clone().into(),
+            GlobalSet { global: size }.into(),
+        ];
+        let ty = InstrSeqType::Simple(Some(self.address));
+        let builder = func.builder_mut();
+        let grown = if last {
+            let consequent = builder.dangling_instr_seq(ty).instr(failed.clone()).id();
+            let alternative = builder.dangling_instr_seq(ty).id();
+            func.block_mut(alternative).instrs = grown.into_iter().map(|i| (i, loc)).collect();
+            vec![
+                LocalGet { local: delta }.into(),
+                MemoryGrow {
+                    memory: self.merged,
+                }
+                .into(),
+                failed.clone().into(),
+                self.address_op(BinaryOp::I32Eq, BinaryOp::I64Eq).into(),
+                IfElse {
+                    consequent,
+                    alternative,
+                }
+                .into(),
+            ]
+        } else {
+            grown
+        };
+
+        let builder = func.builder_mut();
+        let consequent = builder.dangling_instr_seq(ty).instr(failed).id();
+        let alternative = builder.dangling_instr_seq(ty).id();
+        func.block_mut(alternative).instrs = grown.into_iter().map(|i| (i, loc)).collect();
+
+        // Adding `delta` to the size could wrap around with 64-bit addresses.
+        let mut instrs: Vec<Instr> = vec![
+            LocalSet { local: delta }.into(),
+            LocalGet { local: delta }.into(),
+        ];
+        widen(&mut instrs, self.address);
+        instrs.push(
+            Const {
+                value: Value::I64(capacity as i64),
+            }
+            .into(),
+        );
+        instrs.push(GlobalGet { global: size }.into());
+        widen(&mut instrs, self.address);
+        instrs.extend([
+            Binop {
+                op: BinaryOp::I64Sub,
+            }
+            .into(),
+            Binop {
+                op: BinaryOp::I64GtU,
+            }
+            .into(),
+            IfElse {
+                consequent,
+                alternative,
+            }
+            .into(),
+        ]);
+        out.extend(instrs.into_iter().map(|instr| (instr, loc)));
+    }
+
+    fn address_const(&self, value: u64) -> Const {
+        let value = match self.address {
+            ValType::I32 => Value::I32(value as i32),
+            _ => Value::I64(value as i64),
+        };
+        Const { value }
+    }
+
+    fn address_op(&self, op32: BinaryOp, op64: BinaryOp) -> Binop {
+        let op = match self.address {
+            ValType::I32 => op32,
+            _ => op64,
+        };
+        Binop { op }
+    }
+}
+
+/// How far past its address an access reaches.
+fn extent(arg: MemArg, width: u32) -> u64 {
+    arg.offset.saturating_add(width.into())
+}
+
+fn push_gets(out: &mut Vec<(Instr, InstrLocId)>, loc: InstrLocId, locals: &[LocalId]) {
+    out.extend(local_gets(locals).map(|instr| (instr, loc)));
+}
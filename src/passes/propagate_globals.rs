@@ -0,0 +1,223 @@
+//! Propagates the values of constant globals into the code that reads them.
+//!
+//! Compilers often leave behind mutable globals that nothing ever writes, and
+//! immutable globals that merely name a constant. This pass makes the former
+//! immutable, replaces reads of constant globals with their values, and then
+//! removes the globals that nothing reads any more.
+
+use crate::const_expr::ConstOp;
+use crate::ir::*;
+use crate::map::{IdHashMap, IdHashSet};
+use crate::{ConstExpr, DataKind, ElementItems, ElementKind, ExportItem, Global, GlobalKind};
+use crate::{Module, ValType};
+
+/// Run the pass over `module`.
+///
+/// Exported and imported globals are never changed or removed, since they
+/// are visible outside of the module. Relocatable objects are left untouched,
+/// since their symbols may refer to any global.
+pub fn run(module: &mut Module) {
+    if module.linking.is_some() {
+        return;
+    }
+
+    let mut exported = IdHashSet::default();
+    for export in module.exports.iter() {
+        if let ExportItem::Global(g) = export.item {
+            exported.insert(g);
+        }
+    }
+
+    // Promote mutable globals that are never written.
+    let mut written = IdHashSet::default();
+    for (_, func) in module.funcs.iter_local() {
+        dfs_in_order(
+            &mut GlobalSets {
+                written: &mut written,
+            },
+            func,
+            func.entry_block(),
+        );
+    }
+    for global in module.globals.iter_mut() {
+        let id = global.id();
+        if global.mutable
+            && matches!(global.kind, GlobalKind::Local(_))
+            && !exported.contains(&id)
+            && !written.contains(&id)
+        {
+            log::debug!("promoting {:?} to immutable", id);
+            global.mutable = false;
+        }
+    }
+
+    // Find the value of every constant global, and substitute them.
+    let no_imports = |_| None;
+    let mut values = IdHashMap::default();
+    for global in module.globals.iter() {
+        if global.mutable || matches!(global.ty, ValType::Ref(_)) {
+            continue;
+        }
+        if let GlobalKind::Local(init) = &global.kind {
+            if let Ok(Some(value)) = init.eval(module, &no_imports) {
+                values.insert(global.id(), value);
+            }
+        }
+    }
+    if !values.is_empty() {
+        substitute(module, &values);
+    }
+
+    // Remove local globals that are now unread.
+    let mut used = exported;
+    for (_, func) in module.funcs.iter_local() {
+        dfs_in_order(
+            &mut GlobalUses { used: &mut used },
+            func,
+            func.entry_block(),
+        );
+    }
+    for_each_const_expr(module, |expr| match expr {
+        ConstExpr::Global(g) => {
+            used.insert(*g);
+        }
+        ConstExpr::Extended(ops) => {
+            for op in ops {
+                if let ConstOp::GlobalGet(g) = op {
+                    used.insert(*g);
+                }
+            }
+        }
+        _ => {}
+    });
+    let unused = module
+        .globals
+        .iter()
+        .filter(|g| matches!(g.kind, GlobalKind::Local(_)) && !used.contains(&g.id()))
+        .map(Global::id)
+        .collect::<Vec<_>>();
+    for id in unused {
+        log::debug!("removing unused {:?}", id);
+        module.globals.delete(id);
+    }
+}
+
+/// Replace reads of the globals in `values`, in code and in constant
+/// expressions, with their values.
+fn substitute(module: &mut Module, values: &IdHashMap<Global, Value>) {
+    for (_, func) in module.funcs.iter_local_mut() {
+        let entry = func.entry_block();
+        dfs_pre_order_mut(&mut Substitute { values }, func, entry);
+    }
+
+    let substitute_expr = |expr: &mut ConstExpr| match expr {
+        ConstExpr::Global(g) => {
+            if let Some(value) = values.get(g) {
+                *expr = ConstExpr::Value(*value);
+            }
+        }
+        ConstExpr::Extended(ops) => {
+            for op in ops.iter_mut() {
+                if let ConstOp::GlobalGet(g) = op {
+                    if let Some(value) = values.get(g) {
+                        *op = match *value {
+                            Value::I32(v) => ConstOp::I32Const(v),
+                            Value::I64(v) => ConstOp::I64Const(v),
+                            Value::F32(v) => ConstOp::F32Const(v),
+                            Value::F64(v) => ConstOp::F64Const(v),
+                            Value::V128(v) => ConstOp::V128Const(v),
+                        };
+                    }
+                }
+            }
+        }
+        _ => {}
+    };
+    for_each_const_expr_mut(module, substitute_expr);
+}
+
+fn for_each_const_expr(module: &Module, mut f: impl FnMut(&ConstExpr)) {
+    for global in module.globals.iter() {
+        if let GlobalKind::Local(init) = &global.kind {
+            f(init);
+        }
+    }
+    for table in module.tables.iter() {
+        if let Some(init) = &table.init {
+            f(init);
+        }
+    }
+    for data in module.data.iter() {
+        if let DataKind::Active { offset, .. } = &data.kind {
+            f(offset);
+        }
+    }
+    for elem in module.elements.iter() {
+        if let ElementKind::Active { offset, .. } = &elem.kind {
+            f(offset);
+        }
+        if let ElementItems::Expressions(_, exprs) = &elem.items {
+            exprs.iter().for_each(&mut f);
+        }
+    }
+}
+
+fn for_each_const_expr_mut(module: &mut Module, mut f: impl FnMut(&mut ConstExpr)) {
+    for global in module.globals.iter_mut() {
+        if let GlobalKind::Local(init) = &mut global.kind {
+            f(init);
+        }
+    }
+    for table in module.tables.iter_mut() {
+        if let Some(init) = &mut table.init {
+            f(init);
+        }
+    }
+    for data in module.data.iter_mut() {
+        if let DataKind::Active { offset, .. } = &mut data.kind {
+            f(offset);
+        }
+    }
+    for elem in module.elements.iter_mut() {
+        if let ElementKind::Active { offset, .. } = &mut elem.kind {
+            f(offset);
+        }
+        if let ElementItems::Expressions(_, exprs) = &mut elem.items {
+            exprs.iter_mut().for_each(&mut f);
+        }
+    }
+}
+
+struct GlobalSets<'a> {
+    written: &'a mut IdHashSet<Global>,
+}
+
+impl<'instr> Visitor<'instr> for GlobalSets<'_> {
+    fn visit_global_set(&mut self, instr: &GlobalSet) {
+        self.written.insert(instr.global);
+    }
+}
+
+struct GlobalUses<'a> {
+    used: &'a mut IdHashSet<Global>,
+}
+
+impl<'instr> Visitor<'instr> for GlobalUses<'_> {
+    fn visit_global_id(&mut self, global: &crate::GlobalId) {
+        self.used.insert(*global);
+    }
+}
+
+struct Substitute<'a> {
+    values: &'a IdHashMap<Global, Value>,
+}
+
+impl VisitorMut for Substitute<'_> {
+    fn visit_instr_mut(&mut self, instr: &mut Instr, _: &mut InstrLocId) {
+        if let Instr::GlobalGet(GlobalGet { global }) = instr {
+            if let Some(value) = self.values.get(global) {
+                *instr = Const { value: *value }.into();
+            }
+        }
+    }
+}
@@ -0,0 +1,467 @@
+//! Reduces a module to a smaller one that is still "interesting", in the
+//! manner of `wasm-reduce`.
+//!
+//! Interestingness is up to the caller: typically it's whether some engine
+//! still crashes on the module, or whether some tool still misbehaves on it.
+//! The reducer repeatedly tries shrinking the module through the IR, and
+//! keeps each change that leaves a smaller valid module for which the
+//! predicate still holds, so it always terminates. Changes are tried in ever smaller batches, so that a large module
+//! needs far fewer attempts than it has functions or instructions.
+
+use crate::ir::*;
+use crate::map::IdHashSet;
+use crate::passes::instr_seqs;
+use crate::{
+    ConstExpr, ConstOp, DataKind, ElementItems, ExportItem, FunctionId, FunctionKind, GlobalKind,
+};
+use crate::{Module, ModuleConfig, RefType, UntypedCustomSectionId, ValType};
+use anyhow::{bail, Context};
+use std::ops::Range;
+use wasmparser::Validator;
+
+/// Reduce the wasm binary `wasm` while `interesting` holds for it, and return
+/// the smallest binary found, which is `wasm` itself if nothing smaller is
+/// interesting.
+///
+/// `interesting` is only ever given valid modules. It's an error for it not
+/// to hold for `wasm` itself.
+pub fn run(wasm: &[u8], mut interesting: impl FnMut(&[u8]) -> bool) -> anyhow::Result<Vec<u8>> {
+    if !interesting(wasm) {
+        bail!("the module to reduce isn't interesting to begin with");
+    }
+    let mut reducer = Reducer {
+        best: wasm.to_vec(),
+        interesting: &mut interesting,
+    };
+    loop {
+        let mut reduced = false;
+        for strategy in Strategy::ALL {
+            reduced |= reducer.reduce(strategy)?;
+        }
+        if !reduced {
+            break;
+        }
+    }
+    Ok(reducer.best)
+}
+
+/// A kind of change to try, on any of a number of items in the module.
+#[derive(Clone, Copy, Debug)]
+enum Strategy {
+    /// Delete custom sections.
+    Customs,
+    /// Delete exports.
+    Exports,
+    /// Delete functions, replacing calls to them with `unreachable` and
+    /// references to them with null.
+    Functions,
+    /// Replace function bodies with `unreachable`.
+    Bodies,
+    /// Cut data segments in half, and delete empty active ones.
+    Data,
+    /// Replace the rest of an instruction sequence with `unreachable`.
+    Truncate,
+    /// Remove an instruction that produces a value nobody uses, along with
+    /// the `drop` of it.
+    Drops,
+    /// Replace calls and loads with dropping their operands and producing
+    /// constant results.
+    Constants,
+    /// Run the `gc` pass.
+    Gc,
+}
+
+impl Strategy {
+    const ALL: [Strategy; 9] = [
+        Strategy::Customs,
+        Strategy::Exports,
+        Strategy::Functions,
+        Strategy::Bodies,
+        Strategy::Data,
+        Strategy::Truncate,
+        Strategy::Drops,
+        Strategy::Constants,
+        Strategy::Gc,
+    ];
+}
+
+/// Something in a module that a strategy may change.
+#[derive(Clone, Copy)]
+enum Item {
+    Custom(UntypedCustomSectionId),
+    Export(crate::ExportId),
+    Function(FunctionId),
+    Data(crate::DataId),
+    /// An instruction, by its index in its sequence.
+    Instr(FunctionId, InstrSeqId, usize),
+    Module,
+}
+
+struct Reducer<'a, F> {
+    best: Vec<u8>,
+    interesting: &'a mut F,
+}
+
+impl<F> Reducer<'_, F>
+where
+    F: FnMut(&[u8]) -> bool,
+{
+    fn parse(&self) -> anyhow::Result<Module> {
+        // A producers section naming walrus would make every change bigger.
+        ModuleConfig::new()
+            .generate_producers_section(false)
+            .parse(&self.best)
+            .context("failed to parse the module being reduced")
+    }
+
+    /// Apply `strategy` to as many items as possible, trying the items in
+    /// halves, then quarters, and so on. Returns whether anything changed.
+    fn reduce(&mut self, strategy: Strategy) -> anyhow::Result<bool> {
+        let mut reduced = false;
+        let mut count = items(&self.parse()?, strategy).len();
+        let mut chunk = count;
+        while chunk > 0 {
+            let mut start = 0;
+            while start < count {
+                let range = start..(start + chunk).min(count);
+                if self.attempt(strategy, range)? {
+                    // The items are found afresh in the new module; the ones
+                    // before `start` have all been tried already.
+                    reduced = true;
+                    count = items(&self.parse()?, strategy).len();
+                } else {
+                    start += chunk;
+                }
+            }
+            chunk /= 2;
+        }
+        Ok(reduced)
+    }
+
+    /// Apply `strategy` to the items in `range`, and keep the result if it's
+    /// smaller, valid and still interesting.
+    fn attempt(&mut self, strategy: Strategy, range: Range<usize>) -> anyhow::Result<bool> {
+        let mut module = self.parse()?;
+        let items = items(&module, strategy);
+        apply(&mut module, strategy, &items[range]);
+        // Judge the change by what it leaves behind: replacing a call with
+        // constants only pays off once the callee can be deleted.
+        crate::passes::gc::run(&mut module);
+        let features = module.config.get_wasmparser_wasm_features();
+        let wasm = module.emit_wasm();
+        if wasm.len() >= self.best.len()
+            || Validator::new_with_features(features)
+                .validate_all(&wasm)
+                .is_err()
+        {
+            return Ok(false);
+        }
+        if !(self.interesting)(&wasm) {
+            return Ok(false);
+        }
+        log::debug!("reduced to {} bytes with {:?}", wasm.len(), strategy);
+        self.best = wasm;
+        Ok(true)
+    }
+}
+
+/// The items `strategy` may still change in `module`, in a deterministic
+/// order.
+fn items(module: &Module, strategy: Strategy) -> Vec<Item> {
+    match strategy {
+        Strategy::Customs => module
+            .customs
+            .iter()
+            .map(|(id, _)| Item::Custom(id))
+            .collect(),
+        Strategy::Exports => module
+            .exports
+            .iter()
+            .map(|e| Item::Export(e.id()))
+            .collect(),
+        Strategy::Functions => module
+            .funcs
+            .iter()
+            .map(|f| Item::Function(f.id()))
+            .collect(),
+        Strategy::Bodies => module
+            .funcs
+            .iter_local()
+            .filter(|(_, func)| {
+                let body = func.block(func.entry_block());
+                !matches!(&body.instrs[..], [(Instr::Unreachable(_), _)])
+            })
+            .map(|(id, _)| Item::Function(id))
+            .collect(),
+        Strategy::Data => {
+            // Empty segments are deleted, which code referring to them
+            // mustn't see.
+            let mut referenced = IdHashSet::default();
+            for (_, func) in module.funcs.iter_local() {
+                referenced.extend(func.used_data_segments());
+            }
+            module
+                .data
+                .iter()
+                .filter(|d| {
+                    !d.value.is_empty()
+                        || matches!(d.kind, DataKind::Active { .. })
+                            && !referenced.contains(&d.id())
+                })
+                .map(|d| Item::Data(d.id()))
+                .collect()
+        }
+        Strategy::Truncate | Strategy::Drops | Strategy::Constants => {
+            let mut items = Vec::new();
+            for (id, func) in module.funcs.iter_local() {
+                for seq in instr_seqs(func, func.entry_block()) {
+                    let instrs = &func.block(seq).instrs;
+                    for i in 0..instrs.len() {
+                        let simplifiable = match strategy {
+                            Strategy::Truncate => {
+                                i + 1 < instrs.len()
+                                    || !matches!(instrs[i].0, Instr::Unreachable(_))
+                            }
+                            Strategy::Drops => {
+                                unused_value(&instrs[i].0)
+                                    && matches!(instrs.get(i + 1), Some((Instr::Drop(_), _)))
+                            }
+                            _ => constants(module, &instrs[i].0).is_some(),
+                        };
+                        if simplifiable {
+                            items.push(Item::Instr(id, seq, i));
+                        }
+                    }
+                }
+            }
+            items
+        }
+        Strategy::Gc => vec![Item::Module],
+    }
+}
+
+/// Whether `instr` only produces a value, without operands or side effects.
+fn unused_value(instr: &Instr) -> bool {
+    matches!(
+        instr,
+        Instr::Const(_)
+            | Instr::LocalGet(_)
+            | Instr::GlobalGet(_)
+            | Instr::RefNull(_)
+            | Instr::RefFunc(_)
+            | Instr::MemorySize(_)
+            | Instr::TableSize(_)
+    )
+}
+
+/// The instructions to replace a call or load with: drop the operands, and
+/// produce the default value of each result.
+fn constants(module: &Module, instr: &Instr) -> Option<Vec<Instr>> {
+    let (params, results) = match instr {
+        Instr::Call(Call { func }) => {
+            let ty = module.types.get(module.funcs.get(*func).ty());
+            (ty.params().len(), ty.results().to_vec())
+        }
+        Instr::CallIndirect(CallIndirect { ty, .. }) => {
+            let ty = module.types.get(*ty);
+            (ty.params().len() + 1, ty.results().to_vec())
+        }
+        Instr::Load(Load { kind, .. }) => (1, vec![kind.ty()]),
+        _ => return None,
+    };
+    let mut instrs = vec![Drop {}.into(); params];
+    for ty in results {
+        instrs.push(match ty {
+            ValType::I32 => Const {
+                value: Value::I32(0),
+            }
+            .into(),
+            ValType::I64 => Const {
+                value: Value::I64(0),
+            }
+            .into(),
+            ValType::F32 => Const {
+                value: Value::F32(0.0),
+            }
+            .into(),
+            ValType::F64 => Const {
+                value: Value::F64(0.0),
+            }
+            .into(),
+            ValType::V128 => Const {
+                value: Value::V128(0),
+            }
+            .into(),
+            ValType::Ref(ty) if ty.nullable => RefNull { ty }.into(),
+            ValType::Ref(_) => return None,
+        });
+    }
+    Some(instrs)
+}
+
+/// Apply `strategy` to `items`, which were found by `items` in this module.
+fn apply(module: &mut Module, strategy: Strategy, items: &[Item]) {
+    match strategy {
+        Strategy::Functions => {
+            let removed = items
+                .iter()
+                .filter_map(|item| match item {
+                    Item::Function(id) => Some(*id),
+                    _ => None,
+                })
+                .collect();
+            remove_functions(module, &removed);
+            return;
+        }
+        Strategy::Gc => {
+            crate::passes::gc::run(module);
+            return;
+        }
+        _ => {}
+    }
+
+    // Work backwards, so that changing an instruction doesn't move those
+    // before it in the same sequence.
+    let constants = items
+        .iter()
+        .map(|item| match (strategy, item) {
+            (Strategy::Constants, Item::Instr(func, seq, i)) => {
+                let func = module.funcs.get(*func).kind.unwrap_local();
+                constants(module, &func.block(*seq).instrs[*i].0)
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    for (item, constants) in items.iter().zip(constants).rev() {
+        match *item {
+            Item::Custom(id) => {
+                module.customs.delete(id);
+            }
+            Item::Export(id) => module.exports.delete(id),
+            Item::Function(id) => {
+                let func = module.funcs.get_mut(id).kind.unwrap_local_mut();
+                let entry = func.entry_block();
+                func.block_mut(entry).instrs = vec![(Unreachable {}.into(), Default::default())];
+            }
+            Item::Data(id) => {
+                let data = module.data.get_mut(id);
+                if data.value.is_empty() {
+                    if let DataKind::Active { memory, .. } = data.kind {
+                        module.memories.get_mut(memory).data_segments.remove(&id);
+                    }
+                    module.data.delete(id);
+                } else {
+                    data.value.truncate(data.value.len() / 2);
+                }
+            }
+            Item::Instr(func, seq, i) => {
+                let func = module.funcs.get_mut(func).kind.unwrap_local_mut();
+                let instrs = &mut func.block_mut(seq).instrs;
+                match strategy {
+                    Strategy::Truncate => {
+                        instrs.truncate(i);
+                        instrs.push((Unreachable {}.into(), Default::default()));
+                    }
+                    Strategy::Drops => {
+                        instrs.drain(i..i + 2);
+                    }
+                    _ => {
+                        let constants = constants.unwrap().into_iter();
+                        instrs.splice(i..i + 1, constants.map(|c| (c, Default::default())));
+                    }
+                }
+            }
+            Item::Module => {}
+        }
+    }
+}
+
+/// Delete the functions in `removed`, calling `unreachable` instead of them
+/// and replacing references to them with null.
+fn remove_functions(module: &mut Module, removed: &IdHashSet<crate::Function>) {
+    let mut unref = Unref { removed };
+    for (id, func) in module.funcs.iter_local_mut() {
+        if !removed.contains(&id) {
+            dfs_pre_order_mut(&mut unref, func, func.entry_block());
+        }
+    }
+
+    let exports = module
+        .exports
+        .iter()
+        .filter(|e| matches!(e.item, ExportItem::Function(f) if removed.contains(&f)))
+        .map(|e| e.id())
+        .collect::<Vec<_>>();
+    for id in exports {
+        module.exports.delete(id);
+    }
+    if module.start.is_some_and(|f| removed.contains(&f)) {
+        module.start = None;
+    }
+    for elem in module.elements.iter_mut() {
+        match &mut elem.items {
+            ElementItems::Functions(funcs) => funcs.retain(|f| !removed.contains(f)),
+            ElementItems::Expressions(_, exprs) => {
+                for expr in exprs {
+                    unref_const(expr, removed);
+                }
+            }
+        }
+    }
+    for global in module.globals.iter_mut() {
+        if let GlobalKind::Local(expr) = &mut global.kind {
+            unref_const(expr, removed);
+        }
+    }
+    for table in module.tables.iter_mut() {
+        if let Some(expr) = &mut table.init {
+            unref_const(expr, removed);
+        }
+    }
+
+    for id in removed.iter() {
+        if let FunctionKind::Import(import) = &module.funcs.get(*id).kind {
+            module.imports.delete(import.import);
+        }
+        module.funcs.delete(*id);
+    }
+}
+
+fn unref_const(expr: &mut ConstExpr, removed: &IdHashSet<crate::Function>) {
+    match expr {
+        ConstExpr::RefFunc(f) if removed.contains(f) => {
+            *expr = ConstExpr::RefNull(RefType::FUNCREF);
+        }
+        ConstExpr::Extended(ops) => {
+            for op in ops {
+                if matches!(op, ConstOp::RefFunc(f) if removed.contains(f)) {
+                    *op = ConstOp::RefNull(RefType::FUNCREF);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+struct Unref<'a> {
+    removed: &'a IdHashSet<crate::Function>,
+}
+
+impl VisitorMut for Unref<'_> {
+    fn visit_instr_mut(&mut self, instr: &mut Instr, _: &mut InstrLocId) {
+        match instr {
+            Instr::Call(Call { func }) | Instr::ReturnCall(ReturnCall { func })
+                if self.removed.contains(func) =>
+            {
+                *instr = Unreachable {}.into();
+            }
+            Instr::RefFunc(RefFunc { func }) if self.removed.contains(func) => {
+                *instr = RefNull {
+                    ty: RefType::FUNCREF,
+                }
+                .into();
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,181 @@
+//! Limits how deep calls can nest, to keep a module from exhausting the
+//! native stack of the engine running it.
+//!
+//! Each local function's frame is charged to a mutable `i32` stack height
+//! global on entry, and refunded on every exit. A frame's size is the number
+//! of its locals, including its parameters, plus the most values its operand
+//! stack ever holds, and every value counts as 1 whatever its type. When the
+//! height goes over the limit, the module traps with `unreachable`.
+//!
+//! Every way out of a function goes through a single exit point, as in
+//! `passes::instrument`. Direct calls to imported functions go through a
+//! local thunk instead, so that they have a frame charged too.
+
+use crate::ir::*;
+use crate::passes::instr_seqs;
+use crate::passes::instrument::single_exit;
+use crate::passes::stack_types;
+use crate::{
+    ConstExpr, FunctionBuilder, FunctionId, FunctionKind, GlobalId, LocalFunction, Module, Result,
+    ValType,
+};
+use std::collections::HashMap;
+
+/// Options for `run`.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// The highest the stack height may go before trapping.
+    pub limit: u32,
+    /// Export the stack height global with this name.
+    pub export: Option<String>,
+    /// Also refund a function's frame when an exception unwinds out of it,
+    /// like `instrument`'s option of the same name. Without it, the height is
+    /// left too high after an exception.
+    pub exceptions: bool,
+}
+
+/// Limit the stack height of `module`, and return the stack height global.
+///
+/// Returns an error if the module isn't valid, since it's emitted to measure
+/// each function's operand stack.
+pub fn run(module: &mut Module, options: &Options) -> Result<GlobalId> {
+    add_thunks(module);
+    let sizes = stack_types::frame_sizes(module)?;
+
+    let init = ConstExpr::Value(Value::I32(0));
+    let height = module.globals.add_local(ValType::I32, true, false, init);
+    if let Some(name) = &options.export {
+        module.exports.add(name, height);
+    }
+
+    let funcs = module
+        .funcs
+        .iter_local()
+        .map(|(id, _)| id)
+        .collect::<Vec<_>>();
+    for id in funcs {
+        let func = module.funcs.get_mut(id);
+        let results = module.types.results(func.ty()).to_vec();
+        let ty = InstrSeqType::new(&mut module.types, &[], &results);
+        let func = func.kind.unwrap_local_mut();
+        let frame = Frame {
+            height,
+            size: sizes[&id] as i32,
+            limit: options.limit as i32,
+        };
+        single_exit(
+            func,
+            ty,
+            options.exceptions,
+            |func, instrs| frame.enter(func, instrs),
+            |instrs| frame.exit(instrs),
+        );
+    }
+    Ok(height)
+}
+
+/// Add a thunk for every imported function called directly, and call the
+/// thunks instead.
+fn add_thunks(module: &mut Module) {
+    let funcs = module
+        .funcs
+        .iter_local()
+        .map(|(id, _)| id)
+        .collect::<Vec<_>>();
+    let mut thunks = HashMap::new();
+    for id in funcs {
+        let func = module.funcs.get(id).kind.unwrap_local();
+        let mut calls = Vec::new();
+        for seq in instr_seqs(func, func.entry_block()) {
+            for (i, (instr, _)) in func.block(seq).instrs.iter().enumerate() {
+                if let Instr::Call(Call { func }) | Instr::ReturnCall(ReturnCall { func }) = instr {
+                    if let FunctionKind::Import(_) = module.funcs.get(*func).kind {
+                        calls.push((seq, i, *func));
+                    }
+                }
+            }
+        }
+
+        for (seq, i, import) in calls {
+            let thunk = *thunks
+                .entry(import)
+                .or_insert_with(|| add_thunk(module, import));
+            let func = module.funcs.get_mut(id).kind.unwrap_local_mut();
+            match &mut func.block_mut(seq).instrs[i].0 {
+                Instr::Call(Call { func }) | Instr::ReturnCall(ReturnCall { func }) => {
+                    *func = thunk
+                }
+                _ => unreachable!(),
+            }
+        }
+    }
+}
+
+/// Add a function that just calls `import` with its arguments.
+fn add_thunk(module: &mut Module, import: FunctionId) -> FunctionId {
+    let ty = module.funcs.get(import).ty();
+    let params = module.types.params(ty).to_vec();
+    let results = module.types.results(ty).to_vec();
+    let args = params
+        .iter()
+        .map(|ty| module.locals.add(*ty))
+        .collect::<Vec<_>>();
+    let mut builder = FunctionBuilder::new(&mut module.types, &params, &results);
+    let mut body = builder.func_body();
+    for arg in &args {
+        body.local_get(*arg);
+    }
+    body.call(import);
+    builder.finish(args, &mut module.funcs)
+}
+
+/// What a function charges to the stack height.
+struct Frame {
+    height: GlobalId,
+    size: i32,
+    limit: i32,
+}
+
+impl Frame {
+    /// Add the frame to the height, and trap if it goes over the limit.
+    fn enter(&self, func: &mut LocalFunction, instrs: &mut Vec<(Instr, InstrLocId)>) {
+        let over = func.builder_mut().dangling_instr_seq(None).id();
+        let under = func.builder_mut().dangling_instr_seq(None).id();
+        func.block_mut(over).instrs = vec![(Unreachable {}.into(), InstrLocId::default())];
+
+        self.adjust(BinaryOp::I32Add, instrs);
+        let global = self.height;
+        let limit = Value::I32(self.limit);
+        let check: [Instr; 4] = [
+            GlobalGet { global }.into(),
+            Const { value: limit }.into(),
+            Binop {
+                op: BinaryOp::I32GtU,
+            }
+            .into(),
+            IfElse {
+                consequent: over,
+                alternative: under,
+            }
+            .into(),
+        ];
+        instrs.extend(check.map(|instr| (instr, InstrLocId::default())));
+    }
+
+    /// Take the frame off the height again.
+    fn exit(&self, instrs: &mut Vec<(Instr, InstrLocId)>) {
+        self.adjust(BinaryOp::I32Sub, instrs);
+    }
+
+    fn adjust(&self, op: BinaryOp, instrs: &mut Vec<(Instr, InstrLocId)>) {
+        let global = self.height;
+        let size = Value::I32(self.size);
+        let adjust: [Instr; 4] = [
+            GlobalGet { global }.into(),
+            Const { value: size }.into(),
+            Binop { op }.into(),
+            GlobalSet { global }.into(),
+        ];
+        instrs.extend(adjust.map(|instr| (instr, InstrLocId::default())));
+    }
+}
@@ -0,0 +1,199 @@
+//! Finding the types of the values on the operand stack at given instructions,
+//! and how high the stack gets.
+//!
+//! Walrus doesn't track the operand stack itself, so this emits the module
+//! with a marker on each instruction of interest and steps wasmparser's
+//! validator through the result, which knows the type of every operand.
+
+use crate::ir::*;
+use crate::map::IdHashMap;
+use crate::passes::instr_seqs;
+use crate::{FunctionId, Module, Result, ValType};
+use std::collections::HashMap;
+use std::mem;
+use wasmparser::{
+    FuncValidator, OperatorsReader, Parser, ValidPayload, Validator, ValidatorResources,
+};
+
+/// The operands an instruction's own control frame has on the stack before
+/// it executes, bottom first.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Operands {
+    /// The instruction can't be reached, so the stack is polymorphic.
+    Unreachable,
+    /// Every operand has one of these numeric or vector types.
+    Known(Vec<ValType>),
+    /// Some operand is a reference, whose exact type isn't tracked.
+    References,
+}
+
+/// Find the operands on the stack before each of the instructions in `at`,
+/// given as the function, sequence and index of each instruction.
+///
+/// This emits the whole module, so ask for everything at once.
+pub(crate) fn operands(
+    module: &mut Module,
+    at: &[(FunctionId, InstrSeqId, usize)],
+) -> Result<Vec<Operands>> {
+    let mut operands = vec![Operands::Unreachable; at.len()];
+    validate_marked(module, at, |_, mark, validator| {
+        if let Some(i) = mark {
+            operands[i] = frame_operands(validator);
+        }
+    })?;
+    Ok(operands)
+}
+
+/// Find how many values each local function keeps on the stack at most: its
+/// locals, including its parameters, plus the highest its operand stack
+/// gets.
+pub(crate) fn frame_sizes(module: &mut Module) -> Result<IdHashMap<crate::Function, u32>> {
+    // Mark the first instruction of each function, to know which function
+    // the validator is in. Functions without any instructions have no
+    // operands, and only their parameters as locals.
+    let mut sizes = IdHashMap::default();
+    let mut at = Vec::new();
+    for (id, func) in module.funcs.iter_local() {
+        let entry = func.entry_block();
+        if func.block(entry).instrs.is_empty() {
+            sizes.insert(id, func.args.len() as u32);
+        } else {
+            at.push((id, entry, 0));
+        }
+    }
+
+    let mut current = None;
+    let mut maxima = vec![0; at.len()];
+    validate_marked(module, &at, |body, mark, validator| {
+        if let Some(i) = mark {
+            current = Some((body, i));
+        }
+        if let Some((_, i)) = current.filter(|(b, _)| *b == body) {
+            let size = validator.len_locals() + validator.operand_stack_height();
+            maxima[i] = maxima[i].max(size);
+        }
+    })?;
+    sizes.extend(at.iter().zip(maxima).map(|((id, _, _), max)| (*id, max)));
+    Ok(sizes)
+}
+
+/// Emit `module` with a mark on each of the instructions in `at`, and step
+/// wasmparser's validator through every instruction of the result. Before
+/// each one, `f` is given the index of the function body it's in, its index
+/// in `at` if it's one of them, and the validator.
+fn validate_marked(
+    module: &mut Module,
+    at: &[(FunctionId, InstrSeqId, usize)],
+    mut f: impl FnMut(usize, Option<usize>, &FuncValidator<ValidatorResources>),
+) -> Result<()> {
+    // Only the instructions of interest may have a location, so that the
+    // code transform maps exactly their offsets back to them. The ends of
+    // sequences have locations too, so clear those as well.
+    let mut marks = IdHashMap::<crate::Function, HashMap<(InstrSeqId, usize), u32>>::default();
+    for (i, (func, seq, index)) in at.iter().enumerate() {
+        marks
+            .entry(*func)
+            .or_default()
+            .insert((*seq, *index), i as u32);
+    }
+    let mut saved = Vec::new();
+    for (id, func) in module.funcs.iter_local_mut() {
+        let marks = marks.get(&id);
+        for seq in instr_seqs(func, func.entry_block()) {
+            let block = func.block_mut(seq);
+            let end = mem::take(&mut block.end);
+            let locs = block
+                .instrs
+                .iter_mut()
+                .enumerate()
+                .map(|(index, (_, loc))| {
+                    let mark = match marks.and_then(|m| m.get(&(seq, index))) {
+                        Some(i) => InstrLocId::new(*i),
+                        None => InstrLocId::default(),
+                    };
+                    mem::replace(loc, mark)
+                })
+                .collect::<Vec<_>>();
+            saved.push((id, seq, end, locs));
+        }
+    }
+
+    let customs = mem::take(&mut module.customs);
+    let config = module.config.clone();
+    module.config.preserve_code_transform = true;
+    module.config.generate_dwarf = false;
+    module.config.generate_build_id = false;
+    module.config.skip_name_section = true;
+    module.config.skip_producers_section = true;
+    let (wasm, transform, _) = module.emit_wasm_parts(false);
+    let features = module.config.get_wasmparser_wasm_features();
+    module.customs = customs;
+    module.config.preserve_code_transform = config.preserve_code_transform;
+    module.config.generate_dwarf = config.generate_dwarf;
+    module.config.generate_build_id = config.generate_build_id;
+    module.config.skip_name_section = config.skip_name_section;
+    module.config.skip_producers_section = config.skip_producers_section;
+
+    for (id, seq, end, locs) in saved {
+        let block = module
+            .funcs
+            .get_mut(id)
+            .kind
+            .unwrap_local_mut()
+            .block_mut(seq);
+        block.end = end;
+        for ((_, loc), original) in block.instrs.iter_mut().zip(locs) {
+            *loc = original;
+        }
+    }
+
+    let offsets = transform
+        .instruction_map
+        .iter()
+        .map(|(loc, offset)| (*offset, loc.data() as usize))
+        .collect::<HashMap<_, _>>();
+    let mut validator = Validator::new_with_features(features);
+    let mut bodies = 0..;
+    for payload in Parser::new(0).parse_all(&wasm) {
+        let payload = payload?;
+        let (func, body) = match validator.payload(&payload)? {
+            ValidPayload::Func(func, body) => (func, body),
+            _ => continue,
+        };
+        let index = bodies.next().unwrap();
+        let mut validator = func.into_validator(Default::default());
+        let mut reader = body.get_binary_reader();
+        validator.read_locals(&mut reader)?;
+        let mut reader = OperatorsReader::new(reader);
+        while !reader.eof() {
+            let offset = reader.original_position();
+            let op = reader.read()?;
+            f(index, offsets.get(&offset).copied(), &validator);
+            validator.op(offset, &op)?;
+        }
+    }
+    Ok(())
+}
+
+fn frame_operands<T: wasmparser::WasmModuleResources>(
+    validator: &wasmparser::FuncValidator<T>,
+) -> Operands {
+    let frame = validator.get_control_frame(0).unwrap();
+    if frame.unreachable {
+        return Operands::Unreachable;
+    }
+    let height = validator.operand_stack_height() as usize;
+    let mut types = Vec::with_capacity(height - frame.height);
+    for depth in (0..height - frame.height).rev() {
+        types.push(match validator.get_operand_type(depth) {
+            Some(Some(wasmparser::ValType::I32)) => ValType::I32,
+            Some(Some(wasmparser::ValType::I64)) => ValType::I64,
+            Some(Some(wasmparser::ValType::F32)) => ValType::F32,
+            Some(Some(wasmparser::ValType::F64)) => ValType::F64,
+            Some(Some(wasmparser::ValType::V128)) => ValType::V128,
+            Some(Some(wasmparser::ValType::Ref(_))) => return Operands::References,
+            Some(None) | None => return Operands::Unreachable,
+        });
+    }
+    Operands::Known(types)
+}
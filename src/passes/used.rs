@@ -5,7 +5,7 @@ use crate::ty::HeapType;
 use crate::{ConstExpr, Data, DataId, DataKind, Element, ExportItem, Function};
 use crate::{ElementId, ElementItems, ElementKind, Module, RefType, Tag, TagId, Type, TypeId};
 use crate::{FunctionId, FunctionKind, Global, GlobalId};
-use crate::{GlobalKind, Memory, MemoryId, Table, TableId};
+use crate::{GlobalKind, Memory, MemoryId, SymbolKind, Table, TableId};
 
 /// Set of all root used items in a wasm module.
 #[derive(Debug, Default)]
@@ -200,6 +200,40 @@ impl Used {
             }
         }
 
+        // Everything in the symbol table of a relocatable object may be
+        // referenced by other objects at link time.
+        if let Some(linking) = &module.linking {
+            for symbol in linking.symbols.iter() {
+                match &symbol.kind {
+                    SymbolKind::Function { func, .. } => {
+                        stack.push_func(*func);
+                    }
+                    SymbolKind::Global { global, .. } => {
+                        stack.push_global(*global);
+                    }
+                    SymbolKind::Tag { tag, .. } => {
+                        stack.push_tag(*tag);
+                    }
+                    SymbolKind::Table { table, .. } => {
+                        stack.push_table(*table);
+                    }
+                    SymbolKind::Data {
+                        definition: Some(def),
+                        ..
+                    } => {
+                        stack.push_data(def.data);
+                    }
+                    SymbolKind::Data { .. } | SymbolKind::Section { .. } => {}
+                }
+            }
+            for segment in linking.segments.iter() {
+                stack.push_data(segment.data);
+            }
+            for reloc in linking.data_relocs.iter() {
+                stack.push_data(reloc.data);
+            }
+        }
+
         // And finally ask custom sections for their roots
         for (_id, section) in module.customs.iter() {
             section.add_gc_roots(&mut stack);